@@ -0,0 +1,34 @@
+;; Test plugin for the `wasm-plugins` feature. It claims `.vault` files,
+;; imports them as Bitwarden JSON unchanged and exports the adapter input
+;; as-is, which shows what the host passes to plugins.
+(module
+  (import "extism:host/env" "input_offset" (func $input_offset (result i64)))
+  (import "extism:host/env" "input_length" (func $input_length (result i64)))
+  (import "extism:host/env" "alloc" (func $alloc (param i64) (result i64)))
+  (import "extism:host/env" "store_u8" (func $store_u8 (param i64 i32)))
+  (import "extism:host/env" "output_set" (func $output_set (param i64 i64)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "{\"name\":\"passthrough\",\"description\":\"Bitwarden JSON under another extension (test plugin)\",\"extensions\":[\"vault\"]}")
+
+  (func (export "metadata") (result i32)
+    (local $offset i64)
+    (local $i i32)
+    (local.set $offset (call $alloc (i64.const 114)))
+    (block $done
+      (loop $copy
+        (br_if $done (i32.ge_u (local.get $i) (i32.const 114)))
+        (call $store_u8
+          (i64.add (local.get $offset) (i64.extend_i32_u (local.get $i)))
+          (i32.load8_u (local.get $i)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $copy)))
+    (call $output_set (local.get $offset) (i64.const 114))
+    (i32.const 0))
+
+  (func (export "import") (result i32)
+    (call $output_set (call $input_offset) (call $input_length))
+    (i32.const 0))
+
+  (func (export "export") (result i32)
+    (call $output_set (call $input_offset) (call $input_length))
+    (i32.const 0)))
@@ -0,0 +1,247 @@
+//! Breached-password checks against Have I Been Pwned's Pwned Passwords.
+//!
+//! Passwords are looked up by hash, either in an offline corpus (the SHA-1 or
+//! NTLM downloads, `HASH` or `HASH:COUNT` per line) or through the range API.
+//! The API only ever sees the first five hex digits of a password's SHA-1
+//! (k-anonymity), and asks for padded responses so their size gives nothing
+//! away either. The vault's passwords are hashed first and the corpus is read
+//! a line at a time against them, so only the matches are kept in memory,
+//! however large the download.
+
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use tracing::info;
+use zeroize::Zeroizing;
+
+const RANGE_API: &str = "https://api.pwnedpasswords.com/range";
+
+/// Where `--hibp-check` and the hygiene score look passwords up.
+pub(crate) enum BreachSource {
+    /// A corpus of SHA-1 (40 hex digits) or NTLM (32 hex digits) hashes,
+    /// `HASH` or `HASH:COUNT` per line; the two kinds may be mixed.
+    Corpus(PathBuf),
+    RangeApi,
+}
+
+/// One corpus line: a SHA-1 or NTLM hash and the breach count, if given.
+enum CorpusHash {
+    Sha1([u8; 20]),
+    Ntlm([u8; 16]),
+}
+
+fn parse_corpus_line(line: &str, path: &Path) -> Result<(CorpusHash, Option<u64>)> {
+    let (hash, count) = match line.split_once(':') {
+        Some((hash, count)) => (hash, Some(count)),
+        None => (line, None),
+    };
+    let count = count
+        .map(|count| count.trim().parse::<u64>())
+        .transpose()
+        .with_context(|| format!("invalid count in {:?} in {}", line, path.display()))?;
+    let invalid = || {
+        format!(
+            "invalid hash {:?} in {} (expected SHA-1 or NTLM hex)",
+            hash,
+            path.display()
+        )
+    };
+    let hash = match hash.len() {
+        40 => {
+            let mut bytes = [0u8; 20];
+            hex::decode_to_slice(hash, &mut bytes).with_context(invalid)?;
+            CorpusHash::Sha1(bytes)
+        }
+        32 => {
+            let mut bytes = [0u8; 16];
+            hex::decode_to_slice(hash, &mut bytes).with_context(invalid)?;
+            CorpusHash::Ntlm(bytes)
+        }
+        _ => anyhow::bail!(invalid()),
+    };
+    Ok((hash, count))
+}
+
+/// Reads the corpus at `path` a line at a time and returns the entries of
+/// `wanted` it lists. `ntlm` maps the NT hash of every wanted password to
+/// its SHA-1.
+fn scan_corpus(
+    path: &Path,
+    wanted: &HashSet<[u8; 20]>,
+    ntlm: &HashMap<[u8; 16], [u8; 20]>,
+) -> Result<Breached> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("failed to read breached hashes {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut breached = HashMap::new();
+    let mut by_ntlm = HashMap::new();
+    loop {
+        line.clear();
+        let read = reader
+            .read_line(&mut line)
+            .with_context(|| format!("failed to read breached hashes {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_corpus_line(line, path)? {
+            (CorpusHash::Sha1(sha1), count) if wanted.contains(&sha1) => {
+                breached.insert(sha1, count);
+            }
+            (CorpusHash::Ntlm(hash), count) => {
+                if let Some(sha1) = ntlm.get(&hash) {
+                    by_ntlm.insert(*sha1, count);
+                }
+            }
+            _ => {}
+        }
+    }
+    // The SHA-1 entry wins when a corpus lists a password both ways.
+    for (sha1, count) in by_ntlm {
+        breached.entry(sha1).or_insert(count);
+    }
+    Ok(breached)
+}
+
+/// Breached passwords by their SHA-1 (see [`password_sha1`]), so that the
+/// map holds no copy of them, with how often each was seen in breaches when
+/// the source says.
+pub(crate) type Breached = HashMap<[u8; 20], Option<u64>>;
+
+pub(crate) fn password_sha1(password: &str) -> [u8; 20] {
+    Sha1::digest(password).into()
+}
+
+/// The breached ones among `passwords`.
+pub(crate) fn breached_passwords<'a>(
+    passwords: impl IntoIterator<Item = &'a str>,
+    source: &BreachSource,
+) -> Result<Breached> {
+    let mut by_prefix: BTreeMap<String, Vec<([u8; 20], String)>> = BTreeMap::new();
+    let mut hashed = HashSet::new();
+    let mut ntlm_hashes = HashMap::new();
+    for password in passwords {
+        let sha1 = password_sha1(password);
+        match source {
+            BreachSource::Corpus(_) => {
+                hashed.insert(sha1);
+                ntlm_hashes.insert(ntlm(password), sha1);
+            }
+            BreachSource::RangeApi => {
+                let hash = hex::encode_upper(sha1);
+                let (prefix, suffix) = hash.split_at(5);
+                let pending = by_prefix.entry(prefix.to_string()).or_default();
+                if !pending.iter().any(|(seen, _)| *seen == sha1) {
+                    pending.push((sha1, suffix.to_string()));
+                }
+            }
+        }
+    }
+
+    let mut breached = match source {
+        BreachSource::Corpus(path) => scan_corpus(path, &hashed, &ntlm_hashes)?,
+        BreachSource::RangeApi => HashMap::new(),
+    };
+    if !by_prefix.is_empty() {
+        info!(
+            "Checking passwords against Pwned Passwords ({} range request(s))",
+            by_prefix.len()
+        );
+    }
+    for (prefix, pending) in by_prefix {
+        let counts = fetch_range(&prefix)?;
+        for (sha1, suffix) in pending {
+            if let Some(count) = counts.get(&suffix) {
+                breached.insert(sha1, Some(*count));
+            }
+        }
+    }
+    Ok(breached)
+}
+
+fn fetch_range(prefix: &str) -> Result<HashMap<String, u64>> {
+    let url = format!("{}/{}", RANGE_API, prefix);
+    let response = ureq::get(&url)
+        .header("Add-Padding", "true")
+        .header("User-Agent", "bw-passport-dedup")
+        .call()
+        .with_context(|| format!("failed to query {}", url))?;
+    let mut body = String::new();
+    response
+        .into_body()
+        .into_reader()
+        .read_to_string(&mut body)
+        .with_context(|| format!("failed to read the response from {}", url))?;
+    Ok(parse_range(&body))
+}
+
+/// `SUFFIX:COUNT` lines; padding entries have a count of 0.
+fn parse_range(body: &str) -> HashMap<String, u64> {
+    body.lines()
+        .filter_map(|line| {
+            let (suffix, count) = line.trim().split_once(':')?;
+            let count = count.parse::<u64>().ok().filter(|count| *count > 0)?;
+            Some((suffix.to_ascii_uppercase(), count))
+        })
+        .collect()
+}
+
+/// The NT hash Windows stores: MD4 of the UTF-16LE password.
+fn ntlm(password: &str) -> [u8; 16] {
+    let utf16 = Zeroizing::new(
+        password
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>(),
+    );
+    md4::Md4::digest(&*utf16).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus_matches_sha1_and_ntlm_hashes() {
+        let path = std::env::temp_dir().join(format!("bw-dedup-breach-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:9659365\n\
+             \n\
+             8846F7EAEE8FB117AD06BDD830B7586C\n",
+        )
+        .unwrap();
+        let source = BreachSource::Corpus(path.clone());
+        let breached = breached_passwords(["password", "hunter2", "password"], &source).unwrap();
+        assert_eq!(
+            breached,
+            HashMap::from([(password_sha1("password"), Some(9659365))])
+        );
+
+        fs::write(&path, hex::encode(ntlm("hunter2"))).unwrap();
+        let breached = breached_passwords(["hunter2"], &source).unwrap();
+        assert_eq!(breached, HashMap::from([(password_sha1("hunter2"), None)]));
+
+        fs::write(&path, "not-a-hash\n").unwrap();
+        assert!(breached_passwords(["hunter2"], &source).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn range_responses_skip_padding() {
+        let counts = parse_range(
+            "1E4C9B93F3F0682250B6CF8331B7EE68FD8:3\r\n0018A45C4D1DEF81644B54AB7F969B88D65:0\r\n",
+        );
+        assert_eq!(
+            counts,
+            HashMap::from([("1E4C9B93F3F0682250B6CF8331B7EE68FD8".to_string(), 3)])
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Deduplicating a vault in place through the official `bw` CLI.
+//!
+//! `sync` lists the items the CLI is logged in to, runs the dedup pass on
+//! them in memory and deletes only the duplicates by ID, so the kept items
+//! keep their IDs, attachments and organization sharing. `bw delete item`
+//! moves an item to the trash, where `bw restore item` brings it back. Kept
+//! items that `--merge` changed are written back with `bw edit item` before
+//! anything is deleted; the edited item goes to `bw` on stdin, never in its
+//! arguments, which other local users can read. The [`Vault`] trait lets the server API client of
+//! `sync --server` stand in for the CLI.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bw_passport_dedup::item_str;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Where `sync` reads the vault from and applies its changes to.
+pub(crate) trait Vault {
+    /// The live items, decrypted, in the export shape.
+    fn list_items(&self) -> Result<Vec<Value>>;
+    fn apply(&self, action: &SyncAction) -> Result<()>;
+}
+
+/// The `bw` executable. It picks up `BW_SESSION` from the environment like
+/// it does in a shell.
+pub(crate) struct BwCli {
+    program: PathBuf,
+}
+
+/// One change to the live vault.
+#[derive(Debug, PartialEq)]
+pub(crate) enum SyncAction {
+    /// Replace a kept item with its merged version.
+    Edit {
+        id: String,
+        name: String,
+        item: Value,
+    },
+    /// Move a duplicate to the trash.
+    Delete { id: String, name: String },
+}
+
+impl BwCli {
+    pub(crate) fn new(program: &Path) -> Self {
+        Self {
+            program: program.to_path_buf(),
+        }
+    }
+
+    /// Pulls the latest vault from the server into the CLI's local cache,
+    /// which `list` reads from.
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.run(["sync"], None).map(drop)
+    }
+
+    /// Runs `bw` with `args`, feeding it `input` on stdin, and returns its
+    /// stdout; a non-zero exit fails with what it printed to stderr.
+    fn run<I, S>(&self, args: I, input: Option<&str>) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_os_string())
+            .collect::<Vec<_>>();
+        let command = args
+            .iter()
+            .take(2)
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut child = Command::new(&self.program)
+            .args(&args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {}", self.program.display()))?;
+        if let Some(input) = input {
+            // Dropped right after writing, so `bw` sees the end of its input.
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            stdin
+                .write_all(input.as_bytes())
+                .with_context(|| format!("failed to write to bw {}", command))?;
+        }
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("failed to run {}", self.program.display()))?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "bw {} failed ({}): {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl Vault for BwCli {
+    fn list_items(&self) -> Result<Vec<Value>> {
+        let output = self.run(["list", "items"], None)?;
+        serde_json::from_slice(&output).context("failed to parse the output of bw list items")
+    }
+
+    fn apply(&self, action: &SyncAction) -> Result<()> {
+        self.run(action.args(), action.input().as_deref()).map(drop)
+    }
+}
+
+impl SyncAction {
+    /// Arguments to `bw`.
+    fn args(&self) -> Vec<String> {
+        match self {
+            SyncAction::Edit { id, .. } => {
+                vec!["edit".to_string(), "item".to_string(), id.clone()]
+            }
+            SyncAction::Delete { id, .. } => {
+                vec!["delete".to_string(), "item".to_string(), id.clone()]
+            }
+        }
+    }
+
+    /// What `bw` reads on stdin: `edit` takes the item as base64-encoded
+    /// JSON, the form `bw encode` produces.
+    fn input(&self) -> Option<String> {
+        match self {
+            SyncAction::Edit { item, .. } => Some(BASE64.encode(item.to_string())),
+            SyncAction::Delete { .. } => None,
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            SyncAction::Edit { id, name, .. } => format!("Updated {:?} ({})", name, id),
+            SyncAction::Delete { id, name } => format!("Deleted {:?} ({})", name, id),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            SyncAction::Edit { name, .. } | SyncAction::Delete { name, .. } => name,
+        }
+    }
+}
+
+/// Turns a dedup of `original` into changes to the live vault: an edit for
+/// every kept item that differs from its listed version, then a deletion
+/// for every removed item. Also returns the removed items left out, each
+/// with the reason.
+pub(crate) fn plan_sync(
+    original: &[Value],
+    kept: &[Value],
+    removed: &[Value],
+) -> (Vec<SyncAction>, Vec<(String, &'static str)>) {
+    let listed = original
+        .iter()
+        .filter_map(|item| Some((item_str(item, "id")?, item)))
+        .collect::<HashMap<_, _>>();
+    let name = |item: &Value| item_str(item, "name").unwrap_or_else(|| "<unnamed>".to_string());
+
+    let mut actions = Vec::new();
+    for item in kept {
+        if let Some(id) = item_str(item, "id")
+            && listed.get(&id).is_some_and(|listed| *listed != item)
+        {
+            actions.push(SyncAction::Edit {
+                id,
+                name: name(item),
+                item: item.clone(),
+            });
+        }
+    }
+
+    let mut left_out = Vec::new();
+    for item in removed {
+        let has_attachments = item
+            .get("attachments")
+            .and_then(Value::as_array)
+            .is_some_and(|attachments| !attachments.is_empty());
+        match item_str(item, "id") {
+            None => left_out.push((name(item), "it has no id")),
+            Some(_) if has_attachments => left_out.push((
+                name(item),
+                "it has attachments, which would go to the trash with it; move them to the kept item first",
+            )),
+            Some(id) => actions.push(SyncAction::Delete {
+                id,
+                name: name(item),
+            }),
+        }
+    }
+    (actions, left_out)
+}
+
+/// A POSIX shell script that makes the planned changes, for review before
+/// running it (or instead of `--apply`).
+pub(crate) fn render_script(program: &Path, actions: &[SyncAction]) -> String {
+    let edits = actions
+        .iter()
+        .filter(|action| matches!(action, SyncAction::Edit { .. }))
+        .count();
+    let mut script = format!(
+        "#!/bin/sh\n\
+         # Written by bw-passport-dedup: {} edit(s), {} deletion(s).\n\
+         # Deleted items go to the trash; bring one back with `bw restore item <id>`.\n\
+         set -e\n",
+        edits,
+        actions.len() - edits
+    );
+    let program = shell_quote(&program.to_string_lossy());
+    for action in actions {
+        let args = action
+            .args()
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>();
+        let comment = action
+            .name()
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect::<String>();
+        // The item goes in a here-document: an argument would show up in
+        // the process list while `bw` runs. Base64 needs no quoting.
+        let (redirect, body) = match action.input() {
+            Some(input) => (" >/dev/null <<'EOF'", format!("{}\nEOF\n", input)),
+            None => ("", String::new()),
+        };
+        script.push_str(&format!(
+            "{} {}{}  # {}\n{}",
+            program,
+            args.join(" "),
+            redirect,
+            comment,
+            body
+        ));
+    }
+    script
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sync_plan_edits_merged_items_before_deleting_duplicates() {
+        let original = [
+            json!({"id": "a", "name": "Forum", "notes": null}),
+            json!({"id": "b", "name": "Forum", "notes": "pin 1"}),
+            json!({"id": "c", "name": "Bank's", "attachments": [{"id": "x"}]}),
+            json!({"id": "d", "name": "Mail"}),
+        ];
+        let kept = [
+            json!({"id": "a", "name": "Forum", "notes": "pin 1"}),
+            json!({"id": "d", "name": "Mail"}),
+        ];
+        let removed = [original[1].clone(), original[2].clone()];
+
+        let (actions, left_out) = plan_sync(&original, &kept, &removed);
+        assert_eq!(
+            actions,
+            [
+                SyncAction::Edit {
+                    id: "a".to_string(),
+                    name: "Forum".to_string(),
+                    item: kept[0].clone(),
+                },
+                SyncAction::Delete {
+                    id: "b".to_string(),
+                    name: "Forum".to_string(),
+                },
+            ]
+        );
+        assert_eq!(left_out.len(), 1);
+        assert_eq!(left_out[0].0, "Bank's");
+
+        let script = render_script(Path::new("bw"), &actions);
+        assert!(script.contains("# Written by bw-passport-dedup: 1 edit(s), 1 deletion(s)."));
+        let encoded = BASE64.encode(kept[0].to_string());
+        assert!(script.contains(&format!(
+            "'bw' 'edit' 'item' 'a' >/dev/null <<'EOF'  # Forum\n{}\nEOF\n",
+            encoded
+        )));
+        assert!(script.ends_with("'bw' 'delete' 'item' 'b'  # Forum\n"));
+        assert_eq!(shell_quote("Bank's"), r"'Bank'\''s'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edits_pass_the_item_on_stdin_not_in_the_arguments() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("bw-dedup-bw-cli-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("bw");
+        std::fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{0}/args'\ncat >> '{0}/stdin'\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        let item = json!({"id": "a", "login": {"password": "hunter2"}});
+        let edit = SyncAction::Edit {
+            id: "a".to_string(),
+            name: "Forum".to_string(),
+            item: item.clone(),
+        };
+        let encoded = BASE64.encode(item.to_string());
+        BwCli::new(&program).apply(&edit).unwrap();
+        // The script the same action renders to does the same.
+        let script = dir.join("sync.sh");
+        std::fs::write(&script, render_script(&program, &[edit])).unwrap();
+        let status = Command::new("sh").arg(&script).status().unwrap();
+        assert!(status.success());
+
+        let args = std::fs::read_to_string(dir.join("args")).unwrap();
+        assert_eq!(args, "edit item a\nedit item a\n");
+        let stdin = std::fs::read_to_string(dir.join("stdin")).unwrap();
+        assert_eq!(stdin, format!("{0}{0}\n", encoded));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+//! Config settings from `BW_DEDUP_*` environment variables, for jobs where
+//! variables are easier to inject than a file or a long command line.
+//!
+//! They apply on top of the config file and under the command-line flags.
+//! Each variable is named after the key it sets; lists are comma-separated.
+
+use anyhow::{Context, Result};
+use bw_passport_dedup::Config;
+use tracing::debug;
+
+/// How a variable's text becomes a TOML value.
+#[derive(Copy, Clone)]
+enum Kind {
+    Bool,
+    Integer,
+    String,
+    List,
+}
+
+/// A variable and the config key it sets.
+pub(crate) struct EnvVar {
+    pub(crate) name: &'static str,
+    pub(crate) path: &'static [&'static str],
+    kind: Kind,
+}
+
+const fn var(name: &'static str, path: &'static [&'static str], kind: Kind) -> EnvVar {
+    EnvVar { name, path, kind }
+}
+
+/// Every setting of `config.toml` but the tables of aliases, Android apps and
+/// custom equivalent-domain groups.
+pub(crate) const ENV_VARS: &[EnvVar] = &[
+    var("BW_DEDUP_KEEP", &["dedup", "keep"], Kind::String),
+    var("BW_DEDUP_PREFER", &["dedup", "prefer"], Kind::List),
+    var(
+        "BW_DEDUP_POLICY_KEYS",
+        &["dedup", "policy_keys"],
+        Kind::List,
+    ),
+    var(
+        "BW_DEDUP_POLICY_PATHS",
+        &["dedup", "policy_paths"],
+        Kind::List,
+    ),
+    var(
+        "BW_DEDUP_DOMAIN_GRANULARITY",
+        &["dedup", "domain_granularity"],
+        Kind::String,
+    ),
+    var("BW_DEDUP_SCOPE", &["dedup", "scope"], Kind::String),
+    var(
+        "BW_DEDUP_HASH_SECTIONS",
+        &["dedup", "hash_sections"],
+        Kind::List,
+    ),
+    var("BW_DEDUP_MERGE", &["dedup", "merge"], Kind::Bool),
+    var(
+        "BW_DEDUP_MAX_AUTO_REMOVE_PER_GROUP",
+        &["dedup", "max_auto_remove_per_group"],
+        Kind::Integer,
+    ),
+    var(
+        "BW_DEDUP_CREATION_WINDOW_SECS",
+        &["dedup", "creation_window_secs"],
+        Kind::Integer,
+    ),
+    var("BW_DEDUP_IGNORE_KEYS", &["ignore", "keys"], Kind::List),
+    var("BW_DEDUP_IGNORE_PATHS", &["ignore", "paths"], Kind::List),
+    var(
+        "BW_DEDUP_TRIM_STRINGS",
+        &["normalize", "trim_strings"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_LOWERCASE_STRINGS",
+        &["normalize", "lowercase_strings"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_SORT_URIS",
+        &["normalize", "sort_uris"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_USERNAME_FROM_NAME",
+        &["normalize", "username_from_name"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_STRIP_SCHEME",
+        &["normalize", "uri", "strip_scheme"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_STRIP_WWW",
+        &["normalize", "uri", "strip_www"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_STRIP_DEFAULT_PORT",
+        &["normalize", "uri", "strip_default_port"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_STRIP_TRAILING_SLASH",
+        &["normalize", "uri", "strip_trailing_slash"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_STRIP_PATH",
+        &["normalize", "uri", "strip_path"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_STRIP_QUERY",
+        &["normalize", "uri", "strip_query"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_GLOBAL_EQUIVALENT_DOMAINS",
+        &["normalize", "equivalent_domains", "global"],
+        Kind::Bool,
+    ),
+    var(
+        "BW_DEDUP_EXCLUDE_GLOBAL_DOMAINS",
+        &["normalize", "equivalent_domains", "exclude_global"],
+        Kind::List,
+    ),
+    var("BW_DEDUP_PRETTY", &["output", "pretty"], Kind::Bool),
+    var(
+        "BW_DEDUP_APPLY_FIELD_ALIASES",
+        &["output", "apply_field_aliases"],
+        Kind::Bool,
+    ),
+];
+
+impl Kind {
+    fn parse(self, text: &str) -> Result<toml::Value> {
+        let text = text.trim();
+        Ok(match self {
+            Kind::Bool => toml::Value::Boolean(match text.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => anyhow::bail!("expected true or false, not {:?}", text),
+            }),
+            Kind::Integer => toml::Value::Integer(
+                text.parse()
+                    .with_context(|| format!("expected a number, not {:?}", text))?,
+            ),
+            Kind::String => toml::Value::String(text.to_string()),
+            Kind::List => toml::Value::Array(
+                text.split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| toml::Value::String(entry.to_string()))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// Applies the variables `lookup` finds (the process environment outside
+/// tests) to `config`, in the order of [`ENV_VARS`].
+pub(crate) fn apply(mut config: Config, lookup: impl Fn(&str) -> Option<String>) -> Result<Config> {
+    for var in ENV_VARS {
+        let Some(text) = lookup(var.name) else {
+            continue;
+        };
+        let value = var
+            .kind
+            .parse(&text)
+            .with_context(|| format!("invalid ${}", var.name))?;
+        let mut root = toml::Value::try_from(&config).context("failed to convert the config")?;
+        let (key, parents) = var.path.split_last().expect("config paths are not empty");
+        let table = parents
+            .iter()
+            .try_fold(&mut root, |value, parent| value.get_mut(*parent))
+            .and_then(toml::Value::as_table_mut)
+            .with_context(|| format!("no [{}] table in the config", parents.join(".")))?;
+        table.insert(key.to_string(), value);
+        config = root
+            .try_into()
+            .with_context(|| format!("invalid ${}", var.name))?;
+        debug!("${} sets {}", var.name, var.path.join("."));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bw_passport_dedup::policy::{DedupKey, Keep, Preference};
+    use std::collections::HashMap;
+
+    fn apply_vars(vars: &[(&str, &str)]) -> Result<Config> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        apply(Config::default(), |name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn variables_set_config_keys_and_bad_values_name_the_variable() {
+        let config = apply_vars(&[
+            ("BW_DEDUP_KEEP", "newest,first"),
+            ("BW_DEDUP_PREFER", "with-totp, favorite"),
+            ("BW_DEDUP_POLICY_KEYS", "domain, username"),
+            ("BW_DEDUP_PRETTY", "yes"),
+            ("BW_DEDUP_STRIP_WWW", "1"),
+            ("BW_DEDUP_CREATION_WINDOW_SECS", "3600"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.dedup.keep.to_string(),
+            Keep::parse("newest,first").unwrap().to_string()
+        );
+        assert_eq!(
+            config.dedup.prefer,
+            [Preference::WithTotp, Preference::Favorite]
+        );
+        assert_eq!(
+            config.dedup.policy_keys,
+            [DedupKey::Domain, DedupKey::Username]
+        );
+        assert!(config.output.pretty);
+        assert!(config.normalize.uri.strip_www);
+        assert_eq!(config.dedup.creation_window_secs, Some(3600));
+        assert!(!config.normalize.trim_strings);
+
+        let error = apply_vars(&[("BW_DEDUP_PRETTY", "maybe")]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid $BW_DEDUP_PRETTY");
+        let error = format!(
+            "{:#}",
+            apply_vars(&[("BW_DEDUP_KEEP", "fresh")]).unwrap_err()
+        );
+        assert!(error.starts_with("invalid $BW_DEDUP_KEEP: "));
+        assert!(error.contains("unknown keep strategy \"fresh\""));
+
+        for var in ENV_VARS {
+            let value = match var.kind {
+                Kind::Bool => "true",
+                Kind::Integer => "1",
+                Kind::String | Kind::List => continue,
+            };
+            apply_vars(&[(var.name, value)]).unwrap();
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Equivalent domains: registrable domains that belong to one site.
+//!
+//! Bitwarden autofills a login on any domain of its group (`amazon.de` for
+//! an `amazon.com` login), and `[normalize.equivalent_domains]` lets the
+//! `domain` policy key compare the same way. [`GLOBAL_EQUIVALENT_DOMAINS`]
+//! is a snapshot of the global groups Bitwarden ships; custom groups come
+//! from the config or `--equivalent-domains`.
+
+use serde::{Deserialize, Serialize};
+
+/// Bitwarden's global equivalent-domain groups. The first domain of a group
+/// is the name its members are compared by.
+pub const GLOBAL_EQUIVALENT_DOMAINS: &[&[&str]] = &[
+    &["ameritrade.com", "tdameritrade.com"],
+    &["bankofamerica.com", "bofa.com", "mbna.com", "usecfo.com"],
+    &["sprint.com", "sprintpcs.com", "nextel.com"],
+    &["google.com", "youtube.com", "gmail.com"],
+    &["apple.com", "icloud.com"],
+    &["wellsfargo.com", "wf.com", "wellsfargoadvisors.com"],
+    &["mymerrill.com", "ml.com", "merrilledge.com"],
+    &[
+        "citi.com",
+        "accountonline.com",
+        "citibank.com",
+        "citicards.com",
+        "citibankonline.com",
+    ],
+    &[
+        "cnet.com",
+        "cnet.tv",
+        "com.com",
+        "download.com",
+        "news.com",
+        "search.com",
+        "upload.com",
+    ],
+    &[
+        "gap.com",
+        "bananarepublic.com",
+        "oldnavy.com",
+        "piperlime.com",
+    ],
+    &[
+        "microsoft.com",
+        "bing.com",
+        "hotmail.com",
+        "live.com",
+        "msn.com",
+        "passport.net",
+        "windows.com",
+        "microsoftonline.com",
+        "office.com",
+        "office365.com",
+        "microsoftstore.com",
+        "xbox.com",
+        "azure.com",
+        "windowsazure.com",
+    ],
+    &["united.com", "ua2go.com", "ual.com", "unitedwifi.com"],
+    &["yahoo.com", "overture.com"],
+    &["zonealarm.com", "zonelabs.com"],
+    &["paypal.com", "paypal-search.com"],
+    &["avon.com", "youravon.com"],
+    &["1800contacts.com", "800contacts.com"],
+    &[
+        "amazon.com",
+        "amazon.ae",
+        "amazon.ca",
+        "amazon.co.jp",
+        "amazon.co.uk",
+        "amazon.com.au",
+        "amazon.com.be",
+        "amazon.com.br",
+        "amazon.com.mx",
+        "amazon.com.tr",
+        "amazon.de",
+        "amazon.es",
+        "amazon.fr",
+        "amazon.in",
+        "amazon.it",
+        "amazon.nl",
+        "amazon.pl",
+        "amazon.sa",
+        "amazon.se",
+        "amazon.sg",
+    ],
+    &["cox.com", "cox.net", "coxbusiness.com"],
+    &["norton.com", "mynortonaccount.com"],
+    &["verizon.com", "verizon.net"],
+    &["rakuten.com", "buy.com"],
+    &["siriusxm.com", "sirius.com"],
+    &[
+        "ea.com",
+        "origin.com",
+        "play4free.com",
+        "tiberiumalliance.com",
+    ],
+    &[
+        "atlassian.com",
+        "bitbucket.org",
+        "trello.com",
+        "statuspage.io",
+        "atlassian.net",
+        "jira.com",
+    ],
+    &["steampowered.com", "steamcommunity.com", "steamgames.com"],
+    &["chart.io", "chartio.com"],
+    &["gotomeeting.com", "citrixonline.com"],
+    &["gogoair.com", "gogoinflight.com"],
+    &["oracle.com", "mysql.com"],
+    &["discover.com", "discovercard.com"],
+    &["dcu.org", "dcu-online.org"],
+    &["healthcare.gov", "cuidadodesalud.gov", "cms.gov"],
+    &["pepco.com", "pepcoholdings.com"],
+    &["century21.com", "21online.com"],
+    &["comcast.com", "comcast.net", "xfinity.com"],
+    &["cricketwireless.com", "aiowireless.com"],
+    &["mtb.com", "mandtbank.com"],
+    &["dropbox.com", "getdropbox.com"],
+    &["snapfish.com", "snapfish.ca"],
+    &["alibaba.com", "aliexpress.com", "aliyun.com", "net.cn"],
+    &["playstation.com", "sonyentertainmentnetwork.com"],
+    &["zendesk.com", "zopim.com"],
+    &["autodesk.com", "tinkercad.com"],
+    &["mi.com", "xiaomi.com"],
+    &["facebook.com", "messenger.com"],
+    &["poste.it", "postepay.it"],
+    &["skysports.com", "skybet.com", "skyvegas.com"],
+    &[
+        "disney.com",
+        "disneymoviesanywhere.com",
+        "go.com",
+        "dadt.com",
+        "disneyplus.com",
+    ],
+    &["pokemon.com", "pokemon-gl.com"],
+    &["sears.com", "shld.net"],
+    &["intuit.com", "turbotax.com"],
+    &["shopify.com", "myshopify.com"],
+    &[
+        "ebay.com",
+        "ebay.at",
+        "ebay.be",
+        "ebay.ca",
+        "ebay.ch",
+        "ebay.co.uk",
+        "ebay.com.au",
+        "ebay.com.hk",
+        "ebay.com.sg",
+        "ebay.de",
+        "ebay.es",
+        "ebay.fr",
+        "ebay.ie",
+        "ebay.in",
+        "ebay.it",
+        "ebay.nl",
+        "ebay.pl",
+    ],
+    &["schwab.com", "schwabplan.com"],
+    &["tesla.com", "teslamotors.com"],
+    &[
+        "morganstanley.com",
+        "morganstanleyclientserv.com",
+        "stockplanconnect.com",
+        "ms.com",
+    ],
+    &["taxact.com", "taxactonline.com"],
+    &[
+        "wikipedia.org",
+        "mediawiki.org",
+        "wikibooks.org",
+        "wikidata.org",
+        "wikimedia.org",
+        "wikinews.org",
+        "wikiquote.org",
+        "wikisource.org",
+        "wikiversity.org",
+        "wikivoyage.org",
+        "wiktionary.org",
+    ],
+    &[
+        "stackexchange.com",
+        "stackoverflow.com",
+        "superuser.com",
+        "serverfault.com",
+        "askubuntu.com",
+        "mathoverflow.net",
+        "stackapps.com",
+    ],
+    &["docusign.com", "docusign.net"],
+    &[
+        "envato.com",
+        "themeforest.net",
+        "codecanyon.net",
+        "videohive.net",
+        "audiojungle.net",
+        "graphicriver.net",
+        "photodune.net",
+        "3docean.net",
+    ],
+    &["opendns.com", "dnsomatic.com", "umbrella.com"],
+    &["ui.com", "ubnt.com"],
+    &["discord.com", "discordapp.com"],
+    &["netcup.de", "netcup.eu", "customercontrolpanel.de"],
+    &["proton.me", "protonmail.com", "protonvpn.com"],
+    &["ubisoft.com", "ubi.com"],
+    &["wise.com", "transferwise.com"],
+];
+
+/// `[normalize.equivalent_domains]`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EquivalentDomains {
+    /// Use [`GLOBAL_EQUIVALENT_DOMAINS`].
+    pub global: bool,
+    /// Leave out the global groups that contain any of these domains.
+    pub exclude_global: Vec<String>,
+    /// Custom groups, which win over the global ones for domains in both.
+    pub groups: Vec<Vec<String>>,
+}
+
+impl EquivalentDomains {
+    pub fn is_enabled(&self) -> bool {
+        self.global || !self.groups.is_empty()
+    }
+
+    /// The domain a registrable domain is compared by: the first one of its
+    /// group, or `None` when it is in no group.
+    pub fn canonical(&self, domain: &str) -> Option<String> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let custom = self
+            .groups
+            .iter()
+            .find(|group| {
+                group
+                    .iter()
+                    .any(|member| member.eq_ignore_ascii_case(&domain))
+            })
+            .and_then(|group| group.first())
+            .map(|first| first.to_ascii_lowercase());
+        if custom.is_some() || !self.global {
+            return custom;
+        }
+        GLOBAL_EQUIVALENT_DOMAINS
+            .iter()
+            .filter(|group| {
+                !group.iter().any(|member| {
+                    self.exclude_global
+                        .iter()
+                        .any(|excluded| excluded.eq_ignore_ascii_case(member))
+                })
+            })
+            .find(|group| group.contains(&domain.as_str()))
+            .map(|group| group[0].to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_groups_win_over_global_ones() {
+        let mut domains = EquivalentDomains::default();
+        assert!(!domains.is_enabled());
+        assert_eq!(domains.canonical("amazon.de"), None);
+
+        domains.global = true;
+        assert_eq!(
+            domains.canonical("Amazon.co.uk."),
+            Some("amazon.com".to_string())
+        );
+        assert_eq!(domains.canonical("example.com"), None);
+
+        domains.groups = vec![vec!["Amazon.de".to_string(), "amazon.at".to_string()]];
+        assert_eq!(
+            domains.canonical("amazon.at"),
+            Some("amazon.de".to_string())
+        );
+        assert_eq!(
+            domains.canonical("amazon.de"),
+            Some("amazon.de".to_string())
+        );
+        assert_eq!(
+            domains.canonical("amazon.fr"),
+            Some("amazon.com".to_string())
+        );
+
+        domains.exclude_global = vec!["amazon.com".to_string()];
+        assert_eq!(domains.canonical("amazon.fr"), None);
+        assert_eq!(
+            domains.canonical("gmail.com"),
+            Some("google.com".to_string())
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Bundled sample exports with golden outputs (`test-fixtures` feature).
+//!
+//! `verify-fixtures` runs each sample through the dedup pipeline with the
+//! built-in default config and compares the result with the expected output
+//! stored next to it, so packagers and contributors can check a build or a
+//! behavior change against realistic data. `--bless` rewrites the expected
+//! files in the source tree after an intended change. `cargo test` runs
+//! the same comparison.
+
+// Only the comparison itself is used when built for `cargo test` alone.
+#![cfg_attr(not(feature = "test-fixtures"), allow(dead_code))]
+
+use crate::{
+    Config, IgnoreKeys, check_input_sanity, formats, items_at_mut, locate_items_path,
+    parse_ignore_paths,
+};
+use anyhow::{Context, Result};
+use bw_passport_dedup::{check_invariants, dedup_items};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+struct Fixture {
+    /// File name under `fixtures/`; its extension selects the format.
+    file: &'static str,
+    input: &'static str,
+    expected_file: &'static str,
+    expected: &'static str,
+}
+
+macro_rules! fixture {
+    ($file:literal, $expected:literal) => {
+        Fixture {
+            file: $file,
+            input: include_str!(concat!("../fixtures/", $file)),
+            expected_file: $expected,
+            expected: include_str!(concat!("../fixtures/", $expected)),
+        }
+    };
+}
+
+const FIXTURES: &[Fixture] = &[
+    fixture!("personal.json", "personal.expected.json"),
+    fixture!("org.json", "org.expected.json"),
+    fixture!("encrypted.json", "encrypted.expected.json"),
+    fixture!("mixed.json", "mixed.expected.json"),
+    #[cfg(feature = "bitwarden-csv")]
+    fixture!("personal.csv", "personal.expected.csv"),
+];
+
+pub(crate) fn run_verify_fixtures(bless: bool) -> Result<()> {
+    let mut failed = 0usize;
+    for fixture in FIXTURES {
+        let (actual, summary) = run_fixture(fixture)
+            .with_context(|| format!("fixture {} failed to run", fixture.file))?;
+
+        if bless {
+            let path = fixtures_dir().join(fixture.expected_file);
+            fs::write(&path, &actual)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            println!("blessed {} ({})", fixture.file, summary);
+        } else if actual == fixture.expected {
+            println!("ok      {} ({})", fixture.file, summary);
+        } else {
+            failed += 1;
+            println!("FAILED  {} ({})", fixture.file, summary);
+            print_first_difference(fixture.expected, &actual);
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} fixture(s) differ from their expected output",
+            failed,
+            FIXTURES.len()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the rendered output and a short "N -> M items" summary.
+fn run_fixture(fixture: &Fixture) -> Result<(String, String)> {
+    let path = Path::new(fixture.file);
+    let mut root = formats::import_adapter_for(path).import(fixture.input)?;
+    let items_path = locate_items_path(&root, None)?;
+    let items = items_at_mut(&mut root, &items_path)
+        .map(std::mem::take)
+        .unwrap_or_default();
+    check_input_sanity(&items)?;
+
+    let config = Config::default();
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let input = items.clone();
+    let outcome = dedup_items(items, &config, &ignore_keys, &ignore_paths);
+    check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths)?;
+
+    let summary = format!("{} -> {} items", input.len(), outcome.kept.len());
+    if let Some(slot) = items_at_mut(&mut root, &items_path) {
+        *slot = outcome.kept;
+    }
+    let mut output = formats::export_adapter_for(path).export(&root, &items_path, true)?;
+    if !output.ends_with('\n') {
+        output.push('\n');
+    }
+    Ok((output, summary))
+}
+
+fn print_first_difference(expected: &str, actual: &str) {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) => break,
+            (expected, actual) if expected == actual => continue,
+            (expected, actual) => {
+                println!("        line {}:", line);
+                println!("        - {}", expected.unwrap_or("<end of file>"));
+                println!("        + {}", actual.unwrap_or("<end of file>"));
+                break;
+            }
+        }
+    }
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_match_expected_output() {
+        for fixture in FIXTURES {
+            let (actual, summary) = run_fixture(fixture).unwrap();
+            assert_eq!(actual, fixture.expected, "{} ({})", fixture.file, summary);
+        }
+    }
+}
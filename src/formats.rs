@@ -0,0 +1,1126 @@
+//! Import and export adapters for vault file formats.
+//!
+//! Every format is converted to and from the classic Bitwarden JSON export
+//! shape (`encrypted`, `folders`, `items`), which is what the rest of the tool
+//! works on. Adapters are registered in [`import_adapters`] and
+//! [`export_adapters`]; formats that need extra code or dependencies sit
+//! behind a cargo feature so the default build stays small. With the
+//! `wasm-plugins` feature, Extism plugins passed with `--plugin` are
+//! registered after the built-in adapters.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde::de::value::SeqAccessDeserializer;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+/// Reads a vault file into the classic Bitwarden export shape.
+pub(crate) trait ImportAdapter {
+    /// Name shown by `--list-formats`.
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    /// File extensions (lowercase, without the dot) this adapter claims.
+    fn extensions(&self) -> &'static [&'static str];
+    fn import(&self, contents: &str) -> Result<Value>;
+    /// Like [`ImportAdapter::import`], for formats that can parse straight
+    /// from the file instead of holding its text as well.
+    fn import_reader(&self, reader: &mut dyn Read) -> Result<Value> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        self.import(&contents)
+    }
+}
+
+/// Parses a Bitwarden JSON export from `reader`, handing each element of the
+/// top-level `items` array to `on_item` as soon as it is parsed instead of
+/// collecting them. Returns the rest of the export with `items` left empty;
+/// exports without a top-level `items` array (`bw list items` dumps,
+/// password-protected exports) come back whole.
+pub(crate) fn stream_items(
+    reader: impl Read,
+    on_item: &mut dyn FnMut(Value) -> Result<()>,
+) -> Result<Value> {
+    let mut failure = None;
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let root = Envelope {
+        on_item,
+        failure: &mut failure,
+    }
+    .deserialize(&mut deserializer);
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    let mut root = root.context("failed to parse JSON")?;
+    deserializer.end().context("failed to parse JSON")?;
+    adapt_export_version(&mut root)?;
+    Ok(root)
+}
+
+/// The export as a whole, for [`stream_items`].
+struct Envelope<'a> {
+    on_item: &'a mut dyn FnMut(Value) -> Result<()>,
+    /// The error `on_item` stopped the parse with.
+    failure: &'a mut Option<anyhow::Error>,
+}
+
+impl<'de> DeserializeSeed<'de> for Envelope<'_> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Envelope<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON object or array")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut root = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            let value = if key == "items" {
+                map.next_value_seed(Items {
+                    on_item: &mut *self.on_item,
+                    failure: &mut *self.failure,
+                })?
+            } else {
+                map.next_value()?
+            };
+            root.insert(key, value);
+        }
+        Ok(Value::Object(root))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Value, A::Error> {
+        Value::deserialize(SeqAccessDeserializer::new(seq))
+    }
+}
+
+/// The `items` array, whose elements go to `on_item` one by one.
+struct Items<'a> {
+    on_item: &'a mut dyn FnMut(Value) -> Result<()>,
+    failure: &'a mut Option<anyhow::Error>,
+}
+
+impl<'de> DeserializeSeed<'de> for Items<'_> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Items<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of items")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        while let Some(item) = seq.next_element::<Value>()? {
+            if let Err(err) = (self.on_item)(item) {
+                *self.failure = Some(err);
+                return Err(de::Error::custom("stopped reading the items"));
+            }
+        }
+        Ok(Value::Array(Vec::new()))
+    }
+}
+
+/// Writes a vault in the classic Bitwarden export shape to another format.
+pub(crate) trait ExportAdapter {
+    /// Name shown by `--list-formats`.
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    /// File extensions (lowercase, without the dot) this adapter claims.
+    fn extensions(&self) -> &'static [&'static str];
+    /// `items_path` is where `root` keeps its items (see `--items-path`).
+    fn export(&self, root: &Value, items_path: &[String], pretty: bool) -> Result<String>;
+}
+
+/// All import adapters compiled into this build. The first one is the
+/// fallback for unknown extensions.
+pub(crate) fn import_adapters() -> Vec<Box<dyn ImportAdapter>> {
+    #[allow(unused_mut)]
+    let mut adapters: Vec<Box<dyn ImportAdapter>> = vec![
+        Box::new(BitwardenJson),
+        #[cfg(feature = "bitwarden-csv")]
+        Box::new(csv::BitwardenCsv),
+    ];
+    #[cfg(feature = "wasm-plugins")]
+    adapters.extend(plugins::import_adapters());
+    adapters
+}
+
+/// All export adapters compiled into this build. The first one is the
+/// fallback for unknown extensions.
+pub(crate) fn export_adapters() -> Vec<Box<dyn ExportAdapter>> {
+    #[allow(unused_mut)]
+    let mut adapters: Vec<Box<dyn ExportAdapter>> = vec![
+        Box::new(BitwardenJson),
+        #[cfg(feature = "bitwarden-csv")]
+        Box::new(csv::BitwardenCsv),
+        Box::new(key_value::KeyValue::Env),
+        Box::new(key_value::KeyValue::SopsYaml),
+    ];
+    #[cfg(feature = "wasm-plugins")]
+    adapters.extend(plugins::export_adapters());
+    adapters
+}
+
+pub(crate) fn import_adapter_for(path: &Path) -> Box<dyn ImportAdapter> {
+    let extension = lowercase_extension(path);
+    let mut adapters = import_adapters();
+    let index = adapters
+        .iter()
+        .position(|adapter| adapter.extensions().contains(&extension.as_str()))
+        .unwrap_or(0);
+    adapters.swap_remove(index)
+}
+
+pub(crate) fn export_adapter_for(path: &Path) -> Box<dyn ExportAdapter> {
+    let extension = lowercase_extension(path);
+    let mut adapters = export_adapters();
+    let index = adapters
+        .iter()
+        .position(|adapter| adapter.extensions().contains(&extension.as_str()))
+        .unwrap_or(0);
+    adapters.swap_remove(index)
+}
+
+/// Looks an export adapter up by the name `--list-formats` shows.
+pub(crate) fn export_adapter_named(name: &str) -> Result<Box<dyn ExportAdapter>> {
+    let mut adapters = export_adapters();
+    match adapters
+        .iter()
+        .position(|adapter| adapter.name().eq_ignore_ascii_case(name))
+    {
+        Some(index) => Ok(adapters.swap_remove(index)),
+        None => {
+            let names = adapters
+                .iter()
+                .map(|adapter| adapter.name())
+                .collect::<Vec<_>>();
+            anyhow::bail!(
+                "unknown output format {:?} (expected one of: {})",
+                name,
+                names.join(", ")
+            )
+        }
+    }
+}
+
+fn lowercase_extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Export layouts stamped with a top-level `version` (or `formatVersion`)
+/// that this build can read. Unversioned exports use the classic layout and
+/// need no adapter; each known version maps its envelope onto that layout.
+const EXPORT_VERSIONS: &[ExportVersion] = &[ExportVersion {
+    version: "1",
+    adapt: adapt_export_v1,
+}];
+
+struct ExportVersion {
+    version: &'static str,
+    adapt: fn(&mut Map<String, Value>),
+}
+
+/// Bitwarden's own JSON export, including `bw list items` and API dumps.
+struct BitwardenJson;
+
+impl ImportAdapter for BitwardenJson {
+    fn name(&self) -> &'static str {
+        "bitwarden-json"
+    }
+
+    fn description(&self) -> &'static str {
+        "Bitwarden JSON export, bw list/serve dumps"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["json"]
+    }
+
+    fn import(&self, contents: &str) -> Result<Value> {
+        let mut root = serde_json::from_str(contents).context("failed to parse JSON")?;
+        adapt_export_version(&mut root)?;
+        Ok(root)
+    }
+
+    fn import_reader(&self, reader: &mut dyn Read) -> Result<Value> {
+        let mut root = serde_json::from_reader(reader).context("failed to parse JSON")?;
+        adapt_export_version(&mut root)?;
+        Ok(root)
+    }
+}
+
+impl ExportAdapter for BitwardenJson {
+    fn name(&self) -> &'static str {
+        "bitwarden-json"
+    }
+
+    fn description(&self) -> &'static str {
+        "Bitwarden JSON export, same layout as the input"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["json"]
+    }
+
+    fn export(&self, root: &Value, _items_path: &[String], pretty: bool) -> Result<String> {
+        let data = if pretty {
+            serde_json::to_string_pretty(root)?
+        } else {
+            serde_json::to_string(root)?
+        };
+        Ok(data)
+    }
+}
+
+/// Reads the envelope version marker, if the export has one.
+/// Reads the version marker, spelling numeric versions the way the table
+/// does so `1`, `1.0` and `"1.0"` all select the same adapter.
+fn export_version(root: &Value) -> Option<String> {
+    let version = root.get("version").or_else(|| root.get("formatVersion"))?;
+    let version = match version {
+        Value::String(version) => version.trim().to_string(),
+        Value::Number(version) => version.to_string(),
+        other => other.to_string(),
+    };
+    match version.parse::<f64>() {
+        Ok(number) if number.is_finite() && number.fract() == 0.0 && number >= 0.0 => {
+            Some(format!("{}", number as u64))
+        }
+        _ => Some(version),
+    }
+}
+
+/// Unknown versions are an error: a newer layout could keep an `items`
+/// array that means something else, and guessing wrong deletes the wrong
+/// items.
+fn adapt_export_version(root: &mut Value) -> Result<()> {
+    let Some(version) = export_version(root) else {
+        return Ok(());
+    };
+    let Some(format) = EXPORT_VERSIONS
+        .iter()
+        .find(|format| format.version == version)
+    else {
+        anyhow::bail!(
+            "unsupported export version {}, please file an issue at https://github.com/sguzman/bw-passsport-dedup/issues",
+            version
+        );
+    };
+    if let Some(root) = root.as_object_mut() {
+        (format.adapt)(root);
+    }
+    Ok(())
+}
+
+/// Version 1 keeps the classic body (`encrypted`, `folders`, `items`) next to
+/// the marker, so there is nothing to move.
+fn adapt_export_v1(_root: &mut Map<String, Value>) {}
+
+/// Bitwarden's CSV layout (`folder,favorite,type,name,notes,fields,reprompt,
+/// login_uri,login_username,login_password,login_totp`). It only holds logins
+/// and secure notes.
+#[cfg(feature = "bitwarden-csv")]
+mod csv {
+    use super::{ExportAdapter, ImportAdapter};
+    use anyhow::{Context, Result};
+    use serde_json::{Map, Value, json};
+
+    const COLUMNS: [&str; 11] = [
+        "folder",
+        "favorite",
+        "type",
+        "name",
+        "notes",
+        "fields",
+        "reprompt",
+        "login_uri",
+        "login_username",
+        "login_password",
+        "login_totp",
+    ];
+
+    pub(super) struct BitwardenCsv;
+
+    impl ImportAdapter for BitwardenCsv {
+        fn name(&self) -> &'static str {
+            "bitwarden-csv"
+        }
+
+        fn description(&self) -> &'static str {
+            "Bitwarden CSV export (logins and secure notes)"
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            &["csv"]
+        }
+
+        fn import(&self, contents: &str) -> Result<Value> {
+            let mut rows = parse_csv(contents)?.into_iter();
+            let header = rows.next().context("CSV file is empty")?;
+            let column = |name: &str| header.iter().position(|column| column == name);
+            let indexes = COLUMNS.map(column);
+
+            let mut folders: Vec<String> = Vec::new();
+            let mut items = Vec::new();
+            for row in rows {
+                if row.iter().all(String::is_empty) {
+                    continue;
+                }
+                let [
+                    folder,
+                    favorite,
+                    kind,
+                    name,
+                    notes,
+                    fields,
+                    reprompt,
+                    uri,
+                    username,
+                    password,
+                    totp,
+                ] = indexes.map(|index| {
+                    index
+                        .and_then(|index| row.get(index))
+                        .map(String::as_str)
+                        .unwrap_or_default()
+                });
+                let optional = |value: &str| {
+                    if value.is_empty() {
+                        Value::Null
+                    } else {
+                        Value::String(value.to_string())
+                    }
+                };
+
+                let folder_id = if folder.is_empty() {
+                    Value::Null
+                } else {
+                    let index = match folders.iter().position(|known| known == folder) {
+                        Some(index) => index,
+                        None => {
+                            folders.push(folder.to_string());
+                            folders.len() - 1
+                        }
+                    };
+                    Value::String(format!("csv-folder-{}", index + 1))
+                };
+
+                let mut item = Map::new();
+                item.insert("folderId".to_string(), folder_id);
+                item.insert("name".to_string(), Value::String(name.to_string()));
+                item.insert("notes".to_string(), optional(notes));
+                item.insert("favorite".to_string(), Value::Bool(favorite == "1"));
+                item.insert(
+                    "reprompt".to_string(),
+                    Value::from(reprompt.parse::<u64>().unwrap_or(0)),
+                );
+                let fields = fields
+                    .lines()
+                    .filter_map(|line| line.split_once(": "))
+                    .map(|(name, value)| json!({ "name": name, "value": value, "type": 0 }))
+                    .collect::<Vec<_>>();
+                if !fields.is_empty() {
+                    item.insert("fields".to_string(), Value::Array(fields));
+                }
+                if kind == "note" {
+                    item.insert("type".to_string(), Value::from(2));
+                    item.insert("secureNote".to_string(), json!({ "type": 0 }));
+                } else {
+                    let uris = uri
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|uri| !uri.is_empty())
+                        .map(|uri| json!({ "match": null, "uri": uri }))
+                        .collect::<Vec<_>>();
+                    item.insert("type".to_string(), Value::from(1));
+                    item.insert(
+                        "login".to_string(),
+                        json!({
+                            "uris": uris,
+                            "username": optional(username),
+                            "password": optional(password),
+                            "totp": optional(totp),
+                        }),
+                    );
+                }
+                items.push(Value::Object(item));
+            }
+
+            let folders = folders
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    json!({ "id": format!("csv-folder-{}", index + 1), "name": name })
+                })
+                .collect::<Vec<_>>();
+            Ok(json!({ "encrypted": false, "folders": folders, "items": items }))
+        }
+    }
+
+    impl ExportAdapter for BitwardenCsv {
+        fn name(&self) -> &'static str {
+            "bitwarden-csv"
+        }
+
+        fn description(&self) -> &'static str {
+            "Bitwarden CSV import file (logins and secure notes only)"
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            &["csv"]
+        }
+
+        fn export(&self, root: &Value, items_path: &[String], _pretty: bool) -> Result<String> {
+            let folder_names = root
+                .get("folders")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|folder| {
+                    Some((
+                        folder.get("id")?.as_str()?.to_string(),
+                        folder.get("name")?.as_str()?.to_string(),
+                    ))
+                })
+                .collect::<std::collections::HashMap<_, _>>();
+
+            let mut csv = COLUMNS.join(",");
+            csv.push('\n');
+            let items = crate::value_at_path(root, items_path)
+                .and_then(Value::as_array)
+                .context("no items array to write as CSV")?;
+            for item in items {
+                let text = |value: Option<&Value>| {
+                    value
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let kind = match item.get("type").and_then(Value::as_u64) {
+                    Some(1) => "login",
+                    Some(2) => "note",
+                    other => anyhow::bail!(
+                        "item {} has type {:?}, which Bitwarden CSV cannot hold; write JSON instead",
+                        text(item.get("id")),
+                        other
+                    ),
+                };
+                let login = item.get("login");
+                let uris = login
+                    .and_then(|login| login.get("uris"))
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|uri| uri.get("uri").and_then(Value::as_str))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let fields = item
+                    .get("fields")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .map(|field| {
+                        format!("{}: {}", text(field.get("name")), text(field.get("value")))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let folder = item
+                    .get("folderId")
+                    .and_then(Value::as_str)
+                    .and_then(|id| folder_names.get(id))
+                    .cloned()
+                    .unwrap_or_default();
+                let favorite = item.get("favorite").and_then(Value::as_bool) == Some(true);
+                let reprompt = item.get("reprompt").and_then(Value::as_u64).unwrap_or(0);
+
+                let cells = [
+                    folder,
+                    if favorite { "1" } else { "" }.to_string(),
+                    kind.to_string(),
+                    text(item.get("name")),
+                    text(item.get("notes")),
+                    fields,
+                    reprompt.to_string(),
+                    uris,
+                    text(login.and_then(|login| login.get("username"))),
+                    text(login.and_then(|login| login.get("password"))),
+                    text(login.and_then(|login| login.get("totp"))),
+                ];
+                let cells = cells.map(|cell| escape(&cell));
+                csv.push_str(&cells.join(","));
+                csv.push('\n');
+            }
+            Ok(csv)
+        }
+    }
+
+    fn escape(cell: &str) -> String {
+        if cell.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell.to_string()
+        }
+    }
+
+    /// RFC 4180 parser: quoted cells may hold commas, doubled quotes and
+    /// line breaks.
+    fn parse_csv(contents: &str) -> Result<Vec<Vec<String>>> {
+        let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        let mut cell = String::new();
+        let mut quoted = false;
+        let mut chars = contents.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    cell.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => cell.push(c),
+                (false, '"') if cell.is_empty() => quoted = true,
+                (false, ',') => row.push(std::mem::take(&mut cell)),
+                (false, '\r') if chars.peek() == Some(&'\n') => {}
+                (false, '\n') => {
+                    row.push(std::mem::take(&mut cell));
+                    rows.push(std::mem::take(&mut row));
+                }
+                (false, c) => cell.push(c),
+            }
+        }
+        if quoted {
+            anyhow::bail!("unterminated quoted cell in CSV");
+        }
+        if !cell.is_empty() || !row.is_empty() {
+            row.push(cell);
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parse_csv_handles_quotes_and_line_breaks() {
+            let rows = parse_csv(
+                "\u{feff}name,notes\r\n\"Bank, main\",\"say \"\"hi\"\"\nline 2\"\r\nlast,\n",
+            )
+            .unwrap();
+            assert_eq!(
+                rows,
+                [
+                    vec!["name", "notes"],
+                    vec!["Bank, main", "say \"hi\"\nline 2"],
+                    vec!["last", ""],
+                ]
+            );
+            assert!(parse_csv("a,\"open").is_err());
+        }
+    }
+}
+
+pub(crate) use key_value::{KeyValueOptions, set_key_value_options};
+
+/// Key/value files for secret managers: `NAME=password` lines for `.env`
+/// files, or a flat YAML mapping to encrypt with sops. Only logins with a
+/// password are written, one entry per login, named by a template over the
+/// item (`{name}`, `{username}`, `{domain}`, `{folder}`, `{id}`).
+mod key_value {
+    use super::ExportAdapter;
+    use anyhow::{Context, Result};
+    use serde_json::Value;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::OnceLock;
+
+    static OPTIONS: OnceLock<KeyValueOptions> = OnceLock::new();
+
+    /// `--kv-name-template` and `--kv-folder`.
+    #[derive(Debug)]
+    pub(crate) struct KeyValueOptions {
+        pub(crate) name_template: String,
+        /// Folder names to export from; empty exports every login.
+        pub(crate) folders: Vec<String>,
+    }
+
+    impl Default for KeyValueOptions {
+        fn default() -> Self {
+            Self {
+                name_template: "{name}".to_string(),
+                folders: Vec::new(),
+            }
+        }
+    }
+
+    /// Sets the options once per run, before anything is written.
+    pub(crate) fn set_key_value_options(options: KeyValueOptions) -> Result<()> {
+        if OPTIONS.set(options).is_err() {
+            anyhow::bail!("key/value export options were already set");
+        }
+        Ok(())
+    }
+
+    pub(super) enum KeyValue {
+        Env,
+        SopsYaml,
+    }
+
+    impl ExportAdapter for KeyValue {
+        fn name(&self) -> &'static str {
+            match self {
+                KeyValue::Env => "env",
+                KeyValue::SopsYaml => "sops-yaml",
+            }
+        }
+
+        fn description(&self) -> &'static str {
+            match self {
+                KeyValue::Env => "NAME=\"password\" lines for logins (see --kv-name-template)",
+                KeyValue::SopsYaml => "YAML mapping of names to passwords, to encrypt with sops",
+            }
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            match self {
+                KeyValue::Env => &["env"],
+                KeyValue::SopsYaml => &["yaml", "yml"],
+            }
+        }
+
+        fn export(&self, root: &Value, items_path: &[String], _pretty: bool) -> Result<String> {
+            let items = crate::value_at_path(root, items_path)
+                .and_then(Value::as_array)
+                .context("no items array to write as key/value pairs")?;
+            let options = OPTIONS.get_or_init(KeyValueOptions::default);
+            let pairs = key_value_pairs(root, items, options, matches!(self, KeyValue::Env));
+
+            let mut out = String::new();
+            if let KeyValue::SopsYaml = self {
+                out.push_str("# Plain text: encrypt with `sops --encrypt --in-place <file>`\n");
+            }
+            for (key, value) in pairs {
+                match self {
+                    KeyValue::Env => out.push_str(&format!("{}=\"{}\"\n", key, escape_env(&value))),
+                    // A JSON string is a valid YAML double-quoted scalar.
+                    KeyValue::SopsYaml => {
+                        out.push_str(&format!("{}: {}\n", key, Value::String(value)))
+                    }
+                }
+            }
+            Ok(out)
+        }
+    }
+
+    /// Names and passwords of the selected logins, in item order. Names that
+    /// come out the same get `_2`, `_3`, ... appended.
+    pub(super) fn key_value_pairs(
+        root: &Value,
+        items: &[Value],
+        options: &KeyValueOptions,
+        uppercase: bool,
+    ) -> Vec<(String, String)> {
+        let folder_names = root
+            .get("folders")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|folder| {
+                Some((
+                    folder.get("id")?.as_str()?.to_string(),
+                    folder.get("name")?.as_str()?.to_string(),
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut seen = HashSet::new();
+        let mut pairs = Vec::new();
+        for item in items
+            .iter()
+            .filter(|item| crate::item_type(item) == Some(1))
+        {
+            let Some(password) =
+                crate::login_password(item).filter(|password| !password.is_empty())
+            else {
+                continue;
+            };
+            let folder = item
+                .get("folderId")
+                .and_then(Value::as_str)
+                .and_then(|id| folder_names.get(id))
+                .map(String::as_str)
+                .unwrap_or_default();
+            if !options.folders.is_empty()
+                && !options
+                    .folders
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(folder))
+            {
+                continue;
+            }
+
+            let username = crate::extract_login_field(item, "username");
+            let domains = crate::extract_domains(item);
+            let text = |field: &str| item.get(field).and_then(Value::as_str).unwrap_or_default();
+            let name = options
+                .name_template
+                .replace("{name}", text("name"))
+                .replace("{username}", username.as_str().unwrap_or_default())
+                .replace(
+                    "{domain}",
+                    domains.first().and_then(Value::as_str).unwrap_or_default(),
+                )
+                .replace("{folder}", folder)
+                .replace("{id}", text("id"));
+            let base = key_name(&name, uppercase);
+            let mut key = base.clone();
+            let mut copy = 1;
+            while !seen.insert(key.clone()) {
+                copy += 1;
+                key = format!("{}_{}", base, copy);
+            }
+            pairs.push((key, password.to_string()));
+        }
+        pairs
+    }
+
+    /// Letters, digits and underscores only, never starting with a digit.
+    fn key_name(name: &str, uppercase: bool) -> String {
+        let mut key = String::new();
+        for c in name.chars() {
+            if c.is_ascii_alphanumeric() {
+                key.push(if uppercase {
+                    c.to_ascii_uppercase()
+                } else {
+                    c.to_ascii_lowercase()
+                });
+            } else if !key.is_empty() && !key.ends_with('_') {
+                key.push('_');
+            }
+        }
+        let key = key.trim_end_matches('_');
+        match key.chars().next() {
+            None => if uppercase { "ITEM" } else { "item" }.to_string(),
+            Some(first) if first.is_ascii_digit() => format!("_{}", key),
+            Some(_) => key.to_string(),
+        }
+    }
+
+    /// Escapes for a double-quoted value as dotenv parsers read it. A POSIX
+    /// shell's `source` agrees, except that it keeps `\n` as two characters.
+    fn escape_env(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '\\' | '"' | '$' | '`' => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                }
+                '\n' => escaped.push_str("\\n"),
+                _ => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+pub(crate) use plugins::load_plugins;
+
+/// Formats provided by Extism WebAssembly plugins.
+///
+/// A plugin exports `metadata`, returning
+/// `{"name": ..., "description": ..., "extensions": [...]}`, plus `import`
+/// (file contents in, classic export JSON out) and/or `export`
+/// (`{"root": ..., "items_path": [...], "pretty": bool}` in, file contents
+/// out). Plugins run without WASI, so they can't touch files or the network.
+#[cfg(feature = "wasm-plugins")]
+mod plugins {
+    use super::{ExportAdapter, ImportAdapter};
+    use anyhow::{Context, Result};
+    use extism::{Manifest, Plugin, Wasm};
+    use serde::Deserialize;
+    use serde_json::{Value, json};
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+
+    static PLUGINS: OnceLock<Vec<PluginFormat>> = OnceLock::new();
+
+    pub(super) struct PluginFormat {
+        path: PathBuf,
+        name: String,
+        description: String,
+        // Plugins stay loaded until the process exits, so the list is leaked
+        // once to fit the adapters' `'static` signature.
+        extensions: &'static [&'static str],
+        import: bool,
+        export: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct Metadata {
+        name: String,
+        #[serde(default)]
+        description: String,
+        extensions: Vec<String>,
+    }
+
+    /// Loads the plugins once per run, before any adapter is looked up.
+    pub(crate) fn load_plugins(paths: &[PathBuf]) -> Result<()> {
+        let formats = paths
+            .iter()
+            .map(|path| load_plugin(path))
+            .collect::<Result<Vec<_>>>()?;
+        if PLUGINS.set(formats).is_err() {
+            anyhow::bail!("plugins were already loaded");
+        }
+        Ok(())
+    }
+
+    pub(super) fn load_plugin(path: &Path) -> Result<PluginFormat> {
+        let mut plugin = instantiate(path)?;
+        let metadata = plugin
+            .call::<&str, &str>("metadata", "")
+            .with_context(|| format!("plugin {} has no usable metadata export", path.display()))?;
+        let metadata: Metadata = serde_json::from_str(metadata)
+            .with_context(|| format!("plugin {} returned invalid metadata", path.display()))?;
+        if metadata.extensions.is_empty() {
+            anyhow::bail!("plugin {} claims no file extensions", path.display());
+        }
+        let extensions = metadata
+            .extensions
+            .into_iter()
+            .map(|extension| -> &'static str {
+                extension
+                    .trim_start_matches('.')
+                    .to_ascii_lowercase()
+                    .leak()
+            })
+            .collect::<Vec<_>>()
+            .leak();
+        Ok(PluginFormat {
+            path: path.to_path_buf(),
+            name: metadata.name,
+            description: metadata.description,
+            extensions,
+            import: plugin.function_exists("import"),
+            export: plugin.function_exists("export"),
+        })
+    }
+
+    fn instantiate(path: &Path) -> Result<Plugin> {
+        Plugin::new(Manifest::new([Wasm::file(path)]), [], false)
+            .with_context(|| format!("failed to load plugin {}", path.display()))
+    }
+
+    fn loaded() -> impl Iterator<Item = &'static PluginFormat> {
+        PLUGINS.get().into_iter().flatten()
+    }
+
+    pub(super) fn import_adapters() -> impl Iterator<Item = Box<dyn ImportAdapter>> {
+        loaded()
+            .filter(|format| format.import)
+            .map(|format| Box::new(PluginAdapter(format)) as Box<dyn ImportAdapter>)
+    }
+
+    pub(super) fn export_adapters() -> impl Iterator<Item = Box<dyn ExportAdapter>> {
+        loaded()
+            .filter(|format| format.export)
+            .map(|format| Box::new(PluginAdapter(format)) as Box<dyn ExportAdapter>)
+    }
+
+    pub(super) struct PluginAdapter(pub(super) &'static PluginFormat);
+
+    impl ImportAdapter for PluginAdapter {
+        fn name(&self) -> &'static str {
+            &self.0.name
+        }
+
+        fn description(&self) -> &'static str {
+            &self.0.description
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            self.0.extensions
+        }
+
+        fn import(&self, contents: &str) -> Result<Value> {
+            let mut plugin = instantiate(&self.0.path)?;
+            let output = plugin
+                .call::<&str, &str>("import", contents)
+                .with_context(|| format!("plugin {} failed to import", self.0.name))?;
+            serde_json::from_str(output)
+                .with_context(|| format!("plugin {} returned invalid JSON", self.0.name))
+        }
+    }
+
+    impl ExportAdapter for PluginAdapter {
+        fn name(&self) -> &'static str {
+            &self.0.name
+        }
+
+        fn description(&self) -> &'static str {
+            &self.0.description
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            self.0.extensions
+        }
+
+        fn export(&self, root: &Value, items_path: &[String], pretty: bool) -> Result<String> {
+            let input = json!({ "root": root, "items_path": items_path, "pretty": pretty });
+            let mut plugin = instantiate(&self.0.path)?;
+            let output = plugin
+                .call::<&str, &str>("export", input.to_string().as_str())
+                .with_context(|| format!("plugin {} failed to export", self.0.name))?;
+            Ok(output.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn export_version_normalizes_numbers() {
+        assert_eq!(export_version(&json!({"version": 1})).as_deref(), Some("1"));
+        assert_eq!(
+            export_version(&json!({"version": 1.0})).as_deref(),
+            Some("1")
+        );
+        assert_eq!(
+            export_version(&json!({"formatVersion": " 1.0 "})).as_deref(),
+            Some("1")
+        );
+        assert_eq!(
+            export_version(&json!({"version": "2.1"})).as_deref(),
+            Some("2.1")
+        );
+        assert_eq!(export_version(&json!({"items": []})), None);
+    }
+
+    #[test]
+    fn items_are_streamed_out_of_the_export() {
+        let export = r#"{"encrypted": false, "items": [{"id": "a"}, {"id": "b"}], "folders": []}"#;
+        let mut seen = Vec::new();
+        let root = stream_items(export.as_bytes(), &mut |item| {
+            seen.push(item);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, [json!({"id": "a"}), json!({"id": "b"})]);
+        assert_eq!(
+            serde_json::to_string(&root).unwrap(),
+            r#"{"encrypted":false,"items":[],"folders":[]}"#
+        );
+
+        // Item-only dumps have no items array to stream and come back whole.
+        let root = stream_items(&b"[{\"id\": \"a\"}]"[..], &mut |_| unreachable!()).unwrap();
+        assert_eq!(root, json!([{"id": "a"}]));
+
+        let error = stream_items(export.as_bytes(), &mut |item| {
+            anyhow::ensure!(item["id"] == "a", "not a");
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "not a");
+        assert!(stream_items(&b"{\"items\": [{}"[..], &mut |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn unknown_export_versions_are_rejected() {
+        let mut root = json!({"version": "1.0", "items": [{"id": "a"}]});
+        let expected = root.clone();
+        adapt_export_version(&mut root).unwrap();
+        assert_eq!(root, expected);
+
+        let mut root = json!({"version": 7, "items": [{"id": "a"}]});
+        let error = adapt_export_version(&mut root).unwrap_err().to_string();
+        assert!(error.starts_with("unsupported export version 7, please file an issue"));
+        let export = r#"{"items": [{"id": "a"}], "formatVersion": 2}"#;
+        assert!(stream_items(export.as_bytes(), &mut |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn key_value_exports_name_logins_by_template() {
+        let root = json!({
+            "folders": [{"id": "f1", "name": "Homelab"}],
+            "items": [
+                {"type": 1, "name": "Grafana", "folderId": "f1",
+                 "login": {"username": "admin", "password": "pa$$\"word"}},
+                {"type": 1, "name": "grafana", "folderId": "f1",
+                 "login": {"username": "viewer", "password": "view"}},
+                {"type": 1, "name": "Bank", "login": {"password": "money"}},
+                {"type": 1, "name": "No password", "folderId": "f1", "login": {}},
+                {"type": 2, "name": "Note", "folderId": "f1"},
+            ],
+        });
+        let items = root["items"].as_array().unwrap();
+        let options = key_value::KeyValueOptions {
+            name_template: "{folder} {name}".to_string(),
+            folders: vec!["homelab".to_string()],
+        };
+        let pairs = key_value::key_value_pairs(&root, items, &options, true);
+        assert_eq!(
+            pairs,
+            [
+                ("HOMELAB_GRAFANA".to_string(), "pa$$\"word".to_string()),
+                ("HOMELAB_GRAFANA_2".to_string(), "view".to_string()),
+            ]
+        );
+
+        let env = key_value::KeyValue::Env
+            .export(&root, &["items".to_string()], false)
+            .unwrap();
+        assert!(env.starts_with("GRAFANA=\"pa\\$\\$\\\"word\"\n"));
+        assert!(env.contains("BANK=\"money\"\n"));
+        let yaml = key_value::KeyValue::SopsYaml
+            .export(&root, &["items".to_string()], false)
+            .unwrap();
+        assert!(yaml.contains("\ngrafana_2: \"view\"\n"));
+    }
+
+    #[cfg(feature = "wasm-plugins")]
+    #[test]
+    fn plugin_adapter_round_trips_through_wasm() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/plugins/passthrough.wat");
+        let format: &'static _ = Box::leak(Box::new(plugins::load_plugin(&path).unwrap()));
+        let adapter = plugins::PluginAdapter(format);
+        assert_eq!(ImportAdapter::name(&adapter), "passthrough");
+        assert_eq!(ImportAdapter::extensions(&adapter), &["vault"]);
+
+        let root = adapter.import(r#"{"items": [{"id": "a"}]}"#).unwrap();
+        assert_eq!(root, json!({"items": [{"id": "a"}]}));
+
+        let exported = adapter
+            .export(&root, &["items".to_string()], false)
+            .unwrap();
+        let exported: Value = serde_json::from_str(&exported).unwrap();
+        assert_eq!(exported["root"], root);
+        assert_eq!(exported["items_path"], json!(["items"]));
+    }
+}
@@ -0,0 +1,419 @@
+//! Comparison keys.
+//!
+//! An item's key is its normalized value (policy keys or the whole item
+//! minus ignored parts) serialized to a string; items with equal keys are
+//! duplicates.
+
+use crate::Config;
+use crate::normalize::{
+    IgnoreKeys, apply_field_aliases, canonicalize, normalize_login_uris, normalize_strings,
+    remove_ignored_keys, remove_path, retain_sections, sort_login_uris, strip_api_fields,
+};
+use crate::policy::{
+    DedupScope, build_policy_value, username_from_name, uses_policy_keys, with_username,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Version of the key semantics: bumped whenever a change to normalization
+/// or key building can group the same items differently. Files that
+/// remember groups across runs (state, conflicts, reports) record it.
+/// Files written before it was recorded count as version 1.
+///
+/// 2: URI match types (never-match URIs left out, prefix, exact and regex
+/// URIs compared whole by the `domain` key).
+/// 3: Android app URIs compared by package name instead of as hosts.
+/// 4: the `totp` key compares the normalized secret.
+pub const KEY_VERSION: u32 = 4;
+
+/// SHA-256 of a comparison key. The dedup pass groups by these so that it
+/// never holds more than one key string at a time.
+pub type KeyDigest = [u8; 32];
+
+pub fn key_digest(key: &str) -> KeyDigest {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Keys already hashed in this run, by a digest of the item's serialized
+/// content. The dedup pass and the report key the same items, and
+/// serializing an item is much cheaper than normalizing it. Checks of the
+/// dedup pass (`--self-check`, `--verify-idempotent`) take a fresh cache, so
+/// that they recompute every key instead of trusting the ones under test.
+///
+/// Each entry is the hasher with the key fed in, so that a creation-window
+/// suffix can still be appended. A cache is only valid for one config and
+/// set of ignore rules; it may be shared across item sets.
+#[derive(Debug, Default)]
+pub struct KeyCache {
+    hashers: RefCell<HashMap<KeyDigest, Sha256>>,
+}
+
+impl KeyCache {
+    pub fn len(&self) -> usize {
+        self.hashers.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashers.borrow().is_empty()
+    }
+
+    fn hasher(
+        &self,
+        item: &Value,
+        config: &Config,
+        ignore_keys: &IgnoreKeys,
+        ignore_paths: &[Vec<String>],
+    ) -> Sha256 {
+        let serialized = Zeroizing::new(serde_json::to_vec(item).unwrap_or_default());
+        let content: KeyDigest = Sha256::digest(&*serialized).into();
+        self.hashers
+            .borrow_mut()
+            .entry(content)
+            .or_insert_with(|| {
+                let key = Zeroizing::new(build_key(item, config, ignore_keys, ignore_paths));
+                Sha256::new_with_prefix(key.as_bytes())
+            })
+            .clone()
+    }
+}
+
+/// The dedup key of every item. With `creation_window_secs` set, items that
+/// share a key are further split wherever the gap between consecutive
+/// `creationDate`s exceeds the window, and the earliest date of each run is
+/// appended to the key. Items without a parseable `creationDate` never match.
+/// Keys can hold passwords and are wiped when dropped.
+pub fn build_keys(
+    items: &[Value],
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> Vec<Zeroizing<String>> {
+    let mut keys = items
+        .iter()
+        .map(|item| Zeroizing::new(build_key(item, config, ignore_keys, ignore_paths)))
+        .collect::<Vec<_>>();
+    if let Some(window) = config.dedup.creation_window_secs {
+        let plain = keys.iter().map(|key| key.as_str()).collect::<Vec<_>>();
+        let suffixes = creation_window_suffixes(items, &plain, window);
+        for (key, suffix) in keys.iter_mut().zip(suffixes) {
+            key.push_str(&suffix);
+        }
+    }
+    keys
+}
+
+/// [`key_digest`] of every key [`build_keys`] would return, without keeping
+/// the keys themselves, along with each key's creation-window suffix (empty
+/// without a window).
+pub fn build_key_digests(
+    items: &[Value],
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+    cache: &KeyCache,
+) -> (Vec<KeyDigest>, Vec<String>) {
+    let hashers = items
+        .iter()
+        .map(|item| cache.hasher(item, config, ignore_keys, ignore_paths))
+        .collect::<Vec<_>>();
+    let Some(window) = config.dedup.creation_window_secs else {
+        let digests = hashers
+            .into_iter()
+            .map(|hasher| hasher.finalize().into())
+            .collect();
+        return (digests, vec![String::new(); items.len()]);
+    };
+
+    // Hash the key first and the suffix once the windows are known.
+    let bases = hashers
+        .iter()
+        .map(|hasher| hasher.clone().finalize())
+        .collect::<Vec<_>>();
+    let suffixes = creation_window_suffixes(items, &bases, window);
+    let digests = hashers
+        .into_iter()
+        .zip(&suffixes)
+        .map(|(mut hasher, suffix)| {
+            hasher.update(suffix.as_bytes());
+            hasher.finalize().into()
+        })
+        .collect();
+    (digests, suffixes)
+}
+
+fn creation_window_suffixes<K: Ord>(items: &[Value], keys: &[K], window: u64) -> Vec<String> {
+    let window = chrono::Duration::seconds(i64::try_from(window).unwrap_or(i64::MAX));
+    let created = items.iter().map(creation_date).collect::<Vec<_>>();
+    let mut order = (0..items.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| keys[*a].cmp(&keys[*b]).then(created[*a].cmp(&created[*b])));
+
+    let mut suffixes = vec![String::new(); items.len()];
+    let mut previous: Option<usize> = None;
+    for index in order {
+        let joins = previous.is_some_and(|prev| {
+            keys[prev] == keys[index]
+                && matches!(
+                    (created[prev], created[index]),
+                    (Some(a), Some(b)) if b - a <= window
+                )
+        });
+        suffixes[index] = match (joins, previous, created[index]) {
+            (true, Some(prev), _) => suffixes[prev].clone(),
+            (_, _, Some(date)) => format!(" @created {}", date.to_rfc3339()),
+            (_, _, None) => format!(" @item {}", index),
+        };
+        previous = Some(index);
+    }
+    suffixes
+}
+
+fn creation_date(item: &Value) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    let date = item.get("creationDate")?.as_str()?;
+    chrono::DateTime::parse_from_rfc3339(date).ok()
+}
+
+/// The dedup key of a single item, before any creation-window suffix.
+pub fn build_key(
+    item: &Value,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> String {
+    let mut canonical = normalized_value(item, config, ignore_keys, ignore_paths);
+    let mut key = serde_json::to_string(&canonical).unwrap_or_default();
+    wipe_strings(&mut canonical);
+    key.push_str(&scope_suffix(item, config.dedup.scope));
+    key
+}
+
+/// Overwrites every string in `value` with zeros, so that the copies of
+/// passwords made while building a key do not linger in freed memory.
+fn wipe_strings(value: &mut Value) {
+    match value {
+        Value::String(string) => string.zeroize(),
+        Value::Array(values) => values.iter_mut().for_each(wipe_strings),
+        Value::Object(map) => map.values_mut().for_each(wipe_strings),
+        _ => {}
+    }
+}
+
+/// Keeps items in different folders or collections apart under
+/// [`DedupScope::Folder`] and [`DedupScope::Collection`]. Empty for the
+/// global scope, so its keys are unchanged.
+fn scope_suffix(item: &Value, scope: DedupScope) -> String {
+    match scope {
+        DedupScope::Global => String::new(),
+        DedupScope::Folder => format!(
+            "|folder:{}",
+            item.get("folderId")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+        ),
+        DedupScope::Collection => {
+            let mut collections = item
+                .get("collectionIds")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>();
+            collections.sort_unstable();
+            collections.dedup();
+            format!("|collections:{}", collections.join(","))
+        }
+    }
+}
+
+/// The value an item is compared by once the configured policy, ignore
+/// rules and normalization have been applied.
+pub fn normalized_value(
+    item: &Value,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> Value {
+    let filled;
+    let item = if config.normalize.username_from_name
+        && let Some(username) = username_from_name(item)
+    {
+        filled = with_username(item, username);
+        &filled
+    } else {
+        item
+    };
+    let rewritten;
+    let item = if config.normalize.rewrites_uris() {
+        let mut copy = item.clone();
+        normalize_login_uris(&mut copy, &config.normalize);
+        rewritten = copy;
+        &rewritten
+    } else {
+        item
+    };
+
+    if uses_policy_keys(item, config) {
+        let mut policy_value =
+            build_policy_value(item, &config.dedup, &config.normalize.equivalent_domains);
+        if config.normalize.sort_uris {
+            sort_login_uris(&mut policy_value);
+        }
+        normalize_strings(
+            &mut policy_value,
+            config.normalize.trim_strings,
+            config.normalize.lowercase_strings,
+        );
+        let canonical = canonicalize(&policy_value);
+        wipe_strings(&mut policy_value);
+        return canonical;
+    }
+
+    let mut working = item.clone();
+    apply_field_aliases(&mut working, &config.normalize.field_aliases);
+    retain_sections(&mut working, &config.dedup.hash_sections);
+    strip_api_fields(&mut working);
+    remove_ignored_keys(&mut working, ignore_keys, &mut Vec::new());
+    for path in ignore_paths {
+        remove_path(&mut working, path);
+    }
+    if config.normalize.sort_uris {
+        sort_login_uris(&mut working);
+    }
+    normalize_strings(
+        &mut working,
+        config.normalize.trim_strings,
+        config.normalize.lowercase_strings,
+    );
+    let canonical = canonicalize(&working);
+    wipe_strings(&mut working);
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::{IgnoreKeys, parse_path};
+    use crate::tests::login;
+    use serde_json::json;
+
+    #[test]
+    fn key_digests_match_the_digests_of_the_full_keys() {
+        let copy = |id: &str, password: &str, created: &str| {
+            let mut item = login(id, password, json!([]));
+            item["creationDate"] = json!(created);
+            item
+        };
+        let items = vec![
+            copy("a", "one", "2024-03-01T10:00:00Z"),
+            copy("b", "one", "2024-03-01T10:05:00Z"),
+            copy("c", "two", "2024-03-01T10:00:00Z"),
+            copy("d", "one", "2024-05-01T10:00:00Z"),
+            login("e", "one", json!([])),
+        ];
+
+        let mut config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = vec![parse_path("id")];
+        let cache = KeyCache::default();
+        for window in [None, Some(3600)] {
+            config.dedup.creation_window_secs = window;
+            let keys = build_keys(&items, &config, &ignore_keys, &ignore_paths);
+            let (digests, suffixes) =
+                build_key_digests(&items, &config, &ignore_keys, &ignore_paths, &cache);
+            let expected = keys.iter().map(|key| key_digest(key)).collect::<Vec<_>>();
+            assert_eq!(digests, expected);
+            for (key, suffix) in keys.iter().zip(&suffixes) {
+                assert!(key.ends_with(suffix.as_str()));
+            }
+            assert_eq!(digests[0], digests[1]);
+            assert_ne!(digests[0], digests[2]);
+            assert_eq!(digests[0] == digests[3], window.is_none());
+            assert_eq!(digests[0] == digests[4], window.is_none());
+        }
+        // The second pass reuses every key computed by the first.
+        assert_eq!(cache.len(), items.len());
+    }
+
+    #[test]
+    fn scopes_keep_other_folders_and_collections_apart() {
+        let copy = |id: &str, folder: Value, collections: Value| {
+            let mut item = login(id, "one", json!([]));
+            item["folderId"] = folder;
+            item["collectionIds"] = collections;
+            item
+        };
+        let items = [
+            copy("a", json!("personal"), json!(["c1", "c2"])),
+            copy("b", json!("shared"), json!(["c2", "c1"])),
+            copy("c", json!("personal"), json!(["c1"])),
+            copy("d", Value::Null, json!([])),
+        ];
+        let mut config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let same = |config: &Config| {
+            let keys = build_keys(&items, config, &ignore_keys, &[]);
+            [1, 2, 3].map(|other| keys[0] == keys[other])
+        };
+
+        assert_eq!(same(&config), [true, true, true]);
+        config.dedup.scope = DedupScope::Folder;
+        assert_eq!(same(&config), [false, true, false]);
+        config.dedup.scope = DedupScope::Collection;
+        assert_eq!(same(&config), [true, false, false]);
+    }
+
+    #[test]
+    fn uri_normalization_applies_to_domain_and_whole_item_keys() {
+        let site = |id: &str, uri: &str| {
+            let mut item = login(id, "one", json!([]));
+            item["login"]["uris"] = json!([{"uri": uri, "match": null}]);
+            item
+        };
+        let items = [
+            site("a", "https://example.com/"),
+            site("b", "http://www.example.com/login?next=/"),
+        ];
+        let mut config: Config = toml::from_str(
+            "[normalize.uri]\nstrip_scheme = true\nstrip_www = true\nstrip_path = true\nstrip_query = true",
+        )
+        .unwrap();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let same = |config: &Config| {
+            let keys = build_keys(&items, config, &ignore_keys, &[]);
+            keys[0] == keys[1]
+        };
+
+        assert!(same(&config));
+        config.dedup.policy_keys.clear();
+        assert!(same(&config));
+        config.normalize.uri.strip_www = false;
+        assert!(!same(&config));
+    }
+
+    #[test]
+    fn wiping_clears_every_string_and_leaves_the_key_intact() {
+        let item = login("a", "hunter2", json!([{"name": "pin", "value": "1234"}]));
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let key = build_key(&item, &config, &ignore_keys, &[]);
+        assert!(key.contains("hunter2"));
+
+        let mut value = normalized_value(&item, &config, &ignore_keys, &[]);
+        wipe_strings(&mut value);
+        let mut strings = Vec::new();
+        let mut pending = vec![&value];
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::String(string) => strings.push(string.as_str()),
+                Value::Array(values) => pending.extend(values),
+                Value::Object(map) => pending.extend(map.values()),
+                _ => {}
+            }
+        }
+        assert!(!strings.is_empty());
+        assert!(strings.iter().all(|string| string.is_empty()));
+        assert_eq!(build_key(&item, &config, &ignore_keys, &[]), key);
+    }
+}
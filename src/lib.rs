@@ -0,0 +1,929 @@
+//! Deduplicate Bitwarden vault exports.
+//!
+//! [`Deduper`] is the entry point for calling the dedup pass from other
+//! tools: configure it with its builder methods (or from a [`Config`] read
+//! from `config.toml`) and run it on a parsed export.
+//!
+//! ```no_run
+//! use bw_passport_dedup::{Deduper, policy::Keep};
+//!
+//! let mut vault: serde_json::Value =
+//!     serde_json::from_str(&std::fs::read_to_string("export.json")?)?;
+//! let report = Deduper::new().keep(Keep::NEWEST).merge(true).dedup(&mut vault)?;
+//! println!("removed {} of {} items", report.removed.len(), report.total_items);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The [`normalize`], [`policy`], [`keying`] and [`equivalent_domains`]
+//! modules hold the pieces the pass is built from; the CLI drives them
+//! directly for its extra passes.
+
+pub mod equivalent_domains;
+pub mod keying;
+pub mod normalize;
+pub mod policy;
+
+use anyhow::{Context, Result};
+use equivalent_domains::EquivalentDomains;
+use keying::{KeyCache, KeyDigest, build_key, build_key_digests, key_digest};
+use normalize::{
+    IgnoreConfig, IgnoreKeys, NormalizeConfig, UriNormalization, apply_field_aliases,
+    parse_ignore_paths, parse_path,
+};
+use policy::{
+    DedupConfig, DedupKey, DedupScope, DomainGranularity, Keep, PolicyPath, Preference, carry_over,
+    merge_item, should_replace,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+use tracing::trace;
+use zeroize::Zeroizing;
+
+/// Everything `config.toml` can set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub dedup: DedupConfig,
+    pub ignore: IgnoreConfig,
+    pub normalize: NormalizeConfig,
+    pub output: OutputConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub pretty: bool,
+    /// Also rename aliased custom fields in the written vault.
+    pub apply_field_aliases: bool,
+}
+
+/// The result of [`dedup_items`].
+#[derive(Debug)]
+pub struct DedupOutcome {
+    pub kept: Vec<Value>,
+    /// Parallel to `kept`: whether the item differs from its input version.
+    pub changed: Vec<bool>,
+    pub removed: Vec<Value>,
+    /// Comparison keys of the groups that had duplicates, sorted. They can
+    /// hold passwords, so they are wiped when dropped.
+    pub group_keys: Vec<Zeroizing<String>>,
+    /// Key digests of the groups kept whole (held by the caller or over
+    /// `max_auto_remove_per_group`), sorted.
+    pub held_keys: Vec<KeyDigest>,
+}
+
+/// A configured dedup pass, built up with chained setters and run on a
+/// parsed export with [`Deduper::dedup`]. Settings not touched keep the
+/// `config.toml` defaults.
+#[derive(Clone, Debug, Default)]
+pub struct Deduper {
+    config: Config,
+    items_path: Option<Vec<String>>,
+    self_check: bool,
+}
+
+/// What [`Deduper::dedup`] did to a vault.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DedupReport {
+    pub total_items: usize,
+    pub kept: usize,
+    /// The dropped items, in input order.
+    pub removed: Vec<Value>,
+    pub duplicate_groups: usize,
+    /// Groups left whole by `max_auto_remove_per_group`.
+    pub held_groups: usize,
+    /// Kept items that differ from their input (merged or renamed fields).
+    pub changed: usize,
+}
+
+impl Deduper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from a full config, e.g. one parsed from `config.toml`.
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn keep(mut self, keep: Keep) -> Self {
+        self.config.dedup.keep = keep;
+        self
+    }
+
+    /// Properties a survivor should have, consulted before the keep strategy.
+    pub fn prefer(mut self, preferences: impl IntoIterator<Item = Preference>) -> Self {
+        self.config.dedup.prefer = preferences.into_iter().collect();
+        self
+    }
+
+    /// Fields items are compared by; an empty list compares whole items.
+    pub fn policy_keys(mut self, keys: impl IntoIterator<Item = DedupKey>) -> Self {
+        self.config.dedup.policy_keys = keys.into_iter().collect();
+        self
+    }
+
+    /// Item paths compared along with the policy keys.
+    pub fn policy_paths(mut self, paths: impl IntoIterator<Item = PolicyPath>) -> Self {
+        self.config.dedup.policy_paths = paths.into_iter().collect();
+        self
+    }
+
+    /// Only treat items in the same folder or collections as duplicates.
+    pub fn scope(mut self, scope: DedupScope) -> Self {
+        self.config.dedup.scope = scope;
+        self
+    }
+
+    /// How much of a URI the `domain` policy key compares.
+    pub fn domain_granularity(mut self, granularity: DomainGranularity) -> Self {
+        self.config.dedup.domain_granularity = granularity;
+        self
+    }
+
+    /// Top-level sections compared in whole-item mode (empty = whole item).
+    pub fn hash_sections<S: Into<String>>(mut self, sections: impl IntoIterator<Item = S>) -> Self {
+        self.config.dedup.hash_sections = sections.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// `ignore.keys` patterns; replaces the defaults.
+    pub fn ignore_keys<S: Into<String>>(mut self, keys: impl IntoIterator<Item = S>) -> Self {
+        self.config.ignore.keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// `ignore.paths` entries, such as `login.totp`.
+    pub fn ignore_paths<S: Into<String>>(mut self, paths: impl IntoIterator<Item = S>) -> Self {
+        self.config.ignore.paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn merge(mut self, merge: bool) -> Self {
+        self.config.dedup.merge = merge;
+        self
+    }
+
+    pub fn trim_strings(mut self, trim: bool) -> Self {
+        self.config.normalize.trim_strings = trim;
+        self
+    }
+
+    pub fn lowercase_strings(mut self, lowercase: bool) -> Self {
+        self.config.normalize.lowercase_strings = lowercase;
+        self
+    }
+
+    pub fn sort_uris(mut self, sort: bool) -> Self {
+        self.config.normalize.sort_uris = sort;
+        self
+    }
+
+    pub fn username_from_name(mut self, enabled: bool) -> Self {
+        self.config.normalize.username_from_name = enabled;
+        self
+    }
+
+    /// Registrable domains the `domain` key treats as one site.
+    pub fn equivalent_domains(mut self, domains: EquivalentDomains) -> Self {
+        self.config.normalize.equivalent_domains = domains;
+        self
+    }
+
+    /// Parts of login URIs dropped before comparing (`[normalize.uri]`).
+    pub fn uri_normalization(mut self, uri: UriNormalization) -> Self {
+        self.config.normalize.uri = uri;
+        self
+    }
+
+    pub fn max_auto_remove_per_group(mut self, max: usize) -> Self {
+        self.config.dedup.max_auto_remove_per_group = Some(max);
+        self
+    }
+
+    pub fn creation_window(mut self, window: Duration) -> Self {
+        self.config.dedup.creation_window_secs = Some(window.as_secs());
+        self
+    }
+
+    /// Dotted path of the items array; by default the places in
+    /// [`ITEMS_PATH_CANDIDATES`] are tried in order.
+    pub fn items_path(mut self, path: &str) -> Self {
+        self.items_path = Some(parse_path(path));
+        self
+    }
+
+    /// Verify the dedup invariants (nothing lost, no kept duplicates) before
+    /// touching the vault.
+    pub fn self_check(mut self, enabled: bool) -> Self {
+        self.self_check = enabled;
+        self
+    }
+
+    /// Deduplicates the items array of `root` in place. On error `root` is
+    /// left unchanged.
+    pub fn dedup(&self, root: &mut Value) -> Result<DedupReport> {
+        let items_path = match &self.items_path {
+            Some(path) => path.clone(),
+            None => ITEMS_PATH_CANDIDATES
+                .iter()
+                .map(|candidate| parse_path(candidate))
+                .find(|path| value_at_path(root, path).is_some_and(Value::is_array))
+                .with_context(|| {
+                    format!(
+                        "expected an items array in Bitwarden export (looked for {})",
+                        ITEMS_PATH_CANDIDATES.join(", ")
+                    )
+                })?,
+        };
+        let items = items_at_mut(root, &items_path)
+            .with_context(|| format!("no items array at {:?}", items_path.join(".")))?;
+
+        let config = &self.config;
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let input = self.self_check.then(|| items.clone());
+        let cache = KeyCache::default();
+        let mut outcome = dedup_items_with(
+            std::mem::take(items),
+            config,
+            &ignore_keys,
+            &ignore_paths,
+            &cache,
+            &[],
+        );
+        if config.output.apply_field_aliases {
+            rename_aliased_fields(&mut outcome, &config.normalize.field_aliases);
+        }
+        if let Some(input) = input
+            && let Err(err) = check_invariants_with(
+                &input,
+                &outcome,
+                config,
+                &ignore_keys,
+                &ignore_paths,
+                &cache,
+            )
+        {
+            *items = input;
+            return Err(err);
+        }
+
+        let report = DedupReport {
+            total_items: outcome.kept.len() + outcome.removed.len(),
+            kept: outcome.kept.len(),
+            removed: outcome.removed,
+            duplicate_groups: outcome.group_keys.len(),
+            held_groups: outcome.held_keys.len(),
+            changed: outcome.changed.iter().filter(|changed| **changed).count(),
+        };
+        *items = outcome.kept;
+        Ok(report)
+    }
+}
+
+/// Places where exports and API dumps keep the items array, tried in order
+/// when `--items-path` is not given.
+pub const ITEMS_PATH_CANDIDATES: &[&str] = &["items", "data.data", "ciphers", "data"];
+
+pub fn value_at_path<'a>(root: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(root, |current, segment| current.get(segment.as_str()))
+}
+
+pub fn items_at_mut<'a>(root: &'a mut Value, path: &[String]) -> Option<&'a mut Vec<Value>> {
+    path.iter()
+        .try_fold(root, |current, segment| current.get_mut(segment.as_str()))
+        .and_then(Value::as_array_mut)
+}
+
+pub fn dedup_items(
+    items: Vec<Value>,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> DedupOutcome {
+    dedup_items_with(
+        items,
+        config,
+        ignore_keys,
+        ignore_paths,
+        &KeyCache::default(),
+        &[],
+    )
+}
+
+/// [`dedup_items`] with keys shared through `cache` with the other passes
+/// of the same run. Groups whose key digest is in `hold` are kept whole, like
+/// those over `max_auto_remove_per_group`.
+pub fn dedup_items_with(
+    items: Vec<Value>,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+    cache: &KeyCache,
+    hold: &[KeyDigest],
+) -> DedupOutcome {
+    let mut survivors = Survivors::new(config, items.len());
+    let mut removed: Vec<Value> = Vec::new();
+
+    let (digests, suffixes) = build_key_digests(&items, config, ignore_keys, ignore_paths, cache);
+    let mut held_keys = hold.to_vec();
+    if let Some(max) = config.dedup.max_auto_remove_per_group {
+        let mut sizes: HashMap<&KeyDigest, usize> = HashMap::new();
+        for digest in &digests {
+            *sizes.entry(digest).or_default() += 1;
+        }
+        held_keys.extend(
+            sizes
+                .into_iter()
+                .filter(|(_, size)| size - 1 > max)
+                .map(|(digest, _)| *digest),
+        );
+    }
+    held_keys.sort();
+    held_keys.dedup();
+
+    for ((item, key), suffix) in items.into_iter().zip(digests).zip(suffixes) {
+        if held_keys.binary_search(&key).is_ok() {
+            trace!(item = %traced_id(&item), key = %traced_key(&key), "kept: its group is held for review");
+            survivors.keep(item);
+            continue;
+        }
+        removed.extend(survivors.add(item, key, &suffix, config, ignore_keys, ignore_paths));
+    }
+
+    survivors.finish(removed, held_keys)
+}
+
+/// Deduplicates items one at a time as they are read, so that a duplicate
+/// is folded into its survivor and released at once: memory follows the
+/// size of the deduplicated vault, not of the input. Keys must not depend on
+/// the other items, so `creation_window_secs` and
+/// `max_auto_remove_per_group` are refused; use [`dedup_items`] for those.
+pub struct DedupStream<'a> {
+    config: &'a Config,
+    ignore_keys: IgnoreKeys,
+    ignore_paths: Vec<Vec<String>>,
+    survivors: Survivors,
+}
+
+impl<'a> DedupStream<'a> {
+    pub fn new(config: &'a Config) -> Result<Self> {
+        if config.dedup.creation_window_secs.is_some() {
+            anyhow::bail!(
+                "creation_window_secs compares each item with the others created around it, so items cannot be deduplicated as they are read"
+            );
+        }
+        if config.dedup.max_auto_remove_per_group.is_some() {
+            anyhow::bail!(
+                "max_auto_remove_per_group needs the size of every group first, so items cannot be deduplicated as they are read"
+            );
+        }
+        Ok(Self {
+            config,
+            ignore_keys: IgnoreKeys::parse(&config.ignore.keys),
+            ignore_paths: parse_ignore_paths(&config.ignore.paths),
+            survivors: Survivors::new(config, 0),
+        })
+    }
+
+    /// Adds the next item of the input and returns the item it made
+    /// redundant, if any: itself, or the survivor it replaced.
+    pub fn push(&mut self, item: Value) -> Option<Value> {
+        let key = Zeroizing::new(build_key(
+            &item,
+            self.config,
+            &self.ignore_keys,
+            &self.ignore_paths,
+        ));
+        let digest = key_digest(&key);
+        self.survivors.add(
+            item,
+            digest,
+            "",
+            self.config,
+            &self.ignore_keys,
+            &self.ignore_paths,
+        )
+    }
+
+    /// The kept items, in input order. `removed` is left empty: the dropped
+    /// items went back to the caller of [`DedupStream::push`].
+    pub fn finish(self) -> DedupOutcome {
+        self.survivors.finish(Vec::new(), Vec::new())
+    }
+}
+
+/// The items kept so far and the groups among them, built up one item at a
+/// time by [`dedup_items_with`] and [`DedupStream`].
+struct Survivors {
+    ranking: Keep,
+    merge: bool,
+    seen: HashMap<KeyDigest, usize>,
+    kept: Vec<Value>,
+    /// Parallel to `kept`, see [`DedupOutcome::changed`].
+    changed: Vec<bool>,
+    /// Parallel to `kept`: whether the item heads a group with duplicates.
+    grouped: Vec<bool>,
+    group_keys: Vec<Zeroizing<String>>,
+}
+
+impl Survivors {
+    fn new(config: &Config, capacity: usize) -> Self {
+        Self {
+            ranking: config.dedup.ranking(),
+            merge: config.dedup.merge,
+            seen: HashMap::new(),
+            kept: Vec::with_capacity(capacity),
+            changed: Vec::with_capacity(capacity),
+            grouped: Vec::with_capacity(capacity),
+            group_keys: Vec::new(),
+        }
+    }
+
+    /// Keeps `item` without comparing it, as for a held group.
+    fn keep(&mut self, item: Value) -> usize {
+        self.kept.push(item);
+        self.changed.push(false);
+        self.grouped.push(false);
+        self.kept.len() - 1
+    }
+
+    /// Keeps `item` if it is the first with its key digest, and otherwise
+    /// folds it into the survivor of its group and returns whichever of the
+    /// two lost. `suffix` is the creation-window part of the key.
+    fn add(
+        &mut self,
+        item: Value,
+        key: KeyDigest,
+        suffix: &str,
+        config: &Config,
+        ignore_keys: &IgnoreKeys,
+        ignore_paths: &[Vec<String>],
+    ) -> Option<Value> {
+        let Some(existing_index) = self.seen.get(&key).copied() else {
+            trace!(item = %traced_id(&item), key = %traced_key(&key), "kept: first item with this key");
+            let index = self.keep(item);
+            self.seen.insert(key, index);
+            return None;
+        };
+        if !std::mem::replace(&mut self.grouped[existing_index], true) {
+            // Only groups keep their key in full, for the state file.
+            let mut key = Zeroizing::new(build_key(&item, config, ignore_keys, ignore_paths));
+            key.push_str(suffix);
+            self.group_keys.push(key);
+        }
+        let replace = should_replace(&self.kept[existing_index], &item, &self.ranking);
+        let existing = &self.kept[existing_index];
+        if replace {
+            trace!(
+                item = %traced_id(&item),
+                key = %traced_key(&key),
+                "kept, replacing {}: {}",
+                traced_id(existing),
+                self.ranking.explain(&item, existing)
+            );
+        } else {
+            trace!(
+                item = %traced_id(&item),
+                key = %traced_key(&key),
+                "removed as a duplicate of {}: {}",
+                traced_id(existing),
+                self.ranking.explain(existing, &item)
+            );
+        }
+        let dropped = if replace {
+            std::mem::replace(&mut self.kept[existing_index], item)
+        } else {
+            item
+        };
+        let merged = if self.merge {
+            merge_item(&mut self.kept[existing_index], &dropped)
+        } else {
+            carry_over(&mut self.kept[existing_index], &dropped)
+        };
+        // A replacing item starts out unchanged from its input.
+        self.changed[existing_index] = merged || (!replace && self.changed[existing_index]);
+        Some(dropped)
+    }
+
+    fn finish(mut self, removed: Vec<Value>, held_keys: Vec<KeyDigest>) -> DedupOutcome {
+        self.group_keys.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        DedupOutcome {
+            changed: self.changed,
+            kept: self.kept,
+            removed,
+            group_keys: self.group_keys,
+            held_keys,
+        }
+    }
+}
+
+// Traced per item at -vv, with the start of its key digest.
+fn traced_id(item: &Value) -> String {
+    item_str(item, "id").unwrap_or_else(|| "<no id>".to_string())
+}
+
+fn traced_key(key: &KeyDigest) -> String {
+    hex::encode(&key[..6])
+}
+
+/// Renames aliased custom fields in the kept items and flags them as
+/// changed. Returns how many items were modified.
+pub fn rename_aliased_fields(
+    outcome: &mut DedupOutcome,
+    aliases: &BTreeMap<String, String>,
+) -> usize {
+    let mut renamed = 0;
+    for (item, changed) in outcome.kept.iter_mut().zip(outcome.changed.iter_mut()) {
+        if apply_field_aliases(item, aliases) {
+            *changed = true;
+            renamed += 1;
+        }
+    }
+    renamed
+}
+
+/// Runs every dedup invariant against an outcome and fails on the first
+/// violation.
+pub fn check_invariants(
+    input: &[Value],
+    outcome: &DedupOutcome,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> Result<()> {
+    check_invariants_with(
+        input,
+        outcome,
+        config,
+        ignore_keys,
+        ignore_paths,
+        &KeyCache::default(),
+    )
+}
+
+/// [`check_invariants`] reusing the keys of an earlier pass from `cache`.
+pub fn check_invariants_with(
+    input: &[Value],
+    outcome: &DedupOutcome,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+    cache: &KeyCache,
+) -> Result<()> {
+    check_counts(input.len(), outcome)?;
+    check_partition(input, outcome)?;
+    check_unique_keys(outcome, config, ignore_keys, ignore_paths, cache)?;
+    Ok(())
+}
+
+/// kept + removed == total.
+fn check_counts(total: usize, outcome: &DedupOutcome) -> Result<()> {
+    let accounted = outcome.kept.len() + outcome.removed.len();
+    if accounted != total {
+        anyhow::bail!(
+            "self-check failed: {} kept + {} removed != {} input items",
+            outcome.kept.len(),
+            outcome.removed.len(),
+            total
+        );
+    }
+    Ok(())
+}
+
+/// Every input item ends up exactly once in either the kept or removed set.
+/// Kept items edited after deduplication (merged conflicts, renamed fields)
+/// no longer match their input, so they are accounted for by ID instead.
+fn check_partition(input: &[Value], outcome: &DedupOutcome) -> Result<()> {
+    let mut balance: HashMap<String, (isize, Option<String>)> = HashMap::new();
+    for item in input {
+        let entry = balance
+            .entry(serde_json::to_string(item)?)
+            .or_insert_with(|| (0, item_str(item, "id")));
+        entry.0 += 1;
+    }
+    let (edited, unchanged): (Vec<_>, Vec<_>) = outcome
+        .kept
+        .iter()
+        .zip(&outcome.changed)
+        .partition(|(_, changed)| **changed);
+    let unchanged = unchanged.into_iter().map(|(item, _)| item);
+    for item in unchanged.chain(outcome.removed.iter()) {
+        let entry = balance
+            .entry(serde_json::to_string(item)?)
+            .or_insert_with(|| (0, item_str(item, "id")));
+        entry.0 -= 1;
+    }
+
+    let mut mismatched = 0usize;
+    let mut by_id: HashMap<Option<String>, isize> = HashMap::new();
+    for (count, id) in balance.into_values() {
+        match count.cmp(&0) {
+            Ordering::Greater => *by_id.entry(id).or_default() += count,
+            Ordering::Less => mismatched += 1,
+            Ordering::Equal => {}
+        }
+    }
+    for (item, _) in edited {
+        *by_id.entry(item_str(item, "id")).or_default() -= 1;
+    }
+    mismatched += by_id.values().filter(|count| **count != 0).count();
+    if mismatched > 0 {
+        anyhow::bail!(
+            "self-check failed: {} item(s) are not accounted for exactly once in kept/removed",
+            mismatched
+        );
+    }
+    Ok(())
+}
+
+/// No two kept items share a dedup key, except in groups held for review.
+fn check_unique_keys(
+    outcome: &DedupOutcome,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+    cache: &KeyCache,
+) -> Result<()> {
+    let mut seen = HashSet::new();
+    let (keys, _) = build_key_digests(&outcome.kept, config, ignore_keys, ignore_paths, cache);
+    for (item, key) in outcome.kept.iter().zip(keys) {
+        if outcome.held_keys.binary_search(&key).is_err() && !seen.insert(key) {
+            let name = item
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or("<unnamed>");
+            anyhow::bail!(
+                "self-check failed: kept item {:?} shares its key with another kept item",
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn item_type(item: &Value) -> Option<u64> {
+    item.get("type").and_then(Value::as_u64)
+}
+
+pub fn item_str(item: &Value, field: &str) -> Option<String> {
+    item.get(field).and_then(Value::as_str).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::parse_ignore_paths;
+    use crate::policy::{DOUBLE_IMPORT_WINDOW_SECS, DedupKey, Keep, apply_double_import_preset};
+    use serde_json::json;
+
+    pub(crate) fn dedup_default(items: Vec<Value>) -> DedupOutcome {
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        dedup_items(items, &config, &ignore_keys, &ignore_paths)
+    }
+
+    pub(crate) fn login(id: &str, password: &str, fields: Value) -> Value {
+        json!({
+            "id": id,
+            "type": 1,
+            "name": "Forum",
+            "login": {
+                "uris": [{"uri": "https://forum.example.com"}],
+                "username": "user",
+                "password": password,
+            },
+            "fields": fields,
+        })
+    }
+
+    #[test]
+    fn check_invariants_catches_lost_and_colliding_items() {
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let input = vec![
+            login("a", "one", json!([])),
+            login("b", "one", json!([])),
+            login("c", "two", json!([])),
+        ];
+        let outcome = dedup_default(input.clone());
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+
+        let mut lost = dedup_default(input.clone());
+        lost.removed.clear();
+        assert!(check_invariants(&input, &lost, &config, &ignore_keys, &ignore_paths).is_err());
+
+        let mut colliding = dedup_default(input.clone());
+        colliding.kept.push(colliding.removed.remove(0));
+        colliding.changed.push(false);
+        assert!(
+            check_invariants(&input, &colliding, &config, &ignore_keys, &ignore_paths).is_err()
+        );
+    }
+
+    #[test]
+    fn merge_mode_folds_removed_duplicates_into_the_kept_item() {
+        let mut first = login("a", "one", json!([]));
+        first["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+        first["notes"] = json!("shared");
+        let mut second = login("b", "one", json!([{"name": "PIN", "value": "1"}]));
+        second["revisionDate"] = json!("2023-01-01T00:00:00.000Z");
+        second["notes"] = json!("older copy");
+        second["favorite"] = json!(true);
+        second["passwordHistory"] = json!([{"lastUsedDate": null, "password": "zero"}]);
+        let other = login("c", "two", json!([]));
+
+        let mut config = Config::default();
+        config.dedup.merge = true;
+        config.dedup.keep = Keep::NEWEST;
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let input = vec![second.clone(), first, other];
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+
+        assert_eq!(outcome.changed, [true, false]);
+        let kept = &outcome.kept[0];
+        assert_eq!(kept["id"], "a");
+        assert_eq!(kept["notes"], "shared\n\nolder copy");
+        assert_eq!(kept["favorite"], true);
+        assert_eq!(kept["fields"], second["fields"]);
+        assert_eq!(kept["passwordHistory"], second["passwordHistory"]);
+        assert_eq!(outcome.removed, [second]);
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+    }
+
+    #[test]
+    fn streamed_items_are_deduplicated_like_the_whole_array() {
+        let mut older = login("a", "one", json!([]));
+        older["revisionDate"] = json!("2023-01-01T00:00:00.000Z");
+        older["notes"] = json!("older copy");
+        let mut newer = login("b", "one", json!([]));
+        newer["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+        let input = vec![older.clone(), login("c", "two", json!([])), newer];
+
+        let mut config = Config::default();
+        config.dedup.merge = true;
+        config.dedup.keep = Keep::NEWEST;
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let whole = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+
+        let mut stream = DedupStream::new(&config).unwrap();
+        let dropped = input
+            .into_iter()
+            .filter_map(|item| stream.push(item))
+            .collect::<Vec<_>>();
+        let streamed = stream.finish();
+        assert_eq!(dropped, [older]);
+        assert_eq!(dropped, whole.removed);
+        assert_eq!(streamed.kept, whole.kept);
+        assert_eq!(streamed.changed, whole.changed);
+        assert_eq!(streamed.group_keys, whole.group_keys);
+        assert!(streamed.removed.is_empty());
+
+        config.dedup.creation_window_secs = Some(60);
+        assert!(DedupStream::new(&config).is_err());
+    }
+
+    #[test]
+    fn groups_over_the_removal_cap_are_held_whole() {
+        let mut config = Config::default();
+        config.dedup.max_auto_remove_per_group = Some(1);
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let input = vec![
+            login("a", "one", json!([])),
+            login("b", "one", json!([])),
+            login("c", "one", json!([])),
+            login("d", "two", json!([])),
+            login("e", "two", json!([])),
+        ];
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+
+        assert_eq!(outcome.held_keys.len(), 1);
+        let kept: Vec<_> = outcome.kept.iter().map(|item| item["id"].clone()).collect();
+        assert_eq!(kept, ["a", "b", "c", "d"]);
+        assert_eq!(outcome.removed, [input[4].clone()]);
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+    }
+
+    #[test]
+    fn double_import_preset_matches_copies_created_close_together() {
+        let copy = |id: &str, folder: &str, created: &str| {
+            let mut item = login(id, "one", json!([]));
+            item["folderId"] = json!(folder);
+            item["creationDate"] = json!(created);
+            item["revisionDate"] = json!(created);
+            item
+        };
+        let input = vec![
+            copy("b", "f2", "2024-03-01T10:05:00Z"),
+            copy("a", "f1", "2024-03-01T10:00:00Z"),
+            copy("c", "f1", "2024-05-01T10:00:00Z"),
+        ];
+
+        let mut config = Config::default();
+        config.dedup.keep = Keep::NEWEST;
+        config.dedup.policy_keys = vec![DedupKey::Password];
+        apply_double_import_preset(&mut config);
+        assert_eq!(config.dedup.keep, Keep::OLDEST);
+        assert_eq!(
+            config.dedup.creation_window_secs,
+            Some(DOUBLE_IMPORT_WINDOW_SECS)
+        );
+
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+        let kept: Vec<_> = outcome.kept.iter().map(|item| item["id"].clone()).collect();
+        assert_eq!(kept, ["a", "c"]);
+        assert_eq!(outcome.removed, [input[0].clone()]);
+        assert_eq!(outcome.group_keys.len(), 1);
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+
+        config.dedup.keep = Keep::NEWEST;
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+    }
+
+    #[test]
+    fn removed_duplicates_pass_on_their_passkeys() {
+        let passkey =
+            |id: &str| json!([{"credentialId": id, "rpId": "example.com", "keyValue": "k"}]);
+        let mut with_passkey = login("b", "one", json!([]));
+        with_passkey["login"]["fido2Credentials"] = passkey("cred-1");
+        let input = vec![login("a", "one", json!([])), with_passkey];
+
+        let mut config = Config::default();
+        config.dedup.policy_keys = vec![DedupKey::Password];
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+        assert_eq!(outcome.kept[0]["id"], "a");
+        assert_eq!(
+            outcome.kept[0]["login"]["fido2Credentials"],
+            passkey("cred-1")
+        );
+        assert_eq!(outcome.changed, [true]);
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+
+        config.dedup.keep = Keep::parse("has-passkey,first").unwrap();
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+        assert_eq!(outcome.kept[0]["id"], "b");
+        assert_eq!(outcome.changed, [false]);
+
+        config.dedup.policy_keys = vec![DedupKey::Passkey];
+        let outcome = dedup_items(input.clone(), &config, &ignore_keys, &ignore_paths);
+        assert_eq!(outcome.kept.len(), 2);
+    }
+
+    #[test]
+    fn deduper_rewrites_the_items_array_in_place() {
+        let mut vault = json!({
+            "encrypted": false,
+            "data": {"data": [
+                login("a", "one", json!([])),
+                login("b", "one", json!([{"name": "PIN", "value": "1"}])),
+                login("c", "two", json!([])),
+            ]},
+        });
+        let report = Deduper::new()
+            .policy_keys([DedupKey::Username, DedupKey::Password])
+            .merge(true)
+            .self_check(true)
+            .dedup(&mut vault)
+            .unwrap();
+
+        assert_eq!(report.total_items, 3);
+        assert_eq!(report.kept, 2);
+        assert_eq!(report.duplicate_groups, 1);
+        assert_eq!(report.changed, 1);
+        assert_eq!(report.removed[0]["id"], "b");
+        let items = vault["data"]["data"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["fields"][0]["name"], "PIN");
+
+        let mut empty = json!({"folders": []});
+        assert!(Deduper::new().dedup(&mut empty).is_err());
+        assert!(
+            Deduper::new()
+                .items_path("folders")
+                .dedup(&mut empty)
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Progress, warnings and debugging output through `tracing`.
+//!
+//! `-q` keeps warnings and errors, `-v` adds debugging detail and `-vv`
+//! traces every item: the value it was compared by, the key it hashed to and
+//! why it was kept or removed. `--log-format json` writes one JSON object
+//! per event instead of text.
+//!
+//! Progress lines go to stdout and warnings to stderr, except while stdout
+//! carries the output itself; see [`set_status_to_stderr`].
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::EitherWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Events of this crate; other crates only get to log warnings.
+const TARGET: &str = "bw_passport_dedup";
+
+/// Set while a dedup run writes its output (`--output -`) or its JSON summary
+/// (`--summary-json -`) to stdout, and while other commands write their
+/// result there, so that stdout carries only that.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_status_to_stderr(to_stderr: bool) {
+    STATUS_TO_STDERR.store(to_stderr, Ordering::Relaxed);
+}
+
+#[derive(Copy, Clone, Debug, Default, ValueEnum, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// The most detailed level shown for `-v` given `verbose` times, or `-q`.
+pub(crate) fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    }
+}
+
+/// Installs the logger for the rest of the process.
+pub(crate) fn init(level: LevelFilter, format: LogFormat) -> Result<()> {
+    tracing::subscriber::set_global_default(subscriber(level, format, Route))
+        .context("failed to set up logging")
+}
+
+fn subscriber<W>(
+    level: LevelFilter,
+    format: LogFormat,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let targets = Targets::new()
+        .with_default(LevelFilter::WARN.min(level))
+        .with_target(TARGET, level);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.event_format(Plain).finish().with(targets)),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .finish()
+                .with(targets),
+        ),
+    }
+}
+
+/// Text as the tool has always printed it: progress lines as they are, the
+/// rest prefixed with their level (`warning: ...`).
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let prefix = match *event.metadata().level() {
+            Level::ERROR => "error: ",
+            Level::WARN => "warning: ",
+            Level::INFO => "",
+            Level::DEBUG => "debug: ",
+            Level::TRACE => "trace: ",
+        };
+        writer.write_str(prefix)?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Warnings and errors to stderr, everything else to stdout unless
+/// [`STATUS_TO_STDERR`] is set.
+struct Route;
+
+impl<'a> MakeWriter<'a> for Route {
+    type Writer = EitherWriter<io::Stdout, io::Stderr>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EitherWriter::A(io::stdout())
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        if matches!(*meta.level(), Level::ERROR | Level::WARN)
+            || STATUS_TO_STDERR.load(Ordering::Relaxed)
+        {
+            EitherWriter::B(io::stderr())
+        } else {
+            EitherWriter::A(io::stdout())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn capture(level: LevelFilter, format: LogFormat) -> String {
+        let buffer = Buffer::default();
+        tracing::subscriber::with_default(subscriber(level, format, buffer.clone()), || {
+            tracing::info!("Items: 3 -> 2 (removed 1)");
+            tracing::warn!("the output keeps 1 structural problem(s)");
+            tracing::debug!(items = 3, "read the export");
+            tracing::trace!(item = %"b", key = %"1f2e3d", "removed");
+            tracing::trace!(target: "other_crate", "not ours");
+        });
+        String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn verbosity_picks_the_levels_and_format_the_lines() {
+        assert_eq!(level(0, true), LevelFilter::WARN);
+        assert_eq!(level(0, false), LevelFilter::INFO);
+        assert_eq!(level(1, false), LevelFilter::DEBUG);
+        assert_eq!(level(4, false), LevelFilter::TRACE);
+
+        assert_eq!(
+            capture(level(0, true), LogFormat::Text),
+            "warning: the output keeps 1 structural problem(s)\n"
+        );
+        assert_eq!(
+            capture(level(0, false), LogFormat::Text),
+            "Items: 3 -> 2 (removed 1)\nwarning: the output keeps 1 structural problem(s)\n"
+        );
+        assert_eq!(
+            capture(level(2, false), LogFormat::Text),
+            "Items: 3 -> 2 (removed 1)\n\
+             warning: the output keeps 1 structural problem(s)\n\
+             debug: read the export items=3\n\
+             trace: removed item=b key=1f2e3d\n"
+        );
+
+        let json = capture(level(2, false), LogFormat::Json);
+        let events = json
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[1]["level"], "WARN");
+        assert_eq!(events[2]["items"], 3);
+        assert_eq!(events[3]["message"], "removed");
+        assert_eq!(events[3]["item"], "b");
+    }
+}
@@ -1,23 +1,158 @@
 use anyhow::{Context, Result};
-use clap::{ArgAction, Parser, ValueEnum};
+use bw_passport_dedup::keying::{
+    KEY_VERSION, KeyCache, KeyDigest, build_key, build_key_digests, normalized_value,
+};
+use bw_passport_dedup::normalize::{
+    API_ONLY_FIELDS, IgnoreKeys, canonicalize, is_ignored_path, parse_ignore_paths, parse_path,
+    path_matches, remove_path, retain_sections,
+};
+use bw_passport_dedup::policy::{
+    DedupKey, DedupScope, DomainGranularity, KEEP_STRATEGIES, Keep, PolicyPath, Preference,
+    apply_double_import_preset, best_date, carry_over, compare_dates, extract_domains,
+    extract_domains_with, extract_login_field, extract_uris, login_password, merge_item,
+    merge_login, short_date, should_replace, totp_secret, uses_policy_keys,
+};
+use bw_passport_dedup::{
+    Config, DedupOutcome, DedupStream, Deduper, ITEMS_PATH_CANDIDATES, check_invariants_with,
+    dedup_items_with, item_str, item_type, items_at_mut, rename_aliased_fields, value_at_path,
+};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
+use zeroize::Zeroizing;
+
+mod breach;
+mod bw_cli;
+mod env_config;
+#[cfg(any(test, feature = "test-fixtures"))]
+mod fixtures;
+mod formats;
+mod logging;
+mod protected;
+mod redact;
+mod secure_file;
+#[cfg(feature = "server-api")]
+mod server_api;
+mod validate;
 
 #[derive(Parser, Debug)]
 #[command(name = "bw-passport-dedup", version, about = "Deduplicate Bitwarden JSON exports")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Bitwarden JSON export file
-    #[arg(short, long, value_name = "FILE")]
-    input: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the flags of `dedup` apply
+    #[command(flatten)]
+    dedup: DedupArgs,
+
+    /// Show more: -v adds detail, -vv traces every item's key and why it was
+    /// kept or removed
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only show warnings and errors
+    #[arg(short, long, action = ArgAction::SetTrue, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Format of progress lines, warnings and traces
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t,
+        global = true
+    )]
+    log_format: logging::LogFormat,
+}
+
+#[derive(clap::Args, Debug)]
+struct DedupArgs {
+    /// Bitwarden JSON export file (`-` for stdin); repeat to combine
+    /// several exports into one deduplicated vault
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present_any = ["bw_serve", "input_file", "list_formats", "list_keep_strategies"]
+    )]
+    input: Vec<PathBuf>,
+
+    /// Bitwarden JSON export file, as a bare argument (same as --input)
+    #[arg(value_name = "FILE", conflicts_with = "input")]
+    input_file: Option<PathBuf>,
+
+    /// Dot-separated path to the items array (auto-detects items, data.data, ciphers)
+    #[arg(long, value_name = "PATH")]
+    items_path: Option<String>,
 
-    /// Output file (defaults to <input>.dedup.json)
+    /// List the import and export formats built into this binary (and any
+    /// `--plugin`) and exit
+    #[arg(long, action = ArgAction::SetTrue)]
+    list_formats: bool,
+
+    /// List the keep strategies accepted by --keep and exit
+    #[arg(long, action = ArgAction::SetTrue)]
+    list_keep_strategies: bool,
+
+    /// Load import/export formats from an Extism WebAssembly plugin (repeatable)
+    #[cfg(feature = "wasm-plugins")]
+    #[arg(long, value_name = "FILE")]
+    plugin: Vec<PathBuf>,
+
+    /// Read the vault from a local `bw serve` API instead of an export file
+    #[arg(long, value_name = "URL", conflicts_with_all = ["input", "input_file"])]
+    bw_serve: Option<String>,
+
+    /// Ask the server behind `--bw-serve` for its public `/api/config` to tell
+    /// Vaultwarden from Bitwarden (an unauthenticated request to that server)
+    #[arg(long, action = ArgAction::SetTrue, requires = "bw_serve")]
+    probe_server: bool,
+
+    /// Output file (defaults to <input>.dedup.json, or stdout when reading
+    /// stdin); `-` writes to stdout and moves the progress lines to stderr
     #[arg(short, long, value_name = "FILE")]
     output: Option<PathBuf>,
 
+    /// Also write every removed item to this file as a Bitwarden JSON export,
+    /// to re-import them if the dedup went wrong
+    #[arg(long, value_name = "FILE")]
+    removed_output: Option<PathBuf>,
+
+    /// Output format by name (see --list-formats); defaults to the output's extension
+    #[arg(long, value_name = "FORMAT")]
+    output_format: Option<String>,
+
+    /// Key names for the env and sops-yaml formats, from {name}, {username},
+    /// {domain}, {folder} and {id}
+    #[arg(long, value_name = "TEMPLATE", default_value = "{name}")]
+    kv_name_template: String,
+
+    /// Only write logins from this folder to the env and sops-yaml formats (repeatable)
+    #[arg(long, value_name = "NAME")]
+    kv_folder: Vec<String>,
+
+    /// Read the password of a password-protected export from this file
+    /// (defaults to $BW_EXPORT_PASSWORD, then a prompt)
+    #[arg(long, value_name = "FILE")]
+    password_file: Option<PathBuf>,
+
+    /// Encrypt the output again with the password of the protected input export
+    #[arg(long, action = ArgAction::SetTrue)]
+    encrypt_output: bool,
+
     /// Overwrite the output file if it exists
     #[arg(long, action = ArgAction::SetTrue)]
     force: bool,
@@ -26,779 +161,8875 @@ struct Args {
     #[arg(long, action = ArgAction::SetTrue)]
     dry_run: bool,
 
-    /// Write pretty-printed JSON
+    /// Never write any file; reports may still go to stdout with `--report -`
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["output", "state", "conflicts", "split_output"]
+    )]
+    read_only: bool,
+
+    /// Write into directories every user can write to, such as /tmp (output
+    /// files hold plaintext passwords)
+    #[arg(long, action = ArgAction::SetTrue)]
+    allow_insecure_location: bool,
+
+    /// Collapse folders with identical names into the first of them, moving
+    /// their items there, before items are deduplicated
+    #[arg(long, action = ArgAction::SetTrue)]
+    dedup_folders: bool,
+
+    /// Collapse collections of one organization with identical names into the
+    /// first of them, updating the `collectionIds` of their items
     #[arg(long, action = ArgAction::SetTrue)]
-    pretty: bool,
+    dedup_collections: bool,
+
+    /// Refuse to write the result when it would remove more than N items, or
+    /// more than P percent of them (e.g. `5%`)
+    #[arg(long, value_name = "N|P%", value_parser = parse_removal_limit)]
+    max_removed: Option<RemovalLimit>,
+
+    /// Write the result even when it removes more than --max-removed allows
+    #[arg(long, action = ArgAction::SetTrue, requires = "max_removed")]
+    yes: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
 
-    /// Keep strategy when duplicates are found
-    #[arg(long, value_enum, default_value_t = Keep::First)]
-    keep: Keep,
+    #[command(flatten)]
+    overrides: ConfigOverrides,
 
-    /// Config file (TOML)
+    /// Write a JSON report of duplicate groups (`-` for stdout)
     #[arg(long, value_name = "FILE")]
-    config: Option<PathBuf>,
+    report: Option<PathBuf>,
+
+    /// Write a JSON summary of the run (counts per item type, the effective
+    /// config and the elapsed time) for scripts (`-` for stdout)
+    #[arg(long, value_name = "FILE")]
+    summary_json: Option<PathBuf>,
+
+    /// Show passwords and TOTP secrets in reports as they are, instead of
+    /// their length and a hash prefix (for debugging on a trusted machine)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_redact: bool,
+
+    /// Render the report through a Handlebars template instead of JSON
+    #[arg(long, value_name = "FILE", requires = "report")]
+    report_template: Option<PathBuf>,
+
+    /// Report format (defaults to csv for .csv, md for .md reports, json otherwise)
+    #[arg(long, value_enum, conflicts_with = "report_template")]
+    report_format: Option<ReportFormat>,
+
+    /// Write only kept items that were modified, plus a deletion plan next to the output
+    #[arg(long, action = ArgAction::SetTrue)]
+    emit_changed_only: bool,
+
+    /// List the removed items for deleting them from the live vault: a `bw`
+    /// script (bw-cli), one ID per line (ids) or JSON lines (jsonl); also
+    /// written in dry runs
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    emit_deletions: Option<DeletionFormat>,
+
+    /// Write --emit-deletions here instead of stdout
+    #[arg(long, value_name = "FILE", requires = "emit_deletions")]
+    deletions_file: Option<PathBuf>,
+
+    /// Write the result as numbered import files of at most N items each
+    /// (`<output stem>.partN.json`), repeating folders and other top-level data
+    #[arg(long, value_name = "N")]
+    split_output: Option<NonZeroUsize>,
+
+    /// Re-run dedup on the result in memory and fail if it removes anything more
+    #[arg(long, action = ArgAction::SetTrue)]
+    verify_idempotent: bool,
+
+    /// Validate dedup invariants before writing anything
+    #[arg(long, action = ArgAction::SetTrue)]
+    self_check: bool,
+
+    /// Report login and identity items that describe the same account
+    #[arg(long, action = ArgAction::SetTrue)]
+    find_related: bool,
+
+    /// Report logins whose current password shows up in the password history
+    /// of another login for the same site and username
+    #[arg(long, action = ArgAction::SetTrue)]
+    find_stale: bool,
+
+    /// Remove stale logins when the login holding them in its history is strictly newer (implies --find-stale)
+    #[arg(long, action = ArgAction::SetTrue)]
+    remove_stale: bool,
+
+    /// Report TOTP secrets shared by logins for different sites
+    #[arg(long, action = ArgAction::SetTrue)]
+    find_totp_reuse: bool,
+
+    /// Report username+password pairs shared by logins for different sites,
+    /// ranked by how many registrable domains share them
+    #[arg(long, action = ArgAction::SetTrue)]
+    find_credential_reuse: bool,
+
+    /// Report logins for the same site and username whose passwords differ
+    /// (stale or rotated passwords to review by hand); nothing is removed
+    #[arg(long, action = ArgAction::SetTrue)]
+    find_password_conflicts: bool,
+
+    /// Report passwords used by more than one account (site and username),
+    /// identified by a salted hash so the report does not reveal them
+    #[arg(long, action = ArgAction::SetTrue)]
+    find_password_reuse: bool,
+
+    /// Fail when the vault hygiene score (0-100) is below this, after writing everything
+    #[arg(long, value_name = "SCORE", value_parser = clap::value_parser!(u32).range(0..=100))]
+    min_score: Option<u32>,
+
+    /// SHA-1 or NTLM hashes of breached passwords, one per line (optionally
+    /// `HASH:COUNT` as in Have I Been Pwned downloads), counted in the hygiene
+    /// score and used by `--hibp-check` instead of the online API
+    #[arg(long, value_name = "FILE")]
+    breached_hashes: Option<PathBuf>,
+
+    /// Report logins whose passwords appear in known breaches, looked up in
+    /// Have I Been Pwned's range API (only a hash prefix leaves the machine)
+    /// or in the `--breached-hashes` file
+    #[arg(long, action = ArgAction::SetTrue)]
+    hibp_check: bool,
+
+    /// Write logins for the same site and username whose contents differ to this
+    /// JSON file, with an editable `decision` for a later `--resolve` run
+    #[arg(long, value_name = "FILE")]
+    conflicts: Option<PathBuf>,
+
+    /// Apply the decisions (keep-a, keep-b, merge, skip) from a file written by `--conflicts`
+    #[arg(long, value_name = "FILE")]
+    resolve: Option<PathBuf>,
+
+    /// Decide every duplicate group by hand: show its items side by side and
+    /// ask which one to keep, or to leave the group whole
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "watch")]
+    interactive: bool,
+
+    /// Give fresh ids to the second and later items sharing an id
+    #[arg(long, action = ArgAction::SetTrue)]
+    fix_duplicate_ids: bool,
+
+    /// Record run totals in this JSON state file and print the trend since the last run
+    #[arg(long, value_name = "FILE")]
+    state: Option<PathBuf>,
+
+    /// Run the whole pipeline on N randomly chosen items without writing the output
+    #[arg(long, value_name = "N", conflicts_with = "state")]
+    sample: Option<usize>,
+
+    /// Seed for --sample (printed when omitted so a run can be repeated)
+    #[arg(long, value_name = "SEED", requires = "sample")]
+    seed: Option<u64>,
+
+    /// Make repeated runs on the same input and config byte-identical: sampling
+    /// defaults to seed 0 and report group IDs use a salt derived from the input
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "time_budget")]
+    deterministic: bool,
+
+    /// Stop the optional candidate passes (--find-related, --find-stale) after this many
+    /// seconds and mark the report as partial; the exact dedup pass always completes
+    #[arg(long, value_name = "SECS")]
+    time_budget: Option<u64>,
+
+    /// Keep running and deduplicate again whenever the input file changes (or
+    /// on every tick with --bw-serve), checking every SECS seconds; implies --force
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    watch: Option<u64>,
+
+    /// In --watch mode, write Prometheus metrics (runs, items, removals, errors)
+    /// to FILE after every run, for node_exporter's textfile collector
+    #[arg(long, value_name = "FILE", requires = "watch")]
+    metrics_file: Option<PathBuf>,
+
+    /// In --watch mode, serve Prometheus metrics over HTTP on this loopback
+    /// address (e.g. 127.0.0.1:9898)
+    #[arg(long, value_name = "ADDR", requires = "watch")]
+    metrics_addr: Option<SocketAddr>,
 
-    /// Ignore any keys with these names, anywhere in the item
+    /// Send the JSON run summary to `exec:<command>` (on stdin) or `webhook:<url>` (POST)
+    #[arg(long, value_name = "TARGET", value_parser = parse_notifier)]
+    notify: Vec<Notifier>,
+}
+
+/// Flags that override settings of the config; see [`effective_config`].
+#[derive(clap::Args, Debug, Default, Clone)]
+struct ConfigOverrides {
+    /// Write pretty-printed JSON (`--pretty=false` for compact JSON)
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        overrides_with = "no_pretty"
+    )]
+    pretty: Option<bool>,
+
+    /// Write compact JSON even if the config asks for pretty output
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "pretty")]
+    no_pretty: bool,
+
+    /// Keep strategy when duplicates are found: first, last, newest, oldest,
+    /// most-complete, has-passkey or latest-password, or a comma-separated
+    /// list where later ones break ties (see --list-keep-strategies; default:
+    /// first)
+    #[arg(
+        long,
+        value_name = "STRATEGY[,...]",
+        value_parser = KeepParser,
+        hide_possible_values = true
+    )]
+    keep: Option<Keep>,
+
+    /// Narrow each group to the copies with a TOTP secret, then to favorites,
+    /// then to those in a folder, before the keep strategy picks (any of
+    /// with-totp, favorite, in-folder, comma-separated, in order). Overrides
+    /// config.
+    #[arg(long, value_delimiter = ',', value_name = "PREFERENCE[,...]")]
+    prefer: Option<Vec<Preference>>,
+
+    /// Merge each removed duplicate into the kept item (URIs, notes, custom
+    /// fields, password history, favorite) instead of dropping its data
     #[arg(
         long,
-        value_delimiter = ',',
-        value_name = "KEYS"
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        overrides_with = "no_merge"
     )]
+    merge: Option<bool>,
+
+    /// Drop the data of removed duplicates, whatever the config says
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "merge")]
+    no_merge: bool,
+
+    /// Leave groups that would lose more than N items untouched and list them
+    /// in the report for review
+    #[arg(long, value_name = "N")]
+    max_auto_remove_per_group: Option<usize>,
+
+    /// Clean up after importing the same export twice: compare whole items
+    /// minus ids, folders and dates, require creation dates within the
+    /// creation window, and keep the earlier copy
+    #[arg(long, action = ArgAction::SetTrue)]
+    assume_double_import: bool,
+
+    /// Only treat items created within SECS of each other as duplicates
+    #[arg(long, value_name = "SECS")]
+    creation_window: Option<u64>,
+
+    /// Ignore keys: `name` (top-level), `fields.name` (under a parent), `**.name` (anywhere)
+    #[arg(long, value_delimiter = ',', value_name = "KEYS")]
     ignore_key: Option<Vec<String>>,
 
-    /// Ignore specific paths (dot-separated), relative to each item
+    /// Ignore specific paths (dot-separated), relative to each item; `[N]` is an
+    /// array index and `*` any key or element, e.g. `login.uris[*].match`
     #[arg(long, value_delimiter = ',', value_name = "PATHS")]
     ignore_path: Option<Vec<String>>,
 
     /// Trim whitespace from all string values before hashing
-    #[arg(long, action = ArgAction::SetTrue)]
-    trim_strings: bool,
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        overrides_with = "no_trim_strings"
+    )]
+    trim_strings: Option<bool>,
+
+    /// Do not trim strings, whatever the config says
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "trim_strings")]
+    no_trim_strings: bool,
 
     /// Lowercase all string values before hashing
-    #[arg(long, action = ArgAction::SetTrue)]
-    lowercase_strings: bool,
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        overrides_with = "no_lowercase_strings"
+    )]
+    lowercase_strings: Option<bool>,
+
+    /// Do not lowercase strings, whatever the config says
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "lowercase_strings")]
+    no_lowercase_strings: bool,
+
+    /// Take the username from names like "example.com (user@mail.com)" when the
+    /// login's username is empty
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        overrides_with = "no_username_from_name"
+    )]
+    username_from_name: Option<bool>,
+
+    /// Do not take usernames from item names, whatever the config says
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "username_from_name")]
+    no_username_from_name: bool,
+
+    /// Sort login.uris entries by URI before hashing (default: from config, else true)
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        overrides_with = "no_sort_uris"
+    )]
+    sort_uris: Option<bool>,
 
-    /// Sort login.uris entries by URI before hashing
-    #[arg(long, default_value_t = true, action = ArgAction::Set)]
-    sort_uris: bool,
+    /// Keep login.uris in their original order, whatever the config says
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "sort_uris")]
+    no_sort_uris: bool,
+
+    /// Restrict whole-item hashing to these top-level sections (e.g. login,fields,notes)
+    #[arg(long, value_delimiter = ',', value_name = "SECTIONS")]
+    hash_sections: Option<Vec<String>>,
 
     /// Deduplication keys (comma-separated). Overrides config.
     #[arg(long, value_delimiter = ',', value_name = "KEYS")]
     policy_key: Option<Vec<DedupKey>>,
 
-    /// Write a JSON report of duplicate groups
+    /// Item paths compared along with the policy keys (comma-separated), e.g.
+    /// `fields[name=Account ID].value`. Overrides config.
+    #[arg(long, value_delimiter = ',', value_name = "PATHS", value_parser = PolicyPath::parse)]
+    policy_path: Option<Vec<PolicyPath>>,
+
+    /// How much of a URI the domain policy key compares. Overrides config.
+    #[arg(long, value_enum, value_name = "GRANULARITY")]
+    domain_granularity: Option<DomainGranularity>,
+
+    /// Extra equivalent-domain groups for the domain policy key, one group per
+    /// line (domains separated by commas or spaces, `#` comments)
     #[arg(long, value_name = "FILE")]
-    report: Option<PathBuf>,
+    equivalent_domains: Option<PathBuf>,
+
+    /// Only treat items in the same folder or collections as duplicates. Overrides config.
+    #[arg(long, value_enum)]
+    scope: Option<DedupScope>,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, ValueEnum, PartialEq, Eq)]
-enum Keep {
-    First,
-    Last,
-    Newest,
-    Oldest,
+/// Where the config comes from; see [`load_config`].
+#[derive(clap::Args, Debug, Default, Clone)]
+struct ConfigArgs {
+    /// Config file (TOML); by default `config.toml` in the user config
+    /// directory
+    #[arg(long, value_name = "FILE", env = "BW_DEDUP_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Use the built-in defaults instead of looking for a config file
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "config")]
+    no_config: bool,
+
+    /// Apply the `[profile.NAME]` tables of the config over the rest of it
+    #[arg(
+        long,
+        value_name = "NAME",
+        env = "BW_DEDUP_PROFILE",
+        conflicts_with = "no_config"
+    )]
+    profile: Option<String>,
+
+    /// Leave the `BW_DEDUP_*` variables out too, for runs that promise the
+    /// built-in defaults (the file picker).
+    #[arg(skip)]
+    no_env: bool,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
-struct Config {
-    dedup: DedupConfig,
-    ignore: IgnoreConfig,
-    normalize: NormalizeConfig,
-    output: OutputConfig,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Deduplicate a vault and write the result (the default without a subcommand)
+    Dedup(DedupArgs),
+    /// Exit non-zero if the vault has duplicates, without writing a vault (for CI/cron)
+    Check(DedupArgs),
+    /// Write the duplicate-group report (to stdout unless --report is given)
+    /// without writing a vault
+    Report(DedupArgs),
+    /// List logins for the same site and username whose passwords differ,
+    /// without writing a vault (--find-password-conflicts as a dry run)
+    Conflicts(DedupArgs),
+    /// Report passwords reused across accounts, by salted hash, without
+    /// writing a vault (--find-password-reuse as a dry run)
+    Analyze(DedupArgs),
+    /// Write a commented sample config, or check one and print the settings
+    /// it amounts to
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Check a config file for risky setting combinations
+    LintConfig {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Print one item before and after the configured normalization pipeline
+    PreviewNormalize {
+        /// Id of the item to preview
+        item_id: String,
+
+        /// Path to Bitwarden JSON export
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        /// Dot-separated path to the items array (auto-detected by default)
+        #[arg(long, value_name = "PATH")]
+        items_path: Option<String>,
+
+        /// Show passwords and TOTP secrets as they are
+        #[arg(long, action = ArgAction::SetTrue)]
+        no_redact: bool,
+    },
+    /// Show how an item is keyed, which items share its group and which of
+    /// them is kept
+    Explain {
+        /// Id of the item to explain
+        #[arg(long, value_name = "ID", required_unless_present = "name")]
+        id: Option<String>,
+
+        /// Explain every item whose name matches (`*` and `?` wildcards,
+        /// ignoring case)
+        #[arg(long, value_name = "GLOB", conflicts_with = "id")]
+        name: Option<String>,
+
+        /// Path to Bitwarden JSON export
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        /// Dot-separated path to the items array (auto-detected by default)
+        #[arg(long, value_name = "PATH")]
+        items_path: Option<String>,
+
+        /// Show passwords and TOTP secrets as they are
+        #[arg(long, action = ArgAction::SetTrue)]
+        no_redact: bool,
+    },
+    /// Print `<item id><TAB><key fingerprint>` for every item, for joining
+    /// exports over time; nothing is written or removed
+    Fingerprint {
+        /// Path to Bitwarden JSON export
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        /// Dot-separated path to the items array (auto-detected by default)
+        #[arg(long, value_name = "PATH")]
+        items_path: Option<String>,
+
+        /// Salt the fingerprints with the first line of this file, so they
+        /// cannot be checked against guessed passwords; use the same file
+        /// everywhere fingerprints are compared
+        #[arg(long, value_name = "FILE")]
+        salt_file: Option<PathBuf>,
+
+        /// Write the fingerprints here instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Check an export for references to missing folders or collections,
+    /// duplicate item IDs and malformed URIs
+    Validate {
+        /// Path to Bitwarden JSON export
+        #[arg(
+            short,
+            long,
+            value_name = "FILE",
+            required_unless_present = "input_file"
+        )]
+        input: Option<PathBuf>,
+
+        /// Bitwarden JSON export file, as a bare argument (same as --input)
+        #[arg(value_name = "FILE", conflicts_with = "input")]
+        input_file: Option<PathBuf>,
+
+        /// Dot-separated path to the items array (auto-detected by default)
+        #[arg(long, value_name = "PATH")]
+        items_path: Option<String>,
+    },
+    /// Compare two exports item by item: the items only in one of them and
+    /// the pairs that differ
+    #[command(alias = "compare")]
+    Diff {
+        /// First vault export
+        left: PathBuf,
+
+        /// Second vault export
+        right: PathBuf,
+
+        /// Write the listing (or matrix) here instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Write the conflict matrix instead: logins in both exports, by host
+        /// and username, whose passwords differ
+        #[arg(long, action = ArgAction::SetTrue)]
+        matrix: bool,
+
+        /// Matrix format (defaults to html for .html/.htm outputs, csv
+        /// otherwise); implies --matrix
+        #[arg(long, value_enum)]
+        format: Option<MatrixFormat>,
+
+        /// The item diff, which is now the default
+        #[arg(long, action = ArgAction::SetTrue, hide = true, conflicts_with_all = ["matrix", "format"])]
+        items: bool,
+
+        /// Config file (TOML) whose keys items are matched by
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["matrix", "format"])]
+        config: Option<PathBuf>,
+
+        /// Match items by the default keys instead of the user config
+        #[arg(
+            long,
+            action = ArgAction::SetTrue,
+            conflicts_with_all = ["config", "matrix", "format"]
+        )]
+        no_config: bool,
+    },
+    /// Deduplicate the vault the `bw` CLI is logged in to, deleting only the
+    /// duplicates by ID instead of re-importing everything
+    Sync {
+        /// The Bitwarden CLI executable
+        #[arg(long, value_name = "PATH", default_value = "bw")]
+        bw: PathBuf,
+
+        /// Run the edits and deletions instead of printing them as a script
+        #[arg(long, action = ArgAction::SetTrue)]
+        apply: bool,
+
+        /// Write the script here instead of stdout
+        #[arg(long, value_name = "FILE", conflicts_with = "apply")]
+        script: Option<PathBuf>,
+
+        /// Overwrite the script file if it exists
+        #[arg(long, action = ArgAction::SetTrue)]
+        force: bool,
+
+        /// List the CLI's cached vault without running `bw sync` first
+        #[arg(long, action = ArgAction::SetTrue)]
+        no_sync: bool,
+
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        /// Keep strategy, as for dedup
+        #[arg(
+            long,
+            value_name = "STRATEGY[,...]",
+            value_parser = KeepParser,
+            hide_possible_values = true
+        )]
+        keep: Option<Keep>,
+
+        /// Preferences applied before the keep strategy, as for dedup
+        #[arg(long, value_delimiter = ',', value_name = "PREFERENCE[,...]")]
+        prefer: Option<Vec<Preference>>,
+
+        /// Merge removed duplicates into the kept item, which is then updated
+        /// with `bw edit item` (`--merge=false` to only delete them)
+        #[arg(
+            long,
+            value_name = "BOOL",
+            num_args = 0..=1,
+            default_missing_value = "true"
+        )]
+        merge: Option<bool>,
+
+        /// Talk to this Bitwarden or Vaultwarden server's API instead of the
+        /// CLI, with the API key in $BW_CLIENTID and $BW_CLIENTSECRET
+        #[cfg(feature = "server-api")]
+        #[arg(long, value_name = "URL", conflicts_with = "no_sync")]
+        server: Option<String>,
+
+        /// Read the master password for --server from this file (defaults to
+        /// $BW_PASSWORD, then a prompt)
+        #[cfg(feature = "server-api")]
+        #[arg(long, value_name = "FILE", requires = "server")]
+        password_file: Option<PathBuf>,
+    },
+    /// Print a completion script for a shell, e.g.
+    /// `completions bash > ~/.local/share/bash-completion/completions/bw-passport-dedup`
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page
+    Manpage {
+        /// Write a page for every subcommand as well into this directory
+        /// instead of printing the main page
+        #[arg(long, value_name = "DIR")]
+        out_dir: Option<PathBuf>,
+    },
+    /// Run the bundled sample exports and compare them with their golden outputs
+    #[cfg(feature = "test-fixtures")]
+    VerifyFixtures {
+        /// Overwrite the golden outputs in fixtures/ with the current results
+        #[arg(long, action = ArgAction::SetTrue)]
+        bless: bool,
+    },
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
-struct DedupConfig {
-    keep: Keep,
-    policy_keys: Vec<DedupKey>,
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Write the sample config with every setting explained
+    Init {
+        /// Where to write it (default: config.toml in the user config
+        /// directory; `-` for stdout)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Overwrite the file if it exists
+        #[arg(long, action = ArgAction::SetTrue)]
+        force: bool,
+    },
+    /// Parse the config, rejecting unknown keys, and print the effective
+    /// settings as TOML after applying the flags given here as for dedup
+    Validate {
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
-struct IgnoreConfig {
-    keys: Vec<String>,
-    paths: Vec<String>,
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq, Eq)]
+enum MatrixFormat {
+    Csv,
+    Html,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
-struct NormalizeConfig {
-    trim_strings: bool,
-    lowercase_strings: bool,
-    sort_uris: bool,
+/// CSV and Markdown reports list the duplicate groups only; the other
+/// passes' findings are in the JSON report.
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq, Eq)]
+enum ReportFormat {
+    Json,
+    Csv,
+    #[value(alias = "markdown")]
+    Md,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
-struct OutputConfig {
-    pretty: bool,
+impl ReportFormat {
+    fn for_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("csv") => ReportFormat::Csv,
+            Some("md" | "markdown") => ReportFormat::Md,
+            _ => ReportFormat::Json,
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, ValueEnum, Eq, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-enum DedupKey {
-    Domain,
-    Username,
-    Password,
-    Name,
-    Uri,
-    Totp,
+/// What `--emit-deletions` lists the removed items as.
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq, Eq)]
+enum DeletionFormat {
+    /// A shell script of `bw delete item <id>` commands.
+    BwCli,
+    Ids,
+    /// One `{"id": ..., "name": ...}` object per line.
+    Jsonl,
+}
+
+/// How many items `--max-removed` lets a run remove.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RemovalLimit {
+    Count(usize),
+    Percent(f64),
+}
+
+impl RemovalLimit {
+    fn exceeded_by(self, removed: usize, total: usize) -> bool {
+        match self {
+            RemovalLimit::Count(max) => removed > max,
+            RemovalLimit::Percent(max) => removed as f64 > total as f64 * max / 100.0,
+        }
+    }
+}
+
+impl std::fmt::Display for RemovalLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemovalLimit::Count(max) => write!(f, "{}", max),
+            RemovalLimit::Percent(max) => write!(f, "{}%", max),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Notifier {
+    Exec(String),
+    Webhook(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+struct LintFinding {
+    severity: Severity,
+    message: String,
 }
 
 #[derive(Debug, Serialize)]
 struct Report {
+    /// Set when `--time-budget` cut an optional pass short.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    incomplete_passes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<InputSummary>,
+    /// Where the kept items came from, when several inputs were combined.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    input_files: Vec<InputFile>,
+    /// [`KEY_VERSION`] the groups were formed with.
+    key_version: u32,
     total_items: usize,
     duplicate_groups: usize,
+    /// Groups left untouched by `max_auto_remove_per_group`.
+    held_groups: usize,
     removed: usize,
     groups: Vec<ReportGroup>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    duplicate_ids: Vec<DuplicateId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    merged_folders: Vec<MergedContainer>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    merged_collections: Vec<MergedContainer>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    related: Vec<RelatedRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stale: Vec<StaleRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    totp_reuse: Vec<TotpReuse>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    credential_reuse: Vec<CredentialReuse>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    password_conflicts: Vec<PasswordConflict>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    password_reuse: Vec<PasswordReuse>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    breached: Vec<BreachedLogin>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hygiene: Option<HygieneScore>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resolved: Vec<Conflict>,
+    /// Groups decided with `--interactive`; they are not listed in `groups`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    chosen: Vec<ChosenGroup>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    split_files: Vec<SplitFile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    integrity: Option<Integrity>,
+}
+
+/// Merkle roots over the canonicalized input and output items plus a digest
+/// of the effective config, so an output file can later be tied to the input
+/// and settings that produced it.
+#[derive(Debug, Serialize)]
+struct Integrity {
+    algorithm: &'static str,
+    input_root: String,
+    output_root: String,
+    config_hash: String,
 }
 
 #[derive(Debug, Serialize)]
 struct ReportGroup {
-    key: String,
-    policy_value: Option<Value>,
+    id: String,
+    /// The raw comparison key, which can hold passwords. Only used to order
+    /// groups; reports carry the salted `id` instead. Wiped when dropped.
+    #[serde(skip)]
+    key: Zeroizing<String>,
     count: usize,
     sample_names: Vec<String>,
     sample_ids: Vec<String>,
     differing_paths: Vec<String>,
+    /// What the items were compared by, e.g. `domain+username+password`.
+    basis: String,
+    /// The normalized policy key values the group shares, with secrets
+    /// redacted. Empty for groups matched on item content.
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    matched: Map<String, Value>,
+    kept_id: Option<String>,
+    kept_name: Option<String>,
+    /// Over `max_auto_remove_per_group`: nothing was removed and `removals`
+    /// lists what would have been, for review.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    held: bool,
+    removals: Vec<ReportRemoval>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            dedup: DedupConfig::default(),
-            ignore: IgnoreConfig::default(),
-            normalize: NormalizeConfig::default(),
-            output: OutputConfig::default(),
-        }
-    }
+/// A duplicate group settled by hand with `--interactive`.
+#[derive(Debug, Serialize)]
+struct ChosenGroup {
+    kept_id: Option<String>,
+    kept_name: Option<String>,
+    removals: Vec<ReportRemoval>,
 }
 
-impl Default for DedupConfig {
-    fn default() -> Self {
-        Self {
-            keep: Keep::First,
-            policy_keys: vec![DedupKey::Domain, DedupKey::Username, DedupKey::Password],
-        }
-    }
+#[derive(Debug, Serialize)]
+struct ReportRemoval {
+    id: Option<String>,
+    name: Option<String>,
+    reason: String,
 }
 
-impl Default for IgnoreConfig {
-    fn default() -> Self {
-        Self {
-            keys: vec![
-                "id".to_string(),
-                "revisionDate".to_string(),
-                "creationDate".to_string(),
-                "passwordHistory".to_string(),
-            ],
-            paths: Vec::new(),
-        }
-    }
+/// Top-level shape of the input, printed before processing starts.
+#[derive(Debug, Serialize)]
+struct InputSummary {
+    /// Canonical input path or `bw serve` URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Server behind `--bw-serve`, when it could be detected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<ServerInfo>,
+    encrypted: Option<bool>,
+    items: usize,
+    folders: usize,
+    collections: usize,
+    /// An organization export: a `collections` array, or items owned by an
+    /// organization.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    organization: bool,
+    types: BTreeMap<String, usize>,
 }
 
-impl Default for NormalizeConfig {
-    fn default() -> Self {
-        Self {
-            trim_strings: false,
-            lowercase_strings: false,
-            sort_uris: true,
-        }
-    }
+/// The server a `bw serve` instance is logged in to, read from the server's
+/// public `/api/config` endpoint.
+#[derive(Clone, Debug, Serialize)]
+struct ServerInfo {
+    url: String,
+    name: String,
+    version: Option<String>,
 }
 
-impl Default for OutputConfig {
-    fn default() -> Self {
-        Self { pretty: false }
+impl ServerInfo {
+    fn is_vaultwarden(&self) -> bool {
+        self.name.eq_ignore_ascii_case("vaultwarden")
     }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// Counts handed to `--notify` targets once a run finishes.
+#[derive(Debug, Serialize)]
+struct RunSummary {
+    source: String,
+    output: Option<String>,
+    dry_run: bool,
+    total_items: usize,
+    kept: usize,
+    removed: usize,
+    duplicate_groups: usize,
+    /// The counts above per item type (`login`, `secure_note`, ...).
+    types: BTreeMap<String, TypeCounts>,
+    elapsed_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hygiene_score: Option<u32>,
+}
 
-    let input = &args.input;
-    let output = args
-        .output
-        .clone()
-        .unwrap_or_else(|| default_output_path(input));
+#[derive(Debug, Default, Serialize)]
+struct TypeCounts {
+    total_items: usize,
+    kept: usize,
+    removed: usize,
+}
 
-    if output.exists() && !args.force && !args.dry_run {
-        anyhow::bail!(
-            "output file already exists: {} (use --force to overwrite)",
-            output.display()
-        );
-    }
+/// What `--summary-json` writes: the run summary and the config it ran with,
+/// after the config file and flags were applied.
+#[derive(Serialize)]
+struct SummaryFile<'a> {
+    #[serde(flatten)]
+    summary: &'a RunSummary,
+    config: &'a Config,
+}
 
-    let mut config = load_config(args.config.as_deref())?;
+/// Funnels every file the tool writes so `--read-only` can refuse them in
+/// one place. Files are created readable by the current user only.
+#[derive(Clone, Copy, Debug)]
+struct FileWriter {
+    read_only: bool,
+    /// Refuse symlinks that escape their directory (`--watch`).
+    contain_symlinks: bool,
+    /// Write into directories other users can write to
+    /// (`--allow-insecure-location`).
+    allow_insecure_location: bool,
+}
 
-    if args.keep != Keep::First {
-        config.dedup.keep = args.keep;
-    }
-    if let Some(keys) = args.policy_key.clone() {
-        config.dedup.policy_keys = keys;
-    }
-    if let Some(keys) = args.ignore_key.clone() {
-        config.ignore.keys = keys;
-    }
-    if let Some(paths) = args.ignore_path.clone() {
-        config.ignore.paths = paths;
-    }
-    if args.trim_strings {
-        config.normalize.trim_strings = true;
+/// Orders item names in reports. With the `collation` feature names follow
+/// the Unicode collation rules of the locale in `LC_ALL`, `LC_COLLATE` or
+/// `LANG`, so Cyrillic or CJK names sort the way their readers expect;
+/// otherwise they are compared by code point.
+struct NameCollator {
+    #[cfg(feature = "collation")]
+    collator: icu_collator::CollatorBorrowed<'static>,
+}
+
+impl NameCollator {
+    #[cfg(feature = "collation")]
+    fn new() -> Result<Self> {
+        use icu_collator::{Collator, CollatorPreferences, options::CollatorOptions};
+
+        let locale = ["LC_ALL", "LC_COLLATE", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| {
+                // POSIX form (`ru_RU.UTF-8@euro`) to a BCP 47 tag (`ru-RU`).
+                let tag = value.split(['.', '@']).next().unwrap_or_default();
+                icu_locale::Locale::try_from_str(&tag.replace('_', "-")).ok()
+            });
+        let preferences = locale
+            .map(|locale| CollatorPreferences::from(&locale))
+            .unwrap_or_default();
+        let collator = Collator::try_new(preferences, CollatorOptions::default())
+            .or_else(|_| Collator::try_new(Default::default(), CollatorOptions::default()))
+            .context("failed to load the root collation")?;
+        Ok(Self { collator })
     }
-    if args.lowercase_strings {
-        config.normalize.lowercase_strings = true;
+
+    #[cfg(not(feature = "collation"))]
+    fn new() -> Result<Self> {
+        Ok(Self {})
     }
-    if args.sort_uris != config.normalize.sort_uris {
-        config.normalize.sort_uris = args.sort_uris;
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        #[cfg(feature = "collation")]
+        return self.collator.compare(a, b).then_with(|| a.cmp(b));
+        #[cfg(not(feature = "collation"))]
+        a.cmp(b)
     }
-    if args.pretty {
-        config.output.pretty = true;
+
+    /// Unnamed items sort last.
+    fn compare_names(&self, a: Option<&str>, b: Option<&str>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => self.compare(a, b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
     }
+}
 
-    let input_data = fs::read_to_string(input)
-        .with_context(|| format!("failed to read input file {}", input.display()))?;
+/// Wall-clock limit for the optional candidate passes (`--time-budget`).
+#[derive(Debug)]
+struct TimeBudget {
+    deadline: Option<Instant>,
+}
 
-    let mut root: Value = serde_json::from_str(&input_data)
-        .with_context(|| format!("failed to parse JSON from {}", input.display()))?;
+impl TimeBudget {
+    fn expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
 
-    let items = root
-        .get_mut("items")
-        .and_then(Value::as_array_mut)
-        .context("expected top-level 'items' array in Bitwarden export")?;
+/// Advisory lock on `<path>.lock`, held until dropped at the end of the run.
+/// The lock file itself stays behind: removing it would let a run that
+/// already opened it lock an unlinked file while a third run locks a new one.
+#[derive(Debug)]
+struct PathLock {
+    _file: fs::File,
+}
 
-    let mut items_vec = std::mem::take(items);
+/// Persistent history kept in the `--state` file across runs.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct State {
+    /// Salt for report group IDs. Generated on first use and never written
+    /// anywhere else, so IDs stay stable across runs and cannot be matched
+    /// against guessed keys by someone holding only the report.
+    salt: Option<String>,
+    /// [`KEY_VERSION`] the recorded group IDs were computed with.
+    key_version: Option<u32>,
+    runs: Vec<RunRecord>,
+}
 
-    let ignore_keys = config
-        .ignore
-        .keys
-        .iter()
-        .map(|s| s.to_string())
-        .collect::<HashSet<_>>();
+#[derive(Debug, Deserialize, Serialize)]
+struct RunRecord {
+    timestamp: String,
+    source: String,
+    total_items: usize,
+    kept: usize,
+    removed: usize,
+    duplicate_groups: usize,
+    /// Salted IDs of this run's duplicate groups, to tell which ones are new.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    group_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hygiene_score: Option<u32>,
+}
 
-    let ignore_paths = config
-        .ignore
-        .paths
-        .iter()
-        .filter(|s| !s.trim().is_empty())
-        .map(|s| parse_path(s))
-        .collect::<Vec<_>>();
+/// A login and an identity item sharing the same account name. These are
+/// reported for review only and never removed.
+#[derive(Debug, Serialize)]
+struct RelatedRecord {
+    account: String,
+    login_id: Option<String>,
+    login_name: Option<String>,
+    identity_id: Option<String>,
+    identity_name: Option<String>,
+    domains: Vec<String>,
+}
 
-    if let Some(report_path) = args.report.as_ref() {
-        let report = build_report(
-            &items_vec,
-            &config,
-            &ignore_keys,
-            &ignore_paths,
-        );
-        let report_data = serde_json::to_string_pretty(&report)?;
-        fs::write(report_path, report_data).with_context(|| {
-            format!("failed to write report file {}", report_path.display())
-        })?;
-        println!("Wrote report {}", report_path.display());
-    }
+/// A login whose current password is an old password of another login for
+/// the same site and username, so it is most likely an outdated copy.
+#[derive(Debug, Serialize)]
+struct StaleRecord {
+    /// Position of the stale login in the items, so `--remove-stale` does not
+    /// depend on ids being unique.
+    #[serde(skip)]
+    stale_index: usize,
+    stale_id: Option<String>,
+    stale_name: Option<String>,
+    current_id: Option<String>,
+    current_name: Option<String>,
+    username: String,
+    domains: Vec<String>,
+    /// The current login is strictly newer, so `--remove-stale` drops the stale one.
+    newer: bool,
+    removed: bool,
+}
 
-    let mut seen: HashMap<String, usize> = HashMap::new();
-    let mut deduped: Vec<Value> = Vec::with_capacity(items_vec.len());
-    let mut removed = 0usize;
+/// An `id` carried by more than one item, independent of content. With
+/// `--fix-duplicate-ids` every item after the first gets a fresh id.
+#[derive(Debug, Serialize)]
+struct DuplicateId {
+    id: String,
+    count: usize,
+    names: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    replaced_by: Vec<String>,
+}
 
-    for item in items_vec.drain(..) {
-        let key = build_key(
-            &item,
-            &config,
-            &ignore_keys,
-            &ignore_paths,
-        );
+/// Written by `--conflicts` and read back by `--resolve`.
+#[derive(Debug, Deserialize, Serialize)]
+struct ConflictFile {
+    /// [`KEY_VERSION`] of the run that wrote the file.
+    #[serde(default)]
+    key_version: Option<u32>,
+    source: String,
+    conflicts: Vec<Conflict>,
+}
 
-        match seen.get(&key).copied() {
-            None => {
-                let index = deduped.len();
-                deduped.push(item);
-                seen.insert(key, index);
-            }
-            Some(existing_index) => {
-                let replace = should_replace(
-                    &deduped[existing_index],
-                    &item,
-                    config.dedup.keep,
-                );
-                if replace {
-                    deduped[existing_index] = item;
-                }
-                removed += 1;
-            }
-        }
-    }
+/// Two logins for the same site and username that survived exact dedup
+/// because their contents differ. Nothing happens to them until `decision`
+/// is filled in and the file is passed to `--resolve`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Conflict {
+    username: String,
+    domains: Vec<String>,
+    /// Fields whose values differ between `a` and `b`.
+    differs: Vec<String>,
+    a: ConflictItem,
+    b: ConflictItem,
+    decision: Option<Decision>,
+}
 
-    let total = deduped.len() + removed;
-    root["items"] = Value::Array(deduped);
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ConflictItem {
+    id: String,
+    name: Option<String>,
+    /// Used to skip decisions about items edited since the file was written.
+    #[serde(rename = "revisionDate")]
+    revision_date: Option<String>,
+}
 
-    println!(
-        "Items: {} -> {} (removed {})",
-        total,
-        root["items"].as_array().map(|v| v.len()).unwrap_or(0),
-        removed
-    );
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Decision {
+    KeepA,
+    KeepB,
+    /// Keep `a` and fold the URIs, password, TOTP, notes and fields of `b` into it.
+    Merge,
+    Skip,
+}
 
-    if args.dry_run {
-        return Ok(());
-    }
+/// One file written by `--split-output` and the items it holds.
+#[derive(Debug, Serialize)]
+struct SplitFile {
+    file: String,
+    item_ids: Vec<String>,
+}
 
-    let output_data = if config.output.pretty {
-        serde_json::to_string_pretty(&root)?
-    } else {
-        serde_json::to_string(&root)?
-    };
+/// Logins on different sites sharing one TOTP secret, usually a copy/paste
+/// mistake. The secret itself is not reported.
+#[derive(Debug, Serialize)]
+struct TotpReuse {
+    domains: Vec<String>,
+    item_ids: Vec<String>,
+    item_names: Vec<String>,
+}
 
-    fs::write(&output, output_data)
-        .with_context(|| format!("failed to write output file {}", output.display()))?;
+/// One number for vault health: the weighted average of the category
+/// scores, each 100 minus the percentage of affected items.
+#[derive(Debug, Serialize)]
+struct HygieneScore {
+    score: u32,
+    categories: Vec<HygieneCategory>,
+}
 
-    println!("Wrote {}", output.display());
+#[derive(Debug, Serialize)]
+struct HygieneCategory {
+    name: &'static str,
+    /// Affected items: surplus duplicate copies, logins sharing a password
+    /// with another site, weak or breached passwords, stale copies.
+    count: usize,
+    /// All items for duplicates, logins with a password otherwise.
+    out_of: usize,
+    score: u32,
+    weight: u32,
+}
 
-    Ok(())
+/// One username and password used for logins on different registrable
+/// domains. Not duplicates, but one breach exposes all of them to credential
+/// stuffing. The password itself is not reported.
+#[derive(Debug, Serialize)]
+struct CredentialReuse {
+    username: String,
+    /// Distinct registrable domains, the ranking key.
+    domains: Vec<String>,
+    item_ids: Vec<String>,
+    item_names: Vec<String>,
 }
 
-fn default_output_path(input: &Path) -> PathBuf {
-    let mut output = input.to_path_buf();
-    let ext = input.extension().and_then(|e| e.to_str()).unwrap_or("");
-    let suffix = if ext.is_empty() { "dedup.json" } else { "dedup.json" };
+/// One password shared by logins for different accounts, a site and a
+/// username each.
+#[derive(Debug, Serialize)]
+struct PasswordReuse {
+    /// Salted like report group IDs: stable with `--state`, and meaningless
+    /// without the salt.
+    password_hash: String,
+    /// Distinct registrable domain and username pairs, the ranking key.
+    accounts: usize,
+    domains: Vec<String>,
+    usernames: Vec<String>,
+    item_ids: Vec<String>,
+    item_names: Vec<String>,
+}
 
-    let file_name = input
-        .file_name()
-        .and_then(|f| f.to_str())
-        .unwrap_or("output");
+/// One of several `--input` files and the kept items that came from it, by
+/// ID. An item whose ID is in several inputs is listed under each of them.
+#[derive(Debug, Serialize)]
+struct InputFile {
+    file: String,
+    items: usize,
+    #[serde(skip)]
+    ids: HashSet<String>,
+    kept: usize,
+    kept_ids: Vec<String>,
+}
 
-    let new_name = if ext.is_empty() {
-        format!("{}.{}", file_name, suffix)
-    } else {
-        format!("{}.{}", file_name.trim_end_matches(ext).trim_end_matches('.'), suffix)
-    };
+/// Folders or collections of one name collapsed by `--dedup-folders` or
+/// `--dedup-collections` into the first of them.
+#[derive(Debug, Serialize)]
+struct MergedContainer {
+    name: String,
+    kept_id: String,
+    removed_ids: Vec<String>,
+    /// Items moved from the removed ones to the kept one.
+    moved_items: usize,
+}
 
-    output.set_file_name(new_name);
-    output
+/// A login whose password appears in known breaches (`--hibp-check`). The
+/// password itself is not reported.
+#[derive(Debug, Serialize)]
+struct BreachedLogin {
+    id: Option<String>,
+    name: Option<String>,
+    domains: Vec<String>,
+    /// Times the password was seen in breaches, when the source counts them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    occurrences: Option<u64>,
 }
 
-fn parse_path(path: &str) -> Vec<String> {
-    path.split('.')
-        .filter(|part| !part.is_empty())
-        .map(|part| part.to_string())
-        .collect()
+/// Logins for one site and username with different passwords: a rotated
+/// password next to a stale copy, or two accounts mixed up. They are not
+/// duplicates, so they survive the dedup; the passwords are not reported.
+#[derive(Debug, Serialize)]
+struct PasswordConflict {
+    domain: String,
+    username: String,
+    /// Distinct passwords among `items`.
+    passwords: usize,
+    /// Most recently revised first.
+    items: Vec<ConflictingLogin>,
 }
 
-fn build_key(
-    item: &Value,
-    config: &Config,
-    ignore_keys: &HashSet<String>,
-    ignore_paths: &[Vec<String>],
-) -> String {
-    if !config.dedup.policy_keys.is_empty() {
-        let mut policy_value = build_policy_value(item, &config.dedup.policy_keys);
-        if config.normalize.sort_uris {
-            sort_login_uris(&mut policy_value);
-        }
-        normalize_strings(
-            &mut policy_value,
-            config.normalize.trim_strings,
-            config.normalize.lowercase_strings,
-        );
-        let canonical = canonicalize(&policy_value);
-        return serde_json::to_string(&canonical).unwrap_or_default();
-    }
+#[derive(Debug, Serialize)]
+struct ConflictingLogin {
+    id: Option<String>,
+    name: Option<String>,
+    revision_date: Option<String>,
+    /// `A` for the password of the newest login, `B` for the next different
+    /// one and so on; logins with the same letter share a password.
+    password: String,
+}
 
-    let mut working = item.clone();
-    remove_keys_anywhere(&mut working, ignore_keys);
-    for path in ignore_paths {
-        remove_path(&mut working, path);
-    }
-    if config.normalize.sort_uris {
-        sort_login_uris(&mut working);
-    }
-    normalize_strings(
-        &mut working,
-        config.normalize.trim_strings,
-        config.normalize.lowercase_strings,
-    );
-    let canonical = canonicalize(&working);
-    serde_json::to_string(&canonical).unwrap_or_default()
+/// A login present in both compared vaults (same host and username) whose
+/// passwords differ. Passwords themselves are never written to the matrix.
+#[derive(Debug)]
+struct MatrixRow {
+    domain: String,
+    username: String,
+    left_id: Option<String>,
+    left_name: Option<String>,
+    left_revision: Option<String>,
+    right_id: Option<String>,
+    right_name: Option<String>,
+    right_revision: Option<String>,
+    newer: &'static str,
 }
 
-fn build_policy_value(item: &Value, keys: &[DedupKey]) -> Value {
-    let mut map = Map::new();
-    for key in keys {
-        match key {
-            DedupKey::Domain => {
-                let domains = extract_domains(item);
-                map.insert("domain".to_string(), Value::Array(domains));
+/// Output file used when the vault comes from `bw serve` and no `--output`
+/// is given.
+const BW_SERVE_OUTPUT: &str = "bw-serve.dedup.json";
+const BITWARDEN_CLOUD_URL: &str = "https://vault.bitwarden.com";
+
+fn main() -> Result<()> {
+    #[cfg(feature = "gui")]
+    if std::env::args_os().len() == 1 {
+        logging::init(logging::level(0, false), logging::LogFormat::Text)?;
+        return run_file_picker();
+    }
+
+    let args = Args::parse();
+    logging::init(logging::level(args.verbose, args.quiet), args.log_format)?;
+    match args.command {
+        None => run_dedup_command(args.dedup, DedupMode::Dedup),
+        Some(command) => match command {
+            Command::Dedup(dedup) => run_dedup_command(dedup, DedupMode::Dedup),
+            Command::Check(dedup) => run_dedup_command(dedup, DedupMode::Check),
+            Command::Report(dedup) => run_dedup_command(dedup, DedupMode::Report),
+            Command::Conflicts(dedup) => run_dedup_command(dedup, DedupMode::Conflicts),
+            Command::Analyze(dedup) => run_dedup_command(dedup, DedupMode::Analyze),
+            Command::Config(ConfigCommand::Init { output, force }) => {
+                run_config_init(output.as_deref(), force)
             }
-            DedupKey::Username => {
-                map.insert("username".to_string(), extract_login_field(item, "username"));
+            Command::Config(ConfigCommand::Validate { config, overrides }) => {
+                run_config_validate(&config, &overrides)
             }
-            DedupKey::Password => {
-                map.insert("password".to_string(), extract_login_field(item, "password"));
+            Command::LintConfig { config } => run_lint_config(&config),
+            Command::PreviewNormalize {
+                item_id,
+                input,
+                config,
+                items_path,
+                no_redact,
+            } => {
+                redact::set_enabled(!no_redact);
+                run_preview_normalize(&item_id, &input, &config, items_path.as_deref())
             }
-            DedupKey::Name => {
-                map.insert(
-                    "name".to_string(),
-                    item.get("name").cloned().unwrap_or(Value::Null),
-                );
+            Command::Explain {
+                id,
+                name,
+                input,
+                config,
+                items_path,
+                no_redact,
+            } => {
+                redact::set_enabled(!no_redact);
+                let selector = match (id, name) {
+                    (Some(id), _) => ItemSelector::Id(id),
+                    (None, name) => ItemSelector::Name(name.unwrap_or_default()),
+                };
+                run_explain(&selector, &input, &config, items_path.as_deref())
             }
-            DedupKey::Uri => {
-                let uris = extract_uris(item);
-                map.insert("uri".to_string(), Value::Array(uris));
+            Command::Fingerprint {
+                input,
+                config,
+                items_path,
+                salt_file,
+                output,
+            } => run_fingerprint(
+                &input,
+                &config,
+                items_path.as_deref(),
+                salt_file.as_deref(),
+                output.as_deref(),
+            ),
+            Command::Validate {
+                input,
+                input_file,
+                items_path,
+            } => {
+                let input = input.or(input_file).expect("clap requires an input");
+                run_validate(&input, items_path.as_deref())
             }
-            DedupKey::Totp => {
-                map.insert("totp".to_string(), extract_login_field(item, "totp"));
+            Command::Diff {
+                left,
+                right,
+                output,
+                matrix,
+                format,
+                ..
+            } if matrix || format.is_some() => {
+                run_compare(&left, &right, output.as_deref(), format)
             }
-        }
-    }
-    Value::Object(map)
-}
-
-fn extract_login_field(item: &Value, field: &str) -> Value {
-    item.get("login")
-        .and_then(Value::as_object)
-        .and_then(|login| login.get(field))
-        .cloned()
-        .unwrap_or(Value::Null)
-}
-
-fn extract_uris(item: &Value) -> Vec<Value> {
-    let mut uris = Vec::new();
-    if let Some(login) = item.get("login").and_then(Value::as_object) {
-        if let Some(Value::Array(items)) = login.get("uris") {
-            for entry in items {
-                match entry {
-                    Value::Object(map) => {
-                        if let Some(Value::String(uri)) = map.get("uri") {
-                            uris.push(Value::String(uri.clone()));
-                        }
-                    }
-                    Value::String(uri) => uris.push(Value::String(uri.clone())),
-                    _ => {}
+            Command::Diff {
+                left,
+                right,
+                output,
+                config,
+                no_config,
+                ..
+            } => run_item_diff(
+                &left,
+                &right,
+                output.as_deref(),
+                &ConfigArgs {
+                    config,
+                    no_config,
+                    ..ConfigArgs::default()
+                },
+            ),
+            Command::Sync {
+                bw,
+                apply,
+                script,
+                force,
+                no_sync,
+                config,
+                keep,
+                prefer,
+                merge,
+                #[cfg(feature = "server-api")]
+                server,
+                #[cfg(feature = "server-api")]
+                password_file,
+            } => {
+                let mut config = load_config(&config)?;
+                if let Some(keep) = keep {
+                    config.dedup.keep = keep;
+                }
+                if let Some(prefer) = prefer {
+                    config.dedup.prefer = prefer;
+                }
+                if let Some(merge) = merge {
+                    config.dedup.merge = merge;
+                }
+                let target = match (apply, script) {
+                    (true, _) => SyncTarget::Apply,
+                    (false, Some(path)) => SyncTarget::Script { path, force },
+                    (false, None) => SyncTarget::Stdout,
+                };
+                #[cfg(feature = "server-api")]
+                if let Some(server) = server {
+                    check_server_sync(&config)?;
+                    let credentials = server_api_credentials(&server, password_file.as_deref())?;
+                    let api = server_api::ServerApi::login(&server, &credentials)?;
+                    return run_sync(&api, &bw, config, target);
                 }
+                let cli = bw_cli::BwCli::new(&bw);
+                if !no_sync {
+                    cli.sync()?;
+                }
+                run_sync(&cli, &bw, config, target)
             }
-        }
-    }
-    uris
-}
-
-fn extract_domains(item: &Value) -> Vec<Value> {
-    let mut domains: Vec<String> = Vec::new();
-    for uri_value in extract_uris(item) {
-        if let Value::String(uri) = uri_value {
-            if let Some(host) = extract_domain_from_uri(&uri) {
-                domains.push(host);
-            } else {
-                domains.push(uri);
+            Command::Completions { shell } => {
+                write_completions(shell, &mut std::io::stdout());
+                Ok(())
             }
-        }
+            Command::Manpage { out_dir } => run_manpage(out_dir.as_deref()),
+            #[cfg(feature = "test-fixtures")]
+            Command::VerifyFixtures { bless } => fixtures::run_verify_fixtures(bless),
+        },
     }
-    domains.sort();
-    domains.dedup();
-    domains.into_iter().map(Value::String).collect()
 }
 
-fn extract_domain_from_uri(uri: &str) -> Option<String> {
-    let without_scheme = uri.split("://").nth(1).unwrap_or(uri);
-    let host_port = without_scheme.split('/').next().unwrap_or(without_scheme);
-    let host = host_port.split('@').last().unwrap_or(host_port);
-    let host = host.split(':').next().unwrap_or(host);
-    if host.is_empty() {
-        None
-    } else {
-        Some(host.to_string())
-    }
+/// What `dedup`, `check` and `report` do with the shared pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DedupMode {
+    Dedup,
+    /// Dry run that fails when anything would be removed.
+    Check,
+    /// Dry run that writes the report, to stdout by default.
+    Report,
+    /// Dry run that lists password conflicts.
+    Conflicts,
+    /// Dry run that lists reused passwords.
+    Analyze,
 }
 
-fn build_report(
-    items: &[Value],
-    config: &Config,
-    ignore_keys: &HashSet<String>,
-    ignore_paths: &[Vec<String>],
-) -> Report {
-    let mut groups: HashMap<String, Vec<&Value>> = HashMap::new();
-    for item in items {
-        let key = build_key(item, config, ignore_keys, ignore_paths);
-        groups.entry(key).or_default().push(item);
+fn run_dedup_command(mut args: DedupArgs, mode: DedupMode) -> Result<()> {
+    if args.input.is_empty() {
+        args.input.extend(args.input_file.take());
+    }
+    let from_stdin = args.input.iter().filter(|input| is_stdio(input)).count();
+    if from_stdin > 1 {
+        anyhow::bail!("--input - can only be given once");
+    }
+    if from_stdin == 1 && args.output.is_none() {
+        args.output = Some(PathBuf::from("-"));
+    }
+    let to_stdout = args.output.as_deref().is_some_and(is_stdio);
+    if (from_stdin == 1 || to_stdout) && args.watch.is_some() {
+        anyhow::bail!("--watch needs files, not stdin or stdout");
+    }
+    if from_stdin == 1 && args.interactive {
+        anyhow::bail!(
+            "--interactive reads its answers from stdin, so the export cannot come from there"
+        );
     }
+    logging::set_status_to_stderr(to_stdout || args.summary_json.as_deref().is_some_and(is_stdio));
+    redact::set_enabled(!args.no_redact);
 
-    let mut report_groups = Vec::new();
-    let mut removed = 0usize;
+    #[cfg(feature = "wasm-plugins")]
+    formats::load_plugins(&args.plugin)?;
+    formats::set_key_value_options(formats::KeyValueOptions {
+        name_template: args.kv_name_template.clone(),
+        folders: args.kv_folder.clone(),
+    })?;
 
-    for (key, group) in groups {
-        if group.len() <= 1 {
-            continue;
-        }
-        removed += group.len() - 1;
-        let sample_names = group
-            .iter()
-            .filter_map(|item| item.get("name").and_then(Value::as_str))
-            .take(5)
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>();
-        let sample_ids = group
-            .iter()
-            .filter_map(|item| item.get("id").and_then(Value::as_str))
-            .take(5)
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>();
-        let differing_paths = collect_differing_paths(group.as_slice(), ignore_keys, ignore_paths);
-        let policy_value = if config.dedup.policy_keys.is_empty() {
-            None
-        } else {
-            Some(build_policy_value(group[0], &config.dedup.policy_keys))
-        };
+    if args.list_formats {
+        list_formats();
+        return Ok(());
+    }
+    if args.list_keep_strategies {
+        list_keep_strategies();
+        return Ok(());
+    }
 
-        report_groups.push(ReportGroup {
-            key,
-            policy_value,
-            count: group.len(),
-            sample_names,
-            sample_ids,
-            differing_paths,
-        });
+    if mode != DedupMode::Dedup {
+        if args.watch.is_some() {
+            anyhow::bail!("--watch only applies to dedup");
+        }
+        if args.interactive {
+            anyhow::bail!("--interactive only applies to dedup");
+        }
+        args.dry_run = true;
+    }
+    if mode == DedupMode::Conflicts {
+        args.find_password_conflicts = true;
+    }
+    if mode == DedupMode::Analyze {
+        args.find_password_reuse = true;
+    }
+    if mode == DedupMode::Report && args.report.is_none() {
+        args.report = Some(PathBuf::from("-"));
     }
 
-    report_groups.sort_by(|a, b| b.count.cmp(&a.count));
+    if let Some(interval) = args.watch {
+        args.force = true;
+        return run_watch(&args, Duration::from_secs(interval));
+    }
 
-    Report {
-        total_items: items.len(),
-        duplicate_groups: report_groups.len(),
-        removed,
-        groups: report_groups,
+    let summary = run_dedup(&args)?;
+    if mode == DedupMode::Check && summary.removed > 0 {
+        anyhow::bail!(
+            "{} duplicate item(s) found in {}",
+            summary.removed,
+            summary.source
+        );
+    }
+    if let (Some(min_score), Some(score)) = (args.min_score, summary.hygiene_score)
+        && score < min_score
+    {
+        anyhow::bail!("hygiene score {} is below --min-score {}", score, min_score);
     }
+    Ok(())
 }
 
-fn collect_differing_paths(
-    items: &[&Value],
-    ignore_keys: &HashSet<String>,
-    ignore_paths: &[Vec<String>],
-) -> Vec<String> {
-    if items.len() < 2 {
-        return Vec::new();
-    }
-    let mut diffs = HashSet::new();
-    let baseline = items[0];
-    for item in &items[1..] {
-        diff_values(
-            baseline,
-            item,
-            &mut Vec::new(),
-            &mut diffs,
-            ignore_keys,
-            ignore_paths,
+/// Where `sync` sends its changes.
+enum SyncTarget {
+    /// Run them through the CLI.
+    Apply,
+    Script {
+        path: PathBuf,
+        force: bool,
+    },
+    Stdout,
+}
+
+/// Dedups the live vault in memory and applies the result as edits and
+/// deletions by ID, or writes them as a script calling the `bw` at
+/// `program`. Progress goes to stderr so the script can be piped from stdout.
+/// The server API mode only deletes items, so merging, wherever it was turned
+/// on, must be refused before the first request rather than at the first
+/// edit, after some deletions went out.
+#[cfg(feature = "server-api")]
+fn check_server_sync(config: &Config) -> Result<()> {
+    if config.dedup.merge {
+        anyhow::bail!(
+            "--server only deletes duplicates and cannot write merged items back, but merging is on (from --merge, the config or $BW_DEDUP_MERGE); pass --merge false"
         );
     }
-    let mut paths = diffs.into_iter().collect::<Vec<_>>();
-    paths.sort();
-    paths
+    Ok(())
 }
 
-fn diff_values(
-    a: &Value,
-    b: &Value,
-    path: &mut Vec<String>,
-    diffs: &mut HashSet<String>,
-    ignore_keys: &HashSet<String>,
-    ignore_paths: &[Vec<String>],
-) {
-    if a == b {
-        return;
+fn run_sync(
+    vault: &dyn bw_cli::Vault,
+    program: &Path,
+    config: Config,
+    target: SyncTarget,
+) -> Result<()> {
+    // The script may go to stdout.
+    logging::set_status_to_stderr(true);
+    let listed = vault.list_items()?;
+    let mut root = serde_json::json!({"encrypted": false, "items": listed.clone()});
+    let report = Deduper::from_config(config).dedup(&mut root)?;
+    let kept = root["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    info!(
+        "Items: {} -> {} (removed {})",
+        report.total_items,
+        report.kept,
+        report.removed.len()
+    );
+    let (actions, left_out) = bw_cli::plan_sync(&listed, kept, &report.removed);
+    if !left_out.is_empty() {
+        let mut message = format!("left {} item(s) in the vault:", left_out.len());
+        for (name, reason) in &left_out {
+            message.push_str(&format!("\n  {:?}: {}", name, reason));
+        }
+        warn!("{}", message);
     }
-    if is_ignored_path(path, ignore_paths) {
-        return;
+    if actions.is_empty() {
+        info!("Nothing to change in the vault");
+        return Ok(());
     }
 
-    match (a, b) {
-        (Value::Object(a_map), Value::Object(b_map)) => {
-            let mut keys: Vec<&String> = a_map.keys().chain(b_map.keys()).collect();
-            keys.sort();
-            keys.dedup();
-            for key in keys {
-                if ignore_keys.contains(key) {
-                    continue;
-                }
-                let a_val = a_map.get(key);
-                let b_val = b_map.get(key);
-                if a_val == b_val {
-                    continue;
-                }
-                path.push(key.clone());
-                match (a_val, b_val) {
-                    (Some(av), Some(bv)) => {
-                        diff_values(av, bv, path, diffs, ignore_keys, ignore_paths)
-                    }
-                    _ => {
-                        diffs.insert(path.join("."));
-                    }
-                }
-                path.pop();
+    match target {
+        SyncTarget::Apply => {
+            for action in &actions {
+                vault.apply(action)?;
+                println!("{}", action.describe());
             }
         }
-        (Value::Array(a_arr), Value::Array(b_arr)) => {
-            if a_arr.len() != b_arr.len() {
-                diffs.insert(format!("{}[]", path.join(".")));
-                return;
-            }
-            for (av, bv) in a_arr.iter().zip(b_arr.iter()) {
-                if av != bv {
-                    diffs.insert(format!("{}[]", path.join(".")));
-                    break;
-                }
+        SyncTarget::Script { path, force } => {
+            if path.exists() && !force {
+                anyhow::bail!(
+                    "script file already exists: {} (use --force to overwrite)",
+                    path.display()
+                );
             }
+            let writer = FileWriter {
+                read_only: false,
+                contain_symlinks: false,
+                allow_insecure_location: false,
+            };
+            writer.write(
+                &path,
+                bw_cli::render_script(program, &actions),
+                "sync script",
+            )?;
+            info!("Wrote {} change(s) to {}", actions.len(), path.display());
         }
-        _ => {
-            diffs.insert(path.join("."));
+        SyncTarget::Stdout => print!("{}", bw_cli::render_script(program, &actions)),
+    }
+    Ok(())
+}
+
+/// Re-runs the dedup whenever the input's modification time changes, or on
+/// every tick when reading from `bw serve`. A failed run is reported and the
+/// watch carries on, so a half-written export doesn't stop the service.
+fn run_watch(args: &DedupArgs, interval: Duration) -> Result<()> {
+    let writer = FileWriter {
+        read_only: args.read_only,
+        contain_symlinks: true,
+        allow_insecure_location: args.allow_insecure_location,
+    };
+    if let Some(path) = args.metrics_file.as_deref()
+        && args.read_only
+    {
+        anyhow::bail!(
+            "--read-only cannot write metrics to {}; use --metrics-addr",
+            path.display()
+        );
+    }
+    let metrics = Arc::new(Mutex::new(WatchMetrics::default()));
+    if let Some(addr) = args.metrics_addr {
+        let listener = bind_metrics_listener(addr)?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        let metrics = Arc::clone(&metrics);
+        std::thread::spawn(move || serve_metrics(listener, &metrics));
+    }
+
+    let modified = |input: &Path| fs::metadata(input).and_then(|meta| meta.modified()).ok();
+    let mut last_seen = Vec::new();
+    let mut first = true;
+    loop {
+        let seen = args
+            .input
+            .iter()
+            .map(|input| modified(input))
+            .collect::<Vec<_>>();
+        if first || args.bw_serve.is_some() || seen != last_seen {
+            first = false;
+            last_seen = seen;
+            let result = run_dedup(args);
+            if let Err(err) = &result {
+                error!("{:#}", err);
+            }
+            let rendered = {
+                let mut metrics = metrics.lock().unwrap_or_else(PoisonError::into_inner);
+                metrics.record(result.as_ref().ok());
+                metrics.render()
+            };
+            if let Some(path) = args.metrics_file.as_deref()
+                && let Err(err) = write_metrics_file(&writer, path, &rendered)
+            {
+                error!("{:#}", err);
+            }
         }
+        std::thread::sleep(interval);
     }
 }
 
-fn is_ignored_path(path: &[String], ignore_paths: &[Vec<String>]) -> bool {
-    ignore_paths.iter().any(|ignore| ignore == path)
+/// Counters exposed by `--metrics-file` and `--metrics-addr` in watch mode.
+#[derive(Debug, Default)]
+struct WatchMetrics {
+    runs: u64,
+    errors: u64,
+    items_processed: u64,
+    duplicates_removed: u64,
+    last_run: i64,
+    last_success: i64,
 }
 
-fn remove_keys_anywhere(value: &mut Value, ignore_keys: &HashSet<String>) {
-    match value {
-        Value::Object(map) => {
-            let keys: Vec<String> = map.keys().cloned().collect();
-            for key in keys {
-                if ignore_keys.contains(&key) {
-                    map.remove(&key);
-                } else if let Some(child) = map.get_mut(&key) {
-                    remove_keys_anywhere(child, ignore_keys);
-                }
+impl WatchMetrics {
+    /// Counts a finished run; `None` for one that failed.
+    fn record(&mut self, summary: Option<&RunSummary>) {
+        let now = chrono::Utc::now().timestamp();
+        self.runs += 1;
+        self.last_run = now;
+        match summary {
+            Some(summary) => {
+                self.items_processed += summary.total_items as u64;
+                self.duplicates_removed += summary.removed as u64;
+                self.last_success = now;
             }
+            None => self.errors += 1,
         }
-        Value::Array(items) => {
-            for item in items {
-                remove_keys_anywhere(item, ignore_keys);
-            }
+    }
+
+    /// Prometheus text exposition format.
+    fn render(&self) -> String {
+        let metrics = [
+            (
+                "runs_total",
+                "counter",
+                "Dedup runs started by --watch.",
+                self.runs.to_string(),
+            ),
+            (
+                "run_errors_total",
+                "counter",
+                "Dedup runs that failed.",
+                self.errors.to_string(),
+            ),
+            (
+                "items_processed_total",
+                "counter",
+                "Items read across all successful runs.",
+                self.items_processed.to_string(),
+            ),
+            (
+                "duplicates_removed_total",
+                "counter",
+                "Items removed across all successful runs.",
+                self.duplicates_removed.to_string(),
+            ),
+            (
+                "last_run_timestamp_seconds",
+                "gauge",
+                "Unix time of the last run.",
+                self.last_run.to_string(),
+            ),
+            (
+                "last_success_timestamp_seconds",
+                "gauge",
+                "Unix time of the last successful run.",
+                self.last_success.to_string(),
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            out.push_str(&format!(
+                "# HELP bw_passport_dedup_{name} {help}\n\
+                 # TYPE bw_passport_dedup_{name} {kind}\n\
+                 bw_passport_dedup_{name} {value}\n"
+            ));
         }
-        _ => {}
+        out
     }
 }
 
-fn remove_path(value: &mut Value, path: &[String]) {
-    if path.is_empty() {
-        return;
+/// Writes the metrics next to `path` and renames them into place, so the
+/// textfile collector never reads a half-written file.
+fn write_metrics_file(writer: &FileWriter, path: &Path, contents: &str) -> Result<()> {
+    let path = writer.resolve(path)?;
+    let mut temp = path.clone().into_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    writer.write(&temp, contents, "metrics file")?;
+    fs::rename(&temp, &path)
+        .with_context(|| format!("failed to write metrics file {}", path.display()))
+}
+
+fn bind_metrics_listener(addr: SocketAddr) -> Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        anyhow::bail!(
+            "--metrics-addr {} is not a loopback address; metrics are only served locally",
+            addr
+        );
     }
+    TcpListener::bind(addr).with_context(|| format!("failed to listen on {}", addr))
+}
 
-    let mut current = value;
-    for (index, segment) in path.iter().enumerate() {
-        match current {
-            Value::Object(map) => {
-                if index == path.len() - 1 {
-                    map.remove(segment);
-                    return;
-                }
-                if let Some(next) = map.get_mut(segment) {
-                    current = next;
+/// Answers every `GET` with the current metrics, whatever the path; other
+/// methods get `405`. One connection at a time is plenty for a scraper.
+fn serve_metrics(listener: TcpListener, metrics: &Mutex<WatchMetrics>) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut request = [0u8; 1024];
+        let read = stream.read(&mut request).unwrap_or(0);
+        let response = if request[..read].starts_with(b"GET ") {
+            let body = metrics
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        };
+        let _ = stream.write_all(response.as_bytes());
+    }
+}
+
+/// Fallback for launches without arguments (e.g. a double-click): asks for
+/// the export with a native dialog, then writes the deduplicated vault and a
+/// report next to it using the default settings.
+#[cfg(feature = "gui")]
+fn run_file_picker() -> Result<()> {
+    use rfd::{FileDialog, MessageDialog, MessageLevel};
+
+    let Some(input) = FileDialog::new()
+        .set_title("Choose a Bitwarden JSON export")
+        .add_filter("Bitwarden export", &["json"])
+        .pick_file()
+    else {
+        return Ok(());
+    };
+    let output = default_output_path(&input);
+    let report = input.with_extension("report.json");
+
+    let mut args = Args::try_parse_from([
+        std::ffi::OsString::from("bw-passport-dedup"),
+        "--input".into(),
+        input.into_os_string(),
+        "--output".into(),
+        output.clone().into_os_string(),
+        "--report".into(),
+        report.clone().into_os_string(),
+    ])?
+    .dedup;
+    // The defaults, as promised: no config file from wherever the binary was
+    // started, and nothing from `BW_DEDUP_CONFIG`, `BW_DEDUP_PROFILE` or the
+    // other variables.
+    args.config = ConfigArgs {
+        no_config: true,
+        no_env: true,
+        ..ConfigArgs::default()
+    };
+
+    let (level, description) = match run_dedup(&args) {
+        Ok(_) => (
+            MessageLevel::Info,
+            format!("Wrote {}\nReport: {}", output.display(), report.display()),
+        ),
+        Err(err) => (MessageLevel::Error, format!("{:#}", err)),
+    };
+    MessageDialog::new()
+        .set_title("bw-passport-dedup")
+        .set_level(level)
+        .set_description(description)
+        .show();
+    Ok(())
+}
+
+fn run_dedup(args: &DedupArgs) -> Result<RunSummary> {
+    let started = Instant::now();
+    if args.read_only
+        && let Some(report) = args.report.as_deref()
+        && report != Path::new("-")
+    {
+        anyhow::bail!(
+            "--read-only cannot write the report to {}; use --report - to print it",
+            report.display()
+        );
+    }
+    if let Some(path) = args.summary_json.as_deref() {
+        if args.read_only && !is_stdio(path) {
+            anyhow::bail!(
+                "--read-only cannot write the summary to {}; use --summary-json - to print it",
+                path.display()
+            );
+        }
+        if is_stdio(path) && args.report.as_deref().is_some_and(is_stdio) {
+            anyhow::bail!("--summary-json - and --report - would both write to stdout");
+        }
+    }
+    let budget = TimeBudget {
+        deadline: args
+            .time_budget
+            .map(|secs| Instant::now() + Duration::from_secs(secs)),
+    };
+    let mut incomplete_passes = Vec::new();
+
+    let mut output = match (&args.output, args.input.first()) {
+        (Some(output), _) => output.clone(),
+        (None, Some(input)) => default_output_path(input),
+        (None, None) => PathBuf::from(BW_SERVE_OUTPUT),
+    };
+    if args.output.is_none()
+        && let Some(name) = args.output_format.as_deref()
+        && let Some(extension) = formats::export_adapter_named(name)?.extensions().first()
+    {
+        output.set_extension(extension);
+    }
+    let writer = FileWriter {
+        read_only: args.read_only,
+        contain_symlinks: args.watch.is_some(),
+        allow_insecure_location: args.allow_insecure_location,
+    };
+    let to_stdout = is_stdio(&output);
+    let output = if to_stdout {
+        output
+    } else {
+        writer.resolve(&output)?
+    };
+    let output_name = if to_stdout {
+        "stdout".to_string()
+    } else {
+        output.display().to_string()
+    };
+    if to_stdout {
+        if args.split_output.is_some() {
+            anyhow::bail!("--split-output writes several files and cannot write to stdout");
+        }
+        if args.emit_changed_only {
+            anyhow::bail!(
+                "--emit-changed-only writes a deletion plan next to the output and needs an output file"
+            );
+        }
+        if args.report.as_deref().is_some_and(is_stdio) {
+            anyhow::bail!("--report - and --output - would both write to stdout");
+        }
+        if args.summary_json.as_deref().is_some_and(is_stdio) {
+            anyhow::bail!("--summary-json - and --output - would both write to stdout");
+        }
+        if args.emit_deletions.is_some() && args.deletions_file.is_none() {
+            anyhow::bail!("--emit-deletions needs --deletions-file when the output goes to stdout");
+        }
+    }
+    let state_path = args
+        .state
+        .as_deref()
+        .map(|path| writer.resolve(path))
+        .transpose()?;
+    let removed_output = args
+        .removed_output
+        .as_deref()
+        .map(|path| writer.resolve(path))
+        .transpose()?;
+
+    let dry_run = args.dry_run || args.read_only || args.sample.is_some();
+
+    if args.split_output.is_none() && !to_stdout && output.exists() && !args.force && !dry_run {
+        anyhow::bail!(
+            "output file already exists: {} (use --force to overwrite)",
+            output.display()
+        );
+    }
+    if let Some(path) = removed_output.as_deref() {
+        if path == output {
+            anyhow::bail!(
+                "--removed-output must not be the output file {}",
+                path.display()
+            );
+        }
+        if path.exists() && !args.force && !dry_run {
+            anyhow::bail!(
+                "removed items file already exists: {} (use --force to overwrite)",
+                path.display()
+            );
+        }
+    }
+
+    // Lock every file this run writes whose name is known up front; split
+    // parts are locked once their count is known, before they are written.
+    let mut lock_paths = Vec::new();
+    if !dry_run && args.split_output.is_none() && !to_stdout {
+        lock_paths.push(output.clone());
+    }
+    if !dry_run && args.emit_changed_only {
+        lock_paths.push(output.with_extension("deletions.json"));
+    }
+    if !dry_run {
+        lock_paths.extend(removed_output.clone());
+    }
+    if !dry_run {
+        lock_paths.extend(state_path.clone());
+    }
+    lock_paths.extend(
+        args.report
+            .iter()
+            .filter(|path| path.as_path() != Path::new("-"))
+            .cloned(),
+    );
+    lock_paths.extend(args.conflicts.clone());
+    lock_paths.extend(args.deletions_file.clone());
+    lock_paths.extend(
+        args.summary_json
+            .iter()
+            .filter(|path| !is_stdio(path))
+            .cloned(),
+    );
+    let mut locks = lock_paths
+        .iter()
+        .map(|path| writer.lock(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let config = effective_config(&args.config, &args.overrides)?;
+
+    let source = match &args.bw_serve {
+        Some(url) => url.clone(),
+        None => args
+            .input
+            .iter()
+            .map(|input| input_label(input))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    if streams(args, &config)
+        && let Some(summary) =
+            run_streamed_dedup(args, &config, &writer, &output, &source, started)?
+    {
+        return Ok(summary);
+    }
+
+    let server = args
+        .bw_serve
+        .as_deref()
+        .and_then(|url| detect_bw_serve_server(url, args.probe_server));
+    let (mut root, export_key, mut input_files) = match (&args.bw_serve, args.input.as_slice()) {
+        (Some(url), _) => (fetch_bw_serve_vault(url)?, None, Vec::new()),
+        (None, []) => anyhow::bail!("missing --input <FILE>"),
+        (None, inputs) => read_inputs(inputs, args)?,
+    };
+    match export_key {
+        Some(_) => info!("Decrypted password-protected export"),
+        None if args.encrypt_output => {
+            anyhow::bail!("--encrypt-output needs a password-protected input export")
+        }
+        None => {}
+    }
+
+    let items_path = locate_items_path(&root, args.items_path.as_deref())?;
+    let mut items_vec = items_at_mut(&mut root, &items_path)
+        .map(std::mem::take)
+        .unwrap_or_default();
+    debug!(
+        items = items_vec.len(),
+        path = %items_path.join("."),
+        "read the items array"
+    );
+
+    let mut input_summary = summarize_input(&root, &items_vec);
+    input_summary.source = Some(source.clone());
+    if let Some(server) = server.as_ref() {
+        info!(
+            "Server: {} {} at {}",
+            server.name,
+            server.version.as_deref().unwrap_or("(unknown version)"),
+            server.url
+        );
+        if !args.probe_server && server.url != BITWARDEN_CLOUD_URL {
+            info!("  (use --probe-server to check for Vaultwarden and its known caveats)");
+        }
+    }
+    input_summary.server = server.clone();
+    let input_root = args.report.is_some().then(|| merkle_root(&items_vec));
+    print_input_summary(&input_summary);
+    check_input_sanity(&items_vec)?;
+
+    if let Some(sample_size) = args.sample {
+        let seed = match args.seed {
+            Some(seed) => seed,
+            None if args.deterministic => 0,
+            None => rand::random(),
+        };
+        let total = items_vec.len();
+        items_vec = sample_items(items_vec, sample_size, seed);
+        info!(
+            "Sample mode: {} of {} items (seed {}); the vault output is not written",
+            items_vec.len(),
+            total,
+            seed
+        );
+    }
+
+    let mut duplicate_ids = find_duplicate_ids(&items_vec);
+    // Kept items edited before the dedup pass, flagged as changed after it.
+    let mut edited_ids = HashSet::new();
+    if !duplicate_ids.is_empty() {
+        let extra = duplicate_ids
+            .iter()
+            .map(|duplicate| duplicate.count - 1)
+            .sum::<usize>();
+        if args.fix_duplicate_ids {
+            fix_duplicate_ids(&mut items_vec, &mut duplicate_ids, args.deterministic)?;
+            edited_ids.extend(
+                duplicate_ids
+                    .iter()
+                    .flat_map(|duplicate| duplicate.replaced_by.iter().cloned()),
+            );
+            info!("Assigned fresh ids to {} item(s) with duplicate ids", extra);
+        } else {
+            warn!(
+                "{} id(s) are shared by more than one item ({} extra item(s)); use --fix-duplicate-ids to reassign them",
+                duplicate_ids.len(),
+                extra
+            );
+        }
+    }
+
+    let merged_folders = if args.dedup_folders {
+        collapse_containers(
+            &mut root,
+            &mut items_vec,
+            Container::Folder,
+            &mut edited_ids,
+        )
+    } else {
+        Vec::new()
+    };
+    let merged_collections = if args.dedup_collections {
+        collapse_containers(
+            &mut root,
+            &mut items_vec,
+            Container::Collection,
+            &mut edited_ids,
+        )
+    } else {
+        Vec::new()
+    };
+
+    // The output is checked against these: the dedup must not add any.
+    let input_problems = validate::validate_export(&root, &items_vec)
+        .into_iter()
+        .map(|problem| problem.message)
+        .collect::<HashSet<_>>();
+
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+
+    let mut state = state_path.as_deref().map(load_state).transpose()?;
+    if let (Some(state_path), Some(state)) = (state_path.as_deref(), state.as_mut()) {
+        migrate_state(state, state_path);
+    }
+    let group_salt = match state.as_mut() {
+        _ if args.deterministic => deterministic_salt(&items_vec),
+        Some(state) => state_salt(state)?,
+        None => {
+            if args.report.is_some() {
+                let what = if args.find_password_reuse {
+                    "report group IDs and password hashes"
                 } else {
-                    return;
-                }
+                    "report group IDs"
+                };
+                info!(
+                    "note: {} use a one-off salt and will differ next run; pass --state to keep them stable",
+                    what
+                );
             }
-            _ => return,
+            generate_salt()?
         }
-    }
-}
+    };
+
+    let related = if args.find_related {
+        let (related, complete) = find_related_records(&items_vec, &budget);
+        if !complete {
+            incomplete_passes.push("related".to_string());
+        }
+        info!("Related login/identity records: {}", related.len());
+        if args.report.is_none() {
+            for record in &related {
+                info!(
+                    "  {} <-> {} ({})",
+                    record.login_name.as_deref().unwrap_or("<unnamed>"),
+                    record.identity_name.as_deref().unwrap_or("<unnamed>"),
+                    record.account
+                );
+            }
+        }
+        related
+    } else {
+        Vec::new()
+    };
 
-fn normalize_strings(value: &mut Value, trim_strings: bool, lowercase_strings: bool) {
-    match value {
-        Value::String(s) => {
-            if trim_strings {
-                let trimmed = s.trim().to_string();
-                *s = trimmed;
+    let mut stale = if args.find_stale || args.remove_stale {
+        let (stale, complete) = find_stale_records(&items_vec, &budget);
+        if !complete {
+            incomplete_passes.push("stale".to_string());
+        }
+        stale
+    } else {
+        Vec::new()
+    };
+    if !incomplete_passes.is_empty() {
+        warn!(
+            "time budget exceeded; results of the {} pass(es) are incomplete",
+            incomplete_passes.join(", ")
+        );
+    }
+    if args.find_stale || args.remove_stale {
+        info!("Stale duplicate candidates: {}", stale.len());
+        if args.report.is_none() {
+            for record in &stale {
+                info!(
+                    "  {} is an older copy of {} ({} @ {})",
+                    record.stale_name.as_deref().unwrap_or("<unnamed>"),
+                    record.current_name.as_deref().unwrap_or("<unnamed>"),
+                    record.username,
+                    record.domains.join(",")
+                );
+            }
+        }
+    }
+    let totp_reuse = if args.find_totp_reuse {
+        let totp_reuse = find_totp_reuse(&items_vec);
+        info!("TOTP secrets reused across sites: {}", totp_reuse.len());
+        if args.report.is_none() {
+            for reuse in &totp_reuse {
+                info!(
+                    "  {} ({})",
+                    reuse.item_names.join(", "),
+                    reuse.domains.join(", ")
+                );
             }
-            if lowercase_strings {
-                *s = s.to_ascii_lowercase();
+        }
+        totp_reuse
+    } else {
+        Vec::new()
+    };
+    let credential_reuse = if args.find_credential_reuse {
+        let credential_reuse = find_credential_reuse(&items_vec);
+        info!(
+            "Username/password pairs shared across sites: {}",
+            credential_reuse.len()
+        );
+        if args.report.is_none() {
+            for reuse in &credential_reuse {
+                info!(
+                    "  {} on {} site(s): {}",
+                    reuse.username,
+                    reuse.domains.len(),
+                    reuse.domains.join(", ")
+                );
             }
         }
-        Value::Array(items) => {
-            for item in items {
-                normalize_strings(item, trim_strings, lowercase_strings);
+        credential_reuse
+    } else {
+        Vec::new()
+    };
+    let password_conflicts = if args.find_password_conflicts {
+        let password_conflicts = find_password_conflicts(&items_vec);
+        info!(
+            "Site/username pairs with different passwords: {}",
+            password_conflicts.len()
+        );
+        if args.report.is_none() {
+            for conflict in &password_conflicts {
+                info!(
+                    "  {} @ {}: {} passwords",
+                    conflict.username, conflict.domain, conflict.passwords
+                );
+                for login in &conflict.items {
+                    info!(
+                        "    {} {} ({}, revised {})",
+                        login.password,
+                        login.name.as_deref().unwrap_or("<unnamed>"),
+                        login.id.as_deref().unwrap_or("no id"),
+                        login.revision_date.as_deref().map_or("never", short_date)
+                    );
+                }
             }
         }
-        Value::Object(map) => {
-            for value in map.values_mut() {
-                normalize_strings(value, trim_strings, lowercase_strings);
+        password_conflicts
+    } else {
+        Vec::new()
+    };
+    let password_reuse = if args.find_password_reuse {
+        let password_reuse = find_password_reuse(&items_vec, &group_salt);
+        info!(
+            "Passwords shared by several accounts: {}",
+            password_reuse.len()
+        );
+        if args.report.is_none() {
+            for reuse in &password_reuse {
+                info!(
+                    "  {} on {} account(s): {}",
+                    reuse.password_hash,
+                    reuse.accounts,
+                    reuse.domains.join(", ")
+                );
             }
         }
-        _ => {}
-    }
-}
+        password_reuse
+    } else {
+        Vec::new()
+    };
 
-fn sort_login_uris(value: &mut Value) {
-    let Value::Object(map) = value else { return };
-    let Some(Value::Object(login)) = map.get_mut("login") else {
-        return;
+    let wants_hygiene = args.report.is_some() || args.min_score.is_some() || state.is_some();
+    let breached_passwords = if args.hibp_check || (wants_hygiene && args.breached_hashes.is_some())
+    {
+        let source = match &args.breached_hashes {
+            Some(path) => breach::BreachSource::Corpus(path.clone()),
+            None => breach::BreachSource::RangeApi,
+        };
+        let passwords = items_vec
+            .iter()
+            .filter(|item| item_type(item) == Some(1))
+            .filter_map(login_password)
+            .filter(|password| !password.is_empty());
+        Some(breach::breached_passwords(passwords, &source)?)
+    } else {
+        None
+    };
+    let breached = match &breached_passwords {
+        Some(breached_passwords) if args.hibp_check => {
+            let breached = find_breached_logins(&items_vec, breached_passwords);
+            info!("Logins with breached passwords: {}", breached.len());
+            if args.report.is_none() {
+                for login in &breached {
+                    info!(
+                        "  {} ({}){}",
+                        login.name.as_deref().unwrap_or("<unnamed>"),
+                        login.id.as_deref().unwrap_or("no id"),
+                        login
+                            .occurrences
+                            .map(|count| format!(", seen {} time(s)", count))
+                            .unwrap_or_default()
+                    );
+                }
+            }
+            breached
+        }
+        _ => Vec::new(),
     };
 
-    let Some(Value::Array(uris)) = login.get_mut("uris") else {
-        return;
+    let key_cache = KeyCache::default();
+    let mut hygiene = if wants_hygiene {
+        let (digests, _) =
+            build_key_digests(&items_vec, &config, &ignore_keys, &ignore_paths, &key_cache);
+        let duplicates = items_vec.len() - digests.iter().collect::<HashSet<_>>().len();
+        let stale = if args.find_stale || args.remove_stale {
+            stale.len()
+        } else {
+            find_stale_records(&items_vec, &budget).0.len()
+        };
+        let hygiene = hygiene_score(&items_vec, duplicates, stale, breached_passwords.as_ref());
+        info!(
+            "Hygiene score: {}/100 ({})",
+            hygiene.score,
+            hygiene
+                .categories
+                .iter()
+                .map(|category| format!("{} {}", category.name, category.score))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Some(hygiene)
+    } else {
+        None
     };
+    let hygiene_score = hygiene.as_ref().map(|hygiene| hygiene.score);
+
+    // Items taken out before the dedup pass: stale copies and the losers of
+    // groups decided with --interactive.
+    let mut removed_early = Vec::new();
+    if args.remove_stale {
+        let mut stale_indexes = HashSet::new();
+        for record in stale.iter_mut().filter(|record| record.newer) {
+            record.removed = true;
+            stale_indexes.insert(record.stale_index);
+        }
+        let (removed, remaining): (Vec<_>, Vec<_>) = items_vec
+            .into_iter()
+            .enumerate()
+            .partition(|(index, _)| stale_indexes.contains(index));
+        items_vec = remaining.into_iter().map(|(_, item)| item).collect();
+        removed_early = removed.into_iter().map(|(_, item)| item).collect();
+        info!("Removed {} stale item(s)", removed_early.len());
+    }
+
+    let mut choices = InteractiveChoices::default();
+    if args.interactive {
+        choices = choose_interactively(
+            &mut items_vec,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &key_cache,
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout(),
+        )?;
+        info!(
+            "Decided {} group(s) by hand, left {} whole",
+            choices.chosen.len(),
+            choices.skipped.len()
+        );
+        edited_ids.extend(choices.merged_ids.iter().cloned());
+        removed_early.append(&mut choices.removed);
+    }
 
-    uris.sort_by(|a, b| {
-        let a_key = uri_sort_key(a);
-        let b_key = uri_sort_key(b);
-        a_key.cmp(&b_key)
+    let report_input = args.report.is_some().then(|| items_vec.clone());
+    let check_input = args.self_check.then(|| {
+        let mut check_input = items_vec.clone();
+        check_input.extend(removed_early.iter().cloned());
+        check_input
     });
-}
+    if tracing::enabled!(tracing::Level::TRACE) {
+        for item in &items_vec {
+            let value = redacted_normalized_value(item, &config, &ignore_keys, &ignore_paths);
+            trace!(
+                item = %item_str(item, "id").unwrap_or_else(|| "<no id>".to_string()),
+                "compared by {}",
+                value
+            );
+        }
+    }
+    let mut outcome = dedup_items_with(
+        items_vec,
+        &config,
+        &ignore_keys,
+        &ignore_paths,
+        &key_cache,
+        &choices.skipped,
+    );
+    debug!(
+        distinct = key_cache.len(),
+        groups = outcome.group_keys.len(),
+        "deduplicated after {:.2}s",
+        started.elapsed().as_secs_f64()
+    );
+    outcome.removed.extend(removed_early);
+    if config.dedup.merge {
+        let merged = outcome.changed.iter().filter(|changed| **changed).count();
+        info!("Merged removed duplicates into {} kept item(s)", merged);
+    }
+    let over_cap = outcome
+        .held_keys
+        .iter()
+        .filter(|key| !choices.skipped.contains(key))
+        .count();
+    if let Some(max) = config.dedup.max_auto_remove_per_group
+        && over_cap > 0
+    {
+        info!(
+            "Held back {} group(s) that would lose more than {} item(s); review them in the report",
+            over_cap, max
+        );
+    }
 
-fn uri_sort_key(value: &Value) -> String {
-    match value {
-        Value::Object(map) => map
-            .get("uri")
-            .and_then(Value::as_str)
-            .unwrap_or("")
-            .to_string(),
-        Value::String(s) => s.to_string(),
-        _ => serde_json::to_string(value).unwrap_or_default(),
+    let mut resolved = Vec::new();
+    let mut skipped_pairs = HashSet::new();
+    if let Some(resolve_path) = args.resolve.as_deref() {
+        let file = load_conflicts(resolve_path)?;
+        let version = file.key_version.unwrap_or(1);
+        if version != KEY_VERSION {
+            warn!(
+                "{} was written with dedup key version {} and this build uses version {}; decisions still apply by item ID and revision date, but the conflicts in it may no longer match this run's groups",
+                resolve_path.display(),
+                version,
+                KEY_VERSION
+            );
+        }
+        skipped_pairs = file
+            .conflicts
+            .iter()
+            .filter(|conflict| conflict.decision == Some(Decision::Skip))
+            .map(|conflict| (conflict.a.id.clone(), conflict.b.id.clone()))
+            .collect();
+        resolved = apply_conflict_decisions(&mut outcome, file.conflicts);
     }
-}
 
-fn canonicalize(value: &Value) -> Value {
-    match value {
-        Value::Object(map) => {
-            let mut keys: Vec<&String> = map.keys().collect();
-            keys.sort();
-            let mut new_map = Map::with_capacity(map.len());
-            for key in keys {
-                if let Some(value) = map.get(key) {
-                    new_map.insert(key.clone(), canonicalize(value));
-                }
+    if !edited_ids.is_empty() {
+        for (item, changed) in outcome.kept.iter().zip(outcome.changed.iter_mut()) {
+            if item_str(item, "id").is_some_and(|id| edited_ids.contains(&id)) {
+                *changed = true;
             }
-            Value::Object(new_map)
         }
-        Value::Array(items) => {
-            let canonical_items = items.iter().map(canonicalize).collect();
-            Value::Array(canonical_items)
+    }
+
+    if config.output.apply_field_aliases {
+        let renamed = rename_aliased_fields(&mut outcome, &config.normalize.field_aliases);
+        info!("Renamed aliased fields in {} item(s)", renamed);
+    }
+
+    // The checks see the final item set, after conflict resolution and edits,
+    // and key it afresh: a wrong cached key must not vouch for itself.
+    if let Some(check_input) = check_input.as_deref() {
+        check_invariants_with(
+            check_input,
+            &outcome,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &KeyCache::default(),
+        )?;
+        info!("Self-check passed");
+    }
+
+    if args.verify_idempotent {
+        let rerun = dedup_items_with(
+            outcome.kept.clone(),
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &KeyCache::default(),
+            &outcome.held_keys,
+        );
+        if !rerun.removed.is_empty() || rerun.kept != outcome.kept {
+            anyhow::bail!(
+                "idempotency check failed: re-running on the output removed {} more item(s); normalization is unstable",
+                rerun.removed.len()
+            );
         }
-        _ => value.clone(),
+        info!("Idempotency check passed");
     }
-}
 
-fn should_replace(existing: &Value, candidate: &Value, keep: Keep) -> bool {
-    match keep {
-        Keep::First => false,
-        Keep::Last => true,
-        Keep::Newest => compare_dates(existing, candidate) == Ordering::Less,
-        Keep::Oldest => compare_dates(existing, candidate) == Ordering::Greater,
+    let (known_problems, new_problems): (Vec<_>, Vec<_>) =
+        validate::validate_export(&root, &outcome.kept)
+            .into_iter()
+            .partition(|problem| input_problems.contains(&problem.message));
+    if let Some(problem) = new_problems.first() {
+        anyhow::bail!(
+            "the deduplicated vault fails validation with {} problem(s) the input did not have, first {}; nothing was written",
+            new_problems.len(),
+            problem
+        );
+    }
+    if !known_problems.is_empty() {
+        warn!(
+            "the output keeps {} structural problem(s) of the input; run `validate` to list them",
+            known_problems.len()
+        );
     }
-}
 
-fn compare_dates(existing: &Value, candidate: &Value) -> Ordering {
-    let existing_date = best_date(existing);
-    let candidate_date = best_date(candidate);
+    check_removal_limit(
+        args,
+        outcome.removed.len(),
+        outcome.kept.len() + outcome.removed.len(),
+        dry_run,
+    )?;
 
-    match (existing_date, candidate_date) {
-        (Some(a), Some(b)) => a.cmp(b),
-        (None, Some(_)) => Ordering::Less,
-        (Some(_), None) => Ordering::Greater,
-        (None, None) => Ordering::Equal,
+    if let Some(conflicts_path) = args.conflicts.as_deref() {
+        let conflicts = find_conflicts(&outcome.kept)
+            .into_iter()
+            .filter(|conflict| {
+                !skipped_pairs.contains(&(conflict.a.id.clone(), conflict.b.id.clone()))
+            })
+            .collect::<Vec<_>>();
+        let file = ConflictFile {
+            key_version: Some(KEY_VERSION),
+            source: source.clone(),
+            conflicts,
+        };
+        writer.write(
+            conflicts_path,
+            serde_json::to_string_pretty(&file)?,
+            "conflicts file",
+        )?;
+        info!(
+            "Wrote {} conflict(s) to {}",
+            file.conflicts.len(),
+            conflicts_path.display()
+        );
     }
-}
 
-fn best_date(item: &Value) -> Option<&str> {
-    item.get("revisionDate")
-        .and_then(Value::as_str)
-        .or_else(|| item.get("creationDate").and_then(Value::as_str))
-}
+    if input_files.len() > 1 {
+        for file in &mut input_files {
+            file.kept_ids = outcome
+                .kept
+                .iter()
+                .filter_map(|item| item_str(item, "id"))
+                .filter(|id| file.ids.contains(id))
+                .collect();
+            file.kept = file.kept_ids.len();
+            info!(
+                "Kept from {}: {} of {} item(s)",
+                file.file, file.kept, file.items
+            );
+        }
+    } else {
+        input_files.clear();
+    }
 
-fn load_config(path: Option<&Path>) -> Result<Config> {
-    let default_path = PathBuf::from("config.toml");
-    let config_path = path.unwrap_or(&default_path);
+    let removed = outcome.removed.len();
+    let total = outcome.kept.len() + removed;
+    let duplicate_groups = outcome.group_keys.len();
+    let group_keys = std::mem::take(&mut outcome.group_keys);
 
-    if config_path.exists() {
-        let contents = fs::read_to_string(config_path).with_context(|| {
-            format!("failed to read config file {}", config_path.display())
-        })?;
-        let config: Config = toml::from_str(&contents).with_context(|| {
-            format!("failed to parse config file {}", config_path.display())
-        })?;
-        Ok(config)
+    let mut report = match report_input.as_deref() {
+        Some(report_input) => {
+            let mut report = build_report(
+                report_input,
+                &config,
+                &ignore_keys,
+                &ignore_paths,
+                &group_salt,
+                &key_cache,
+                &outcome.held_keys,
+            )?;
+            report.duplicate_ids = duplicate_ids;
+            report.merged_folders = merged_folders;
+            report.merged_collections = merged_collections;
+            report.input_files = input_files;
+            report.related = related;
+            report.stale = stale;
+            report.totp_reuse = totp_reuse;
+            report.credential_reuse = credential_reuse;
+            report.password_conflicts = password_conflicts;
+            report.password_reuse = password_reuse;
+            report.breached = breached;
+            report.hygiene = hygiene.take();
+            report.input = Some(input_summary);
+            report.partial = !incomplete_passes.is_empty();
+            report.incomplete_passes = incomplete_passes;
+            // Stale removals and resolved conflicts are not part of any group.
+            report.total_items = total;
+            report.removed = removed;
+            Some(report)
+        }
+        None => None,
+    };
+
+    info!(
+        "Items: {} -> {} (removed {})",
+        total,
+        outcome.kept.len(),
+        removed
+    );
+    let mut types: BTreeMap<String, TypeCounts> = BTreeMap::new();
+    for item in &outcome.kept {
+        types.entry(item_type_name(item)).or_default().kept += 1;
+    }
+    for item in &outcome.removed {
+        types.entry(item_type_name(item)).or_default().removed += 1;
+    }
+    for counts in types.values_mut() {
+        counts.total_items = counts.kept + counts.removed;
+    }
+
+    let deletion_plan = (args.emit_changed_only || args.emit_deletions.is_some()).then(|| {
+        let (plan, left_out) = build_deletion_plan(&outcome.removed, server.as_ref());
+        if !left_out.is_empty() {
+            let mut message = format!(
+                "left {} item(s) out of the deletion plan for Vaultwarden:",
+                left_out.len()
+            );
+            for (name, caveat) in &left_out {
+                message.push_str(&format!("\n  {:?}: {}", name, caveat));
+            }
+            warn!("{}", message);
+        }
+        plan
+    });
+    let items = if args.emit_changed_only {
+        let changed = changed_items(outcome.kept, outcome.changed);
+        info!("Changed items: {}", changed.len());
+        changed
     } else {
-        Ok(Config::default())
+        outcome.kept
+    };
+
+    let split_parts = args
+        .split_output
+        .map(|size| split_output_parts(&output, items.len(), size.get()));
+
+    if let (Some(report_path), Some(report)) = (args.report.as_ref(), report.as_mut()) {
+        report.resolved = std::mem::take(&mut resolved);
+        report.chosen = std::mem::take(&mut choices.chosen);
+        if let Some(parts) = split_parts.as_ref() {
+            report.split_files = parts
+                .iter()
+                .map(|(path, range)| SplitFile {
+                    file: path.display().to_string(),
+                    item_ids: items[range.clone()]
+                        .iter()
+                        .filter_map(|item| item_str(item, "id"))
+                        .collect(),
+                })
+                .collect();
+        }
+        let config_json = serde_json::to_vec(&canonicalize(&serde_json::to_value(&config)?))?;
+        report.integrity = Some(Integrity {
+            algorithm: "sha256-merkle",
+            input_root: input_root.unwrap_or_default(),
+            output_root: merkle_root(&items),
+            config_hash: hex::encode(Sha256::digest(&config_json)),
+        });
+        let format = args
+            .report_format
+            .unwrap_or_else(|| ReportFormat::for_path(report_path));
+        let report_data = match (args.report_template.as_deref(), format) {
+            (Some(template), _) => render_report_template(report, template)?,
+            (None, ReportFormat::Json) => serde_json::to_string_pretty(report)?,
+            (None, ReportFormat::Csv) => render_report_csv(report),
+            (None, ReportFormat::Md) => render_report_markdown(report),
+        };
+        if report_path == Path::new("-") {
+            println!("{}", report_data);
+        } else {
+            let report_path = writer.resolve(report_path)?;
+            writer.write(&report_path, report_data, "report file")?;
+            info!("Wrote report {}", report_path.display());
+        }
+    }
+
+    if let (Some(format), Some(plan)) = (args.emit_deletions, deletion_plan.as_ref()) {
+        let deletions = render_deletions(plan, format);
+        match args.deletions_file.as_deref() {
+            None => print!("{}", deletions),
+            Some(path) => {
+                let path = writer.resolve(path)?;
+                writer.write(&path, deletions, "deletions file")?;
+                info!("Wrote deletions {}", path.display());
+            }
+        }
+    }
+
+    if !dry_run {
+        let exporter = output_exporter(args, &output, &output_name)?;
+        let encrypt_with = export_key.as_ref().filter(|_| args.encrypt_output);
+        if encrypt_with.is_some() && exporter.name() != "bitwarden-json" {
+            anyhow::bail!(
+                "--encrypt-output only writes Bitwarden JSON, not {}",
+                exporter.name()
+            );
+        }
+        if export_key.is_some() && encrypt_with.is_none() {
+            warn!(
+                "the input was password-protected but {} is written in plain text; pass --encrypt-output to protect it with the same password",
+                output_name
+            );
+        }
+        let render_with = |exporter: &dyn formats::ExportAdapter, root: &Value| {
+            let data = exporter.export(root, &items_path, config.output.pretty)?;
+            match encrypt_with {
+                Some(key) => protected::lock(&data, key, config.output.pretty),
+                None => Ok(data),
+            }
+        };
+        let render = |root: &Value| render_with(exporter.as_ref(), root);
+        // Taken before the output replaces the items in `root`.
+        let removed_root = removed_output
+            .as_ref()
+            .map(|_| with_items(&root, &items_path, outcome.removed.clone()));
+
+        match split_parts {
+            Some(parts) => {
+                if !args.force
+                    && let Some((path, _)) = parts.iter().find(|(path, _)| path.exists())
+                {
+                    anyhow::bail!(
+                        "output file already exists: {} (use --force to overwrite)",
+                        path.display()
+                    );
+                }
+                for (path, _) in &parts {
+                    locks.push(writer.lock(path)?);
+                }
+                for (path, range) in parts {
+                    let part = with_items(&root, &items_path, items[range.clone()].to_vec());
+                    let data = render(&part)?;
+                    back_up(&writer, &path)?;
+                    writer.write_checked(&path, &data, "output file", |file| {
+                        verify_output(
+                            &path,
+                            file,
+                            &data,
+                            exporter.as_ref(),
+                            &part,
+                            &items_path,
+                            encrypt_with,
+                        )
+                    })?;
+                    info!("Wrote {} ({} item(s))", path.display(), range.len());
+                }
+            }
+            None => {
+                if let Some(slot) = items_at_mut(&mut root, &items_path) {
+                    *slot = items;
+                }
+                let data = render(&root)?;
+                write_vault(
+                    &writer,
+                    &output,
+                    &data,
+                    exporter.as_ref(),
+                    &root,
+                    &items_path,
+                    encrypt_with,
+                )?;
+            }
+        }
+
+        if let (Some(path), Some(removed_root)) = (removed_output.as_deref(), removed_root) {
+            let json = formats::export_adapter_named("bitwarden-json")?;
+            let data = render_with(json.as_ref(), &removed_root)?;
+            writer.write_checked(path, &data, "removed items file", |file| {
+                verify_output(
+                    path,
+                    file,
+                    &data,
+                    json.as_ref(),
+                    &removed_root,
+                    &items_path,
+                    encrypt_with,
+                )
+            })?;
+            info!(
+                "Wrote {} removed item(s) to {}",
+                outcome.removed.len(),
+                path.display()
+            );
+        }
+
+        if let Some(plan) = deletion_plan.as_ref().filter(|_| args.emit_changed_only) {
+            let plan_path = output.with_extension("deletions.json");
+            writer.write(
+                &plan_path,
+                serde_json::to_string_pretty(plan)?,
+                "deletion plan",
+            )?;
+            info!("Wrote deletion plan {}", plan_path.display());
+        }
+    }
+
+    if let (Some(state_path), Some(state)) = (state_path.as_deref(), state.as_mut()) {
+        // Always the state's own salt, so IDs compare across runs even when
+        // report IDs use a per-input salt (`--deterministic`).
+        let salt = state_salt(state)?;
+        let record = RunRecord {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            source: source.clone(),
+            total_items: total,
+            kept: total - removed,
+            removed,
+            duplicate_groups,
+            group_ids: group_keys.iter().map(|key| group_id(&salt, key)).collect(),
+            hygiene_score,
+        };
+        if let Some(previous) = state.runs.last() {
+            print_trend(previous, &record);
+        }
+        // A dry run removed nothing, so it must not show up in the history
+        // as if it had.
+        if dry_run {
+            info!("Dry run; not recording it in {}", state_path.display());
+        } else {
+            state.runs.push(record);
+            save_state(&writer, state_path, state)?;
+        }
+    }
+
+    let summary = RunSummary {
+        source,
+        output: (!dry_run).then(|| output.display().to_string()),
+        dry_run,
+        total_items: total,
+        kept: total - removed,
+        removed,
+        duplicate_groups,
+        types,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+        hygiene_score,
+    };
+    finish_run(args, &config, &writer, &summary)?;
+    Ok(summary)
+}
+
+/// Whether the run can deduplicate its input while parsing it (see
+/// [`run_streamed_dedup`]): a single JSON export file, no pass that needs
+/// every input item or the removed ones, and keys that do not depend on the
+/// other items.
+fn streams(args: &DedupArgs, config: &Config) -> bool {
+    let [input] = args.input.as_slice() else {
+        return false;
+    };
+    let finds = args.find_related
+        || args.find_stale
+        || args.remove_stale
+        || args.find_totp_reuse
+        || args.find_credential_reuse
+        || args.find_password_conflicts
+        || args.find_password_reuse
+        || args.hibp_check;
+    args.bw_serve.is_none()
+        && !is_stdio(input)
+        && formats::import_adapter_for(input).name() == "bitwarden-json"
+        && args
+            .items_path
+            .as_deref()
+            .is_none_or(|path| path == "items")
+        && !finds
+        && !args.encrypt_output
+        && !args.dedup_folders
+        && !args.dedup_collections
+        && !args.fix_duplicate_ids
+        && !args.interactive
+        && !args.self_check
+        && !args.verify_idempotent
+        && !args.emit_changed_only
+        && args.emit_deletions.is_none()
+        && args.removed_output.is_none()
+        && args.split_output.is_none()
+        && args.report.is_none()
+        && args.state.is_none()
+        && args.min_score.is_none()
+        && args.conflicts.is_none()
+        && args.resolve.is_none()
+        && args.sample.is_none()
+        && config.dedup.creation_window_secs.is_none()
+        && config.dedup.max_auto_remove_per_group.is_none()
+}
+
+/// The dedup run for [`streams`]: each item is keyed as the parser reaches
+/// it, and a duplicate is folded into its survivor and dropped at once, so
+/// memory follows the deduplicated vault rather than the input. Of the input
+/// only what the checks need is kept ([`validate::ItemFacts`]). Returns
+/// `None` for exports without a top-level `items` array, such as
+/// password-protected ones, which take the usual path.
+fn run_streamed_dedup(
+    args: &DedupArgs,
+    config: &Config,
+    writer: &FileWriter,
+    output: &Path,
+    source: &str,
+    started: Instant,
+) -> Result<Option<RunSummary>> {
+    let dry_run = args.dry_run || args.read_only;
+    let label = input_label(&args.input[0]);
+    let file =
+        fs::File::open(&label).with_context(|| format!("failed to read input file {}", label))?;
+
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let mut stream = DedupStream::new(config)?;
+    let mut facts = Vec::new();
+    let mut input_types: BTreeMap<String, usize> = BTreeMap::new();
+    let mut owned_by_organization = false;
+    let mut types: BTreeMap<String, TypeCounts> = BTreeMap::new();
+    let mut removed = 0usize;
+    let root = formats::stream_items(std::io::BufReader::new(file), &mut |item| {
+        if !item.is_object() {
+            anyhow::bail!(
+                "item #{} is not a JSON object; the export looks corrupted or truncated",
+                facts.len()
+            );
+        }
+        facts.push(validate::ItemFacts::of(&item));
+        *input_types.entry(item_type_name(&item)).or_default() += 1;
+        owned_by_organization |= item_str(&item, "organizationId").is_some();
+        if tracing::enabled!(tracing::Level::TRACE) {
+            let value = redacted_normalized_value(&item, config, &ignore_keys, &ignore_paths);
+            trace!(
+                item = %item_str(&item, "id").unwrap_or_else(|| "<no id>".to_string()),
+                "compared by {}",
+                value
+            );
+        }
+        if let Some(dropped) = stream.push(item) {
+            types.entry(item_type_name(&dropped)).or_default().removed += 1;
+            removed += 1;
+        }
+        Ok(())
+    })
+    .with_context(|| format!("failed to read {} as bitwarden-json", label))?;
+    if !root.get("items").is_some_and(Value::is_array) {
+        debug!(
+            "{} has no top-level items array to stream; reading it whole",
+            label
+        );
+        return Ok(None);
+    }
+    let items_path = vec!["items".to_string()];
+    debug!(items = facts.len(), "streamed the items array");
+
+    let mut input_summary = summarize_input(&root, &[]);
+    input_summary.source = Some(source.to_string());
+    input_summary.items = facts.len();
+    input_summary.types = input_types;
+    input_summary.organization |= owned_by_organization;
+    print_input_summary(&input_summary);
+    if facts.is_empty() {
+        anyhow::bail!(
+            "export contains no items; refusing to produce an empty vault (is the file truncated?)"
+        );
+    }
+    let mut uses: HashMap<&str, usize> = HashMap::new();
+    for id in facts.iter().filter_map(|facts| facts.id.as_deref()) {
+        *uses.entry(id).or_default() += 1;
+    }
+    let shared = uses.values().filter(|count| **count > 1).count();
+    if shared > 0 {
+        warn!(
+            "{} id(s) are shared by more than one item ({} extra item(s)); use --fix-duplicate-ids to reassign them",
+            shared,
+            uses.values()
+                .map(|count| count.saturating_sub(1))
+                .sum::<usize>()
+        );
+    }
+    drop(uses);
+    let input_problems = validate::validate_facts(&root, facts)
+        .into_iter()
+        .map(|problem| problem.message)
+        .collect::<HashSet<_>>();
+
+    let mut outcome = stream.finish();
+    debug!(
+        groups = outcome.group_keys.len(),
+        "deduplicated after {:.2}s",
+        started.elapsed().as_secs_f64()
+    );
+    if config.dedup.merge {
+        let merged = outcome.changed.iter().filter(|changed| **changed).count();
+        info!("Merged removed duplicates into {} kept item(s)", merged);
+    }
+    if config.output.apply_field_aliases {
+        let renamed = rename_aliased_fields(&mut outcome, &config.normalize.field_aliases);
+        info!("Renamed aliased fields in {} item(s)", renamed);
+    }
+
+    let (known_problems, new_problems): (Vec<_>, Vec<_>) =
+        validate::validate_export(&root, &outcome.kept)
+            .into_iter()
+            .partition(|problem| input_problems.contains(&problem.message));
+    if let Some(problem) = new_problems.first() {
+        anyhow::bail!(
+            "the deduplicated vault fails validation with {} problem(s) the input did not have, first {}; nothing was written",
+            new_problems.len(),
+            problem
+        );
+    }
+    if !known_problems.is_empty() {
+        warn!(
+            "the output keeps {} structural problem(s) of the input; run `validate` to list them",
+            known_problems.len()
+        );
+    }
+
+    let total = outcome.kept.len() + removed;
+    check_removal_limit(args, removed, total, dry_run)?;
+    info!(
+        "Items: {} -> {} (removed {})",
+        total,
+        outcome.kept.len(),
+        removed
+    );
+    for item in &outcome.kept {
+        types.entry(item_type_name(item)).or_default().kept += 1;
+    }
+    for counts in types.values_mut() {
+        counts.total_items = counts.kept + counts.removed;
+    }
+
+    let kept = outcome.kept.len();
+    let duplicate_groups = outcome.group_keys.len();
+    if !dry_run {
+        let output_name = if is_stdio(output) {
+            "stdout".to_string()
+        } else {
+            output.display().to_string()
+        };
+        let exporter = output_exporter(args, output, &output_name)?;
+        let mut root = root;
+        if let Some(slot) = items_at_mut(&mut root, &items_path) {
+            *slot = outcome.kept;
+        }
+        let data = exporter.export(&root, &items_path, config.output.pretty)?;
+        write_vault(
+            writer,
+            output,
+            &data,
+            exporter.as_ref(),
+            &root,
+            &items_path,
+            None,
+        )?;
+    }
+
+    let summary = RunSummary {
+        source: source.to_string(),
+        output: (!dry_run).then(|| output.display().to_string()),
+        dry_run,
+        total_items: total,
+        kept,
+        removed,
+        duplicate_groups,
+        types,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+        hygiene_score: None,
+    };
+    finish_run(args, config, writer, &summary)?;
+    Ok(Some(summary))
+}
+
+/// Fails the run, before anything is written, when it would remove more
+/// than `--max-removed` allows; dry runs and `--yes` only warn.
+fn check_removal_limit(
+    args: &DedupArgs,
+    removed: usize,
+    total: usize,
+    dry_run: bool,
+) -> Result<()> {
+    let Some(limit) = args.max_removed else {
+        return Ok(());
+    };
+    if !limit.exceeded_by(removed, total) {
+        return Ok(());
+    }
+    let message = format!(
+        "{} of {} item(s) ({:.1}%) would be removed, more than --max-removed {}",
+        removed,
+        total,
+        removed as f64 * 100.0 / total as f64,
+        limit
+    );
+    if dry_run {
+        warn!("{}", message);
+    } else if args.yes {
+        warn!("{}; writing it anyway (--yes)", message);
+    } else {
+        anyhow::bail!(
+            "{}; nothing was written (check the dedup policy, or pass --yes to write it anyway)",
+            message
+        );
+    }
+    Ok(())
+}
+
+/// The format the vault is written in: `--output-format`, else the one the
+/// output's extension names.
+fn output_exporter(
+    args: &DedupArgs,
+    output: &Path,
+    output_name: &str,
+) -> Result<Box<dyn formats::ExportAdapter>> {
+    let exporter = match args.output_format.as_deref() {
+        Some(name) => formats::export_adapter_named(name)?,
+        None => formats::export_adapter_for(output),
+    };
+    if matches!(exporter.name(), "env" | "sops-yaml") {
+        info!(
+            "note: {} holds only login names and passwords; keep the JSON export if you need the rest",
+            output_name
+        );
+    }
+    Ok(exporter)
+}
+
+/// Copies an output file about to be replaced to `<path>.bak`. Only
+/// `--force` gets this far with an existing output file.
+fn back_up(writer: &FileWriter, path: &Path) -> Result<()> {
+    if let Some(backup) = writer.back_up(path)? {
+        info!("Backed up {} to {}", path.display(), backup.display());
+    }
+    Ok(())
+}
+
+/// Writes the rendered vault `data` to `output` (or stdout) and checks it.
+fn write_vault(
+    writer: &FileWriter,
+    output: &Path,
+    data: &str,
+    exporter: &dyn formats::ExportAdapter,
+    root: &Value,
+    items_path: &[String],
+    encrypt_with: Option<&protected::ExportKey>,
+) -> Result<()> {
+    if is_stdio(output) {
+        // Nothing to read back; check the rendering before any of it
+        // reaches the pipe.
+        verify_rendered("stdout", data, exporter, root, items_path, encrypt_with)?;
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(data.as_bytes())
+            .and_then(|()| stdout.flush())
+            .context("failed to write the output to stdout")?;
+    } else {
+        back_up(writer, output)?;
+        writer.write_checked(output, data, "output file", |file| {
+            verify_output(output, file, data, exporter, root, items_path, encrypt_with)
+        })?;
+        info!("Wrote {}", output.display());
+    }
+    Ok(())
+}
+
+/// Writes `--summary-json` and sends the `--notify` notifications.
+fn finish_run(
+    args: &DedupArgs,
+    config: &Config,
+    writer: &FileWriter,
+    summary: &RunSummary,
+) -> Result<()> {
+    if let Some(path) = args.summary_json.as_deref() {
+        let data = serde_json::to_string_pretty(&SummaryFile { summary, config })?;
+        if is_stdio(path) {
+            println!("{}", data);
+        } else {
+            let path = writer.resolve(path)?;
+            writer.write(&path, data, "summary file")?;
+            info!("Wrote summary {}", path.display());
+        }
+    }
+    if !args.notify.is_empty() {
+        send_notifications(&args.notify, summary)?;
+    }
+    Ok(())
+}
+
+impl FileWriter {
+    fn resolve(&self, path: &Path) -> Result<PathBuf> {
+        resolve_write_path(path, self.contain_symlinks)
+    }
+
+    /// Writes to a temporary file next to `path` and renames it into place,
+    /// so a crash leaves the old file or the new one, never a truncated one.
+    fn write(&self, path: &Path, contents: impl AsRef<[u8]>, what: &str) -> Result<()> {
+        self.write_checked(path, contents, what, |_| Ok(()))
+    }
+
+    /// Like [`FileWriter::write`], but runs `check` on the temporary file
+    /// before the rename; if it fails, the temporary file is removed and
+    /// whatever was at `path` stays.
+    fn write_checked(
+        &self,
+        path: &Path,
+        contents: impl AsRef<[u8]>,
+        what: &str,
+        check: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!(
+                "refusing to write {} {} in --read-only mode",
+                what,
+                path.display()
+            );
+        }
+        let path = self.resolve(path)?;
+        self.check_location(&path, what)?;
+        write_atomically(&path, contents.as_ref(), what, check)
+    }
+
+    /// Refuses to write `path` into a directory other users can write to,
+    /// unless that was allowed.
+    fn check_location(&self, path: &Path, what: &str) -> Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        if !self.allow_insecure_location
+            && let Some(reason) = secure_file::insecure_location(dir)
+        {
+            anyhow::bail!(
+                "refusing to write {} {} into {}: {}; pass --allow-insecure-location to write there anyway",
+                what,
+                path.display(),
+                dir.display(),
+                reason
+            );
+        }
+        Ok(())
+    }
+
+    /// Copies `path`, if it exists, to `<path>.bak` before it is overwritten
+    /// and returns the copy.
+    fn back_up(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let path = self.resolve(path)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        let backup = PathBuf::from(backup);
+        if self.read_only {
+            anyhow::bail!(
+                "refusing to write backup {} in --read-only mode",
+                backup.display()
+            );
+        }
+        self.check_location(&backup, "backup")?;
+        // Created private before anything is copied into it: `fs::copy` would
+        // give it the mode of the original until it was restricted.
+        fs::File::open(&path)
+            .and_then(|mut original| {
+                let mut copy = secure_file::create_private(&backup)?;
+                std::io::copy(&mut original, &mut copy)?;
+                copy.sync_all()
+            })
+            .with_context(|| {
+                format!(
+                    "failed to back up {} to {}",
+                    path.display(),
+                    backup.display()
+                )
+            })?;
+        Ok(Some(backup))
+    }
+
+    /// Takes an exclusive advisory lock so concurrent runs fail fast instead
+    /// of interleaving writes to the same file.
+    fn lock(&self, path: &Path) -> Result<PathLock> {
+        let path = &self.resolve(path)?;
+        let mut lock_name = path.as_os_str().to_owned();
+        lock_name.push(".lock");
+        let lock_path = PathBuf::from(lock_name);
+        if self.read_only {
+            anyhow::bail!(
+                "refusing to create lock file {} in --read-only mode",
+                lock_path.display()
+            );
+        }
+        // Checked here too so that a refused location fails the run before
+        // anything is written.
+        self.check_location(path, "file")?;
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("failed to open lock file {}", lock_path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(PathLock { _file: file }),
+            Err(fs::TryLockError::WouldBlock) => anyhow::bail!(
+                "{} is in use by another bw-passport-dedup run (lock held on {})",
+                path.display(),
+                lock_path.display()
+            ),
+            Err(fs::TryLockError::Error(err)) => {
+                Err(err).with_context(|| format!("failed to lock {}", lock_path.display()))
+            }
+        }
+    }
+}
+
+/// Writes `contents` to a hidden temporary file in the directory of `path`,
+/// syncs it, runs `check` on it and renames it over `path`. The new file is
+/// private to the current user (see [`secure_file`]), whatever the old one
+/// allowed.
+fn write_atomically(
+    path: &Path,
+    contents: &[u8],
+    what: &str,
+    check: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp = path.with_file_name(temp_name);
+    let failed = || format!("failed to write {} {}", what, path.display());
+    let result = (|| {
+        let mut file = secure_file::create_private(&temp).with_context(failed)?;
+        file.write_all(contents)
+            .and_then(|()| file.sync_all())
+            .with_context(failed)?;
+        drop(file);
+        check(&temp)?;
+        fs::rename(&temp, path).with_context(failed)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn load_state(path: &Path) -> Result<State> {
+    if !path.exists() {
+        return Ok(State::default());
+    }
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read state file {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse state file {}", path.display()))
+}
+
+/// Group IDs recorded under other key semantics cannot be compared with this
+/// build's, so they are dropped (the next trend line shows "unknown" new
+/// groups) and the state is restamped with the current [`KEY_VERSION`].
+fn migrate_state(state: &mut State, path: &Path) {
+    let version = state.key_version.unwrap_or(1);
+    if version != KEY_VERSION && state.runs.iter().any(|run| !run.group_ids.is_empty()) {
+        warn!(
+            "state file {} holds duplicate groups from dedup key version {}; this build uses version {} and starts tracking groups afresh",
+            path.display(),
+            version,
+            KEY_VERSION
+        );
+        for run in &mut state.runs {
+            run.group_ids.clear();
+        }
+    }
+    state.key_version = Some(KEY_VERSION);
+}
+
+fn save_state(writer: &FileWriter, path: &Path, state: &State) -> Result<()> {
+    let contents = serde_json::to_string_pretty(state)?;
+    writer.write(path, contents, "state file")
+}
+
+fn print_trend(previous: &RunRecord, current: &RunRecord) {
+    let since = short_date(&previous.timestamp);
+    // Records written before group IDs were stored cannot be compared.
+    let new_groups = if previous.group_ids.is_empty() && previous.duplicate_groups > 0 {
+        "unknown".to_string()
+    } else {
+        let previous_ids = previous.group_ids.iter().collect::<HashSet<_>>();
+        current
+            .group_ids
+            .iter()
+            .filter(|id| !previous_ids.contains(id))
+            .count()
+            .to_string()
+    };
+    let hygiene = match (previous.hygiene_score, current.hygiene_score) {
+        (Some(before), Some(after)) => format!(", hygiene score: {} -> {}", before, after),
+        _ => String::new(),
+    };
+    info!(
+        "Since {}: items {} -> {}, duplicates removed: {}, new duplicate groups: {}{}",
+        since, previous.total_items, current.total_items, current.removed, new_groups, hygiene
+    );
+}
+
+fn parse_removal_limit(value: &str) -> Result<RemovalLimit, String> {
+    match value.strip_suffix('%') {
+        Some(percent) => match percent.parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(RemovalLimit::Percent(percent)),
+            _ => Err("expected a percentage from 0% to 100%".to_string()),
+        },
+        None => value
+            .parse()
+            .map(RemovalLimit::Count)
+            .map_err(|_| "expected a number of items or a percentage such as 5%".to_string()),
+    }
+}
+
+/// Parses `--keep` with [`Keep::parse`] and offers the strategy names to
+/// shell completion, which a plain function parser cannot.
+#[derive(Clone)]
+struct KeepParser;
+
+impl clap::builder::TypedValueParser for KeepParser {
+    type Value = Keep;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<Keep, clap::Error> {
+        clap::builder::StringValueParser::new()
+            .try_map(|names| Keep::parse(&names))
+            .parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(
+        &self,
+    ) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue> + '_>> {
+        Some(Box::new(KEEP_STRATEGIES.iter().map(|strategy| {
+            clap::builder::PossibleValue::new(strategy.name())
+        })))
+    }
+}
+
+fn parse_notifier(value: &str) -> Result<Notifier, String> {
+    if let Some(command) = value.strip_prefix("exec:") {
+        Ok(Notifier::Exec(command.to_string()))
+    } else if let Some(url) = value.strip_prefix("webhook:") {
+        Ok(Notifier::Webhook(url.to_string()))
+    } else {
+        Err("expected exec:<command> or webhook:<url>".to_string())
+    }
+}
+
+/// Delivers the run summary to every notifier. A failing notifier is
+/// reported but does not stop the others.
+fn send_notifications(notifiers: &[Notifier], summary: &RunSummary) -> Result<()> {
+    let payload = serde_json::to_string(summary)?;
+    for notifier in notifiers {
+        let result = match notifier {
+            Notifier::Exec(command) => notify_exec(command, &payload),
+            Notifier::Webhook(url) => notify_webhook(url, &payload),
+        };
+        if let Err(err) = result {
+            warn!("notification failed: {:#}", err);
+        }
+    }
+    Ok(())
+}
+
+fn notify_exec(command: &str, payload: &str) -> Result<()> {
+    use std::io::Write;
+    use std::process::{Command as Process, Stdio};
+
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Process::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run notify command {:?}", command))?;
+    // Wait even when the command stops reading early so it is never left as
+    // a zombie and its exit status is still reported.
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(payload.as_bytes()),
+        None => Ok(()),
+    };
+    let status = child
+        .wait()
+        .with_context(|| format!("failed to wait for notify command {:?}", command))?;
+    if !status.success() {
+        anyhow::bail!("notify command {:?} exited with {}", command, status);
+    }
+    written.with_context(|| format!("failed to send summary to notify command {:?}", command))?;
+    Ok(())
+}
+
+fn notify_webhook(url: &str, payload: &str) -> Result<()> {
+    ureq::post(url)
+        .header("Content-Type", "application/json")
+        .send(payload)
+        .with_context(|| format!("failed to post summary to {}", url))?;
+    Ok(())
+}
+
+/// Lists the removed items by ID so they can be deleted from the live vault
+/// instead of re-importing everything. Also returns the items left out of the
+/// plan, each with the reason.
+fn build_deletion_plan(
+    removed: &[Value],
+    server: Option<&ServerInfo>,
+) -> (Value, Vec<(String, &'static str)>) {
+    let vaultwarden = server.is_some_and(ServerInfo::is_vaultwarden);
+    let mut left_out = Vec::new();
+    let entries = removed
+        .iter()
+        .filter(|item| {
+            let caveat = vaultwarden.then(|| vaultwarden_caveat(item)).flatten();
+            if let Some(caveat) = caveat {
+                let name = item_str(item, "name").unwrap_or_else(|| "<unnamed>".to_string());
+                left_out.push((name, caveat));
+            }
+            caveat.is_none()
+        })
+        .map(|item| {
+            let mut entry = Map::new();
+            for field in ["id", "name"] {
+                let value = item.get(field).cloned().unwrap_or(Value::Null);
+                entry.insert(field.to_string(), value);
+            }
+            Value::Object(entry)
+        })
+        .collect();
+    (Value::Array(entries), left_out)
+}
+
+/// Renders a deletion plan for `--emit-deletions`. Entries without an ID
+/// cannot be deleted by ID and are skipped.
+fn render_deletions(plan: &Value, format: DeletionFormat) -> String {
+    let entries = plan.as_array().map(Vec::as_slice).unwrap_or_default();
+    let deletions = entries
+        .iter()
+        .filter_map(|entry| Some((item_str(entry, "id")?, entry)));
+    match format {
+        DeletionFormat::BwCli => {
+            let actions = deletions
+                .map(|(id, entry)| bw_cli::SyncAction::Delete {
+                    id,
+                    name: item_str(entry, "name").unwrap_or_else(|| "<unnamed>".to_string()),
+                })
+                .collect::<Vec<_>>();
+            bw_cli::render_script(Path::new("bw"), &actions)
+        }
+        DeletionFormat::Ids => deletions.map(|(id, _)| id + "\n").collect(),
+        DeletionFormat::Jsonl => deletions.map(|(_, entry)| format!("{}\n", entry)).collect(),
+    }
+}
+
+/// Removed items whose deletion through the CLI is known to go wrong on
+/// Vaultwarden, with the reason. Such items are left for the web vault.
+fn vaultwarden_caveat(item: &Value) -> Option<&'static str> {
+    let has_attachments = item
+        .get("attachments")
+        .and_then(Value::as_array)
+        .is_some_and(|attachments| !attachments.is_empty());
+    if has_attachments {
+        return Some(
+            "it has attachments, which are not in the import file and are served by a different endpoint on Vaultwarden; move them to the kept item in the web vault first",
+        );
+    }
+    if item.get("organizationId").is_some_and(|id| !id.is_null()) {
+        return Some(
+            "it is owned by an organization, and Vaultwarden checks organization keys and permissions differently from Bitwarden; delete it in the web vault",
+        );
+    }
+    None
+}
+
+fn summarize_input(root: &Value, items: &[Value]) -> InputSummary {
+    let count = |field: &str| {
+        root.get(field)
+            .and_then(Value::as_array)
+            .map_or(0, Vec::len)
+    };
+    let mut types = BTreeMap::new();
+    for item in items {
+        *types.entry(item_type_name(item)).or_insert(0) += 1;
+    }
+    InputSummary {
+        source: None,
+        server: None,
+        encrypted: root.get("encrypted").and_then(Value::as_bool),
+        items: items.len(),
+        folders: count("folders"),
+        collections: count("collections"),
+        organization: root.get("collections").is_some_and(Value::is_array)
+            || items
+                .iter()
+                .any(|item| item_str(item, "organizationId").is_some()),
+        types,
+    }
+}
+
+fn print_input_summary(summary: &InputSummary) {
+    let types = summary
+        .types
+        .iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let encrypted = summary
+        .encrypted
+        .map_or("unknown".to_string(), |encrypted| encrypted.to_string());
+    info!(
+        "Input: {} items, {} folders, {} collections, encrypted: {}; types: {}{}",
+        summary.items,
+        summary.folders,
+        summary.collections,
+        encrypted,
+        types,
+        if summary.organization {
+            " (organization export)"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Refuses inputs that would produce an empty or mangled "deduplicated"
+/// vault, which usually means the export was cut short.
+fn check_input_sanity(items: &[Value]) -> Result<()> {
+    if items.is_empty() {
+        anyhow::bail!(
+            "export contains no items; refusing to produce an empty vault (is the file truncated?)"
+        );
+    }
+    if let Some(index) = items.iter().position(|item| !item.is_object()) {
+        anyhow::bail!(
+            "item #{} is not a JSON object; the export looks corrupted or truncated",
+            index
+        );
+    }
+    Ok(())
+}
+
+fn locate_items_path(root: &Value, explicit: Option<&str>) -> Result<Vec<String>> {
+    if let Some(explicit) = explicit {
+        let path = parse_path(explicit);
+        if value_at_path(root, &path).is_some_and(Value::is_array) {
+            return Ok(path);
+        }
+        anyhow::bail!("no items array at --items-path {:?}", explicit);
+    }
+
+    for candidate in ITEMS_PATH_CANDIDATES {
+        let path = parse_path(candidate);
+        if value_at_path(root, &path).is_some_and(Value::is_array) {
+            if *candidate != "items" {
+                info!("Using items array at '{}'", candidate);
+            }
+            return Ok(path);
+        }
+    }
+    anyhow::bail!(
+        "expected an items array in Bitwarden export (looked for {}); use --items-path",
+        ITEMS_PATH_CANDIDATES.join(", ")
+    )
+}
+
+/// Resolves symlinks so messages and the report name the file that is
+/// actually read; falls back to the path as given when it does not exist.
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether a path is the `-` that stands for stdin or stdout.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// How messages and the report name an input: `stdin` for `-`, else its
+/// canonical path.
+fn input_label(input: &Path) -> String {
+    if is_stdio(input) {
+        "stdin".to_string()
+    } else {
+        canonical_path(input).display().to_string()
+    }
+}
+
+/// Resolves the file a write to `path` will land in, following symlinks.
+/// With `contain` (watch mode, where the paths keep being written unattended)
+/// a link whose target leaves the directory holding the link is refused, so
+/// a link swapped in later can't redirect the writes elsewhere on the system.
+fn resolve_write_path(path: &Path, contain: bool) -> Result<PathBuf> {
+    let Some(file_name) = path.file_name() else {
+        anyhow::bail!("{} does not name a file", path.display());
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = canonical_path(parent);
+    let resolved = dir.join(file_name);
+
+    let is_symlink = fs::symlink_metadata(&resolved).is_ok_and(|meta| meta.is_symlink());
+    if !is_symlink {
+        return Ok(resolved);
+    }
+
+    let target = match fs::canonicalize(&resolved) {
+        Ok(target) => target,
+        Err(_) => {
+            // Dangling link: resolve the directory the new file would be
+            // created in.
+            let link = fs::read_link(&resolved)
+                .with_context(|| format!("failed to read symlink {}", resolved.display()))?;
+            let link = dir.join(link);
+            let link_name = link.file_name().map(ToOwned::to_owned).unwrap_or_default();
+            canonical_path(link.parent().unwrap_or(&dir)).join(link_name)
+        }
+    };
+    if contain && !target.starts_with(&dir) {
+        anyhow::bail!(
+            "refusing to write through symlink {} -> {} in --watch mode: the target is outside {}",
+            resolved.display(),
+            target.display(),
+            dir.display()
+        );
+    }
+    Ok(target)
+}
+
+/// Reads every `--input` and combines them into the first: the items of the
+/// others are appended to its items, and their folders and collections added
+/// unless it has one with the same ID. The output is encrypted with the key
+/// of the first password-protected input.
+fn read_inputs(
+    inputs: &[PathBuf],
+    args: &DedupArgs,
+) -> Result<(Value, Option<protected::ExportKey>, Vec<InputFile>)> {
+    let prompt = args.watch.is_none();
+    let mut combined: Option<(Value, Vec<String>)> = None;
+    let mut export_key = None;
+    let mut files = Vec::new();
+    for input in inputs {
+        let (mut root, key) = read_export_with_key(input, args.password_file.as_deref(), prompt)?;
+        if export_key.is_none() {
+            export_key = key;
+        }
+        let items_path = locate_items_path(&root, args.items_path.as_deref())?;
+        let items = items_at_mut(&mut root, &items_path)
+            .map(std::mem::take)
+            .unwrap_or_default();
+        files.push(InputFile {
+            file: input_label(input),
+            items: items.len(),
+            ids: items
+                .iter()
+                .filter_map(|item| item_str(item, "id"))
+                .collect(),
+            kept: 0,
+            kept_ids: Vec::new(),
+        });
+        match combined.as_mut() {
+            None => {
+                if let Some(slot) = items_at_mut(&mut root, &items_path) {
+                    *slot = items;
+                }
+                combined = Some((root, items_path));
+            }
+            Some((first, first_items_path)) => {
+                for field in ["folders", "collections"] {
+                    let Some(entries) = root.get_mut(field).and_then(Value::as_array_mut) else {
+                        continue;
+                    };
+                    let Some(known) = first
+                        .as_object_mut()
+                        .map(|first| {
+                            first
+                                .entry(field)
+                                .or_insert_with(|| Value::Array(Vec::new()))
+                        })
+                        .and_then(Value::as_array_mut)
+                    else {
+                        continue;
+                    };
+                    for entry in entries.drain(..) {
+                        let id = item_str(&entry, "id");
+                        if id.is_none() || !known.iter().any(|known| item_str(known, "id") == id) {
+                            known.push(entry);
+                        }
+                    }
+                }
+                if let Some(slot) = items_at_mut(first, first_items_path) {
+                    slot.extend(items);
+                }
+            }
+        }
+    }
+    let (root, _) = combined.context("missing --input <FILE>")?;
+    if files.len() > 1 {
+        for file in &files {
+            info!("Input {}: {} item(s)", file.file, file.items);
+        }
+    }
+    Ok((root, export_key, files))
+}
+
+fn read_export(input: &Path) -> Result<Value> {
+    Ok(read_export_with_key(input, None, true)?.0)
+}
+
+/// Reads an export, decrypting a password-protected one. The key is returned
+/// so the output can be encrypted again.
+fn read_export_with_key(
+    input: &Path,
+    password_file: Option<&Path>,
+    prompt: bool,
+) -> Result<(Value, Option<protected::ExportKey>)> {
+    let label = input_label(input);
+    // stdin has no extension to pick a format by and is read as Bitwarden
+    // JSON.
+    let adapter = formats::import_adapter_for(input);
+    let root = if is_stdio(input) {
+        adapter.import_reader(&mut std::io::stdin().lock())
+    } else {
+        let file = fs::File::open(&label)
+            .with_context(|| format!("failed to read input file {}", label))?;
+        adapter.import_reader(&mut std::io::BufReader::new(file))
+    }
+    .with_context(|| format!("failed to read {} as {}", label, adapter.name()))?;
+    if !protected::is_password_protected(&root) {
+        return Ok((root, None));
+    }
+
+    let password = export_password(&label, password_file, prompt)?;
+    let (root, key) = protected::unlock(&root, &password)
+        .with_context(|| format!("failed to decrypt {}", label))?;
+    Ok((root, Some(key)))
+}
+
+/// The password for a protected export: the first line of `password_file`,
+/// then `BW_EXPORT_PASSWORD`, then a prompt on the terminal.
+fn export_password(
+    input: &str,
+    password_file: Option<&Path>,
+    prompt: bool,
+) -> Result<Zeroizing<String>> {
+    if let Some(path) = password_file {
+        return read_password_file(path);
+    }
+    if let Ok(password) = std::env::var("BW_EXPORT_PASSWORD") {
+        return Ok(Zeroizing::new(password));
+    }
+    if !prompt || !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        anyhow::bail!(
+            "{} is password-protected; pass --password-file or set BW_EXPORT_PASSWORD",
+            input
+        );
+    }
+    rpassword::prompt_password(format!("Password for {}: ", input))
+        .map(Zeroizing::new)
+        .context("failed to read the export password")
+}
+
+/// The first line of a password file. Both the file's contents and the line
+/// are wiped once dropped.
+fn read_password_file(path: &Path) -> Result<Zeroizing<String>> {
+    let contents = Zeroizing::new(
+        fs::read_to_string(path)
+            .with_context(|| format!("failed to read password file {}", path.display()))?,
+    );
+    Ok(Zeroizing::new(
+        contents.lines().next().unwrap_or_default().to_string(),
+    ))
+}
+
+/// The API key from `BW_CLIENTID` and `BW_CLIENTSECRET`, the variables the
+/// `bw` CLI reads, and the master password: the first line of
+/// `password_file`, then `BW_PASSWORD`, then a prompt on the terminal.
+#[cfg(feature = "server-api")]
+fn server_api_credentials(
+    server: &str,
+    password_file: Option<&Path>,
+) -> Result<server_api::ApiCredentials> {
+    let env = |name: &str| {
+        std::env::var(name).with_context(|| format!("--server needs the API key in ${}", name))
+    };
+    let client_id = env("BW_CLIENTID")?;
+    let client_secret = Zeroizing::new(env("BW_CLIENTSECRET")?);
+    let password = match (password_file, std::env::var("BW_PASSWORD")) {
+        (Some(path), _) => read_password_file(path)?,
+        (None, Ok(password)) => Zeroizing::new(password),
+        (None, Err(_)) => {
+            if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+                anyhow::bail!(
+                    "--server needs the master password; pass --password-file or set BW_PASSWORD"
+                );
+            }
+            rpassword::prompt_password(format!("Master password for {}: ", server))
+                .map(Zeroizing::new)
+                .context("failed to read the master password")?
+        }
+    };
+    Ok(server_api::ApiCredentials {
+        client_id,
+        client_secret,
+        password,
+    })
+}
+
+/// Loads folders and items from a running `bw serve` instance and wraps them
+/// in the shape of an unencrypted export. The API hands out the decrypted
+/// vault, so only loopback addresses are accepted.
+fn fetch_bw_serve_vault(base_url: &str) -> Result<Value> {
+    ensure_loopback_url(base_url)?;
+    let base_url = base_url.trim_end_matches('/');
+
+    let folders = fetch_bw_serve_list(base_url, "folders")?;
+    let items = fetch_bw_serve_list(base_url, "items")?;
+    info!(
+        "Loaded {} item(s) and {} folder(s) from bw serve at {}",
+        items.len(),
+        folders.len(),
+        base_url
+    );
+
+    let mut root = Map::new();
+    root.insert("encrypted".to_string(), Value::Bool(false));
+    root.insert("folders".to_string(), Value::Array(folders));
+    root.insert("items".to_string(), Value::Array(items));
+    Ok(Value::Object(root))
+}
+
+/// Asks `bw serve` which server it is logged in to and, with `probe`, reads
+/// that server's public config. Detection is best effort: failures only
+/// print a warning.
+fn detect_bw_serve_server(base_url: &str, probe: bool) -> Option<ServerInfo> {
+    match query_server_info(base_url.trim_end_matches('/'), probe) {
+        Ok(server) => Some(server),
+        Err(err) => {
+            warn!("could not detect the server behind bw serve: {:#}", err);
+            None
+        }
+    }
+}
+
+fn query_server_info(base_url: &str, probe: bool) -> Result<ServerInfo> {
+    ensure_loopback_url(base_url)?;
+    let status = get_json(&format!("{}/status", base_url))?;
+    let server_url = status
+        .pointer("/data/template/serverUrl")
+        .and_then(Value::as_str)
+        .map(|url| url.trim_end_matches('/'))
+        .filter(|url| !url.is_empty());
+    // The CLI leaves serverUrl empty when logged in to the US cloud.
+    let Some(server_url) = server_url else {
+        return Ok(ServerInfo {
+            url: BITWARDEN_CLOUD_URL.to_string(),
+            name: "Bitwarden".to_string(),
+            version: None,
+        });
+    };
+    if !probe {
+        return Ok(ServerInfo {
+            url: server_url.to_string(),
+            name: "self-hosted".to_string(),
+            version: None,
+        });
+    }
+
+    let config = get_json(&format!("{}/api/config", server_url))?;
+    Ok(ServerInfo {
+        url: server_url.to_string(),
+        // Bitwarden leaves `server` null; Vaultwarden names itself there.
+        name: config
+            .pointer("/server/name")
+            .and_then(Value::as_str)
+            .unwrap_or("Bitwarden")
+            .to_string(),
+        version: config
+            .get("version")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+fn get_json(url: &str) -> Result<Value> {
+    let response = ureq::get(url)
+        .call()
+        .with_context(|| format!("failed to query {}", url))?;
+    serde_json::from_reader(response.into_body().into_reader())
+        .with_context(|| format!("failed to parse response from {}", url))
+}
+
+fn fetch_bw_serve_list(base_url: &str, object: &str) -> Result<Vec<Value>> {
+    let url = format!("{}/list/object/{}", base_url, object);
+    let response = ureq::get(&url)
+        .call()
+        .with_context(|| format!("failed to query bw serve at {}", url))?;
+    let body: Value = serde_json::from_reader(response.into_body().into_reader())
+        .with_context(|| format!("failed to parse bw serve response from {}", url))?;
+
+    if body.get("success").and_then(Value::as_bool) != Some(true) {
+        let message = body
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        anyhow::bail!("bw serve request {} failed: {}", url, message);
+    }
+
+    body.pointer("/data/data")
+        .and_then(Value::as_array)
+        .cloned()
+        .with_context(|| format!("unexpected bw serve response shape from {}", url))
+}
+
+fn ensure_loopback_url(url: &str) -> Result<()> {
+    let without_scheme = url.split("://").nth(1).unwrap_or(url);
+    let host_port = without_scheme.split('/').next().unwrap_or(without_scheme);
+    let host = match host_port.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => host_port.split(':').next().unwrap_or(host_port),
+    };
+
+    let loopback = host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+    if !loopback {
+        anyhow::bail!(
+            "refusing to read the vault from non-local bw serve host {:?}",
+            host
+        );
+    }
+    Ok(())
+}
+
+fn default_output_path(input: &Path) -> PathBuf {
+    let mut output = input.to_path_buf();
+    let ext = input.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let suffix = "dedup.json";
+
+    let file_name = input
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or("output");
+
+    let new_name = if ext.is_empty() {
+        format!("{}.{}", file_name, suffix)
+    } else {
+        format!("{}.{}", file_name.trim_end_matches(ext).trim_end_matches('.'), suffix)
+    };
+
+    output.set_file_name(new_name);
+    output
+}
+
+/// The kept items flagged as modified, for `--emit-changed-only`.
+fn changed_items(kept: Vec<Value>, changed: Vec<bool>) -> Vec<Value> {
+    kept.into_iter()
+        .zip(changed)
+        .filter_map(|(item, changed)| changed.then_some(item))
+        .collect()
+}
+
+fn build_report(
+    items: &[Value],
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+    salt: &str,
+    cache: &KeyCache,
+    held_keys: &[KeyDigest],
+) -> Result<Report> {
+    let mut groups: HashMap<KeyDigest, (Vec<&Value>, String)> = HashMap::new();
+    let (digests, suffixes) = build_key_digests(items, config, ignore_keys, ignore_paths, cache);
+    for ((item, digest), suffix) in items.iter().zip(digests).zip(suffixes) {
+        let (group, _) = groups.entry(digest).or_insert_with(|| (Vec::new(), suffix));
+        group.push(item);
+    }
+
+    let collator = NameCollator::new()?;
+    let mut report_groups = Vec::new();
+    let mut removed = 0usize;
+
+    let ranking = config.dedup.ranking();
+    for (digest, (group, suffix)) in groups {
+        if group.len() <= 1 {
+            continue;
+        }
+        let mut key = Zeroizing::new(build_key(group[0], config, ignore_keys, ignore_paths));
+        key.push_str(&suffix);
+        let held = held_keys.binary_search(&digest).is_ok();
+        if !held {
+            removed += group.len() - 1;
+        }
+        let sample_names = group
+            .iter()
+            .filter_map(|item| item.get("name").and_then(Value::as_str))
+            .take(5)
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let sample_ids = group
+            .iter()
+            .filter_map(|item| item.get("id").and_then(Value::as_str))
+            .take(5)
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let sections = if !uses_policy_keys(group[0], config) {
+            config.dedup.hash_sections.as_slice()
+        } else {
+            &[]
+        };
+        let differing_paths =
+            collect_differing_paths(group.as_slice(), sections, ignore_keys, ignore_paths);
+        let mut winner = 0;
+        for index in 1..group.len() {
+            if should_replace(group[winner], group[index], &ranking) {
+                winner = index;
+            }
+        }
+        let kept = group[winner];
+        let basis = match_basis(group[0], config);
+        let mut removals = group
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != winner)
+            .map(|(_, item)| ReportRemoval {
+                id: item_str(item, "id"),
+                name: item_str(item, "name"),
+                reason: explain_removal(kept, item, &ranking, &basis),
+            })
+            .collect::<Vec<_>>();
+        removals.sort_by(|a, b| {
+            collator
+                .compare_names(a.name.as_deref(), b.name.as_deref())
+                .then_with(|| a.id.cmp(&b.id))
+        });
+
+        report_groups.push(ReportGroup {
+            id: group_id(salt, &key),
+            key,
+            count: group.len(),
+            sample_names,
+            sample_ids,
+            differing_paths,
+            basis,
+            matched: matched_values(group[0], config, ignore_keys, ignore_paths),
+            kept_id: item_str(kept, "id"),
+            kept_name: item_str(kept, "name"),
+            held,
+            removals,
+        });
+    }
+
+    report_groups.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| collator.compare_names(a.kept_name.as_deref(), b.kept_name.as_deref()))
+            .then_with(|| a.key.as_str().cmp(b.key.as_str()))
+    });
+
+    Ok(Report {
+        input: None,
+        input_files: Vec::new(),
+        key_version: KEY_VERSION,
+        total_items: items.len(),
+        duplicate_groups: report_groups.len(),
+        held_groups: report_groups.iter().filter(|group| group.held).count(),
+        removed,
+        groups: report_groups,
+        duplicate_ids: Vec::new(),
+        merged_folders: Vec::new(),
+        merged_collections: Vec::new(),
+        related: Vec::new(),
+        stale: Vec::new(),
+        totp_reuse: Vec::new(),
+        credential_reuse: Vec::new(),
+        password_conflicts: Vec::new(),
+        password_reuse: Vec::new(),
+        breached: Vec::new(),
+        hygiene: None,
+        resolved: Vec::new(),
+        chosen: Vec::new(),
+        split_files: Vec::new(),
+        integrity: None,
+        partial: false,
+        incomplete_passes: Vec::new(),
+    })
+}
+
+/// Describes what made the items of a group equal, e.g. `domain+username+password`.
+fn match_basis(item: &Value, config: &Config) -> String {
+    if uses_policy_keys(item, config) {
+        return policy_basis(config);
+    }
+    if config.dedup.hash_sections.is_empty() {
+        "item content".to_string()
+    } else {
+        format!("{} sections", config.dedup.hash_sections.join("+"))
+    }
+}
+
+/// The policy keys and paths joined with `+`.
+fn policy_basis(config: &Config) -> String {
+    config
+        .dedup
+        .policy_keys
+        .iter()
+        .map(|key| dedup_key_name(*key).to_string())
+        .chain(config.dedup.policy_paths.iter().map(PolicyPath::to_string))
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// The normalized policy key values an item is compared by, with passwords
+/// and TOTP secrets redacted. Empty when the whole item is compared.
+fn matched_values(
+    item: &Value,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> Map<String, Value> {
+    if !uses_policy_keys(item, config) {
+        return Map::new();
+    }
+    let Value::Object(mut values) = normalized_value(item, config, ignore_keys, ignore_paths)
+    else {
+        return Map::new();
+    };
+    redact_policy_values(&mut values, config);
+    values
+}
+
+/// [`normalized_value`] with its secrets redacted, for printing.
+fn redacted_normalized_value(
+    item: &Value,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> Value {
+    let mut normalized = normalized_value(item, config, ignore_keys, ignore_paths);
+    if !uses_policy_keys(item, config) {
+        redact::item(&mut normalized);
+    } else if let Value::Object(values) = &mut normalized {
+        redact_policy_values(values, config);
+    }
+    normalized
+}
+
+/// Redacts the password, TOTP and secret path values of a policy key value.
+fn redact_policy_values(values: &mut Map<String, Value>, config: &Config) {
+    let secret_paths = config
+        .dedup
+        .policy_paths
+        .iter()
+        .filter(|path| path.is_secret())
+        .map(|path| format!("path:{}", path));
+    for secret in ["password".to_string(), "totp".to_string()]
+        .into_iter()
+        .chain(secret_paths)
+    {
+        if let Some(value) = values.get_mut(&secret) {
+            redact::value(value);
+        }
+    }
+}
+
+/// Explains why `removed` lost against `kept` under the preferences and
+/// keep strategy.
+fn explain_removal(kept: &Value, removed: &Value, keep: &Keep, basis: &str) -> String {
+    let decision = keep.explain(kept, removed);
+    format!("identical {}; {}", basis, decision)
+}
+
+/// Returns the records found and whether the pass ran to completion.
+fn find_related_records(items: &[Value], budget: &TimeBudget) -> (Vec<RelatedRecord>, bool) {
+    let normalize = |value: &str| value.trim().to_lowercase();
+
+    let mut identities: HashMap<String, Vec<&Value>> = HashMap::new();
+    for item in items.iter().filter(|item| item_type(item) == Some(4)) {
+        let Some(identity) = item.get("identity").and_then(Value::as_object) else {
+            continue;
+        };
+        let mut accounts = ["email", "username"]
+            .iter()
+            .filter_map(|field| identity.get(*field).and_then(Value::as_str))
+            .map(normalize)
+            .filter(|account| !account.is_empty())
+            .collect::<Vec<_>>();
+        accounts.dedup();
+        for account in accounts {
+            identities.entry(account).or_default().push(item);
+        }
+    }
+
+    let mut related = Vec::new();
+    for login in items.iter().filter(|item| item_type(item) == Some(1)) {
+        if budget.expired() {
+            return (related, false);
+        }
+        let username = extract_login_field(login, "username");
+        let Some(username) = username.as_str().map(normalize) else {
+            continue;
+        };
+        let Some(matches) = identities.get(&username) else {
+            continue;
+        };
+        let domains = extract_domains(login)
+            .into_iter()
+            .filter_map(|domain| domain.as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        for identity in matches {
+            related.push(RelatedRecord {
+                account: username.clone(),
+                login_id: item_str(login, "id"),
+                login_name: item_str(login, "name"),
+                identity_id: item_str(identity, "id"),
+                identity_name: item_str(identity, "name"),
+                domains: domains.clone(),
+            });
+        }
+    }
+    (related, true)
+}
+
+/// Returns the records found and whether the pass ran to completion.
+fn find_stale_records(items: &[Value], budget: &TimeBudget) -> (Vec<StaleRecord>, bool) {
+    let normalize = |value: &str| value.trim().to_lowercase();
+
+    let mut by_username: HashMap<String, Vec<(usize, &Value)>> = HashMap::new();
+    for (index, login) in items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item_type(item) == Some(1))
+    {
+        let username = extract_login_field(login, "username");
+        if let Some(username) = username.as_str().map(normalize)
+            && !username.is_empty()
+        {
+            by_username
+                .entry(username)
+                .or_default()
+                .push((index, login));
+        }
+    }
+
+    let mut stale = Vec::new();
+    let mut complete = true;
+    'outer: for (username, logins) in &by_username {
+        for &(current_index, current) in logins {
+            if budget.expired() {
+                complete = false;
+                break 'outer;
+            }
+            let history = current
+                .get("passwordHistory")
+                .and_then(Value::as_array)
+                .map(|entries| {
+                    entries
+                        .iter()
+                        .filter_map(|entry| entry.get("password").and_then(Value::as_str))
+                        .collect::<HashSet<_>>()
+                })
+                .unwrap_or_default();
+            if history.is_empty() {
+                continue;
+            }
+            let current_password = login_password(current);
+            let current_domains = extract_domains(current);
+            for &(candidate_index, candidate) in logins {
+                if candidate_index == current_index {
+                    continue;
+                }
+                let Some(password) = login_password(candidate).filter(|value| !value.is_empty())
+                else {
+                    continue;
+                };
+                if !history.contains(password) || current_password == Some(password) {
+                    continue;
+                }
+                let domains = extract_domains(candidate)
+                    .into_iter()
+                    .filter(|domain| current_domains.contains(domain))
+                    .filter_map(|domain| domain.as_str().map(str::to_string))
+                    .collect::<Vec<_>>();
+                if domains.is_empty() {
+                    continue;
+                }
+                stale.push(StaleRecord {
+                    stale_index: candidate_index,
+                    stale_id: item_str(candidate, "id"),
+                    stale_name: item_str(candidate, "name"),
+                    current_id: item_str(current, "id"),
+                    current_name: item_str(current, "name"),
+                    username: username.clone(),
+                    domains,
+                    newer: compare_dates(current, candidate) == Ordering::Greater,
+                    removed: false,
+                });
+            }
+        }
+    }
+    stale.sort_by(|a, b| {
+        a.stale_id
+            .cmp(&b.stale_id)
+            .then_with(|| a.current_id.cmp(&b.current_id))
+            .then_with(|| a.stale_index.cmp(&b.stale_index))
+    });
+    (stale, complete)
+}
+
+/// Picks `size` items uniformly at random, keeping their original order.
+fn sample_items(items: Vec<Value>, size: usize, seed: u64) -> Vec<Value> {
+    use rand::SeedableRng;
+
+    if size >= items.len() {
+        return items;
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let mut picked = rand::seq::index::sample(&mut rng, items.len(), size).into_vec();
+    picked.sort_unstable();
+
+    let mut picked = picked.into_iter().peekable();
+    items
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, item)| picked.next_if_eq(&index).map(|_| item))
+        .collect()
+}
+
+fn find_totp_reuse(items: &[Value]) -> Vec<TotpReuse> {
+    let mut by_secret: BTreeMap<String, Vec<&Value>> = BTreeMap::new();
+    for login in items.iter().filter(|item| item_type(item) == Some(1)) {
+        let totp = extract_login_field(login, "totp");
+        if let Some(secret) = totp.as_str().and_then(totp_secret) {
+            by_secret.entry(secret).or_default().push(login);
+        }
+    }
+
+    let mut reuse = Vec::new();
+    for logins in by_secret.values() {
+        let mut domains = logins
+            .iter()
+            .flat_map(|login| extract_domains(login))
+            .filter_map(|domain| domain.as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        domains.sort();
+        domains.dedup();
+        if domains.len() < 2 {
+            continue;
+        }
+        reuse.push(TotpReuse {
+            domains,
+            item_ids: logins
+                .iter()
+                .filter_map(|login| item_str(login, "id"))
+                .collect(),
+            item_names: logins
+                .iter()
+                .filter_map(|login| item_str(login, "name"))
+                .collect(),
+        });
+    }
+    reuse
+}
+
+/// Groups logins by password and keeps the passwords used for more than one
+/// account (registrable domain and username, ignoring case), most accounts
+/// first.
+fn find_password_reuse(items: &[Value], salt: &str) -> Vec<PasswordReuse> {
+    let mut by_password: BTreeMap<&str, Vec<&Value>> = BTreeMap::new();
+    for login in items.iter().filter(|item| item_type(item) == Some(1)) {
+        if let Some(password) = login_password(login)
+            && !password.is_empty()
+        {
+            by_password.entry(password).or_default().push(login);
+        }
+    }
+
+    let mut reuse = Vec::new();
+    for (password, logins) in by_password {
+        let mut accounts = Vec::new();
+        for login in &logins {
+            let username = extract_login_field(login, "username");
+            let username = username.as_str().unwrap_or_default().trim().to_lowercase();
+            let domains = extract_domains_with(login, DomainGranularity::Registrable);
+            for domain in domains.iter().filter_map(Value::as_str) {
+                accounts.push((domain.to_string(), username.clone()));
+            }
+        }
+        accounts.sort();
+        accounts.dedup();
+        if accounts.len() < 2 {
+            continue;
+        }
+        let distinct = |values: Vec<String>| {
+            let mut values = values;
+            values.sort();
+            values.dedup();
+            values
+        };
+        reuse.push(PasswordReuse {
+            password_hash: group_id(salt, password),
+            accounts: accounts.len(),
+            domains: distinct(accounts.iter().map(|(domain, _)| domain.clone()).collect()),
+            usernames: distinct(
+                accounts
+                    .iter()
+                    .map(|(_, username)| username.clone())
+                    .filter(|username| !username.is_empty())
+                    .collect(),
+            ),
+            item_ids: logins
+                .iter()
+                .filter_map(|login| item_str(login, "id"))
+                .collect(),
+            item_names: logins
+                .iter()
+                .filter_map(|login| item_str(login, "name"))
+                .collect(),
+        });
+    }
+    reuse.sort_by(|a, b| {
+        b.accounts
+            .cmp(&a.accounts)
+            .then_with(|| a.password_hash.cmp(&b.password_hash))
+    });
+    reuse
+}
+
+/// Groups logins by host and username (ignoring case) and keeps the groups
+/// holding more than one password, most passwords first.
+fn find_password_conflicts(items: &[Value]) -> Vec<PasswordConflict> {
+    let mut by_account: BTreeMap<(String, String), Vec<&Value>> = BTreeMap::new();
+    for login in items.iter().filter(|item| item_type(item) == Some(1)) {
+        let username = extract_login_field(login, "username");
+        if login_password(login).is_none_or(str::is_empty) {
+            continue;
+        }
+        let username = username.as_str().unwrap_or_default().trim().to_lowercase();
+        for domain in extract_domains(login) {
+            if let Some(domain) = domain.as_str() {
+                by_account
+                    .entry((domain.to_string(), username.clone()))
+                    .or_default()
+                    .push(login);
+            }
+        }
+    }
+
+    let mut conflicts = Vec::new();
+    for ((domain, username), mut logins) in by_account {
+        logins.sort_by(|a, b| compare_dates(b, a));
+        let mut seen: Vec<&Value> = Vec::new();
+        let mut items = Vec::new();
+        for login in logins {
+            let password = login.pointer("/login/password").unwrap_or(&Value::Null);
+            let index = seen
+                .iter()
+                .position(|other| *other == password)
+                .unwrap_or_else(|| {
+                    seen.push(password);
+                    seen.len() - 1
+                });
+            items.push(ConflictingLogin {
+                id: item_str(login, "id"),
+                name: item_str(login, "name"),
+                revision_date: best_date(login).map(str::to_string),
+                password: password_label(index),
+            });
+        }
+        if seen.len() > 1 {
+            conflicts.push(PasswordConflict {
+                domain,
+                username,
+                passwords: seen.len(),
+                items,
+            });
+        }
+    }
+    conflicts.sort_by_key(|conflict| std::cmp::Reverse(conflict.passwords));
+    conflicts
+}
+
+/// `A` to `Z`, then `AA`, `AB`, ...
+fn password_label(index: usize) -> String {
+    let letter = |n: usize| char::from(b'A' + (n % 26) as u8);
+    match index / 26 {
+        0 => letter(index).to_string(),
+        prefix => format!("{}{}", letter(prefix - 1), letter(index)),
+    }
+}
+
+/// Groups logins by username (ignoring case) and password and keeps the
+/// groups spanning more than one registrable domain, most domains first.
+fn find_credential_reuse(items: &[Value]) -> Vec<CredentialReuse> {
+    let mut by_credential: BTreeMap<(String, &str), Vec<&Value>> = BTreeMap::new();
+    for login in items.iter().filter(|item| item_type(item) == Some(1)) {
+        let username = extract_login_field(login, "username");
+        if let (Some(username), Some(password)) = (username.as_str(), login_password(login))
+            && !username.trim().is_empty()
+            && !password.is_empty()
+        {
+            by_credential
+                .entry((username.trim().to_lowercase(), password))
+                .or_default()
+                .push(login);
+        }
+    }
+
+    let mut reuse = Vec::new();
+    for ((username, _), logins) in by_credential {
+        let mut domains = logins
+            .iter()
+            .flat_map(|login| extract_domains_with(login, DomainGranularity::Registrable))
+            .filter_map(|domain| domain.as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        domains.sort();
+        domains.dedup();
+        if domains.len() < 2 {
+            continue;
+        }
+        reuse.push(CredentialReuse {
+            username,
+            domains,
+            item_ids: logins
+                .iter()
+                .filter_map(|login| item_str(login, "id"))
+                .collect(),
+            item_names: logins
+                .iter()
+                .filter_map(|login| item_str(login, "name"))
+                .collect(),
+        });
+    }
+    reuse.sort_by(|a, b| {
+        b.domains
+            .len()
+            .cmp(&a.domains.len())
+            .then_with(|| b.item_ids.len().cmp(&a.item_ids.len()))
+            .then_with(|| a.username.cmp(&b.username))
+    });
+    reuse
+}
+
+/// What `--dedup-folders` and `--dedup-collections` collapse.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Container {
+    /// `folders`, referenced by an item's `folderId`.
+    Folder,
+    /// `collections`, referenced by an item's `collectionIds`.
+    Collection,
+}
+
+impl Container {
+    fn array(self) -> &'static str {
+        match self {
+            Container::Folder => "folders",
+            Container::Collection => "collections",
+        }
+    }
+}
+
+/// Runs [`dedup_containers`] on the input's folders or collections and
+/// prints what it did; the moved items are added to `edited_ids`.
+fn collapse_containers(
+    root: &mut Value,
+    items: &mut [Value],
+    container: Container,
+    edited_ids: &mut HashSet<String>,
+) -> Vec<MergedContainer> {
+    let Some(entries) = root
+        .get_mut(container.array())
+        .and_then(Value::as_array_mut)
+    else {
+        warn!(
+            "--dedup-{}: the input has no {} array",
+            container.array(),
+            container.array()
+        );
+        return Vec::new();
+    };
+    let (merged, moved_ids) = dedup_containers(container, entries, items);
+    info!(
+        "Merged {} duplicate {} into {}, moving {} item(s)",
+        merged
+            .iter()
+            .map(|entry| entry.removed_ids.len())
+            .sum::<usize>(),
+        container.array(),
+        merged.len(),
+        moved_ids.len()
+    );
+    edited_ids.extend(moved_ids);
+    merged
+}
+
+/// Collapses folders or collections with the same name (collections only
+/// within one organization) into the first of them: the items of the others
+/// are pointed at it and the others are dropped. Entries without an ID or
+/// name are left alone. Returns the merges and the IDs of the moved items.
+fn dedup_containers(
+    container: Container,
+    entries: &mut Vec<Value>,
+    items: &mut [Value],
+) -> (Vec<MergedContainer>, HashSet<String>) {
+    let mut by_name: HashMap<(Option<String>, String), usize> = HashMap::new();
+    let mut merged: Vec<MergedContainer> = Vec::new();
+    let mut remap: HashMap<String, usize> = HashMap::new();
+    entries.retain(|entry| {
+        let (Some(id), Some(name)) = (item_str(entry, "id"), item_str(entry, "name")) else {
+            return true;
+        };
+        match by_name.get(&(item_str(entry, "organizationId"), name.clone())) {
+            Some(&index) => {
+                merged[index].removed_ids.push(id.clone());
+                remap.insert(id, index);
+                false
+            }
+            None => {
+                by_name.insert(
+                    (item_str(entry, "organizationId"), name.clone()),
+                    merged.len(),
+                );
+                merged.push(MergedContainer {
+                    name,
+                    kept_id: id,
+                    removed_ids: Vec::new(),
+                    moved_items: 0,
+                });
+                true
+            }
+        }
+    });
+
+    let mut moved_ids = HashSet::new();
+    for item in items.iter_mut() {
+        let mut moved_to = Vec::new();
+        match container {
+            Container::Folder => {
+                if let Some(&index) = item_str(item, "folderId")
+                    .as_ref()
+                    .and_then(|folder_id| remap.get(folder_id))
+                {
+                    item["folderId"] = Value::String(merged[index].kept_id.clone());
+                    moved_to.push(index);
+                }
+            }
+            Container::Collection => {
+                let Some(ids) = item.get_mut("collectionIds").and_then(Value::as_array_mut) else {
+                    continue;
+                };
+                let mut remapped: Vec<Value> = Vec::with_capacity(ids.len());
+                for id in ids.iter() {
+                    let id = match id.as_str().and_then(|id| remap.get(id)) {
+                        Some(&index) => {
+                            moved_to.push(index);
+                            Value::String(merged[index].kept_id.clone())
+                        }
+                        None => id.clone(),
+                    };
+                    if !remapped.contains(&id) {
+                        remapped.push(id);
+                    }
+                }
+                *ids = remapped;
+            }
+        }
+        moved_to.sort_unstable();
+        moved_to.dedup();
+        for &index in &moved_to {
+            merged[index].moved_items += 1;
+        }
+        if !moved_to.is_empty() {
+            moved_ids.extend(item_str(item, "id"));
+        }
+    }
+    merged.retain(|entry| !entry.removed_ids.is_empty());
+    (merged, moved_ids)
+}
+
+/// Logins whose password is among `breached`, most often seen first.
+fn find_breached_logins(items: &[Value], breached: &breach::Breached) -> Vec<BreachedLogin> {
+    let mut logins = items
+        .iter()
+        .filter(|item| item_type(item) == Some(1))
+        .filter_map(|login| {
+            let occurrences = *breached.get(&breach::password_sha1(login_password(login)?))?;
+            let mut domains = extract_domains(login)
+                .into_iter()
+                .filter_map(|domain| domain.as_str().map(str::to_string))
+                .collect::<Vec<_>>();
+            domains.sort();
+            domains.dedup();
+            Some(BreachedLogin {
+                id: item_str(login, "id"),
+                name: item_str(login, "name"),
+                domains,
+                occurrences,
+            })
+        })
+        .collect::<Vec<_>>();
+    logins.sort_by_key(|login| std::cmp::Reverse(login.occurrences));
+    logins
+}
+
+/// Scores the items as read, before anything is removed. `duplicates` is the
+/// number of surplus copies and `stale` the number of stale copies found;
+/// breached passwords only count when they were looked up.
+fn hygiene_score(
+    items: &[Value],
+    duplicates: usize,
+    stale: usize,
+    breached: Option<&breach::Breached>,
+) -> HygieneScore {
+    let passwords = items
+        .iter()
+        .filter(|item| item_type(item) == Some(1))
+        .filter_map(|login| {
+            let password = login_password(login).filter(|value| !value.is_empty())?;
+            Some((login, password))
+        })
+        .collect::<Vec<_>>();
+    let logins = passwords.len();
+
+    let mut domains_by_password: HashMap<&str, HashSet<String>> = HashMap::new();
+    for (login, password) in &passwords {
+        domains_by_password.entry(password).or_default().extend(
+            extract_domains_with(login, DomainGranularity::Registrable)
+                .into_iter()
+                .filter_map(|domain| domain.as_str().map(str::to_string)),
+        );
+    }
+    let reused = passwords
+        .iter()
+        .filter(|(_, password)| domains_by_password[password].len() > 1)
+        .count();
+    let weak = passwords
+        .iter()
+        .filter(|(_, password)| is_weak_password(password))
+        .count();
+
+    let category = |name, count: usize, out_of: usize, weight| HygieneCategory {
+        name,
+        count,
+        out_of,
+        score: match out_of {
+            0 => 100,
+            _ => 100 - ((100 * count.min(out_of) + out_of / 2) / out_of) as u32,
+        },
+        weight,
+    };
+    let mut categories = vec![
+        category("duplicates", duplicates, items.len(), 1),
+        category("reuse", reused, logins, 3),
+        category("weak", weak, logins, 3),
+    ];
+    if let Some(breached) = breached {
+        let count = passwords
+            .iter()
+            .filter(|(_, password)| breached.contains_key(&breach::password_sha1(password)))
+            .count();
+        categories.push(category("breached", count, logins, 4));
+    }
+    categories.push(category("stale", stale, logins, 1));
+
+    let weights = categories
+        .iter()
+        .map(|category| category.weight)
+        .sum::<u32>();
+    let weighted = categories
+        .iter()
+        .map(|category| category.score * category.weight)
+        .sum::<u32>();
+    HygieneScore {
+        score: (weighted + weights / 2) / weights,
+        categories,
+    }
+}
+
+/// Under 60 bits by the usual pool estimate: length times log2 of the
+/// combined size of the character classes used.
+fn is_weak_password(password: &str) -> bool {
+    let uses = |class: fn(&char) -> bool| password.chars().any(|c| class(&c));
+    let pool = [
+        (uses(char::is_ascii_lowercase), 26),
+        (uses(char::is_ascii_uppercase), 26),
+        (uses(char::is_ascii_digit), 10),
+        (uses(|c| c.is_ascii_punctuation() || *c == ' '), 33),
+        (uses(|c| !c.is_ascii()), 100),
+    ]
+    .iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, size)| size)
+    .sum::<u32>();
+    password.chars().count() as f64 * f64::from(pool.max(1)).log2() < 60.0
+}
+
+const CONFLICT_FIELDS: [(&str, &[&str]); 7] = [
+    ("name", &["name"]),
+    ("password", &["login", "password"]),
+    ("totp", &["login", "totp"]),
+    ("uris", &["login", "uris"]),
+    ("notes", &["notes"]),
+    ("fields", &["fields"]),
+    ("folder", &["folderId"]),
+];
+
+/// Pairs of logins sharing a site and a non-empty username. Each group is
+/// reported as its first login paired with every other one.
+fn find_conflicts(items: &[Value]) -> Vec<Conflict> {
+    let field = |item: &Value, path: &[&str]| {
+        path.iter()
+            .try_fold(item, |value, key| value.get(key))
+            .filter(|value| !value.is_null())
+            .cloned()
+    };
+    let side = |item: &Value| {
+        Some(ConflictItem {
+            id: item_str(item, "id")?,
+            name: item_str(item, "name"),
+            revision_date: item_str(item, "revisionDate"),
+        })
+    };
+
+    let mut seen = HashSet::new();
+    let mut conflicts = Vec::new();
+    for ((_, username), logins) in index_credentials(items) {
+        if username.is_empty() {
+            continue;
+        }
+        for (index, first) in logins.iter().enumerate() {
+            for other in &logins[index + 1..] {
+                let (Some(a), Some(b)) = (side(first), side(other)) else {
+                    continue;
+                };
+                if !seen.insert((a.id.clone(), b.id.clone())) {
+                    continue;
+                }
+                let other_domains = extract_domains(other);
+                let domains = extract_domains(first)
+                    .into_iter()
+                    .filter(|domain| other_domains.contains(domain))
+                    .filter_map(|domain| domain.as_str().map(str::to_string))
+                    .collect();
+                let differs = CONFLICT_FIELDS
+                    .iter()
+                    .filter(|(_, path)| field(first, path) != field(other, path))
+                    .map(|(name, _)| name.to_string())
+                    .collect();
+                conflicts.push(Conflict {
+                    username: username.clone(),
+                    domains,
+                    differs,
+                    a,
+                    b,
+                    decision: None,
+                });
+            }
+        }
+    }
+    conflicts
+}
+
+fn load_conflicts(path: &Path) -> Result<ConflictFile> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read conflicts file {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse conflicts file {}", path.display()))
+}
+
+/// Applies the decided conflicts to the kept items and returns the ones acted
+/// on. Decisions about items that are gone or were edited since the file was
+/// written are skipped with a warning.
+fn apply_conflict_decisions(outcome: &mut DedupOutcome, conflicts: Vec<Conflict>) -> Vec<Conflict> {
+    let mut applied = Vec::new();
+    let mut skipped = 0usize;
+    let mut undecided = 0usize;
+    for conflict in conflicts {
+        let decision = match conflict.decision {
+            None => {
+                undecided += 1;
+                continue;
+            }
+            Some(Decision::Skip) => {
+                skipped += 1;
+                continue;
+            }
+            Some(decision) => decision,
+        };
+        let position = |side: &ConflictItem| {
+            outcome
+                .kept
+                .iter()
+                .position(|item| item_str(item, "id").as_deref() == Some(side.id.as_str()))
+        };
+        let (Some(a), Some(b)) = (position(&conflict.a), position(&conflict.b)) else {
+            warn!(
+                "skipping conflict {} / {}: item no longer present",
+                conflict.a.id, conflict.b.id
+            );
+            skipped += 1;
+            continue;
+        };
+        let edited = [(a, &conflict.a), (b, &conflict.b)]
+            .iter()
+            .any(|(index, side)| {
+                item_str(&outcome.kept[*index], "revisionDate") != side.revision_date
+            });
+        if edited {
+            warn!(
+                "skipping conflict {} / {}: item edited since the conflicts file was written",
+                conflict.a.id, conflict.b.id
+            );
+            skipped += 1;
+            continue;
+        }
+
+        let (keep, drop) = match decision {
+            Decision::KeepB => (b, a),
+            _ => (a, b),
+        };
+        if decision == Decision::Merge {
+            let other = outcome.kept[drop].clone();
+            merge_login(&mut outcome.kept[keep], &other);
+            outcome.changed[keep] = true;
+        } else {
+            let other = outcome.kept[drop].clone();
+            if carry_over(&mut outcome.kept[keep], &other) {
+                outcome.changed[keep] = true;
+            }
+        }
+        outcome.changed.remove(drop);
+        outcome.removed.push(outcome.kept.remove(drop));
+        applied.push(conflict);
+    }
+    info!(
+        "Resolved conflicts: {} applied, {} skipped, {} undecided",
+        applied.len(),
+        skipped,
+        undecided
+    );
+    applied
+}
+
+/// What `--interactive` settled before the dedup pass.
+#[derive(Debug, Default)]
+struct InteractiveChoices {
+    /// The items not chosen, already taken out of the input.
+    removed: Vec<Value>,
+    chosen: Vec<ChosenGroup>,
+    /// Key digests of the groups to keep whole.
+    skipped: Vec<KeyDigest>,
+    /// IDs of chosen items the others were merged into (with `--merge`) or
+    /// passed their passkeys or collections to.
+    merged_ids: HashSet<String>,
+}
+
+/// Walks the duplicate groups in input order, shows each side by side and
+/// asks which item to keep. The others are removed from `items` right away,
+/// so the dedup pass only sees the chosen one.
+fn choose_interactively(
+    items: &mut Vec<Value>,
+    config: &Config,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+    cache: &KeyCache,
+    input: &mut dyn std::io::BufRead,
+    output: &mut dyn Write,
+) -> Result<InteractiveChoices> {
+    let (digests, _) = build_key_digests(items, config, ignore_keys, ignore_paths, cache);
+    let mut positions: HashMap<KeyDigest, usize> = HashMap::new();
+    let mut groups: Vec<(KeyDigest, Vec<usize>)> = Vec::new();
+    for (index, digest) in digests.into_iter().enumerate() {
+        let position = *positions.entry(digest).or_insert_with(|| {
+            groups.push((digest, Vec::new()));
+            groups.len() - 1
+        });
+        groups[position].1.push(index);
+    }
+    groups.retain(|(_, group)| group.len() > 1);
+
+    let facts = groups
+        .iter()
+        .map(|(_, group)| {
+            let members = group.iter().map(|index| &items[*index]).collect::<Vec<_>>();
+            group_facts(&members, config)
+        })
+        .collect::<Vec<_>>();
+
+    let mut choices = InteractiveChoices::default();
+    let mut losers = HashSet::new();
+    let mut rules: Vec<BulkRule> = Vec::new();
+    for (number, (digest, group)) in groups.iter().enumerate() {
+        let rule = rules
+            .iter()
+            .find(|rule| rule.filter.matches(&facts[number]));
+        let pick = match rule {
+            Some(rule) => {
+                writeln!(
+                    output,
+                    "\nGroup {} of {}: {} by bulk rule {:?}",
+                    number + 1,
+                    groups.len(),
+                    rule.keep.describe(),
+                    rule.filter.source
+                )?;
+                rule.keep.pick(group.len())
+            }
+            None => {
+                let members = group.iter().map(|index| &items[*index]).collect::<Vec<_>>();
+                writeln!(
+                    output,
+                    "\nGroup {} of {}, identical {} (confidence {:.2}):",
+                    number + 1,
+                    groups.len(),
+                    facts[number].basis,
+                    facts[number].confidence
+                )?;
+                write!(output, "{}", render_side_by_side(&members))?;
+                loop {
+                    match prompt_choice(input, output, group.len())? {
+                        Answer::Pick(pick) => break pick,
+                        Answer::Bulk(filter) => {
+                            let matching = facts[number..]
+                                .iter()
+                                .filter(|facts| filter.matches(facts))
+                                .count();
+                            if matching == 0 {
+                                writeln!(output, "No remaining group matches {:?}", filter.source)?;
+                                continue;
+                            }
+                            let Some(keep) = prompt_bulk_keep(input, output, matching)? else {
+                                continue;
+                            };
+                            let pick = filter
+                                .matches(&facts[number])
+                                .then(|| keep.pick(group.len()));
+                            rules.push(BulkRule { filter, keep });
+                            if let Some(pick) = pick {
+                                break pick;
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let Some(pick) = pick else {
+            choices.skipped.push(*digest);
+            continue;
+        };
+
+        let winner = group[pick];
+        let mut removals = Vec::new();
+        for &index in group.iter().filter(|index| **index != winner) {
+            let loser = items[index].clone();
+            let merged = if config.dedup.merge {
+                merge_item(&mut items[winner], &loser)
+            } else {
+                carry_over(&mut items[winner], &loser)
+            };
+            if merged && let Some(id) = item_str(&items[winner], "id") {
+                choices.merged_ids.insert(id);
+            }
+            removals.push(ReportRemoval {
+                id: item_str(&loser, "id"),
+                name: item_str(&loser, "name"),
+                reason: format!("chosen by hand: kept item {} of the group", pick + 1),
+            });
+            losers.insert(index);
+        }
+        choices.chosen.push(ChosenGroup {
+            kept_id: item_str(&items[winner], "id"),
+            kept_name: item_str(&items[winner], "name"),
+            removals,
+        });
+    }
+
+    let (removed, remaining): (Vec<_>, Vec<_>) = std::mem::take(items)
+        .into_iter()
+        .enumerate()
+        .partition(|(index, _)| losers.contains(index));
+    *items = remaining.into_iter().map(|(_, item)| item).collect();
+    choices.removed = removed.into_iter().map(|(_, item)| item).collect();
+    Ok(choices)
+}
+
+/// An answer at the `--interactive` prompt.
+enum Answer {
+    /// The item to keep, or `None` to leave the group whole.
+    Pick(Option<usize>),
+    /// `bulk <filter>`: decide every remaining group the filter matches.
+    Bulk(GroupFilter),
+}
+
+/// Asks which of `count` items to keep, or for a bulk filter.
+fn prompt_choice(
+    input: &mut dyn std::io::BufRead,
+    output: &mut dyn Write,
+    count: usize,
+) -> Result<Answer> {
+    loop {
+        write!(
+            output,
+            "Keep [f]irst, [l]ast, [1-{}] that item, [s]kip, or [b]ulk <filter>? ",
+            count
+        )?;
+        output.flush()?;
+        let answer = read_answer(input)?;
+        if let Some((command, filter)) = answer.split_once(' ')
+            && matches!(command.to_ascii_lowercase().as_str(), "b" | "bulk")
+        {
+            match parse_group_filter(filter) {
+                Ok(filter) => return Ok(Answer::Bulk(filter)),
+                Err(err) => {
+                    writeln!(output, "{}", err)?;
+                    continue;
+                }
+            }
+        }
+        match answer.to_ascii_lowercase().as_str() {
+            "f" | "first" => return Ok(Answer::Pick(Some(0))),
+            "l" | "last" => return Ok(Answer::Pick(Some(count - 1))),
+            "s" | "skip" => return Ok(Answer::Pick(None)),
+            answer => {
+                if let Ok(number) = answer.parse::<usize>()
+                    && (1..=count).contains(&number)
+                {
+                    return Ok(Answer::Pick(Some(number - 1)));
+                }
+                writeln!(
+                    output,
+                    "Answer f, l, s, a number from 1 to {} or bulk <filter>",
+                    count
+                )?;
+            }
+        }
+    }
+}
+
+/// Asks what to do with the `matching` groups of a bulk filter; `None`
+/// cancels it.
+fn prompt_bulk_keep(
+    input: &mut dyn std::io::BufRead,
+    output: &mut dyn Write,
+    matching: usize,
+) -> Result<Option<BulkKeep>> {
+    loop {
+        write!(
+            output,
+            "{} group(s) match. Keep [f]irst, [l]ast, [s]kip them all, or [c]ancel? ",
+            matching
+        )?;
+        output.flush()?;
+        match read_answer(input)?.to_ascii_lowercase().as_str() {
+            "f" | "first" => return Ok(Some(BulkKeep::First)),
+            "l" | "last" => return Ok(Some(BulkKeep::Last)),
+            "s" | "skip" => return Ok(Some(BulkKeep::Skip)),
+            "c" | "cancel" => return Ok(None),
+            _ => writeln!(output, "Answer f, l, s or c")?,
+        }
+    }
+}
+
+fn read_answer(input: &mut dyn std::io::BufRead) -> Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        anyhow::bail!("input ended before every duplicate group was decided");
+    }
+    Ok(line.trim().to_string())
+}
+
+/// A standing answer for every later group its filter matches.
+struct BulkRule {
+    filter: GroupFilter,
+    keep: BulkKeep,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BulkKeep {
+    First,
+    Last,
+    Skip,
+}
+
+impl BulkKeep {
+    fn pick(self, count: usize) -> Option<usize> {
+        match self {
+            BulkKeep::First => Some(0),
+            BulkKeep::Last => Some(count - 1),
+            BulkKeep::Skip => None,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            BulkKeep::First => "kept the first item",
+            BulkKeep::Last => "kept the last item",
+            BulkKeep::Skip => "skipped",
+        }
+    }
+}
+
+/// What bulk filters look at in a duplicate group.
+#[derive(Debug)]
+struct GroupFacts {
+    domains: Vec<String>,
+    names: Vec<String>,
+    types: Vec<String>,
+    basis: String,
+    size: usize,
+    /// Share of the compared fields (name, password, TOTP, URIs, notes,
+    /// custom fields, folder) that are equal across the whole group, so 1.0
+    /// for exact copies.
+    confidence: f64,
+}
+
+fn group_facts(members: &[&Value], config: &Config) -> GroupFacts {
+    let field = |item: &Value, path: &[&str]| {
+        path.iter()
+            .try_fold(item, |value, key| value.get(key))
+            .filter(|value| !value.is_null())
+            .cloned()
+    };
+    let equal = CONFLICT_FIELDS
+        .iter()
+        .filter(|(_, path)| {
+            let first = field(members[0], path);
+            members[1..]
+                .iter()
+                .all(|member| field(member, path) == first)
+        })
+        .count();
+    let collect = |values: &mut dyn Iterator<Item = String>| {
+        let mut values = values.collect::<Vec<_>>();
+        values.sort();
+        values.dedup();
+        values
+    };
+    GroupFacts {
+        domains: collect(
+            &mut members
+                .iter()
+                .flat_map(|member| extract_domains(member))
+                .filter_map(|domain| domain.as_str().map(str::to_string)),
+        ),
+        names: collect(&mut members.iter().filter_map(|member| item_str(member, "name"))),
+        types: collect(&mut members.iter().map(|member| item_type_name(member))),
+        basis: match_basis(members[0], config),
+        size: members.len(),
+        confidence: equal as f64 / CONFLICT_FIELDS.len() as f64,
+    }
+}
+
+/// A bulk filter such as `domain:*.google.com confidence>0.95`. Every term
+/// has to match.
+#[derive(Debug)]
+struct GroupFilter {
+    source: String,
+    terms: Vec<FilterTerm>,
+}
+
+#[derive(Debug)]
+enum FilterTerm {
+    /// `domain:`, `name:` or `type:` with a `*`/`?` pattern, matching when any
+    /// member does; `basis:` against the group's match basis.
+    Pattern(&'static str, String),
+    /// `size` or `confidence` compared with `<`, `<=`, `=`, `>=` or `>`.
+    Compare(&'static str, &'static str, f64),
+}
+
+impl GroupFilter {
+    fn matches(&self, facts: &GroupFacts) -> bool {
+        self.terms.iter().all(|term| match term {
+            FilterTerm::Pattern(field, pattern) => {
+                let values = match *field {
+                    "domain" => &facts.domains,
+                    "name" => &facts.names,
+                    "type" => &facts.types,
+                    _ => return wildcard_match(pattern, &facts.basis),
+                };
+                values.iter().any(|value| wildcard_match(pattern, value))
+            }
+            FilterTerm::Compare(field, op, value) => {
+                let actual = match *field {
+                    "size" => facts.size as f64,
+                    _ => facts.confidence,
+                };
+                match *op {
+                    "<" => actual < *value,
+                    "<=" => actual <= *value,
+                    ">=" => actual >= *value,
+                    ">" => actual > *value,
+                    _ => actual == *value,
+                }
+            }
+        })
+    }
+}
+
+fn parse_group_filter(value: &str) -> Result<GroupFilter, String> {
+    const PATTERN_FIELDS: [&str; 4] = ["domain", "name", "type", "basis"];
+    const NUMBER_FIELDS: [&str; 2] = ["size", "confidence"];
+    const OPERATORS: [&str; 5] = ["<=", ">=", "<", ">", "="];
+
+    let mut terms = Vec::new();
+    for term in value.split_whitespace() {
+        if let Some((field, pattern)) = term.split_once(':') {
+            let field = PATTERN_FIELDS
+                .into_iter()
+                .find(|known| known.eq_ignore_ascii_case(field))
+                .ok_or_else(|| {
+                    format!(
+                        "unknown filter field {:?} (expected {})",
+                        field,
+                        PATTERN_FIELDS.join(", ")
+                    )
+                })?;
+            terms.push(FilterTerm::Pattern(field, pattern.to_string()));
+            continue;
+        }
+        let split = term
+            .find(['<', '>', '='])
+            .ok_or_else(|| format!("expected field:pattern or field>number, got {:?}", term))?;
+        let (field, rest) = term.split_at(split);
+        let field = NUMBER_FIELDS
+            .into_iter()
+            .find(|known| known.eq_ignore_ascii_case(field))
+            .ok_or_else(|| {
+                format!(
+                    "unknown filter field {:?} (expected {})",
+                    field,
+                    NUMBER_FIELDS.join(", ")
+                )
+            })?;
+        let op = OPERATORS
+            .into_iter()
+            .find(|op| rest.starts_with(op))
+            .unwrap_or("=");
+        let number = rest[op.len()..]
+            .parse::<f64>()
+            .map_err(|_| format!("expected a number after {}{}", field, op))?;
+        terms.push(FilterTerm::Compare(field, op, number));
+    }
+    if terms.is_empty() {
+        return Err("a bulk filter needs at least one term".to_string());
+    }
+    Ok(GroupFilter {
+        source: value.trim().to_string(),
+        terms,
+    })
+}
+
+/// Case-insensitive match where `*` stands for any run of characters and `?`
+/// for exactly one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// One column per item. Passwords are masked; items whose passwords differ
+/// get different letters (`****** (A)`, `****** (B)`).
+fn render_side_by_side(items: &[&Value]) -> String {
+    const WIDTH: usize = 30;
+    let mut passwords: Vec<&str> = Vec::new();
+    let mut columns = Vec::new();
+    for (number, item) in items.iter().enumerate() {
+        let password = match login_password(item) {
+            None | Some("") => String::new(),
+            Some(password) => {
+                let index = passwords
+                    .iter()
+                    .position(|known| *known == password)
+                    .unwrap_or_else(|| {
+                        passwords.push(password);
+                        passwords.len() - 1
+                    });
+                format!("****** ({})", char::from(b'A' + (index % 26) as u8))
+            }
+        };
+        let uris = extract_uris(item)
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let date = |field: &str| {
+            item.get(field)
+                .and_then(Value::as_str)
+                .map(|date| short_date(date).to_string())
+                .unwrap_or_default()
+        };
+        let login_username = extract_login_field(item, "username");
+        columns.push([
+            format!("[{}]", number + 1),
+            item_str(item, "id").unwrap_or_default(),
+            item_str(item, "name").unwrap_or_default(),
+            login_username.as_str().unwrap_or_default().to_string(),
+            password,
+            uris,
+            date("creationDate"),
+            date("revisionDate"),
+        ]);
+    }
+
+    let labels = [
+        "", "id", "name", "username", "password", "uris", "created", "revised",
+    ];
+    let mut table = String::new();
+    for (row, label) in labels.iter().enumerate() {
+        let mut line = format!("  {:<9}", label);
+        for column in &columns {
+            let cell = &column[row];
+            let cell = if cell.chars().count() > WIDTH {
+                format!("{}…", cell.chars().take(WIDTH - 1).collect::<String>())
+            } else {
+                cell.clone()
+            };
+            line.push_str(&format!(" | {:<width$}", cell, width = WIDTH));
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+fn find_duplicate_ids(items: &[Value]) -> Vec<DuplicateId> {
+    let mut by_id: BTreeMap<&str, Vec<&Value>> = BTreeMap::new();
+    for item in items {
+        if let Some(id) = item.get("id").and_then(Value::as_str) {
+            by_id.entry(id).or_default().push(item);
+        }
+    }
+    by_id
+        .into_iter()
+        .filter(|(_, items)| items.len() > 1)
+        .map(|(id, items)| DuplicateId {
+            id: id.to_string(),
+            count: items.len(),
+            names: items
+                .iter()
+                .filter_map(|item| item_str(item, "name"))
+                .collect(),
+            replaced_by: Vec::new(),
+        })
+        .collect()
+}
+
+/// Keeps the first item for each duplicated id and gives the others fresh
+/// ids, recording them in `replaced_by`. Deterministic runs derive the new
+/// ids from the old id and its occurrence instead of drawing random ones.
+fn fix_duplicate_ids(
+    items: &mut [Value],
+    duplicates: &mut [DuplicateId],
+    deterministic: bool,
+) -> Result<()> {
+    let mut seen = HashSet::new();
+    for item in items.iter_mut() {
+        let Some(id) = item_str(item, "id") else {
+            continue;
+        };
+        if seen.insert(id.clone()) {
+            continue;
+        }
+        let Some(duplicate) = duplicates.iter_mut().find(|duplicate| duplicate.id == id) else {
+            continue;
+        };
+        let mut bytes = [0u8; 16];
+        if deterministic {
+            let mut hasher = Sha256::new();
+            hasher.update(b"bw-passport-dedup item id");
+            hasher.update([0u8]);
+            hasher.update(id.as_bytes());
+            hasher.update(duplicate.replaced_by.len().to_le_bytes());
+            bytes.copy_from_slice(&hasher.finalize()[..16]);
+        } else {
+            getrandom::fill(&mut bytes).context("failed to gather randomness for an item id")?;
+        }
+        let fresh = format_uuid_v4(bytes);
+        item["id"] = Value::String(fresh.clone());
+        duplicate.replaced_by.push(fresh);
+    }
+    Ok(())
+}
+
+/// Formats 16 bytes as a version 4 UUID in the lowercase form Bitwarden uses
+/// for ids.
+fn format_uuid_v4(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// File names and item ranges for `--split-output`, numbered from 1 and
+/// zero-padded so they sort in import order.
+fn split_output_parts(output: &Path, total: usize, size: usize) -> Vec<(PathBuf, Range<usize>)> {
+    let count = total.div_ceil(size).max(1);
+    let width = count.to_string().len();
+    (0..count)
+        .map(|index| {
+            let path = output.with_extension(format!("part{:0width$}.json", index + 1));
+            let range = index * size..((index + 1) * size).min(total);
+            (path, range)
+        })
+        .collect()
+}
+
+fn item_type_name(item: &Value) -> String {
+    match item_type(item) {
+        Some(1) => "login".to_string(),
+        Some(2) => "secure_note".to_string(),
+        Some(3) => "card".to_string(),
+        Some(4) => "identity".to_string(),
+        Some(5) => "ssh_key".to_string(),
+        Some(other) => format!("type_{}", other),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Derives a stable, opaque group ID from the salted comparison key.
+fn group_id(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(key.as_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+fn generate_salt() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).context("failed to gather randomness for the group salt")?;
+    Ok(hex::encode(bytes))
+}
+
+/// The state file's salt, generated and stored on first use.
+fn state_salt(state: &mut State) -> Result<String> {
+    if let Some(salt) = &state.salt {
+        return Ok(salt.clone());
+    }
+    let salt = generate_salt()?;
+    state.salt = Some(salt.clone());
+    Ok(salt)
+}
+
+/// Salt for `--deterministic` runs. It only has to hide keys from readers of
+/// the report who lack the vault, so deriving it from the items is enough.
+fn deterministic_salt(items: &[Value]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"bw-passport-dedup group salt");
+    hasher.update([0u8]);
+    hasher.update(merkle_root(items).as_bytes());
+    hex::encode(&hasher.finalize()[..16])
+}
+
+fn render_report_template(report: &Report, template_path: &Path) -> Result<String> {
+    let template = fs::read_to_string(template_path).with_context(|| {
+        format!("failed to read report template {}", template_path.display())
+    })?;
+
+    let mut handlebars = handlebars::Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars
+        .register_template_string("report", template)
+        .with_context(|| {
+            format!("failed to parse report template {}", template_path.display())
+        })?;
+    handlebars.render("report", report).with_context(|| {
+        format!("failed to render report template {}", template_path.display())
+    })
+}
+
+const REPORT_COLUMNS: [&str; 9] = [
+    "group_id", "count", "held", "basis", "matched", "role", "id", "name", "reason",
+];
+
+/// One row per item of every duplicate group: the kept item first, then the
+/// removed ones (`held` instead when the group was left untouched).
+fn render_report_csv(report: &Report) -> String {
+    let escape = |cell: &str| {
+        if cell.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell.to_string()
+        }
+    };
+    let mut csv = REPORT_COLUMNS.join(",");
+    csv.push('\n');
+    for group in &report.groups {
+        let count = group.count.to_string();
+        let matched = matched_summary(&group.matched);
+        for row in report_rows(group) {
+            let cells = [
+                group.id.as_str(),
+                &count,
+                if group.held { "true" } else { "false" },
+                &group.basis,
+                &matched,
+                row.role,
+                row.id.unwrap_or(""),
+                row.name.unwrap_or(""),
+                row.reason.unwrap_or(""),
+            ]
+            .map(escape);
+            csv.push_str(&cells.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+fn render_report_markdown(report: &Report) -> String {
+    let escape = |cell: &str| cell.replace('|', "\\|").replace(['\n', '\r'], " ");
+    let mut md = String::from("# Duplicate report\n\n");
+    md.push_str(&format!(
+        "{} items, {} duplicate group(s), {} removed",
+        report.total_items, report.duplicate_groups, report.removed
+    ));
+    if report.held_groups > 0 {
+        md.push_str(&format!(
+            ", {} group(s) held for review",
+            report.held_groups
+        ));
+    }
+    md.push_str(".\n");
+    if let Some(hygiene) = &report.hygiene {
+        md.push_str(&format!("\nHygiene score: {}/100\n\n", hygiene.score));
+        md.push_str("| category | affected | out of | score | weight |\n");
+        md.push_str("| --- | --- | --- | --- | --- |\n");
+        for category in &hygiene.categories {
+            md.push_str(&format!(
+                "| {} | {} | {} | {} | {} |\n",
+                category.name, category.count, category.out_of, category.score, category.weight
+            ));
+        }
+    }
+    for group in &report.groups {
+        md.push_str(&format!(
+            "\n## {} ({} items{})\n\n",
+            escape(group.kept_name.as_deref().unwrap_or("<unnamed>")),
+            group.count,
+            if group.held { ", held" } else { "" }
+        ));
+        md.push_str(&format!("- Group: `{}`\n", group.id));
+        md.push_str(&format!("- Matched on: {}\n", escape(&group.basis)));
+        if !group.matched.is_empty() {
+            md.push_str(&format!(
+                "- Values: {}\n",
+                escape(&matched_summary(&group.matched))
+            ));
+        }
+        md.push_str("\n| Role | ID | Name | Reason |\n| --- | --- | --- | --- |\n");
+        for row in report_rows(group) {
+            md.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                row.role,
+                escape(row.id.unwrap_or("")),
+                escape(row.name.unwrap_or("")),
+                escape(row.reason.unwrap_or(""))
+            ));
+        }
+    }
+    md
+}
+
+struct ReportRow<'a> {
+    role: &'static str,
+    id: Option<&'a str>,
+    name: Option<&'a str>,
+    reason: Option<&'a str>,
+}
+
+fn report_rows(group: &ReportGroup) -> Vec<ReportRow<'_>> {
+    let kept = ReportRow {
+        role: "kept",
+        id: group.kept_id.as_deref(),
+        name: group.kept_name.as_deref(),
+        reason: None,
+    };
+    let removals = group.removals.iter().map(|removal| ReportRow {
+        role: if group.held { "held" } else { "removed" },
+        id: removal.id.as_deref(),
+        name: removal.name.as_deref(),
+        reason: Some(&removal.reason),
+    });
+    std::iter::once(kept).chain(removals).collect()
+}
+
+/// `domain=example.com; username=me`, with list values comma-separated.
+fn matched_summary(matched: &Map<String, Value>) -> String {
+    matched
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Array(values) => values
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .map_or_else(|| value.to_string(), str::to_string)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Value::Null => String::new(),
+                other => other.to_string(),
+            };
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn collect_differing_paths(
+    items: &[&Value],
+    sections: &[String],
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) -> Vec<String> {
+    if items.len() < 2 {
+        return Vec::new();
+    }
+    let items = items
+        .iter()
+        .map(|item| {
+            let mut item = (*item).clone();
+            retain_sections(&mut item, sections);
+            // Paths inside arrays, which the diff does not descend into.
+            for path in ignore_paths {
+                remove_path(&mut item, path);
+            }
+            item
+        })
+        .collect::<Vec<_>>();
+    let mut diffs = HashSet::new();
+    let baseline = &items[0];
+    for item in &items[1..] {
+        diff_values(
+            baseline,
+            item,
+            &mut Vec::new(),
+            &mut diffs,
+            ignore_keys,
+            ignore_paths,
+        );
+    }
+    let mut paths = diffs.into_iter().collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+fn diff_values(
+    a: &Value,
+    b: &Value,
+    path: &mut Vec<String>,
+    diffs: &mut HashSet<String>,
+    ignore_keys: &IgnoreKeys,
+    ignore_paths: &[Vec<String>],
+) {
+    if a == b {
+        return;
+    }
+    if is_ignored_path(path, ignore_paths) {
+        return;
+    }
+
+    match (a, b) {
+        (Value::Object(a_map), Value::Object(b_map)) => {
+            let mut keys: Vec<&String> = a_map.keys().chain(b_map.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                if ignore_keys.matches(path, key) {
+                    continue;
+                }
+                if path.is_empty() && API_ONLY_FIELDS.contains(&key.as_str()) {
+                    continue;
+                }
+                let a_val = a_map.get(key);
+                let b_val = b_map.get(key);
+                if a_val == b_val {
+                    continue;
+                }
+                path.push(key.clone());
+                match (a_val, b_val) {
+                    (Some(av), Some(bv)) => {
+                        diff_values(av, bv, path, diffs, ignore_keys, ignore_paths)
+                    }
+                    _ => {
+                        diffs.insert(path.join("."));
+                    }
+                }
+                path.pop();
+            }
+        }
+        (Value::Array(a_arr), Value::Array(b_arr)) => {
+            if a_arr.len() != b_arr.len() {
+                diffs.insert(format!("{}[]", path.join(".")));
+                return;
+            }
+            for (av, bv) in a_arr.iter().zip(b_arr.iter()) {
+                if av != bv {
+                    diffs.insert(format!("{}[]", path.join(".")));
+                    break;
+                }
+            }
+        }
+        _ => {
+            diffs.insert(path.join("."));
+        }
+    }
+}
+
+/// A copy of the export with `items` in place of its items, keeping the
+/// folders, collections and other fields as they were.
+fn with_items(root: &Value, items_path: &[String], items: Vec<Value>) -> Value {
+    let mut copy = root.clone();
+    if let Some(slot) = items_at_mut(&mut copy, items_path) {
+        *slot = items;
+    }
+    copy
+}
+
+/// Reads an output file back from `file`, its temporary file, before it is
+/// renamed to `path`, so a partial write or an encoder bug never replaces
+/// anything. The bytes must be the ones rendered, and they must pass
+/// [`verify_rendered`].
+fn verify_output(
+    path: &Path,
+    file: &Path,
+    written: &str,
+    exporter: &dyn formats::ExportAdapter,
+    root: &Value,
+    items_path: &[String],
+    key: Option<&protected::ExportKey>,
+) -> Result<()> {
+    let read_back = fs::read(file)
+        .with_context(|| format!("failed to read back output file {}", path.display()))?;
+    if read_back != written.as_bytes() {
+        anyhow::bail!(
+            "{} did not verify after writing (read back {} bytes, wrote {}); keep the original export",
+            path.display(),
+            read_back.len(),
+            written.len()
+        );
+    }
+    verify_rendered(
+        &path.display().to_string(),
+        written,
+        exporter,
+        root,
+        items_path,
+        key,
+    )
+}
+
+/// Parses rendered output back with the matching importer: formats that can
+/// also be read must give back as many items, and Bitwarden JSON the same
+/// items by Merkle root. `name` is the file (or `stdout`) it goes to.
+fn verify_rendered(
+    name: &str,
+    written: &str,
+    exporter: &dyn formats::ExportAdapter,
+    root: &Value,
+    items_path: &[String],
+    key: Option<&protected::ExportKey>,
+) -> Result<()> {
+    let failed = |problem: String| {
+        anyhow::anyhow!(
+            "{} did not verify after writing ({}); keep the original export",
+            name,
+            problem
+        )
+    };
+
+    let Some(importer) = formats::import_adapters()
+        .into_iter()
+        .find(|importer| importer.name() == exporter.name())
+    else {
+        return Ok(());
+    };
+    let mut parsed = importer
+        .import(written)
+        .map_err(|err| failed(format!("{:#}", err)))?;
+    if let Some(key) = key {
+        parsed =
+            protected::unlock_with_key(&parsed, key).map_err(|err| failed(format!("{:#}", err)))?;
+    }
+    // Other formats are read back into the classic layout.
+    let json = exporter.name() == "bitwarden-json";
+    let parsed_path = if json {
+        items_path
+    } else {
+        &["items".to_string()][..]
+    };
+    let expected = value_at_path(root, items_path)
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let parsed = value_at_path(&parsed, parsed_path)
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    if parsed.len() != expected.len() {
+        return Err(failed(format!(
+            "read back {} item(s), wrote {}",
+            parsed.len(),
+            expected.len()
+        )));
+    }
+    if json && merkle_root(parsed) != merkle_root(expected) {
+        return Err(failed(
+            "items read back differ from the ones written".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Root of a binary Merkle tree over the canonical JSON of each item, in
+/// order. Leaves and inner nodes are domain-separated (0x00 / 0x01 prefix)
+/// and an odd node is carried up unchanged.
+fn merkle_root(items: &[Value]) -> String {
+    let mut level = items
+        .iter()
+        .map(|item| {
+            let canonical = serde_json::to_vec(&canonicalize(item)).unwrap_or_default();
+            let mut hasher = Sha256::new();
+            hasher.update([0u8]);
+            hasher.update(&canonical);
+            hasher.finalize()
+        })
+        .collect::<Vec<_>>();
+    if level.is_empty() {
+        return hex::encode(Sha256::digest(b""));
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([1u8]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize()
+                }
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    hex::encode(level[0])
+}
+
+fn list_formats() {
+    println!("Import formats:");
+    for adapter in formats::import_adapters() {
+        println!(
+            "  {:<16} .{:<6} {}",
+            adapter.name(),
+            adapter.extensions().join(", ."),
+            adapter.description()
+        );
+    }
+    println!("Export formats:");
+    for adapter in formats::export_adapters() {
+        println!(
+            "  {:<16} .{:<6} {}",
+            adapter.name(),
+            adapter.extensions().join(", ."),
+            adapter.description()
+        );
+    }
+}
+
+fn list_keep_strategies() {
+    println!("Keep strategies:");
+    for strategy in KEEP_STRATEGIES {
+        println!("  {:<16} {}", strategy.name(), strategy.description());
+    }
+}
+
+/// The sample `config.toml` with every setting explained, as `config init`
+/// writes it.
+const CONFIG_TEMPLATE: &str = include_str!("../config.toml");
+
+fn run_config_init(output: Option<&Path>, force: bool) -> Result<()> {
+    let path = match output {
+        Some(path) if is_stdio(path) => {
+            print!("{}", CONFIG_TEMPLATE);
+            return Ok(());
+        }
+        Some(path) => path.to_path_buf(),
+        None => user_config_path().context("no user config directory; pass --output")?,
+    };
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists; pass --force to overwrite it",
+            path.display()
+        );
+    }
+    if let Some(dir) = path.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    FileWriter {
+        read_only: false,
+        contain_symlinks: false,
+        allow_insecure_location: false,
+    }
+    .write(&path, CONFIG_TEMPLATE, "config")?;
+    info!("Wrote the sample config to {}", path.display());
+    Ok(())
+}
+
+fn run_config_validate(config_args: &ConfigArgs, overrides: &ConfigOverrides) -> Result<()> {
+    logging::set_status_to_stderr(true);
+    let config = effective_config(config_args, overrides)?;
+    let effective = toml::to_string_pretty(&config).context("failed to print the config")?;
+    print!("{}", effective);
+    Ok(())
+}
+
+fn run_lint_config(config_args: &ConfigArgs) -> Result<()> {
+    let config = load_config(config_args)?;
+    let findings = lint_config(&config);
+
+    for finding in &findings {
+        let label = match finding.severity {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        println!("{}: {}", label, finding.message);
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+    let warnings = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Warning)
+        .count();
+    println!("Lint: {} error(s), {} warning(s)", errors, warnings);
+
+    if errors > 0 {
+        anyhow::bail!("config has {} error(s)", errors);
+    }
+    Ok(())
+}
+
+fn run_validate(input: &Path, items_path: Option<&str>) -> Result<()> {
+    let root = read_export(input)?;
+    let items_path = locate_items_path(&root, items_path)?;
+    let items = value_at_path(&root, &items_path)
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let problems = validate::validate_export(&root, items);
+    for problem in &problems {
+        println!("error: {}", problem);
+    }
+    println!(
+        "Validate: {} item(s), {} problem(s)",
+        items.len(),
+        problems.len()
+    );
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "{} is not a structurally sound export ({} problem(s))",
+            canonical_path(input).display(),
+            problems.len()
+        );
+    }
+    Ok(())
+}
+
+fn run_preview_normalize(
+    item_id: &str,
+    input: &Path,
+    config_args: &ConfigArgs,
+    items_path: Option<&str>,
+) -> Result<()> {
+    let config = load_config(config_args)?;
+    let root = read_export(input)?;
+    let items_path = locate_items_path(&root, items_path)?;
+    let item = value_at_path(&root, &items_path)
+        .and_then(Value::as_array)
+        .and_then(|items| {
+            items
+                .iter()
+                .find(|item| item.get("id").and_then(Value::as_str) == Some(item_id))
+        })
+        .with_context(|| {
+            format!(
+                "no item with id {} in {}",
+                item_id,
+                canonical_path(input).display()
+            )
+        })?;
+
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let normalized = redacted_normalized_value(item, &config, &ignore_keys, &ignore_paths);
+
+    let basis = if !uses_policy_keys(item, &config) {
+        "whole item".to_string()
+    } else {
+        policy_basis(&config)
+    };
+    let mut item = item.clone();
+    redact::item(&mut item);
+    println!("Before:");
+    println!("{}", serde_json::to_string_pretty(&item)?);
+    println!(
+        "After ({}; trim: {}, lowercase: {}, sort uris: {}):",
+        basis,
+        config.normalize.trim_strings,
+        config.normalize.lowercase_strings,
+        config.normalize.sort_uris
+    );
+    println!("{}", serde_json::to_string_pretty(&normalized)?);
+    Ok(())
+}
+
+fn run_fingerprint(
+    input: &Path,
+    config_args: &ConfigArgs,
+    items_path: Option<&str>,
+    salt_file: Option<&Path>,
+    output: Option<&Path>,
+) -> Result<()> {
+    logging::set_status_to_stderr(output.is_none());
+    let config = load_config(config_args)?;
+    let items = read_items(input, items_path)?;
+    let salt = salt_file.map(read_password_file).transpose()?;
+    if let (Some(path), Some(salt)) = (salt_file, &salt)
+        && salt.is_empty()
+    {
+        anyhow::bail!("salt file {} is empty", path.display());
+    }
+    if salt.is_none() {
+        info!(
+            "note: without --salt-file the fingerprints are plain hashes of keys that can hold passwords"
+        );
+    }
+
+    let lines = fingerprints(&items, &config, salt.as_deref().map(String::as_str))
+        .into_iter()
+        .map(|(id, fingerprint)| format!("{}\t{}\n", id, fingerprint))
+        .collect::<String>();
+    match output {
+        Some(path) => {
+            FileWriter {
+                read_only: false,
+                contain_symlinks: false,
+                allow_insecure_location: false,
+            }
+            .write(path, lines, "fingerprints")?;
+            info!("Wrote {} fingerprint(s) to {}", items.len(), path.display());
+        }
+        None => print!("{}", lines),
+    }
+    Ok(())
+}
+
+/// Each item's id (`#N` by position when it has none) and the hex SHA-256
+/// it is grouped by, salted with `salt` when given. Items with the same
+/// fingerprint are duplicates under `config`, in this export or any other
+/// fingerprinted with the same config, salt and [`KEY_VERSION`].
+fn fingerprints(items: &[Value], config: &Config, salt: Option<&str>) -> Vec<(String, String)> {
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let cache = KeyCache::default();
+    let (digests, _) = build_key_digests(items, config, &ignore_keys, &ignore_paths, &cache);
+    items
+        .iter()
+        .zip(digests)
+        .enumerate()
+        .map(|(index, (item, digest))| {
+            let id = item_str(item, "id").unwrap_or_else(|| format!("#{}", index));
+            let fingerprint = match salt {
+                None => hex::encode(digest),
+                Some(salt) => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(salt.as_bytes());
+                    hasher.update([0u8]);
+                    hasher.update(digest);
+                    hex::encode(hasher.finalize())
+                }
+            };
+            (id, fingerprint)
+        })
+        .collect()
+}
+
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn Write) {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+fn run_manpage(out_dir: Option<&Path>) -> Result<()> {
+    let command = Args::command();
+    match out_dir {
+        Some(dir) => {
+            clap_mangen::generate_to(command, dir)
+                .with_context(|| format!("failed to write man pages to {}", dir.display()))?;
+            info!("Wrote man pages to {}", dir.display());
+        }
+        None => clap_mangen::Man::new(command)
+            .render(&mut std::io::stdout())
+            .context("failed to write the man page")?,
+    }
+    Ok(())
+}
+
+/// Which items `explain` explains.
+#[derive(Debug)]
+enum ItemSelector {
+    Id(String),
+    /// A name pattern for [`wildcard_match`].
+    Name(String),
+}
+
+fn run_explain(
+    selector: &ItemSelector,
+    input: &Path,
+    config_args: &ConfigArgs,
+    items_path: Option<&str>,
+) -> Result<()> {
+    let config = load_config(config_args)?;
+    let items = read_items(input, items_path)?;
+    let selected = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| match selector {
+            ItemSelector::Id(id) => item_str(item, "id").as_ref() == Some(id),
+            ItemSelector::Name(pattern) => {
+                wildcard_match(pattern, &item_str(item, "name").unwrap_or_default())
+            }
+        })
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    if selected.is_empty() {
+        let (what, value) = match selector {
+            ItemSelector::Id(id) => ("id", id),
+            ItemSelector::Name(pattern) => ("a name matching", pattern),
+        };
+        anyhow::bail!(
+            "no item with {} {} in {}",
+            what,
+            value,
+            canonical_path(input).display()
+        );
+    }
+
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let cache = KeyCache::default();
+    let (digests, _) = build_key_digests(&items, &config, &ignore_keys, &ignore_paths, &cache);
+    let explanations = selected
+        .into_iter()
+        .map(|index| explain_item(&items, &digests, index, &config))
+        .collect::<Vec<_>>();
+    print!("{}", explanations.join("\n"));
+    Ok(())
+}
+
+/// The key of `items[index]`, the steps that produced it, the items in its
+/// group with the one that is kept, and the items of the same name that
+/// ended up with another key and why. `digests` are the key digests of
+/// `items`.
+fn explain_item(items: &[Value], digests: &[KeyDigest], index: usize, config: &Config) -> String {
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let item = &items[index];
+    let label = |item: &Value| {
+        format!(
+            "{} {:?}",
+            item_str(item, "id").unwrap_or_else(|| "<no id>".to_string()),
+            item_str(item, "name").unwrap_or_default()
+        )
+    };
+
+    let mut out = format!("Item {} ({})\n", label(item), item_type_name(item));
+    out.push_str("Normalization:\n");
+    for step in describe_normalization(item, config) {
+        out.push_str(&format!("  - {}\n", step));
+    }
+    let normalized = redacted_normalized_value(item, config, &ignore_keys, &ignore_paths);
+    out.push_str(&format!("Key: {}\n", normalized));
+    out.push_str(&format!("Key digest: {}\n", hex::encode(digests[index])));
+
+    let group = (0..items.len())
+        .filter(|other| digests[*other] == digests[index])
+        .collect::<Vec<_>>();
+    if group.len() == 1 {
+        out.push_str("No other item has this key, so it is kept.\n");
+    } else {
+        // The same choice the dedup pass makes, in input order.
+        let ranking = config.dedup.ranking();
+        let kept = group[1..].iter().fold(group[0], |kept, candidate| {
+            if should_replace(&items[kept], &items[*candidate], &ranking) {
+                *candidate
+            } else {
+                kept
+            }
+        });
+        let held = config
+            .dedup
+            .max_auto_remove_per_group
+            .is_some_and(|max| group.len() - 1 > max);
+        out.push_str(&format!("Group: {} items with this key", group.len()));
+        out.push_str(if held {
+            ", held whole for review (max_auto_remove_per_group)\n"
+        } else {
+            "\n"
+        });
+        for member in &group {
+            let fate = if held || *member == kept {
+                "kept".to_string()
+            } else {
+                format!(
+                    "removed: {}",
+                    ranking.explain(&items[kept], &items[*member])
+                )
+            };
+            let marker = if *member == index { "*" } else { " " };
+            out.push_str(&format!(
+                "  {} {}: {}\n",
+                marker,
+                label(&items[*member]),
+                fate
+            ));
+        }
+    }
+
+    // Same-named items with another key: what keeps them apart.
+    let name = item_str(item, "name").unwrap_or_default().to_lowercase();
+    let compare_all = IgnoreKeys::parse(&[]);
+    let own_value = normalized_value(item, config, &ignore_keys, &ignore_paths);
+    let apart = (0..items.len())
+        .filter(|other| digests[*other] != digests[index])
+        .filter(|other| {
+            item_str(&items[*other], "name")
+                .unwrap_or_default()
+                .to_lowercase()
+                == name
+        })
+        .collect::<Vec<_>>();
+    if !apart.is_empty() {
+        out.push_str("Same name, different key:\n");
+    }
+    for other in apart {
+        let other_value = normalized_value(&items[other], config, &ignore_keys, &ignore_paths);
+        let mut differs =
+            collect_differing_paths(&[&own_value, &other_value], &[], &compare_all, &[]);
+        if differs.is_empty() {
+            // Equal values, so the scope or creation window kept them apart.
+            differs.push("scope or creation window".to_string());
+        }
+        out.push_str(&format!(
+            "    {}: differs in {}\n",
+            label(&items[other]),
+            differs.join(", ")
+        ));
+    }
+    out
+}
+
+/// The steps between an item and the value it is compared by, as the
+/// config sets them.
+fn describe_normalization(item: &Value, config: &Config) -> Vec<String> {
+    let normalize = &config.normalize;
+    let mut steps = Vec::new();
+    if normalize.username_from_name {
+        steps.push("empty usernames filled from \"site (user)\" names".to_string());
+    }
+    let uri = normalize.uri;
+    let uri_parts = [
+        (uri.strip_scheme, "scheme"),
+        (uri.strip_www, "www."),
+        (uri.strip_default_port, "default port"),
+        (uri.strip_trailing_slash, "trailing slash"),
+        (uri.strip_path, "path"),
+        (uri.strip_query, "query"),
+    ]
+    .into_iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, part)| part)
+    .collect::<Vec<_>>();
+    if !uri_parts.is_empty() {
+        steps.push(format!("URIs stripped of: {}", uri_parts.join(", ")));
+    }
+    if !normalize.android_apps.is_empty() {
+        steps.push(format!(
+            "{} Android app(s) mapped to web domains",
+            normalize.android_apps.len()
+        ));
+    }
+    if uses_policy_keys(item, config) {
+        steps.push(format!(
+            "compared by the policy keys {}",
+            policy_basis(config)
+        ));
+        if config.dedup.policy_keys.contains(&DedupKey::Domain) {
+            steps.push(format!(
+                "domains compared by {} name",
+                format!("{:?}", config.dedup.domain_granularity).to_lowercase()
+            ));
+            if normalize.equivalent_domains.is_enabled() {
+                steps.push("equivalent domains compared as one".to_string());
+            }
+        }
+    } else {
+        steps.push("compared as a whole item".to_string());
+        if !normalize.field_aliases.is_empty() {
+            steps.push(format!(
+                "{} custom field alias(es) renamed",
+                normalize.field_aliases.len()
+            ));
+        }
+        if !config.dedup.hash_sections.is_empty() {
+            steps.push(format!(
+                "only the sections {}",
+                config.dedup.hash_sections.join(", ")
+            ));
+        }
+        steps.push(format!(
+            "API-only fields left out: {}",
+            API_ONLY_FIELDS.join(", ")
+        ));
+        if !config.ignore.keys.is_empty() {
+            steps.push(format!("ignored keys: {}", config.ignore.keys.join(", ")));
+        }
+        if !config.ignore.paths.is_empty() {
+            steps.push(format!("ignored paths: {}", config.ignore.paths.join(", ")));
+        }
+    }
+    if normalize.sort_uris {
+        steps.push("URIs sorted".to_string());
+    }
+    if normalize.trim_strings {
+        steps.push("strings trimmed".to_string());
+    }
+    if normalize.lowercase_strings {
+        steps.push("strings lowercased".to_string());
+    }
+    if config.dedup.scope != DedupScope::Global {
+        steps.push(format!(
+            "only compared within the same {}",
+            format!("{:?}", config.dedup.scope).to_lowercase()
+        ));
+    }
+    if let Some(window) = config.dedup.creation_window_secs {
+        steps.push(format!(
+            "only copies created within {}s of each other match",
+            window
+        ));
+    }
+    steps
+}
+
+fn run_compare(
+    left: &Path,
+    right: &Path,
+    output: Option<&Path>,
+    format: Option<MatrixFormat>,
+) -> Result<()> {
+    let left_items = read_items(left, None)?;
+    let right_items = read_items(right, None)?;
+    let (counts, rows) = conflict_matrix(&left_items, &right_items);
+    // Keep stdout clean when the matrix itself goes there.
+    logging::set_status_to_stderr(output.is_none());
+    info!(
+        "Credentials (host and username) in both vaults: {}",
+        counts.shared
+    );
+    info!(
+        "Credentials with differing passwords: {}",
+        counts.conflicting
+    );
+    info!(
+        "Login pairs with differing passwords (matrix rows): {}",
+        rows.len()
+    );
+
+    let format = format.unwrap_or_else(|| {
+        let is_html = output
+            .and_then(Path::extension)
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"));
+        if is_html {
+            MatrixFormat::Html
+        } else {
+            MatrixFormat::Csv
+        }
+    });
+    let matrix = match format {
+        MatrixFormat::Csv => render_matrix_csv(&rows),
+        MatrixFormat::Html => render_matrix_html(&rows, left, right),
+    };
+    match output {
+        Some(path) => {
+            // Passwords are left out, so any directory will do.
+            FileWriter {
+                read_only: false,
+                contain_symlinks: false,
+                allow_insecure_location: true,
+            }
+            .write(path, matrix, "conflict matrix")?;
+            info!("Wrote conflict matrix {}", path.display());
+        }
+        None => print!("{}", matrix),
+    }
+    Ok(())
+}
+
+fn run_item_diff(
+    left: &Path,
+    right: &Path,
+    output: Option<&Path>,
+    config_args: &ConfigArgs,
+) -> Result<()> {
+    let config = load_config(config_args)?;
+    let left_items = read_items(left, None)?;
+    let right_items = read_items(right, None)?;
+    let diff = diff_items(&left_items, &right_items, &config);
+    let summary = format!(
+        "Items: {} only in {}, {} only in {}, {} changed, {} unchanged",
+        diff.only_left.len(),
+        left.display(),
+        diff.only_right.len(),
+        right.display(),
+        diff.changed.len(),
+        diff.unchanged
+    );
+
+    let describe = |item: &Value| {
+        format!(
+            "{} ({})",
+            item_str(item, "name").as_deref().unwrap_or("<unnamed>"),
+            item_str(item, "id").as_deref().unwrap_or("no id")
+        )
+    };
+    let mut text = String::new();
+    for (side, items, indexes) in [
+        (left, &left_items, &diff.only_left),
+        (right, &right_items, &diff.only_right),
+    ] {
+        text.push_str(&format!(
+            "Only in {} ({}):\n",
+            side.display(),
+            indexes.len()
+        ));
+        for &index in indexes {
+            text.push_str(&format!("  {}\n", describe(&items[index])));
+        }
+    }
+    text.push_str(&format!("Changed ({}):\n", diff.changed.len()));
+    for (left_index, right_index, paths) in &diff.changed {
+        let (before, after) = (
+            describe(&left_items[*left_index]),
+            describe(&right_items[*right_index]),
+        );
+        let names = if before == after {
+            before
+        } else {
+            format!("{} -> {}", before, after)
+        };
+        text.push_str(&format!("  {}: {}\n", names, paths.join(", ")));
+    }
+
+    match output {
+        Some(path) => {
+            FileWriter {
+                read_only: false,
+                contain_symlinks: false,
+                allow_insecure_location: true,
+            }
+            .write(path, text, "item diff")?;
+            info!("{}", summary);
+            info!("Wrote item diff {}", path.display());
+        }
+        None => {
+            logging::set_status_to_stderr(true);
+            info!("{}", summary);
+            print!("{}", text);
+        }
+    }
+    Ok(())
+}
+
+/// Items of two exports matched by dedup key, by index into each export.
+#[derive(Debug, Default)]
+struct ItemDiff {
+    only_left: Vec<usize>,
+    only_right: Vec<usize>,
+    /// Pairs whose items differ, with the paths that differ.
+    changed: Vec<(usize, usize, Vec<String>)>,
+    unchanged: usize,
+}
+
+/// Pairs the items of two exports with the same dedup key, by ID where the
+/// IDs match and else in order, so a key held twice on one side and once on
+/// the other leaves one item unpaired. Items left over are then paired by
+/// ID alone: an item whose password (or another keyed field) was edited is
+/// the same item changed, not one removed and another added.
+fn diff_items(left: &[Value], right: &[Value], config: &Config) -> ItemDiff {
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let cache = KeyCache::default();
+    let (left_keys, _) = build_key_digests(left, config, &ignore_keys, &ignore_paths, &cache);
+    let (right_keys, _) = build_key_digests(right, config, &ignore_keys, &ignore_paths, &cache);
+    let mut unpaired: HashMap<KeyDigest, VecDeque<usize>> = HashMap::new();
+    for (index, key) in right_keys.into_iter().enumerate() {
+        unpaired.entry(key).or_default().push_back(index);
+    }
+
+    // Same key and ID first, so a copy elsewhere in the list does not take
+    // an item's own counterpart.
+    let mut pairs = vec![None; left.len()];
+    for (pair, (item, key)) in pairs.iter_mut().zip(left.iter().zip(&left_keys)) {
+        let (Some(id), Some(candidates)) = (item_str(item, "id"), unpaired.get_mut(key)) else {
+            continue;
+        };
+        if let Some(position) = candidates
+            .iter()
+            .position(|candidate| item_str(&right[*candidate], "id").as_ref() == Some(&id))
+        {
+            *pair = candidates.remove(position);
+        }
+    }
+    for (pair, key) in pairs.iter_mut().zip(&left_keys) {
+        if pair.is_none() {
+            *pair = unpaired.get_mut(key).and_then(VecDeque::pop_front);
+        }
+    }
+
+    let mut rest = unpaired.into_values().flatten().collect::<Vec<_>>();
+    rest.sort_unstable();
+    let mut by_id: HashMap<String, VecDeque<usize>> = HashMap::new();
+    for &index in &rest {
+        if let Some(id) = item_str(&right[index], "id") {
+            by_id.entry(id).or_default().push_back(index);
+        }
+    }
+    for (pair, item) in pairs.iter_mut().zip(left) {
+        if pair.is_none()
+            && let Some(id) = item_str(item, "id")
+        {
+            *pair = by_id.get_mut(&id).and_then(VecDeque::pop_front);
+        }
+    }
+    let paired = pairs.iter().flatten().copied().collect::<HashSet<_>>();
+
+    let compare_all = IgnoreKeys::parse(&[]);
+    let mut diff = ItemDiff::default();
+    for (index, pair) in pairs.into_iter().enumerate() {
+        match pair {
+            Some(other) => {
+                let paths =
+                    collect_differing_paths(&[&left[index], &right[other]], &[], &compare_all, &[]);
+                if paths.is_empty() {
+                    diff.unchanged += 1;
+                } else {
+                    diff.changed.push((index, other, paths));
+                }
+            }
+            None => diff.only_left.push(index),
+        }
+    }
+    diff.only_right = rest
+        .into_iter()
+        .filter(|index| !paired.contains(index))
+        .collect();
+    diff
+}
+
+/// The items of an export, at `items_path` or wherever they are found.
+fn read_items(input: &Path, items_path: Option<&str>) -> Result<Vec<Value>> {
+    let mut root = read_export(input)?;
+    let items_path = locate_items_path(&root, items_path)
+        .with_context(|| format!("no items array in {}", canonical_path(input).display()))?;
+    Ok(items_at_mut(&mut root, &items_path)
+        .map(std::mem::take)
+        .unwrap_or_default())
+}
+
+/// Credentials, each a (host, normalized username), counted by
+/// [`conflict_matrix`].
+#[derive(Debug, Default, PartialEq)]
+struct CredentialCounts {
+    /// In both exports.
+    shared: usize,
+    /// In both exports, with a login on each side whose passwords differ.
+    conflicting: usize,
+}
+
+/// Groups logins by (host, normalized username) for cross-vault matching.
+/// Pairs logins from two exports by domain and username and returns how many
+/// credentials both contain and how many of those conflict, plus a row for
+/// each pair of logins whose passwords differ.
+fn conflict_matrix(
+    left_items: &[Value],
+    right_items: &[Value],
+) -> (CredentialCounts, Vec<MatrixRow>) {
+    let left_index = index_credentials(left_items);
+    let right_index = index_credentials(right_items);
+
+    let mut counts = CredentialCounts::default();
+    let mut rows: Vec<MatrixRow> = Vec::new();
+    // A pair of logins sharing several domains gets one row listing all of
+    // them, keyed by the items themselves so missing IDs can't collide.
+    let mut pair_rows: HashMap<(*const Value, *const Value), usize> = HashMap::new();
+    for ((domain, username), left_logins) in &left_index {
+        let Some(right_logins) = right_index.get(&(domain.clone(), username.clone())) else {
+            continue;
+        };
+        counts.shared += 1;
+        let mut conflicting = false;
+        for left_login in left_logins {
+            for right_login in right_logins {
+                if login_password(left_login) == login_password(right_login) {
+                    continue;
+                }
+                conflicting = true;
+                let pair = (*left_login as *const Value, *right_login as *const Value);
+                if let Some(&row) = pair_rows.get(&pair) {
+                    rows[row].domain.push(' ');
+                    rows[row].domain.push_str(domain);
+                    continue;
+                }
+                pair_rows.insert(pair, rows.len());
+                rows.push(MatrixRow {
+                    domain: domain.clone(),
+                    username: username.clone(),
+                    left_id: item_str(left_login, "id"),
+                    left_name: item_str(left_login, "name"),
+                    left_revision: best_date(left_login).map(str::to_string),
+                    right_id: item_str(right_login, "id"),
+                    right_name: item_str(right_login, "name"),
+                    right_revision: best_date(right_login).map(str::to_string),
+                    newer: match compare_dates(left_login, right_login) {
+                        Ordering::Greater => "left",
+                        Ordering::Less => "right",
+                        Ordering::Equal => "same",
+                    },
+                });
+            }
+        }
+        counts.conflicting += usize::from(conflicting);
+    }
+    (counts, rows)
+}
+
+fn index_credentials(items: &[Value]) -> BTreeMap<(String, String), Vec<&Value>> {
+    let mut index: BTreeMap<(String, String), Vec<&Value>> = BTreeMap::new();
+    for login in items.iter().filter(|item| item_type(item) == Some(1)) {
+        let username = extract_login_field(login, "username");
+        let username = username
+            .as_str()
+            .map(|value| value.trim().to_lowercase())
+            .unwrap_or_default();
+        let mut domains = extract_domains(login)
+            .into_iter()
+            .filter_map(|domain| domain.as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        domains.sort();
+        domains.dedup();
+        for domain in domains {
+            index
+                .entry((domain, username.clone()))
+                .or_default()
+                .push(login);
+        }
+    }
+    index
+}
+
+const MATRIX_COLUMNS: [&str; 9] = [
+    "domain",
+    "username",
+    "left_id",
+    "left_name",
+    "left_revision",
+    "right_id",
+    "right_name",
+    "right_revision",
+    "newer",
+];
+
+fn matrix_cells(row: &MatrixRow) -> [&str; 9] {
+    [
+        &row.domain,
+        &row.username,
+        row.left_id.as_deref().unwrap_or(""),
+        row.left_name.as_deref().unwrap_or(""),
+        row.left_revision.as_deref().unwrap_or(""),
+        row.right_id.as_deref().unwrap_or(""),
+        row.right_name.as_deref().unwrap_or(""),
+        row.right_revision.as_deref().unwrap_or(""),
+        row.newer,
+    ]
+}
+
+fn render_matrix_csv(rows: &[MatrixRow]) -> String {
+    let escape = |cell: &str| {
+        if cell.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell.to_string()
+        }
+    };
+    let mut csv = MATRIX_COLUMNS.join(",");
+    csv.push('\n');
+    for row in rows {
+        let cells = matrix_cells(row).map(escape);
+        csv.push_str(&cells.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn render_matrix_html(rows: &[MatrixRow], left: &Path, right: &Path) -> String {
+    let escape = |cell: &str| {
+        cell.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Vault conflicts</title></head>\n<body>\n",
+    );
+    html.push_str(&format!(
+        "<p>left: {}<br>right: {}</p>\n<table border=\"1\">\n<tr>",
+        escape(&left.display().to_string()),
+        escape(&right.display().to_string())
+    ));
+    for column in MATRIX_COLUMNS {
+        html.push_str(&format!("<th>{}</th>", column));
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in matrix_cells(row) {
+            html.push_str(&format!("<td>{}</td>", escape(cell)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn lint_config(config: &Config) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    let mut push = |severity, message: String| findings.push(LintFinding { severity, message });
+
+    let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+    let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+    let policy_keys = &config.dedup.policy_keys;
+
+    if policy_keys.is_empty() {
+        let identifying = [
+            "name",
+            "notes",
+            "login",
+            "fields",
+            "card",
+            "identity",
+            "secureNote",
+        ];
+        let all_ignored = identifying.iter().all(|section| {
+            let excluded = !config.dedup.hash_sections.is_empty()
+                && !config.dedup.hash_sections.iter().any(|s| s == section);
+            excluded || ignores_path(&[section], &ignore_keys, &ignore_paths)
+        });
+        if all_ignored {
+            push(
+                Severity::Error,
+                "whole-item mode ignores every identifying section; all items will hash equal"
+                    .to_string(),
+            );
+        }
+    } else {
+        let names = policy_keys
+            .iter()
+            .map(|key| dedup_key_name(*key))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if policy_keys.iter().all(|key| *key != DedupKey::Name) {
+            push(
+                Severity::Info,
+                format!(
+                    "policy keys [{}] only exist on login items; secure notes, cards and identities are compared as whole items",
+                    names
+                ),
+            );
+        } else if policy_keys.iter().any(|key| *key != DedupKey::Name) {
+            push(
+                Severity::Info,
+                "non-login items will be compared by name alone".to_string(),
+            );
+        }
+
+        let neutralized = policy_keys
+            .iter()
+            .all(|key| ignores_path(dedup_key_source(*key), &ignore_keys, &ignore_paths));
+        if neutralized {
+            push(
+                Severity::Warning,
+                format!(
+                    "ignore lists cover every policy key [{}], but they only apply to whole-item comparison; these fields are still compared",
+                    names
+                ),
+            );
+        }
+
+        if config.dedup.domain_granularity != DomainGranularity::Host
+            && !policy_keys.contains(&DedupKey::Domain)
+        {
+            push(
+                Severity::Info,
+                "domain_granularity only applies to the domain policy key, which is not used"
+                    .to_string(),
+            );
+        }
+
+        if config.normalize.equivalent_domains.is_enabled()
+            && !policy_keys.contains(&DedupKey::Domain)
+        {
+            push(
+                Severity::Info,
+                "equivalent_domains only applies to the domain policy key, which is not used"
+                    .to_string(),
+            );
+        }
+
+        if !config.dedup.hash_sections.is_empty() && policy_keys.contains(&DedupKey::Name) {
+            push(
+                Severity::Info,
+                "hash_sections only applies to whole-item comparison, which name as a policy key disables"
+                    .to_string(),
+            );
+        }
+    }
+
+    if !config.dedup.policy_paths.is_empty() {
+        push(
+            Severity::Info,
+            "policy paths only apply to items where at least one of them finds a value; other items are compared as before"
+                .to_string(),
+        );
+    }
+
+    let strategies = config.dedup.keep.strategies();
+    if let Some(index) = strategies
+        .iter()
+        .position(|strategy| matches!(strategy.name(), "first" | "last"))
+        && index + 1 < strategies.len()
+    {
+        push(
+            Severity::Warning,
+            format!(
+                "keep strategy {} never ties, so the ones after it in \"{}\" are never used",
+                strategies[index].name(),
+                config.dedup.keep
+            ),
+        );
+    }
+
+    if config.dedup.max_auto_remove_per_group == Some(0) {
+        push(
+            Severity::Warning,
+            "max_auto_remove_per_group = 0 holds back every duplicate group; nothing will be removed"
+                .to_string(),
+        );
+    }
+
+    if config.normalize.lowercase_strings {
+        let compares_password = if policy_keys.is_empty() {
+            let in_sections = config.dedup.hash_sections.is_empty()
+                || config.dedup.hash_sections.iter().any(|s| s == "login");
+            in_sections && !ignores_path(&["login", "password"], &ignore_keys, &ignore_paths)
+        } else {
+            policy_keys.contains(&DedupKey::Password)
+        };
+        if compares_password {
+            push(
+                Severity::Warning,
+                "lowercase_strings also lowercases passwords; credentials differing only in case will be treated as duplicates"
+                    .to_string(),
+            );
+        }
+    }
+
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    findings
+}
+
+fn ignores_path(path: &[&str], ignore_keys: &IgnoreKeys, ignore_paths: &[Vec<String>]) -> bool {
+    let path = path.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    (0..path.len()).any(|index| {
+        ignore_keys.matches(&path[..index], &path[index])
+            || ignore_paths
+                .iter()
+                .any(|ignore| path_matches(ignore, &path[..=index]))
+    })
+}
+
+fn dedup_key_name(key: DedupKey) -> &'static str {
+    match key {
+        DedupKey::Domain => "domain",
+        DedupKey::Username => "username",
+        DedupKey::Password => "password",
+        DedupKey::Name => "name",
+        DedupKey::Uri => "uri",
+        DedupKey::Totp => "totp",
+        DedupKey::Passkey => "passkey",
+    }
+}
+
+fn dedup_key_source(key: DedupKey) -> &'static [&'static str] {
+    match key {
+        DedupKey::Domain | DedupKey::Uri => &["login", "uris"],
+        DedupKey::Username => &["login", "username"],
+        DedupKey::Password => &["login", "password"],
+        DedupKey::Name => &["name"],
+        DedupKey::Totp => &["login", "totp"],
+        DedupKey::Passkey => &["login", "fido2Credentials"],
+    }
+}
+
+/// Reads equivalent-domain groups, one per line.
+fn load_equivalent_domains(path: &Path) -> Result<Vec<Vec<String>>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read equivalent domains {}", path.display()))?;
+    let mut groups = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let group = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|domain| !domain.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>();
+        match group.len() {
+            0 => {}
+            1 => anyhow::bail!(
+                "{}:{}: an equivalent-domain group needs at least two domains",
+                path.display(),
+                number + 1
+            ),
+            _ => groups.push(group),
+        }
+    }
+    Ok(groups)
+}
+
+/// The setting a `--x[=BOOL]`/`--no-x` pair asks for, if either was given.
+/// The two override each other on the command line, so at most one is set.
+fn switch(on: Option<bool>, off: bool) -> Option<bool> {
+    if off { Some(false) } else { on }
+}
+
+/// The config from [`load_config`] with the flags of `overrides` applied.
+/// Any flag given wins over the config, the environment and the
+/// `--assume-double-import` preset, also when it asks for the default.
+fn effective_config(config_args: &ConfigArgs, overrides: &ConfigOverrides) -> Result<Config> {
+    let mut config = load_config(config_args)?;
+
+    if overrides.assume_double_import {
+        apply_double_import_preset(&mut config);
+    }
+    if let Some(keep) = &overrides.keep {
+        config.dedup.keep = keep.clone();
+    }
+    if let Some(prefer) = overrides.prefer.clone() {
+        config.dedup.prefer = prefer;
+    }
+    if let Some(merge) = switch(overrides.merge, overrides.no_merge) {
+        config.dedup.merge = merge;
+    }
+    if let Some(max) = overrides.max_auto_remove_per_group {
+        config.dedup.max_auto_remove_per_group = Some(max);
+    }
+    if let Some(window) = overrides.creation_window {
+        config.dedup.creation_window_secs = Some(window);
+    }
+    if let Some(keys) = overrides.policy_key.clone() {
+        config.dedup.policy_keys = keys;
+    }
+    if let Some(paths) = overrides.policy_path.clone() {
+        config.dedup.policy_paths = paths;
+    }
+    if let Some(granularity) = overrides.domain_granularity {
+        config.dedup.domain_granularity = granularity;
+    }
+    if let Some(path) = &overrides.equivalent_domains {
+        config
+            .normalize
+            .equivalent_domains
+            .groups
+            .extend(load_equivalent_domains(path)?);
+    }
+    if let Some(scope) = overrides.scope {
+        config.dedup.scope = scope;
+    }
+    if overrides.assume_double_import && config.dedup.scope != DedupScope::Global {
+        warn!(
+            "a second import usually gets fresh folder and collection ids, so a non-global scope keeps its copies apart"
+        );
+    }
+    if let Some(sections) = overrides.hash_sections.clone() {
+        config.dedup.hash_sections = sections;
+    }
+    if let Some(keys) = overrides.ignore_key.clone() {
+        config.ignore.keys = keys;
+    }
+    if let Some(paths) = overrides.ignore_path.clone() {
+        config.ignore.paths = paths;
+    }
+    if let Some(trim) = switch(overrides.trim_strings, overrides.no_trim_strings) {
+        config.normalize.trim_strings = trim;
+    }
+    if let Some(lowercase) = switch(overrides.lowercase_strings, overrides.no_lowercase_strings) {
+        config.normalize.lowercase_strings = lowercase;
+    }
+    if let Some(from_name) = switch(
+        overrides.username_from_name,
+        overrides.no_username_from_name,
+    ) {
+        config.normalize.username_from_name = from_name;
+    }
+    if let Some(sort_uris) = switch(overrides.sort_uris, overrides.no_sort_uris) {
+        config.normalize.sort_uris = sort_uris;
+    }
+    if let Some(pretty) = switch(overrides.pretty, overrides.no_pretty) {
+        config.output.pretty = pretty;
+    }
+    Ok(config)
+}
+
+/// The config file from [`find_config`] with the `BW_DEDUP_*` environment
+/// variables applied.
+fn load_config(args: &ConfigArgs) -> Result<Config> {
+    let config = find_config(args)?;
+    if args.no_env {
+        debug!("ignoring the BW_DEDUP_* variables");
+        return Ok(config);
+    }
+    env_config::apply(config, |name| std::env::var(name).ok())
+}
+
+/// Reads the config given with `--config`, or else the first of
+/// [`user_config_path`] if it exists, announcing it so that a stray file is
+/// noticed. The defaults apply when there is none, or with `--no-config`.
+/// A `config.toml` in the current directory is only read when `--config`
+/// names it: whoever controls the directory an export sits in should not
+/// get to decide what counts as a duplicate.
+fn find_config(args: &ConfigArgs) -> Result<Config> {
+    if let Some(path) = &args.config {
+        let config_path = canonical_path(path);
+        if !config_path.exists() {
+            anyhow::bail!("config file {} does not exist", path.display());
+        }
+        return read_config_file(&config_path, args.profile.as_deref());
+    }
+    if args.no_config {
+        debug!("--no-config; using the defaults");
+        return Ok(Config::default());
+    }
+    if Path::new("config.toml").exists() {
+        info!(
+            "Not using config.toml in the current directory; pass --config config.toml to use it"
+        );
+    }
+    match user_config_path().filter(|path| path.exists()) {
+        Some(path) => {
+            let config_path = canonical_path(&path);
+            info!(
+                "Using config file {} (--no-config to ignore it)",
+                config_path.display()
+            );
+            read_config_file(&config_path, args.profile.as_deref())
+        }
+        None => {
+            if let Some(profile) = &args.profile {
+                anyhow::bail!(
+                    "--profile {} needs a config file, but none was found",
+                    profile
+                );
+            }
+            debug!("no config file; using the defaults");
+            Ok(Config::default())
+        }
+    }
+}
+
+/// `config.toml` in the user config directory: `$XDG_CONFIG_HOME/bw-passport-dedup`,
+/// `~/Library/Application Support/bw-passport-dedup` on macOS,
+/// `%APPDATA%\bw-passport-dedup\config` on Windows.
+fn user_config_path() -> Option<PathBuf> {
+    directories::ProjectDirs::from("", "", "bw-passport-dedup")
+        .map(|dirs| dirs.config_dir().join("config.toml"))
+}
+
+fn read_config_file(config_path: &Path, profile: Option<&str>) -> Result<Config> {
+    let contents = fs::read_to_string(config_path)
+        .with_context(|| format!("failed to read config file {}", config_path.display()))?;
+    let config = parse_config(&contents, profile)
+        .with_context(|| format!("failed to parse config file {}", config_path.display()))?;
+    debug!("loaded config file {}", config_path.display());
+    Ok(config)
+}
+
+/// Parses a config with its `[profile.NAME]` tables taken out, then merges
+/// the tables of `profile` over the rest, key by key. Every profile is
+/// checked, so a typo in one that is not in use still surfaces.
+fn parse_config(contents: &str, profile: Option<&str>) -> Result<Config> {
+    let mut base: toml::Table = toml::from_str(contents)?;
+    let profiles = match base.remove("profile") {
+        None => toml::Table::new(),
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!("`profile` must be a table of [profile.NAME] tables"),
+    };
+
+    let mut selected = None;
+    for (name, overrides) in &profiles {
+        let toml::Value::Table(overrides) = overrides else {
+            anyhow::bail!("profile `{}` must be a table", name);
+        };
+        let mut merged = base.clone();
+        merge_toml(&mut merged, overrides);
+        let config = toml::Value::Table(merged)
+            .try_into::<Config>()
+            .with_context(|| format!("invalid profile `{}`", name))?;
+        if profile == Some(name.as_str()) {
+            selected = Some(config);
+        }
+    }
+    match (profile, selected) {
+        (None, _) => Ok(toml::Value::Table(base).try_into()?),
+        (Some(name), Some(config)) => {
+            debug!("using profile {}", name);
+            Ok(config)
+        }
+        (Some(name), None) if profiles.is_empty() => {
+            anyhow::bail!(
+                "no profile `{}`: the config has no [profile.NAME] tables",
+                name
+            )
+        }
+        (Some(name), None) => anyhow::bail!(
+            "no profile `{}` (the config has {})",
+            name,
+            profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+/// Sets every key of `overrides` in `base`, descending into tables both
+/// have so that a profile can change one setting of a section.
+fn merge_toml(base: &mut toml::Table, overrides: &toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge_toml(base, overrides)
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bw_passport_dedup::{check_invariants, dedup_items};
+    use serde_json::json;
+
+    fn dedup_default(items: Vec<Value>) -> DedupOutcome {
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        dedup_items(items, &config, &ignore_keys, &ignore_paths)
+    }
+
+    fn login(id: &str, password: &str, fields: Value) -> Value {
+        json!({
+            "id": id,
+            "type": 1,
+            "name": "Forum",
+            "login": {
+                "uris": [{"uri": "https://forum.example.com"}],
+                "username": "user",
+                "password": password,
+            },
+            "fields": fields,
+        })
+    }
+
+    #[test]
+    fn emit_changed_only_keeps_items_with_renamed_fields() {
+        let mut outcome = dedup_default(vec![
+            login("a", "one", json!([{"name": "pin code", "value": "1"}])),
+            login("b", "one", json!([])),
+            login("c", "two", json!([{"name": "PIN", "value": "2"}])),
+        ]);
+        assert_eq!(outcome.kept.len(), 2);
+
+        let aliases = BTreeMap::from([("pin code".to_string(), "PIN".to_string())]);
+        assert_eq!(rename_aliased_fields(&mut outcome, &aliases), 1);
+
+        let changed = changed_items(outcome.kept, outcome.changed);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0]["id"], "a");
+        assert_eq!(changed[0]["fields"][0]["name"], "PIN");
+    }
+
+    #[test]
+    fn emit_changed_only_is_empty_without_modifications() {
+        let outcome = dedup_default(vec![
+            login("a", "one", json!([])),
+            login("b", "one", json!([])),
+        ]);
+        assert!(changed_items(outcome.kept, outcome.changed).is_empty());
+    }
+
+    #[test]
+    fn emit_deletions_lists_removed_items_by_id() {
+        let mut unsaved = login("", "one", json!([]));
+        unsaved.as_object_mut().unwrap().remove("id");
+        let removed = [login("b", "one", json!([])), unsaved];
+        let (plan, _) = build_deletion_plan(&removed, None);
+
+        assert_eq!(render_deletions(&plan, DeletionFormat::Ids), "b\n");
+        assert_eq!(
+            render_deletions(&plan, DeletionFormat::Jsonl),
+            "{\"id\":\"b\",\"name\":\"Forum\"}\n"
+        );
+        let script = render_deletions(&plan, DeletionFormat::BwCli);
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.ends_with("set -e\n'bw' 'delete' 'item' 'b'  # Forum\n"));
+    }
+
+    #[test]
+    fn conflict_matrix_has_one_row_per_login_pair() {
+        let mut left = login("a", "one", json!([]));
+        left["login"]["uris"] = json!([
+            {"uri": "https://forum.example.com"},
+            {"uri": "https://chat.example.org"},
+        ]);
+        let mut right = left.clone();
+        right["id"] = json!("b");
+        right["login"]["password"] = json!("two");
+
+        let (counts, rows) = conflict_matrix(&[left.clone()], &[right.clone()]);
+        assert_eq!(
+            counts,
+            CredentialCounts {
+                shared: 2,
+                conflicting: 2
+            }
+        );
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].domain, "chat.example.org forum.example.com");
+        assert_eq!(rows[0].left_id.as_deref(), Some("a"));
+        assert_eq!(rows[0].right_id.as_deref(), Some("b"));
+
+        // Two copies on the left make two rows for each credential, which
+        // still counts once.
+        let mut copy = left.clone();
+        copy["id"] = json!("c");
+        let mut same = right.clone();
+        same["login"]["uris"] = json!([{"uri": "https://mail.example.net"}]);
+        let (counts, rows) = conflict_matrix(&[left, copy], &[right, same]);
+        assert_eq!(
+            counts,
+            CredentialCounts {
+                shared: 2,
+                conflicting: 2
+            }
+        );
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[test]
+    fn fix_duplicate_ids_renames_all_but_the_first() {
+        let mut items = vec![
+            login("a", "one", json!([])),
+            login("a", "two", json!([])),
+            login("a", "three", json!([])),
+        ];
+        let mut duplicates = find_duplicate_ids(&items);
+        fix_duplicate_ids(&mut items, &mut duplicates, true).unwrap();
+
+        assert_eq!(items[0]["id"], "a");
+        assert_eq!(duplicates[0].replaced_by.len(), 2);
+        assert_eq!(items[1]["id"], duplicates[0].replaced_by[0].as_str());
+        assert_eq!(items[2]["id"], duplicates[0].replaced_by[1].as_str());
+        assert_ne!(items[1]["id"], items[2]["id"]);
+
+        let mut random = vec![login("a", "one", json!([])), login("a", "two", json!([]))];
+        let mut duplicates = find_duplicate_ids(&random);
+        fix_duplicate_ids(&mut random, &mut duplicates, false).unwrap();
+        assert_eq!(random[1]["id"].as_str().unwrap().len(), 36);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_write_path_contains_symlinks_only_when_asked() {
+        let root = std::env::temp_dir().join(format!("bw-dedup-symlink-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::create_dir_all(root.join("elsewhere")).unwrap();
+        let root = canonical_path(&root);
+        let link = |target: &str, link: &str| {
+            std::os::unix::fs::symlink(root.join(target), root.join(link)).unwrap();
+        };
+        link("elsewhere/vault.json", "out/escape.json");
+        link("out/real.json", "out/inside.json");
+
+        let plain = root.join("out/plain.json");
+        assert_eq!(resolve_write_path(&plain, true).unwrap(), plain);
+        assert_eq!(
+            resolve_write_path(&root.join("out/inside.json"), true).unwrap(),
+            root.join("out/real.json")
+        );
+        assert_eq!(
+            resolve_write_path(&root.join("out/escape.json"), false).unwrap(),
+            root.join("elsewhere/vault.json")
+        );
+        assert!(resolve_write_path(&root.join("out/escape.json"), true).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn writes_replace_the_file_whole_and_back_up_the_old_one() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-atomic-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let writer = FileWriter {
+            read_only: false,
+            contain_symlinks: false,
+            allow_insecure_location: false,
+        };
+        let path = dir.join("vault.json");
+        assert_eq!(writer.back_up(&path).unwrap(), None);
+        writer.write(&path, "old", "output file").unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        let backup = writer.back_up(&path).unwrap().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&backup).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        writer.write(&path, "new", "output file").unwrap();
+        assert_eq!(backup, canonical_path(&dir).join("vault.json.bak"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "old");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        // Nothing but the file and its backup: the temporary file is gone.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // A failed check leaves the old file and no temporary file behind.
+        let err = writer
+            .write_checked(&path, "bad", "output file", |file| {
+                assert_eq!(fs::read_to_string(file).unwrap(), "bad");
+                anyhow::bail!("did not verify")
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "did not verify");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locks_exclude_each_other_and_leave_the_lock_file() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-lock-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let writer = FileWriter {
+            read_only: false,
+            contain_symlinks: false,
+            allow_insecure_location: false,
+        };
+        let path = dir.join("vault.json");
+        let lock = writer.lock(&path).unwrap();
+        let err = writer.lock(&path).unwrap_err();
+        assert!(err.to_string().contains("in use by another"), "{err:#}");
+        drop(lock);
+        // The file stays, so every later run locks the same inode.
+        assert!(dir.join("vault.json.lock").exists());
+        drop(writer.lock(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_conflicts_pairs_every_login_in_a_group() {
+        let conflicts = find_conflicts(&[
+            login("a", "one", json!([])),
+            login("b", "two", json!([])),
+            login("c", "three", json!([])),
+        ]);
+        let pairs = conflicts
+            .iter()
+            .map(|conflict| (conflict.a.id.as_str(), conflict.b.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, [("a", "b"), ("a", "c"), ("b", "c")]);
+    }
+
+    #[test]
+    fn check_invariants_accepts_resolved_conflicts() {
+        let input = vec![
+            login("a", "one", json!([])),
+            login("b", "two", json!([])),
+            login("c", "three", json!([])),
+        ];
+        let mut outcome = dedup_default(input.clone());
+        let mut conflicts = find_conflicts(&outcome.kept);
+        conflicts[0].decision = Some(Decision::Merge);
+        conflicts[1].decision = Some(Decision::KeepA);
+        let applied = apply_conflict_decisions(&mut outcome, conflicts);
+        assert_eq!(applied.len(), 2);
+        assert_eq!(outcome.kept.len(), 1);
+        assert_eq!(outcome.removed.len(), 2);
+
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).unwrap();
+
+        // An item that vanished is still caught.
+        outcome.removed.pop();
+        assert!(check_invariants(&input, &outcome, &config, &ignore_keys, &ignore_paths).is_err());
+    }
+
+    #[test]
+    fn split_output_parts_pads_and_covers_every_item() {
+        let parts = split_output_parts(Path::new("out/vault.json"), 25, 10);
+        let paths = parts
+            .iter()
+            .map(|(path, _)| path.display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "out/vault.part1.json",
+                "out/vault.part2.json",
+                "out/vault.part3.json"
+            ]
+        );
+        let ranges = parts
+            .into_iter()
+            .map(|(_, range)| range)
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [0..10, 10..20, 20..25]);
+
+        let many = split_output_parts(Path::new("vault.json"), 100, 5);
+        assert_eq!(many[0].0, Path::new("vault.part01.json"));
+        assert_eq!(split_output_parts(Path::new("vault.json"), 0, 5).len(), 1);
+    }
+
+    #[test]
+    fn watch_metrics_are_served_over_loopback_only() {
+        let summary = RunSummary {
+            source: "vault.json".to_string(),
+            output: None,
+            dry_run: true,
+            total_items: 10,
+            kept: 7,
+            removed: 3,
+            duplicate_groups: 2,
+            types: BTreeMap::new(),
+            elapsed_secs: 0.5,
+            hygiene_score: None,
+        };
+        let mut metrics = WatchMetrics::default();
+        metrics.record(Some(&summary));
+        metrics.record(None);
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nbw_passport_dedup_runs_total 2\n"));
+        assert!(rendered.contains("\nbw_passport_dedup_run_errors_total 1\n"));
+        assert!(rendered.contains("\nbw_passport_dedup_duplicates_removed_total 3\n"));
+        assert!(rendered.contains("# TYPE bw_passport_dedup_items_processed_total counter\n"));
+
+        assert!(bind_metrics_listener("0.0.0.0:0".parse().unwrap()).is_err());
+        let listener = bind_metrics_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Mutex::new(metrics));
+        let served = Arc::clone(&metrics);
+        std::thread::spawn(move || serve_metrics(listener, &served));
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(&rendered));
+    }
+
+    #[test]
+    fn cli_accepts_subcommands_and_the_flat_form() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+
+        let flat = Args::try_parse_from(["bw", "vault.json", "--merge"]).unwrap();
+        assert!(flat.command.is_none());
+        assert_eq!(flat.dedup.overrides.merge, Some(true));
+        assert_eq!(
+            flat.dedup.input_file.as_deref(),
+            Some(Path::new("vault.json"))
+        );
+
+        for argv in [
+            &["bw", "validate", "vault.json"][..],
+            &["bw", "validate", "-i", "vault.json"],
+        ] {
+            let validate = Args::try_parse_from(argv).unwrap();
+            assert!(matches!(
+                validate.command,
+                Some(Command::Validate { input, input_file, .. })
+                    if input.as_ref().or(input_file.as_ref()) == Some(&PathBuf::from("vault.json"))
+            ));
+        }
+        assert!(Args::try_parse_from(["bw", "validate"]).is_err());
+        assert!(Args::try_parse_from(["bw", "validate", "-i", "a.json", "b.json"]).is_err());
+        let check = Args::try_parse_from(["bw", "check", "-i", "vault.json"]).unwrap();
+        assert!(matches!(check.command, Some(Command::Check(args)) if args.input.len() == 1));
+        let combined = Args::try_parse_from(["bw", "-i", "a.json", "--input", "b.json"]).unwrap();
+        assert_eq!(combined.dedup.input.len(), 2);
+        let diff = Args::try_parse_from(["bw", "compare", "a.json", "b.json"]).unwrap();
+        assert!(matches!(diff.command, Some(Command::Diff { .. })));
+        assert!(Args::try_parse_from(["bw", "--merge", "report", "vault.json"]).is_err());
+        for (flags, expected) in [
+            (&["--merge"][..], Some(true)),
+            (&["--merge", "false"], Some(false)),
+            (&["--merge=false"], Some(false)),
+            (&[], None),
+        ] {
+            let sync = Args::try_parse_from(["bw", "sync"].iter().chain(flags)).unwrap();
+            assert!(
+                matches!(sync.command, Some(Command::Sync { merge, .. }) if merge == expected),
+                "{:?}",
+                flags
+            );
+        }
+    }
+
+    #[cfg(feature = "server-api")]
+    #[test]
+    fn server_sync_refuses_merging_from_any_source() {
+        let mut config = Config::default();
+        check_server_sync(&config).unwrap();
+        config.dedup.merge = true;
+        let error = check_server_sync(&config).unwrap_err().to_string();
+        assert!(error.ends_with("pass --merge false"), "{}", error);
+        assert!(
+            Args::try_parse_from([
+                "bw",
+                "sync",
+                "--server",
+                "https://vault",
+                "--merge",
+                "false"
+            ])
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn flags_given_win_over_the_config_even_when_they_ask_for_the_default() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-precedence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "[dedup]\nkeep = \"newest\"\nmerge = true\n\n[normalize]\ntrim_strings = true\n\n[output]\npretty = true\n",
+        )
+        .unwrap();
+        let effective = |flags: &[&str]| {
+            let argv = ["bw", "vault.json", "--config", path.to_str().unwrap()];
+            let args = Args::try_parse_from(argv.iter().chain(flags)).unwrap();
+            assert_eq!(
+                args.dedup.input_file.as_deref(),
+                Some(Path::new("vault.json"))
+            );
+            effective_config(&args.dedup.config, &args.dedup.overrides).unwrap()
+        };
+
+        let config = effective(&[]);
+        assert_eq!(config.dedup.keep.to_string(), "newest");
+        assert!(config.dedup.merge && config.normalize.trim_strings && config.output.pretty);
+
+        let config = effective(&[
+            "--keep",
+            "first",
+            "--merge=false",
+            "--pretty=false",
+            "--trim-strings=false",
+        ]);
+        assert_eq!(config.dedup.keep.to_string(), "first");
+        assert!(!config.dedup.merge && !config.normalize.trim_strings && !config.output.pretty);
+        let config = effective(&[
+            "--merge",
+            "false",
+            "--pretty",
+            "false",
+            "--trim-strings",
+            "false",
+        ]);
+        assert!(!config.dedup.merge && !config.normalize.trim_strings && !config.output.pretty);
+        let config = effective(&["--lowercase-strings", "--username-from-name", "true"]);
+        assert!(config.normalize.lowercase_strings && config.normalize.username_from_name);
+
+        assert!(!effective(&["--no-merge"]).dedup.merge);
+        assert_eq!(
+            effective(&["--prefer", "with-totp,in-folder"]).dedup.prefer,
+            [Preference::WithTotp, Preference::InFolder]
+        );
+        assert!(effective(&["--no-pretty", "--pretty"]).output.pretty);
+        assert!(!effective(&["--pretty", "--no-pretty"]).output.pretty);
+        assert!(!effective(&["--sort-uris", "false"]).normalize.sort_uris);
+        assert!(
+            effective(&["--no-sort-uris", "--sort-uris"])
+                .normalize
+                .sort_uris
+        );
+        assert_eq!(
+            effective(&["--assume-double-import", "--keep", "last"])
+                .dedup
+                .keep
+                .to_string(),
+            "last"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_is_discovered_unless_given_or_disabled() {
+        if let Some(user) = user_config_path() {
+            assert!(user.ends_with("bw-passport-dedup/config.toml"));
+        }
+
+        let explicit = ConfigArgs {
+            config: Some(PathBuf::from("no-such-config.toml")),
+            ..ConfigArgs::default()
+        };
+        let error = load_config(&explicit).unwrap_err().to_string();
+        assert!(error.contains("does not exist"));
+        let disabled = ConfigArgs {
+            no_config: true,
+            ..ConfigArgs::default()
+        };
+        assert!(load_config(&disabled).is_ok());
+
+        let args =
+            Args::try_parse_from(["bw", "explain", "--id", "a", "-i", "x", "--no-config"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Explain { config, .. }) if config.no_config
+        ));
+        assert!(
+            Args::try_parse_from(["bw", "-i", "x", "--config", "c.toml", "--no-config"]).is_err()
+        );
+        assert!(Args::try_parse_from(["bw", "diff", "a.json", "b.json", "--no-config"]).is_ok());
+        assert!(
+            Args::try_parse_from(["bw", "diff", "a.json", "b.json", "--matrix", "--no-config"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn config_template_parses_and_unknown_keys_are_rejected() {
+        let template: Config = toml::from_str(CONFIG_TEMPLATE).unwrap();
+        assert_eq!(template.dedup.keep.to_string(), "first");
+
+        let error = toml::from_str::<Config>("[dedup]\npolicy_kyes = [\"domain\"]\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `policy_kyes`"));
+        assert!(error.contains("`policy_keys`"));
+        assert!(toml::from_str::<Config>("[normalize.uri]\nstrip_wwww = true\n").is_err());
+
+        let args = Args::try_parse_from([
+            "bw",
+            "config",
+            "validate",
+            "--no-config",
+            "--keep",
+            "newest",
+            "--policy-key",
+            "domain,username",
+            "--no-trim-strings",
+        ])
+        .unwrap();
+        let Some(Command::Config(ConfigCommand::Validate { config, overrides })) = args.command
+        else {
+            panic!("expected config validate");
+        };
+        let effective = effective_config(&config, &overrides).unwrap();
+        let printed = toml::to_string_pretty(&effective).unwrap();
+        assert!(printed.contains("keep = \"newest\""));
+        assert!(printed.contains("trim_strings = false"));
+        let reparsed: Config = toml::from_str(&printed).unwrap();
+        assert_eq!(
+            reparsed.dedup.policy_keys,
+            [DedupKey::Domain, DedupKey::Username]
+        );
+    }
+
+    #[test]
+    fn profiles_are_merged_over_the_config_and_checked() {
+        let contents = r#"
+[dedup]
+keep = "newest"
+policy_keys = ["domain", "username", "password"]
+
+[normalize.uri]
+strip_www = true
+
+[profile.loose.dedup]
+policy_keys = ["name"]
+
+[profile.loose.normalize.uri]
+strip_query = true
+
+[profile.strict.dedup]
+keep = "first"
+"#;
+        let base = parse_config(contents, None).unwrap();
+        assert_eq!(base.dedup.policy_keys.len(), 3);
+        assert!(!base.normalize.uri.strip_query);
+
+        let loose = parse_config(contents, Some("loose")).unwrap();
+        assert_eq!(loose.dedup.policy_keys, [DedupKey::Name]);
+        assert_eq!(loose.dedup.keep.to_string(), "newest");
+        assert!(loose.normalize.uri.strip_www);
+        assert!(loose.normalize.uri.strip_query);
+
+        let error = parse_config(contents, Some("lose"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "no profile `lose` (the config has loose, strict)");
+        let typo = format!("{}\n[profile.typo.dedup]\npolicy_kyes = []\n", contents);
+        let error = format!("{:#}", parse_config(&typo, Some("loose")).unwrap_err());
+        assert!(error.starts_with("invalid profile `typo`: "));
+        assert!(error.contains("policy_kyes"));
+        assert!(parse_config("[dedup]\nkeep = \"newest\"\n", Some("loose")).is_err());
+
+        assert!(
+            Args::try_parse_from(["bw", "-i", "x", "--no-config", "--profile", "loose"]).is_err()
+        );
+    }
+
+    #[test]
+    fn completions_offer_keep_strategies_and_the_man_page_renders() {
+        let mut script = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("fingerprint"));
+        assert!(script.contains("--keep"));
+        assert!(script.contains("most-complete"));
+
+        let error = Args::try_parse_from(["bw", "-i", "a.json", "--keep", "newest,bogus"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown keep strategy \"bogus\""));
+        let help = Args::command().render_long_help().to_string();
+        assert!(!help.contains("[possible values: first"));
+
+        let mut page = Vec::new();
+        clap_mangen::Man::new(Args::command())
+            .render(&mut page)
+            .unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains(".TH bw-passport-dedup 1"));
+    }
+
+    #[test]
+    fn stdin_and_stdout_refuse_what_needs_a_file() {
+        let refused = |argv: &[&str]| {
+            let args = Args::try_parse_from(argv).unwrap();
+            run_dedup_command(args.dedup, DedupMode::Dedup)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            refused(&["bw", "-i", "-", "-i", "-"]),
+            "--input - can only be given once"
+        );
+        assert_eq!(
+            refused(&["bw", "-i", "-", "--watch", "5"]),
+            "--watch needs files, not stdin or stdout"
+        );
+        assert!(refused(&["bw", "-i", "-", "--interactive"]).starts_with("--interactive reads"));
+        // These are refused before the input is read.
+        let refused = |argv: &[&str]| {
+            run_dedup(&Args::try_parse_from(argv).unwrap().dedup)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            refused(&["bw", "-i", "vault.json", "-o", "-", "--report", "-"]),
+            "--report - and --output - would both write to stdout"
+        );
+        assert!(
+            refused(&["bw", "-i", "vault.json", "-o", "-", "--split-output", "2"])
+                .starts_with("--split-output")
+        );
+        assert_eq!(input_label(Path::new("-")), "stdin");
+    }
+
+    #[test]
+    fn report_formats_list_every_group_member_with_secrets_redacted() {
+        let mut renamed = login("b", "hunter2", json!([]));
+        renamed["name"] = json!("Forum, old");
+        let input = vec![
+            login("a", "hunter2", json!([])),
+            renamed,
+            login("c", "other", json!([])),
+        ];
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let report = build_report(
+            &input,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            "salt",
+            &KeyCache::default(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].basis, "domain+username+password");
+        assert_eq!(report.groups[0].matched["password"], "len=7, sha256:f52f…");
+        assert_eq!(report.groups[0].matched["username"], "user");
+
+        let csv = render_report_csv(&report);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], REPORT_COLUMNS.join(","));
+        assert!(lines[1].contains(",kept,a,Forum,"));
+        assert!(lines[2].contains(",removed,b,\"Forum, old\","));
+        assert!(!csv.contains("hunter2"));
+
+        let md = render_report_markdown(&report);
+        assert!(md.contains(
+            "- Values: domain=forum.example.com; password=len=7, sha256:f52f…; username=user\n"
+        ));
+        assert!(md.contains("| removed | b | Forum, old | identical "));
+        assert!(!md.contains("hunter2"));
+
+        assert_eq!(
+            ReportFormat::for_path(Path::new("groups.CSV")),
+            ReportFormat::Csv
+        );
+        assert_eq!(ReportFormat::for_path(Path::new("-")), ReportFormat::Json);
+    }
+
+    #[test]
+    fn state_from_another_key_version_drops_its_group_ids() {
+        let record = |group_ids: &[&str]| RunRecord {
+            timestamp: "2026-01-01T00:00:00Z".to_string(),
+            source: "vault.json".to_string(),
+            total_items: 3,
+            kept: 2,
+            removed: 1,
+            duplicate_groups: group_ids.len(),
+            group_ids: group_ids.iter().map(|id| id.to_string()).collect(),
+            hygiene_score: None,
+        };
+        let mut current = State {
+            salt: Some("salt".to_string()),
+            key_version: Some(KEY_VERSION),
+            runs: vec![record(&["g1"])],
+        };
+        migrate_state(&mut current, Path::new("state.json"));
+        assert_eq!(current.key_version, Some(KEY_VERSION));
+        assert_eq!(current.runs[0].group_ids, ["g1"]);
+
+        // Files from before the version was recorded are version 1.
+        let mut unversioned = State {
+            salt: Some("salt".to_string()),
+            key_version: None,
+            runs: vec![record(&["g1"])],
+        };
+        migrate_state(&mut unversioned, Path::new("state.json"));
+        assert!(unversioned.runs[0].group_ids.is_empty());
+
+        let mut stale = State {
+            key_version: Some(KEY_VERSION + 1),
+            ..current
+        };
+        migrate_state(&mut stale, Path::new("state.json"));
+        assert_eq!(stale.key_version, Some(KEY_VERSION));
+        assert!(stale.runs[0].group_ids.is_empty());
+        assert_eq!(stale.salt.as_deref(), Some("salt"));
+    }
+
+    #[test]
+    fn interactive_choices_remove_the_rest_or_hold_the_group() {
+        let mut other = login("d", "swordfish", json!([]));
+        other["login"]["username"] = json!("someone");
+        let mut items = vec![
+            login("a", "hunter2", json!([])),
+            other.clone(),
+            login("b", "hunter2", json!([])),
+            login("c", "hunter2", json!([])),
+            other,
+        ];
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let cache = KeyCache::default();
+        let mut output = Vec::new();
+        let choices = choose_interactively(
+            &mut items,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &cache,
+            &mut "7\n2\nskip\n".as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Group 1 of 2"));
+        assert!(output.contains("Answer f, l, s, a number from 1 to 3 or bulk <filter>"));
+        assert!(output.contains("****** (A)"));
+        assert!(!output.contains("hunter2"));
+        let ids = |items: &[Value]| {
+            items
+                .iter()
+                .map(|item| item_str(item, "id").unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&items), ["d", "b", "d"]);
+        assert_eq!(ids(&choices.removed), ["a", "c"]);
+        assert_eq!(choices.chosen[0].kept_id.as_deref(), Some("b"));
+        assert_eq!(choices.skipped.len(), 1);
+
+        let outcome = dedup_items_with(
+            items,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &cache,
+            &choices.skipped,
+        );
+        assert_eq!(outcome.kept.len(), 3);
+        assert_eq!(outcome.held_keys, choices.skipped);
+    }
+
+    #[test]
+    fn hygiene_score_weighs_reused_weak_and_breached_passwords() {
+        let mut elsewhere = login("b", "hunter2", json!([]));
+        elsewhere["login"]["uris"] = json!([{"uri": "https://shop.example.org"}]);
+        let items = [
+            login("a", "hunter2", json!([])),
+            elsewhere,
+            login("c", "Tr0ub4dor&3-correct-horse", json!([])),
+        ];
+
+        let hygiene = hygiene_score(&items, 1, 0, None);
+        let scores = hygiene
+            .categories
+            .iter()
+            .map(|category| (category.name, category.count, category.score))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scores,
+            [
+                ("duplicates", 1, 67),
+                ("reuse", 2, 33),
+                ("weak", 2, 33),
+                ("stale", 0, 100),
+            ]
+        );
+        assert_eq!(hygiene.score, 46);
+
+        let breached = HashMap::from([(breach::password_sha1("hunter2"), Some(3))]);
+        let hygiene = hygiene_score(&items, 1, 0, Some(&breached));
+        assert_eq!(hygiene.categories[3].name, "breached");
+        assert_eq!(hygiene.categories[3].count, 2);
+        assert_eq!(hygiene.score, 41);
+        assert!(!is_weak_password("correct horse battery staple"));
+    }
+
+    #[test]
+    fn bulk_filters_decide_every_matching_group() {
+        let site = |id: &str, uri: &str, name: &str| {
+            let mut item = login(id, "hunter2", json!([]));
+            item["login"]["uris"] = json!([{ "uri": uri }]);
+            item["name"] = json!(name);
+            item
+        };
+        let mut items = vec![
+            site("m1", "https://mail.google.com", "Mail"),
+            site("f1", "https://forum.example.com", "Forum"),
+            site("m2", "https://mail.google.com", "Mail"),
+            site("d1", "https://drive.google.com", "Drive"),
+            site("d2", "https://drive.google.com", "Drive copy"),
+            site("f2", "https://forum.example.com", "Forum"),
+        ];
+        let config = Config::default();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let mut output = Vec::new();
+        let choices = choose_interactively(
+            &mut items,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &KeyCache::default(),
+            &mut "bulk domain:*.google.com size>5\nb domain:*.GOOGLE.com\nl\ns\n".as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("No remaining group matches"));
+        assert!(output.contains("2 group(s) match"));
+        assert!(output.contains("Group 3 of 3: kept the last item by bulk rule"));
+        let kept = choices
+            .chosen
+            .iter()
+            .map(|group| group.kept_id.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(kept, ["m2", "d2"]);
+        assert_eq!(choices.skipped.len(), 1);
+
+        let facts = GroupFacts {
+            domains: vec!["drive.google.com".to_string()],
+            names: vec!["Drive".to_string(), "Drive copy".to_string()],
+            types: vec!["login".to_string()],
+            basis: "domain+username+password".to_string(),
+            size: 2,
+            confidence: 6.0 / 7.0,
+        };
+        let matches = |filter: &str| parse_group_filter(filter).unwrap().matches(&facts);
+        assert!(matches("domain:*.google.com confidence>0.85"));
+        assert!(!matches("domain:*.google.com confidence>0.95"));
+        assert!(matches("name:drive?copy size=2 type:login"));
+        assert!(!matches("domain:google.com"));
+        assert!(parse_group_filter("owner:me").is_err());
+        assert!(parse_group_filter("confidence>high").is_err());
+        assert!(wildcard_match("*a*b", "xaxxab"));
+    }
+
+    #[test]
+    fn password_conflicts_label_passwords_newest_first() {
+        let mut old = login("a", "old", json!([]));
+        old["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+        let mut new = login("b", "new", json!([]));
+        new["revisionDate"] = json!("2024-05-01T00:00:00.000Z");
+        new["login"]["username"] = json!("USER ");
+        let mut copy = login("c", "old", json!([]));
+        copy["revisionDate"] = json!("2024-02-01T00:00:00.000Z");
+        let mut elsewhere = login("d", "other", json!([]));
+        elsewhere["login"]["uris"] = json!([{"uri": "https://mail.example.com"}]);
+
+        let conflicts = find_password_conflicts(&[old, new, copy, elsewhere]);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].domain, "forum.example.com");
+        assert_eq!(conflicts[0].username, "user");
+        assert_eq!(conflicts[0].passwords, 2);
+        let labels = conflicts[0]
+            .items
+            .iter()
+            .map(|login| (login.id.as_deref().unwrap(), login.password.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(labels, [("b", "A"), ("c", "B"), ("a", "B")]);
+        assert_eq!(password_label(27), "AB");
+    }
+
+    #[test]
+    fn password_reuse_counts_accounts_and_hides_the_password() {
+        let mut forum = login("a", "hunter2", json!([]));
+        forum["login"]["username"] = json!("me");
+        let mut alt = login("b", "hunter2", json!([]));
+        alt["login"]["username"] = json!("Alt");
+        alt["login"]["uris"] = json!([{"uri": "https://www.example.com"}]);
+        let mut copy = login("c", "hunter2", json!([]));
+        copy["login"]["username"] = json!("ME");
+        let unique = login("d", "unique", json!([]));
+
+        let reuse = find_password_reuse(&[forum.clone(), alt, copy, unique], "salt");
+        assert_eq!(reuse.len(), 1);
+        assert_eq!(reuse[0].accounts, 2);
+        assert_eq!(reuse[0].domains, ["example.com"]);
+        assert_eq!(reuse[0].usernames, ["alt", "me"]);
+        assert_eq!(reuse[0].item_ids, ["a", "b", "c"]);
+        assert_eq!(reuse[0].password_hash, group_id("salt", "hunter2"));
+        assert_ne!(reuse[0].password_hash, group_id("other salt", "hunter2"));
+        // The same account twice is not reuse.
+        assert!(find_password_reuse(&[forum.clone(), forum], "salt").is_empty());
+    }
+
+    #[test]
+    fn breached_logins_are_listed_most_seen_first() {
+        let items = [
+            login("a", "hunter2", json!([])),
+            login("b", "password", json!([])),
+            login("c", "unique", json!([])),
+        ];
+        let breached = HashMap::from([
+            (breach::password_sha1("hunter2"), Some(17)),
+            (breach::password_sha1("password"), Some(9659365)),
+        ]);
+        let logins = find_breached_logins(&items, &breached);
+        let ids = logins
+            .iter()
+            .map(|login| login.id.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["b", "a"]);
+        assert_eq!(logins[0].domains, ["forum.example.com"]);
+        let json = serde_json::to_string(&logins[1]).unwrap();
+        assert!(json.contains("\"occurrences\":17") && !json.contains("hunter2"));
+    }
+
+    #[test]
+    fn credential_reuse_ranks_pairs_by_registrable_domains() {
+        let site = |id: &str, uri: &str, username: &str, password: &str| {
+            let mut item = login(id, password, json!([]));
+            item["login"]["uris"] = json!([{ "uri": uri }]);
+            item["login"]["username"] = json!(username);
+            item
+        };
+        let items = [
+            site("a", "https://login.example.com", "me@mail.com", "hunter2"),
+            site("b", "https://www.example.com", "me@mail.com", "hunter2"),
+            site("c", "https://shop.example.org", "Me@Mail.com", "hunter2"),
+            site("d", "https://forum.example.net", "me@mail.com", "hunter2"),
+            site("e", "https://a.example.com", "admin", "swordfish"),
+            site("f", "https://b.example.org", "admin", "swordfish"),
+            site("g", "https://c.example.net", "admin", "different"),
+        ];
+
+        let reuse = find_credential_reuse(&items);
+        let summary = reuse
+            .iter()
+            .map(|reuse| {
+                (
+                    reuse.username.as_str(),
+                    reuse.domains.len(),
+                    reuse.item_ids.len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(summary, [("me@mail.com", 3, 4), ("admin", 2, 2)]);
+        assert_eq!(
+            reuse[0].domains,
+            ["example.com", "example.net", "example.org"]
+        );
+        let json = serde_json::to_string(&reuse).unwrap();
+        assert!(!json.contains("hunter2"));
+    }
+
+    #[test]
+    fn written_output_is_read_back_before_it_counts() {
+        let path =
+            std::env::temp_dir().join(format!("bw-dedup-verify-{}.json", std::process::id()));
+        let root = json!({"encrypted": false, "items": [login("a", "one", json!([])), login("b", "two", json!([]))]});
+        let items_path = ["items".to_string()];
+        let exporter = formats::export_adapter_for(&path);
+        let verify = |on_disk: &Value, written: &Value| {
+            fs::write(&path, on_disk.to_string()).unwrap();
+            verify_output(
+                &path,
+                &path,
+                &written.to_string(),
+                exporter.as_ref(),
+                &root,
+                &items_path,
+                None,
+            )
+            .map_err(|err| err.to_string())
+        };
+
+        assert_eq!(verify(&root, &root), Ok(()));
+        let mut truncated = root.clone();
+        truncated["items"].as_array_mut().unwrap().pop();
+        assert!(verify(&truncated, &root).unwrap_err().contains("bytes"));
+        assert!(
+            verify(&truncated, &truncated)
+                .unwrap_err()
+                .contains("read back 1 item(s), wrote 2")
+        );
+        let mut altered = root.clone();
+        altered["items"][1]["login"]["password"] = json!("three");
+        assert!(verify(&altered, &altered).unwrap_err().contains("differ"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn removed_output_holds_the_dropped_items_as_an_export() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-removed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("vault.json");
+        let root = json!({
+            "encrypted": false,
+            "folders": [{"id": "f1", "name": "Work"}],
+            "items": [login("a", "one", json!([])), login("b", "one", json!([]))],
+        });
+        fs::write(&input, root.to_string()).unwrap();
+        let args = Args::try_parse_from([
+            std::ffi::OsString::from("bw-passport-dedup"),
+            "--input".into(),
+            input.clone().into_os_string(),
+            "--output".into(),
+            dir.join("kept.json").into_os_string(),
+            "--removed-output".into(),
+            dir.join("removed.json").into_os_string(),
+        ])
+        .unwrap()
+        .dedup;
+
+        run_dedup(&args).unwrap();
+        let removed: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("removed.json")).unwrap()).unwrap();
+        assert_eq!(removed["folders"], root["folders"]);
+        assert_eq!(removed["items"], json!([root["items"][1]]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn equivalent_domains_file_joins_sites_for_the_domain_key() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-equivalent-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let groups = dir.join("groups.txt");
+        fs::write(&groups, "# corp\nexample.com, example.net\n\n").unwrap();
+        assert_eq!(
+            load_equivalent_domains(&groups).unwrap(),
+            [["example.com", "example.net"]]
+        );
+
+        let mut other = login("b", "one", json!([]));
+        other["login"]["uris"] = json!([{"uri": "https://www.example.net/login"}]);
+        let input = dir.join("vault.json");
+        let root = json!({"encrypted": false, "items": [login("a", "one", json!([])), other]});
+        fs::write(&input, root.to_string()).unwrap();
+        let run = |extra: &[&std::ffi::OsStr]| {
+            let mut argv = vec![
+                std::ffi::OsString::from("bw-passport-dedup"),
+                "--input".into(),
+                input.clone().into_os_string(),
+                "--output".into(),
+                dir.join("kept.json").into_os_string(),
+                "--force".into(),
+            ];
+            argv.extend(extra.iter().map(|arg| arg.to_os_string()));
+            run_dedup(&Args::try_parse_from(argv).unwrap().dedup).unwrap();
+            let kept: Value =
+                serde_json::from_str(&fs::read_to_string(dir.join("kept.json")).unwrap()).unwrap();
+            kept["items"].as_array().unwrap().len()
+        };
+        assert_eq!(run(&[]), 2);
+        assert_eq!(
+            run(&["--equivalent-domains".as_ref(), groups.as_os_str()]),
+            1
+        );
+
+        fs::write(&groups, "example.com\n").unwrap();
+        assert!(load_equivalent_domains(&groups).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dedup_folders_moves_items_to_the_first_folder_of_a_name() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-folders-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let in_folder = |id: &str, password: &str, folder: &str| {
+            let mut item = login(id, password, json!([]));
+            item["folderId"] = json!(folder);
+            item
+        };
+        let input = dir.join("vault.json");
+        let root = json!({
+            "encrypted": false,
+            "folders": [
+                {"id": "f1", "name": "Work"},
+                {"id": "f2", "name": "Home"},
+                {"id": "f3", "name": "Work"},
+                {"id": "f4", "name": "Work"},
+            ],
+            "items": [
+                in_folder("a", "one", "f1"),
+                in_folder("b", "one", "f3"),
+                in_folder("c", "two", "f4"),
+                in_folder("d", "two", "f2"),
+            ],
+        });
+        fs::write(&input, root.to_string()).unwrap();
+        let args = Args::try_parse_from([
+            std::ffi::OsString::from("bw-passport-dedup"),
+            "--input".into(),
+            input.clone().into_os_string(),
+            "--output".into(),
+            dir.join("kept.json").into_os_string(),
+            "--scope".into(),
+            "folder".into(),
+            "--dedup-folders".into(),
+        ])
+        .unwrap()
+        .dedup;
+
+        run_dedup(&args).unwrap();
+        let kept: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("kept.json")).unwrap()).unwrap();
+        assert_eq!(
+            kept["folders"],
+            json!([{"id": "f1", "name": "Work"}, {"id": "f2", "name": "Home"}])
+        );
+        let items = kept["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| (item["id"].clone(), item["folderId"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            items,
+            [
+                (json!("a"), json!("f1")),
+                (json!("c"), json!("f1")),
+                (json!("d"), json!("f2"))
+            ]
+        );
+
+        let mut folders = root["folders"].as_array().unwrap().clone();
+        let mut items = root["items"].as_array().unwrap().clone();
+        let (merged, moved) = dedup_containers(Container::Folder, &mut folders, &mut items);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].removed_ids, ["f3", "f4"]);
+        assert_eq!(merged[0].moved_items, 2);
+        assert_eq!(moved, HashSet::from(["b".to_string(), "c".to_string()]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn several_inputs_are_combined_and_kept_items_traced_to_them() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-inputs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("old.json");
+        let current = dir.join("current.json");
+        fs::write(
+            &old,
+            json!({
+                "encrypted": false,
+                "folders": [{"id": "f1", "name": "Work"}],
+                "items": [login("a", "one", json!([])), login("b", "two", json!([]))],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        fs::write(
+            &current,
+            json!({
+                "encrypted": false,
+                "folders": [{"id": "f1", "name": "Work"}, {"id": "f2", "name": "Home"}],
+                "items": [login("c", "one", json!([])), login("d", "three", json!([]))],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let args = Args::try_parse_from([
+            std::ffi::OsString::from("bw-passport-dedup"),
+            "--input".into(),
+            old.clone().into_os_string(),
+            "--input".into(),
+            current.clone().into_os_string(),
+            "--output".into(),
+            dir.join("kept.json").into_os_string(),
+            "--report".into(),
+            dir.join("report.json").into_os_string(),
+        ])
+        .unwrap()
+        .dedup;
+
+        run_dedup(&args).unwrap();
+        let kept: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("kept.json")).unwrap()).unwrap();
+        let ids = kept["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "b", "d"]);
+        assert_eq!(kept["folders"].as_array().unwrap().len(), 2);
+        let report: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+        let files = report["input_files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["kept_ids"], json!(["a", "b"]));
+        assert_eq!(files[1]["kept_ids"], json!(["d"]));
+        assert_eq!(files[1]["items"], 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn summary_json_counts_items_per_type_and_records_the_config() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-summary-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("vault.json");
+        let note = json!({"id": "n", "type": 2, "name": "Note", "notes": "hello"});
+        fs::write(
+            &input,
+            json!({
+                "encrypted": false,
+                "items": [
+                    login("a", "one", json!([])),
+                    login("b", "one", json!([])),
+                    login("c", "one", json!([])),
+                    note,
+                ],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let args = Args::try_parse_from([
+            std::ffi::OsString::from("bw-passport-dedup"),
+            "--input".into(),
+            input.into_os_string(),
+            "--dry-run".into(),
+            "--keep".into(),
+            "last".into(),
+            "--summary-json".into(),
+            dir.join("summary.json").into_os_string(),
+        ])
+        .unwrap()
+        .dedup;
+
+        run_dedup(&args).unwrap();
+        let summary: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("summary.json")).unwrap()).unwrap();
+        assert_eq!(summary["total_items"], 4);
+        assert_eq!(summary["removed"], 2);
+        assert_eq!(summary["duplicate_groups"], 1);
+        assert_eq!(
+            summary["types"]["login"],
+            json!({"total_items": 3, "kept": 1, "removed": 2})
+        );
+        assert_eq!(summary["types"]["secure_note"]["kept"], 1);
+        assert_eq!(summary["config"]["dedup"]["keep"], "last");
+        assert!(summary["elapsed_secs"].is_f64());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_removed_takes_a_count_or_a_percentage() {
+        assert_eq!(parse_removal_limit("3"), Ok(RemovalLimit::Count(3)));
+        assert_eq!(parse_removal_limit("2.5%"), Ok(RemovalLimit::Percent(2.5)));
+        assert!(parse_removal_limit("120%").is_err());
+        assert!(parse_removal_limit("some").is_err());
+
+        assert!(!RemovalLimit::Count(3).exceeded_by(3, 10));
+        assert!(RemovalLimit::Count(3).exceeded_by(4, 10));
+        assert!(!RemovalLimit::Percent(5.0).exceeded_by(5, 100));
+        assert!(RemovalLimit::Percent(5.0).exceeded_by(6, 100));
+        assert!(RemovalLimit::Percent(0.0).exceeded_by(1, 1000));
+
+        let dir = std::env::temp_dir().join(format!("bw-dedup-max-removed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("vault.json");
+        let items = ["a", "b", "c"].map(|id| login(id, "one", json!([])));
+        fs::write(&input, json!({"items": items}).to_string()).unwrap();
+        let output = dir.join("kept.json");
+        let run = |extra: &[&str]| {
+            let mut argv = vec![
+                std::ffi::OsString::from("bw-passport-dedup"),
+                "--input".into(),
+                input.clone().into_os_string(),
+                "--output".into(),
+                output.clone().into_os_string(),
+                "--max-removed".into(),
+                "1".into(),
+            ];
+            argv.extend(extra.iter().map(Into::into));
+            run_dedup(&Args::try_parse_from(argv).unwrap().dedup)
+        };
+        let err = run(&[]).unwrap_err().to_string();
+        assert!(
+            err.starts_with("2 of 3 item(s) (66.7%) would be removed"),
+            "{}",
+            err
+        );
+        assert!(!output.exists());
+        run(&["--yes"]).unwrap();
+        assert!(output.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_runs_leave_the_state_file_alone() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-dry-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("vault.json");
+        let items = ["a", "b"].map(|id| login(id, "one", json!([])));
+        fs::write(&input, json!({"items": items}).to_string()).unwrap();
+        let state = dir.join("state.json");
+        let run = |extra: &[&str]| {
+            let mut argv = vec![
+                std::ffi::OsString::from("bw-passport-dedup"),
+                "--input".into(),
+                input.clone().into_os_string(),
+                "--state".into(),
+                state.clone().into_os_string(),
+                "--force".into(),
+            ];
+            argv.extend(extra.iter().map(Into::into));
+            run_dedup(&Args::try_parse_from(argv).unwrap().dedup).unwrap();
+        };
+        run(&["--dry-run"]);
+        assert!(!state.exists());
+        run(&[]);
+        let recorded = fs::read_to_string(&state).unwrap();
+        run(&["--dry-run"]);
+        assert_eq!(fs::read_to_string(&state).unwrap(), recorded);
+        assert_eq!(load_state(&state).unwrap().runs.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn item_diff_pairs_by_key_and_then_by_id() {
+        let left = vec![
+            login("a1", "one", json!([])),
+            login("a2", "one", json!([])),
+            login("b", "two", json!([])),
+        ];
+        let mut edited = login("a2", "one", json!([]));
+        edited["notes"] = json!("moved");
+        let right = vec![edited, login("c", "three", json!([]))];
+
+        let diff = diff_items(&left, &right, &Config::default());
+        assert_eq!(diff.only_left, [0, 2]);
+        assert_eq!(diff.only_right, [1]);
+        assert_eq!(diff.changed, [(1, 0, vec!["notes".to_string()])]);
+        assert_eq!(diff.unchanged, 0);
+
+        let diff = diff_items(&left, &left, &Config::default());
+        assert_eq!(diff.unchanged, 3);
+        assert!(diff.changed.is_empty() && diff.only_right.is_empty());
+
+        // A new password changes the key, but the ID still pairs the item.
+        let right = vec![
+            login("a1", "one", json!([])),
+            login("b", "rotated", json!([])),
+        ];
+        let diff = diff_items(&left, &right, &Config::default());
+        assert_eq!(diff.only_left, [1]);
+        assert!(diff.only_right.is_empty());
+        assert_eq!(diff.changed, [(2, 1, vec!["login.password".to_string()])]);
+        assert_eq!(diff.unchanged, 1);
+    }
+
+    #[test]
+    fn dedup_collections_stays_within_an_organization() {
+        let mut collections = vec![
+            json!({"id": "c1", "organizationId": "o1", "name": "Ops"}),
+            json!({"id": "c2", "organizationId": "o1", "name": "Ops"}),
+            json!({"id": "c3", "organizationId": "o2", "name": "Ops"}),
+        ];
+        let in_collections = |id: &str, collections: Value| {
+            let mut item = login(id, "one", json!([]));
+            item["organizationId"] = json!("o1");
+            item["collectionIds"] = collections;
+            item
+        };
+        let mut items = vec![
+            in_collections("a", json!(["c1", "c2"])),
+            in_collections("b", json!(["c2"])),
+            login("c", "one", json!([])),
+        ];
+        let (merged, moved) = dedup_containers(Container::Collection, &mut collections, &mut items);
+        assert_eq!(collections.len(), 2);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].kept_id, "c1");
+        assert_eq!(merged[0].moved_items, 2);
+        assert_eq!(moved.len(), 2);
+        assert_eq!(items[0]["collectionIds"], json!(["c1"]));
+        assert_eq!(items[1]["collectionIds"], json!(["c1"]));
+
+        let root = json!({"collections": collections, "items": items});
+        let summary = summarize_input(&root, root["items"].as_array().unwrap());
+        assert!(summary.organization);
+        assert!(!summarize_input(&json!({}), &[login("d", "one", json!([]))]).organization);
+    }
+
+    #[test]
+    fn explain_lists_the_group_and_what_keeps_same_named_items_apart() {
+        let mut newer = login("b", "hunter2", json!([]));
+        newer["revisionDate"] = json!("2024-03-02T00:00:00Z");
+        let items = vec![
+            login("a", "hunter2", json!([])),
+            newer,
+            login("c", "other", json!([])),
+        ];
+        let mut config = Config::default();
+        config.dedup.keep = Keep::parse("newest").unwrap();
+        let ignore_keys = IgnoreKeys::parse(&config.ignore.keys);
+        let ignore_paths = parse_ignore_paths(&config.ignore.paths);
+        let (digests, _) = build_key_digests(
+            &items,
+            &config,
+            &ignore_keys,
+            &ignore_paths,
+            &KeyCache::default(),
+        );
+
+        let explanation = explain_item(&items, &digests, 0, &config);
+        let lines = explanation.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Item a \"Forum\" (login)");
+        assert!(lines.contains(&"  - compared by the policy keys domain+username+password"));
+        assert!(explanation.contains("\"password\":\"len=7, sha256:f52f…\""));
+        assert!(!explanation.contains("hunter2"));
+        assert!(explanation.contains("Group: 2 items with this key\n"));
+        assert!(explanation.contains("  * a \"Forum\": removed: kept item revised"));
+        assert!(explanation.contains("    b \"Forum\": kept\n"));
+        assert!(
+            explanation
+                .ends_with("Same name, different key:\n    c \"Forum\": differs in password\n")
+        );
+
+        let alone = explain_item(&items, &digests, 2, &config);
+        assert!(alone.contains("No other item has this key, so it is kept.\n"));
+    }
+
+    #[test]
+    fn fingerprints_are_shared_by_duplicates_and_change_with_the_salt() {
+        let mut unnamed = login("x", "other", json!([]));
+        unnamed.as_object_mut().unwrap().remove("id");
+        let items = vec![
+            login("a", "hunter2", json!([])),
+            login("b", "hunter2", json!([])),
+            unnamed,
+        ];
+        let config = Config::default();
+
+        let plain = fingerprints(&items, &config, None);
+        let ids = plain.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "b", "#2"]);
+        assert_eq!(plain[0].1, plain[1].1);
+        assert_ne!(plain[0].1, plain[2].1);
+        assert_eq!(plain[0].1.len(), 64);
+        assert!(!plain[0].1.contains("hunter2"));
+
+        let salted = fingerprints(&items, &config, Some("pepper"));
+        assert_eq!(salted[0].1, salted[1].1);
+        assert_ne!(salted[0].1, plain[0].1);
+        assert_ne!(
+            salted[0].1,
+            fingerprints(&items, &config, Some("salt"))[0].1
+        );
     }
 }
@@ -0,0 +1,625 @@
+//! Normalization applied to items before they are compared.
+//!
+//! Ignore rules (`[ignore]` keys and paths), string trimming and lowercasing,
+//! URI rewriting and ordering and custom field aliases all live here. None of it touches
+//! the items that are written out, except [`apply_field_aliases`] when
+//! `output.apply_field_aliases` is set.
+
+use crate::equivalent_domains::EquivalentDomains;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IgnoreConfig {
+    pub keys: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+impl Default for IgnoreConfig {
+    fn default() -> Self {
+        Self {
+            keys: vec![
+                "**.id".to_string(),
+                "**.revisionDate".to_string(),
+                "**.creationDate".to_string(),
+                "**.passwordHistory".to_string(),
+            ],
+            paths: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NormalizeConfig {
+    pub trim_strings: bool,
+    pub lowercase_strings: bool,
+    pub sort_uris: bool,
+    /// Fill empty usernames from "site (user)" style names (browser imports).
+    pub username_from_name: bool,
+    /// Custom field name aliases (`"pin code" = "PIN"`), matched ignoring
+    /// case and surrounding whitespace.
+    pub field_aliases: BTreeMap<String, String>,
+    pub uri: UriNormalization,
+    /// Web domains standing in for Android app packages
+    /// (`"com.vendor.app" = "vendor.com"`), so `androidapp://` URIs compare
+    /// like the vendor's site.
+    pub android_apps: BTreeMap<String, String>,
+    pub equivalent_domains: EquivalentDomains,
+}
+
+/// `[normalize.uri]`: parts of login URIs to drop before they are compared
+/// or reduced to a domain. All off by default.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UriNormalization {
+    /// `https://` and the like.
+    pub strip_scheme: bool,
+    /// A leading `www.` on the host.
+    pub strip_www: bool,
+    /// `:80` on `http` and `:443` on `https` URIs.
+    pub strip_default_port: bool,
+    /// Slashes at the end of the path.
+    pub strip_trailing_slash: bool,
+    /// Everything from the first `/` after the host.
+    pub strip_path: bool,
+    /// The query string and fragment.
+    pub strip_query: bool,
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        Self {
+            trim_strings: false,
+            lowercase_strings: false,
+            sort_uris: true,
+            username_from_name: false,
+            field_aliases: BTreeMap::new(),
+            uri: UriNormalization::default(),
+            android_apps: BTreeMap::new(),
+            equivalent_domains: EquivalentDomains::default(),
+        }
+    }
+}
+
+impl NormalizeConfig {
+    /// Whether login URIs are rewritten before comparison at all.
+    pub fn rewrites_uris(&self) -> bool {
+        self.uri.is_enabled() || !self.android_apps.is_empty()
+    }
+}
+
+/// Parsed `ignore.keys` patterns. A bare `name` matches a top-level key,
+/// `fields.name` matches `name` directly under `fields` (array elements are
+/// transparent), and a leading `**.` matches the rest of the pattern at any
+/// depth.
+#[derive(Debug, Default)]
+pub struct IgnoreKeys {
+    anchored: Vec<Vec<String>>,
+    anywhere: Vec<Vec<String>>,
+}
+
+impl IgnoreKeys {
+    pub fn parse(patterns: &[String]) -> Self {
+        let mut ignore_keys = Self::default();
+        for pattern in patterns {
+            let (anywhere, rest) = match pattern.strip_prefix("**.") {
+                Some(rest) => (true, rest),
+                None => (false, pattern.as_str()),
+            };
+            let path = parse_path(rest);
+            if path.is_empty() {
+                continue;
+            }
+            if anywhere {
+                ignore_keys.anywhere.push(path);
+            } else {
+                ignore_keys.anchored.push(path);
+            }
+        }
+        ignore_keys
+    }
+
+    pub fn matches(&self, parent: &[String], key: &str) -> bool {
+        let matches_key = |pattern: &[String]| pattern.last().is_some_and(|last| last == key);
+        let anchored = self
+            .anchored
+            .iter()
+            .any(|pattern| matches_key(pattern) && pattern[..pattern.len() - 1] == *parent);
+        anchored
+            || self.anywhere.iter().any(|pattern| {
+                matches_key(pattern) && parent.ends_with(&pattern[..pattern.len() - 1])
+            })
+    }
+}
+
+/// Top-level fields that only appear on items pulled from the Bitwarden API
+/// (`bw list items`, `bw serve`, the server sync endpoint). They describe the
+/// caller's permissions or wrap the cipher key, so they never take part in
+/// comparisons, but items keep them untouched on output.
+pub const API_ONLY_FIELDS: &[&str] = &[
+    "object",
+    "edit",
+    "viewPassword",
+    "key",
+    "organizationUseTotp",
+];
+
+pub fn parse_ignore_paths(paths: &[String]) -> Vec<Vec<String>> {
+    paths
+        .iter()
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| parse_path(s))
+        .collect()
+}
+
+/// Splits a dotted path into segments. `[N]` and `[*]` are segments of their
+/// own, so `login.uris[*].match` and `login.uris.*.match` are the same path.
+pub fn parse_path(path: &str) -> Vec<String> {
+    path.split(['.', '[', ']'])
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_string())
+        .collect()
+}
+
+/// Whether `path` is `pattern`, where a `*` segment in the pattern stands
+/// for any key or array index.
+pub fn path_matches(pattern: &[String], path: &[String]) -> bool {
+    pattern.len() == path.len()
+        && pattern
+            .iter()
+            .zip(path)
+            .all(|(wanted, segment)| wanted == "*" || wanted == segment)
+}
+
+pub fn is_ignored_path(path: &[String], ignore_paths: &[Vec<String>]) -> bool {
+    ignore_paths.iter().any(|ignore| path_matches(ignore, path))
+}
+
+pub fn retain_sections(value: &mut Value, sections: &[String]) {
+    if sections.is_empty() {
+        return;
+    }
+    if let Value::Object(map) = value {
+        map.retain(|key, _| sections.iter().any(|section| section == key));
+    }
+}
+
+pub(crate) fn strip_api_fields(value: &mut Value) {
+    if let Value::Object(map) = value {
+        for field in API_ONLY_FIELDS {
+            map.remove(*field);
+        }
+    }
+}
+
+pub(crate) fn remove_ignored_keys(
+    value: &mut Value,
+    ignore_keys: &IgnoreKeys,
+    path: &mut Vec<String>,
+) {
+    match value {
+        Value::Object(map) => {
+            let keys: Vec<String> = map.keys().cloned().collect();
+            for key in keys {
+                if ignore_keys.matches(path, &key) {
+                    map.remove(&key);
+                } else if let Some(child) = map.get_mut(&key) {
+                    path.push(key);
+                    remove_ignored_keys(child, ignore_keys, path);
+                    path.pop();
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                remove_ignored_keys(item, ignore_keys, path);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes what `path` points at. On objects a segment is a key, on arrays
+/// an index; `*` is every key or element.
+pub fn remove_path(value: &mut Value, path: &[String]) {
+    let Some((segment, rest)) = path.split_first() else {
+        return;
+    };
+    let every = segment == "*";
+    match value {
+        Value::Object(map) => match (every, rest.is_empty()) {
+            (true, true) => map.clear(),
+            (true, false) => {
+                for child in map.values_mut() {
+                    remove_path(child, rest);
+                }
+            }
+            (false, true) => {
+                map.remove(segment);
+            }
+            (false, false) => {
+                if let Some(child) = map.get_mut(segment) {
+                    remove_path(child, rest);
+                }
+            }
+        },
+        Value::Array(items) => {
+            if every {
+                if rest.is_empty() {
+                    items.clear();
+                }
+                for item in items {
+                    remove_path(item, rest);
+                }
+            } else if let Ok(index) = segment.parse::<usize>()
+                && index < items.len()
+            {
+                if rest.is_empty() {
+                    items.remove(index);
+                } else {
+                    remove_path(&mut items[index], rest);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Renames custom fields to their canonical names. Returns whether anything changed.
+pub fn apply_field_aliases(item: &mut Value, aliases: &BTreeMap<String, String>) -> bool {
+    if aliases.is_empty() {
+        return false;
+    }
+    let Some(fields) = item.get_mut("fields").and_then(Value::as_array_mut) else {
+        return false;
+    };
+
+    let mut renamed = false;
+    for field in fields {
+        let Some(name) = field.get("name").and_then(Value::as_str) else {
+            continue;
+        };
+        let lookup = name.trim().to_lowercase();
+        let canonical = aliases
+            .iter()
+            .find(|(alias, _)| alias.trim().to_lowercase() == lookup)
+            .map(|(_, canonical)| canonical.clone());
+        if let Some(canonical) = canonical
+            && canonical != name
+        {
+            field["name"] = Value::String(canonical);
+            renamed = true;
+        }
+    }
+    renamed
+}
+
+pub(crate) fn normalize_strings(value: &mut Value, trim_strings: bool, lowercase_strings: bool) {
+    match value {
+        Value::String(s) => {
+            if trim_strings {
+                let trimmed = s.trim().to_string();
+                *s = trimmed;
+            }
+            if lowercase_strings {
+                *s = s.to_ascii_lowercase();
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                normalize_strings(item, trim_strings, lowercase_strings);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                normalize_strings(value, trim_strings, lowercase_strings);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl UriNormalization {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// `uri` with the configured parts removed. Text that is not a URL comes
+    /// back as it was, give or take a trailing slash, and so do Android app
+    /// URIs.
+    pub fn apply(&self, uri: &str) -> String {
+        if android_package(uri).is_some() {
+            return uri.to_string();
+        }
+        let (scheme, rest) = match uri.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), rest),
+            None => (None, uri),
+        };
+        let tail_start = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, tail) = rest.split_at(tail_start);
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+        let (mut host, port) = match host_port.rsplit_once(':') {
+            Some((host, port))
+                if !port.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit()) =>
+            {
+                (host, Some(port))
+            }
+            _ => (host_port, None),
+        };
+        let query_start = tail.find(['?', '#']).unwrap_or(tail.len());
+        let (mut path, mut query) = tail.split_at(query_start);
+
+        if self.strip_www && host.len() > 4 && host[..4].eq_ignore_ascii_case("www.") {
+            host = &host[4..];
+        }
+        let port = port.filter(|port| {
+            let default = match scheme.map(str::to_ascii_lowercase).as_deref() {
+                Some("http") => "80",
+                Some("https") => "443",
+                _ => return true,
+            };
+            !(self.strip_default_port && *port == default)
+        });
+        if self.strip_path {
+            path = "";
+        }
+        if self.strip_trailing_slash {
+            path = path.trim_end_matches('/');
+        }
+        if self.strip_query {
+            query = "";
+        }
+
+        let mut normalized = String::with_capacity(uri.len());
+        if let Some(scheme) = scheme.filter(|_| !self.strip_scheme) {
+            normalized.push_str(scheme);
+            normalized.push_str("://");
+        }
+        if let Some(userinfo) = userinfo {
+            normalized.push_str(userinfo);
+            normalized.push('@');
+        }
+        normalized.push_str(host);
+        if let Some(port) = port {
+            normalized.push(':');
+            normalized.push_str(port);
+        }
+        normalized.push_str(path);
+        normalized.push_str(query);
+        normalized
+    }
+}
+
+/// The package name of an `androidapp://` URI.
+pub fn android_package(uri: &str) -> Option<&str> {
+    let (scheme, package) = uri.split_once("://")?;
+    let package = package.trim().trim_end_matches('/');
+    (scheme.eq_ignore_ascii_case("androidapp") && !package.is_empty()).then_some(package)
+}
+
+/// Rewrites the item's login URIs by `[normalize.android_apps]` and then
+/// `[normalize.uri]`.
+pub(crate) fn normalize_login_uris(value: &mut Value, normalize: &NormalizeConfig) {
+    let Some(Value::Array(uris)) = value.pointer_mut("/login/uris") else {
+        return;
+    };
+    for entry in uris {
+        let text = match entry {
+            Value::Object(map) => map.get_mut("uri"),
+            other => Some(other),
+        };
+        if let Some(Value::String(text)) = text {
+            let mapped = android_package(text)
+                .and_then(|package| normalize.android_apps.get(package))
+                .map(|domain| format!("https://{}", domain));
+            *text = normalize.uri.apply(mapped.as_deref().unwrap_or(text));
+        }
+    }
+}
+
+pub(crate) fn sort_login_uris(value: &mut Value) {
+    let Value::Object(map) = value else { return };
+    let Some(Value::Object(login)) = map.get_mut("login") else {
+        return;
+    };
+
+    let Some(Value::Array(uris)) = login.get_mut("uris") else {
+        return;
+    };
+
+    uris.sort_by_key(uri_sort_key);
+}
+
+fn uri_sort_key(value: &Value) -> String {
+    match value {
+        Value::Object(map) => map
+            .get("uri")
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string(),
+        Value::String(s) => s.to_string(),
+        _ => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
+
+pub fn canonicalize(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let mut new_map = Map::with_capacity(map.len());
+            for key in keys {
+                if let Some(value) = map.get(key) {
+                    new_map.insert(key.clone(), canonicalize(value));
+                }
+            }
+            Value::Object(new_map)
+        }
+        Value::Array(items) => {
+            let canonical_items = items.iter().map(canonicalize).collect();
+            Value::Array(canonical_items)
+        }
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_keys_anchored_and_anywhere() {
+        let keys = IgnoreKeys::parse(&[
+            "id".to_string(),
+            "login.totp".to_string(),
+            "**.revisionDate".to_string(),
+            "".to_string(),
+        ]);
+        let path = |parts: &[&str]| {
+            parts
+                .iter()
+                .map(|part| part.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(keys.matches(&[], "id"));
+        assert!(!keys.matches(&path(&["fields"]), "id"));
+        assert!(keys.matches(&path(&["login"]), "totp"));
+        assert!(!keys.matches(&[], "totp"));
+        assert!(keys.matches(&[], "revisionDate"));
+        assert!(keys.matches(&path(&["login", "fido2Credentials"]), "revisionDate"));
+        assert!(!keys.matches(&[], "name"));
+    }
+
+    #[test]
+    fn ignore_paths_reach_into_arrays() {
+        assert_eq!(
+            parse_path("login.uris[*].match"),
+            ["login", "uris", "*", "match"]
+        );
+        assert_eq!(
+            parse_path("fields.0.linkedId"),
+            parse_path("fields[0].linkedId")
+        );
+
+        let item = serde_json::json!({
+            "login": {"uris": [{"uri": "a", "match": 0}, {"uri": "b", "match": null}]},
+            "fields": [{"name": "x", "linkedId": 1}, {"name": "y", "linkedId": 2}],
+        });
+        let removed = |path: &str| {
+            let mut copy = item.clone();
+            remove_path(&mut copy, &parse_path(path));
+            copy
+        };
+        assert_eq!(
+            removed("login.uris[*].match")["login"]["uris"],
+            serde_json::json!([{"uri": "a"}, {"uri": "b"}])
+        );
+        assert_eq!(
+            removed("fields[1].linkedId")["fields"],
+            serde_json::json!([{"name": "x", "linkedId": 1}, {"name": "y"}])
+        );
+        assert_eq!(removed("fields[0]")["fields"].as_array().unwrap().len(), 1);
+        assert_eq!(removed("fields[5].linkedId"), item);
+        assert_eq!(
+            removed("*.uris"),
+            serde_json::json!({
+                "login": {},
+                "fields": item["fields"],
+            })
+        );
+
+        let ignore = vec![parse_path("fields.*.linkedId")];
+        assert!(is_ignored_path(&parse_path("fields.3.linkedId"), &ignore));
+        assert!(!is_ignored_path(&parse_path("fields.linkedId"), &ignore));
+    }
+
+    #[test]
+    fn uri_normalization_strips_the_configured_parts() {
+        let site = UriNormalization {
+            strip_scheme: true,
+            strip_www: true,
+            strip_path: true,
+            strip_query: true,
+            ..UriNormalization::default()
+        };
+        assert!(site.is_enabled() && !UriNormalization::default().is_enabled());
+        assert_eq!(site.apply("https://example.com/"), "example.com");
+        assert_eq!(
+            site.apply("http://www.example.com/login?next=/"),
+            "example.com"
+        );
+        assert_eq!(
+            site.apply("http://WWW.example.com:8080"),
+            "example.com:8080"
+        );
+
+        let tidy = UriNormalization {
+            strip_default_port: true,
+            strip_trailing_slash: true,
+            ..UriNormalization::default()
+        };
+        assert_eq!(
+            tidy.apply("https://user@example.com:443/app/?tab=1#top"),
+            "https://user@example.com/app?tab=1#top"
+        );
+        assert_eq!(
+            tidy.apply("http://example.com:443/"),
+            "http://example.com:443"
+        );
+        assert_eq!(tidy.apply("ftp://example.com/"), "ftp://example.com");
+        assert_eq!(
+            tidy.apply("androidapp://com.example/"),
+            "androidapp://com.example/"
+        );
+        assert_eq!(
+            UriNormalization::default().apply("https://[::1]:8443/x?y"),
+            "https://[::1]:8443/x?y"
+        );
+
+        let mut item = serde_json::json!({"login": {"uris": [
+            {"uri": "https://www.example.com/", "match": null},
+            "http://example.com",
+        ]}});
+        let normalize = NormalizeConfig {
+            uri: site,
+            ..NormalizeConfig::default()
+        };
+        normalize_login_uris(&mut item, &normalize);
+        assert_eq!(item["login"]["uris"][0]["uri"], "example.com");
+        assert_eq!(item["login"]["uris"][1], "example.com");
+    }
+
+    #[test]
+    fn android_apps_map_to_their_web_domains() {
+        assert_eq!(
+            android_package("androidapp://com.vendor.app/"),
+            Some("com.vendor.app")
+        );
+        assert_eq!(android_package("androidapp://"), None);
+        assert_eq!(android_package("https://com.vendor.app"), None);
+
+        let normalize: NormalizeConfig = toml::from_str(
+            "android_apps = { \"com.vendor.app\" = \"vendor.com\" }\n\
+             [uri]\n\
+             strip_scheme = true",
+        )
+        .unwrap();
+        assert!(normalize.rewrites_uris());
+        let mut item = serde_json::json!({"login": {"uris": [
+            {"uri": "androidapp://com.vendor.app", "match": null},
+            {"uri": "androidapp://com.other.app", "match": null},
+        ]}});
+        normalize_login_uris(&mut item, &normalize);
+        assert_eq!(item["login"]["uris"][0]["uri"], "vendor.com");
+        assert_eq!(
+            item["login"]["uris"][1]["uri"],
+            "androidapp://com.other.app"
+        );
+    }
+}
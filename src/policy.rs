@@ -0,0 +1,1652 @@
+//! What makes two items duplicates and what happens to them.
+//!
+//! The `[dedup]` config section: the policy keys items are compared by, the
+//! keep strategy that picks the survivor of a group, and the merge rules that
+//! fold a removed duplicate into it.
+
+use crate::equivalent_domains::EquivalentDomains;
+use crate::normalize::{IgnoreConfig, android_package};
+use crate::{Config, item_type};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Picks the survivor when two items turn out to be duplicates. Strategies
+/// are registered in [`KEEP_STRATEGIES`] and selected by name with `keep` in
+/// the config or `--keep`.
+pub trait KeepStrategy: Sync {
+    /// Name used in the config and on the command line.
+    fn name(&self) -> &'static str;
+    /// One line for `--list-keep-strategies`.
+    fn description(&self) -> &'static str;
+    /// How `candidate` ranks against the `existing` survivor: `Greater`
+    /// replaces it, `Less` keeps it, `Equal` is a tie, which keeps it too.
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering;
+    /// Why `removed` lost against `kept`, for the report.
+    fn explain(&self, kept: &Value, removed: &Value) -> String;
+}
+
+struct First;
+struct Last;
+struct Newest;
+struct Oldest;
+struct MostComplete;
+struct HasPasskey;
+struct LatestPassword;
+
+/// Every built-in keep strategy, in `--list-keep-strategies` order.
+pub static KEEP_STRATEGIES: &[&dyn KeepStrategy] = &[
+    &First,
+    &Last,
+    &Newest,
+    &Oldest,
+    &MostComplete,
+    &HasPasskey,
+    &LatestPassword,
+];
+
+impl KeepStrategy for First {
+    fn name(&self) -> &'static str {
+        "first"
+    }
+
+    fn description(&self) -> &'static str {
+        "the item that appears first in the export"
+    }
+
+    fn prefer(&self, _existing: &Value, _candidate: &Value) -> Ordering {
+        Ordering::Less
+    }
+
+    fn explain(&self, _kept: &Value, _removed: &Value) -> String {
+        "kept the first occurrence".to_string()
+    }
+}
+
+impl KeepStrategy for Last {
+    fn name(&self) -> &'static str {
+        "last"
+    }
+
+    fn description(&self) -> &'static str {
+        "the item that appears last in the export"
+    }
+
+    fn prefer(&self, _existing: &Value, _candidate: &Value) -> Ordering {
+        Ordering::Greater
+    }
+
+    fn explain(&self, _kept: &Value, _removed: &Value) -> String {
+        "kept the last occurrence".to_string()
+    }
+}
+
+impl KeepStrategy for Newest {
+    fn name(&self) -> &'static str {
+        "newest"
+    }
+
+    fn description(&self) -> &'static str {
+        "the most recently revised item (revisionDate, else creationDate)"
+    }
+
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        compare_dates(candidate, existing)
+    }
+
+    fn explain(&self, kept: &Value, removed: &Value) -> String {
+        explain_dates(kept, removed)
+    }
+}
+
+impl KeepStrategy for Oldest {
+    fn name(&self) -> &'static str {
+        "oldest"
+    }
+
+    fn description(&self) -> &'static str {
+        "the least recently revised item (revisionDate, else creationDate)"
+    }
+
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        compare_dates(existing, candidate)
+    }
+
+    fn explain(&self, kept: &Value, removed: &Value) -> String {
+        explain_dates(kept, removed)
+    }
+}
+
+impl KeepStrategy for MostComplete {
+    fn name(&self) -> &'static str {
+        "most-complete"
+    }
+
+    fn description(&self) -> &'static str {
+        "the item with the most filled-in values (URIs, fields, notes, ...)"
+    }
+
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        filled_values(candidate).cmp(&filled_values(existing))
+    }
+
+    fn explain(&self, kept: &Value, removed: &Value) -> String {
+        format!(
+            "kept item has {} filled values, removed {}",
+            filled_values(kept),
+            filled_values(removed)
+        )
+    }
+}
+
+impl KeepStrategy for HasPasskey {
+    fn name(&self) -> &'static str {
+        "has-passkey"
+    }
+
+    fn description(&self) -> &'static str {
+        "the item with a passkey (login.fido2Credentials); ties otherwise"
+    }
+
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        has_passkey(candidate).cmp(&has_passkey(existing))
+    }
+
+    fn explain(&self, kept: &Value, removed: &Value) -> String {
+        match (has_passkey(kept), has_passkey(removed)) {
+            (true, false) => "kept item has a passkey".to_string(),
+            (false, true) => "removed item has a passkey".to_string(),
+            _ => "both or neither have a passkey; kept the earlier occurrence".to_string(),
+        }
+    }
+}
+
+impl KeepStrategy for LatestPassword {
+    fn name(&self) -> &'static str {
+        "latest-password"
+    }
+
+    fn description(&self) -> &'static str {
+        "the item whose password changed last (passwordRevisionDate, passwordHistory)"
+    }
+
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        password_changed(candidate).cmp(&password_changed(existing))
+    }
+
+    fn explain(&self, kept: &Value, removed: &Value) -> String {
+        match (password_changed(kept), password_changed(removed)) {
+            (Some(kept), Some(removed)) if kept != removed => format!(
+                "kept item's password changed {} > {}",
+                short_date(kept),
+                short_date(removed)
+            ),
+            (Some(kept), None) => format!(
+                "kept item's password changed {}; removed item has no password dates",
+                short_date(kept)
+            ),
+            _ => "passwords changed at the same time or not recorded; kept the earlier occurrence"
+                .to_string(),
+        }
+    }
+}
+
+/// Narrows a duplicate group before the keep strategy picks from it (`prefer`
+/// in the config, `--prefer`): a copy with the property beats one without,
+/// whatever its dates, so that a TOTP seed or a favorite is not lost to
+/// `keep = "newest"`. Listed preferences apply in order.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preference {
+    /// Logins with a TOTP secret (`login.totp`).
+    WithTotp,
+    /// Items marked as favorites.
+    Favorite,
+    /// Items filed in a folder (`folderId`).
+    InFolder,
+}
+
+impl Preference {
+    fn holds(self, item: &Value) -> bool {
+        match self {
+            Preference::WithTotp => item
+                .pointer("/login/totp")
+                .and_then(Value::as_str)
+                .and_then(totp_secret)
+                .is_some(),
+            Preference::Favorite => item.get("favorite").and_then(Value::as_bool) == Some(true),
+            Preference::InFolder => item
+                .get("folderId")
+                .and_then(Value::as_str)
+                .is_some_and(|folder| !folder.is_empty()),
+        }
+    }
+
+    /// What the kept or removed item has, for the report.
+    fn property(self) -> &'static str {
+        match self {
+            Preference::WithTotp => "a TOTP secret",
+            Preference::Favorite => "the favorite mark",
+            Preference::InFolder => "a folder",
+        }
+    }
+
+    fn strategy(self) -> &'static dyn KeepStrategy {
+        match self {
+            Preference::WithTotp => &Preference::WithTotp,
+            Preference::Favorite => &Preference::Favorite,
+            Preference::InFolder => &Preference::InFolder,
+        }
+    }
+}
+
+impl KeepStrategy for Preference {
+    fn name(&self) -> &'static str {
+        match self {
+            Preference::WithTotp => "with-totp",
+            Preference::Favorite => "favorite",
+            Preference::InFolder => "in-folder",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Preference::WithTotp => "the item with a TOTP secret; ties otherwise",
+            Preference::Favorite => "the favorite item; ties otherwise",
+            Preference::InFolder => "the item in a folder; ties otherwise",
+        }
+    }
+
+    fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        self.holds(candidate).cmp(&self.holds(existing))
+    }
+
+    fn explain(&self, kept: &Value, removed: &Value) -> String {
+        match (self.holds(kept), self.holds(removed)) {
+            (true, false) => format!("kept item has {}", self.property()),
+            (false, true) => format!("removed item has {}", self.property()),
+            _ => format!(
+                "both or neither have {}; kept the earlier occurrence",
+                self.property()
+            ),
+        }
+    }
+}
+
+/// When the login's current password was set: `login.passwordRevisionDate`,
+/// or the latest `lastUsedDate` in its password history, the moment the
+/// password after that entry took over, whichever is later. Unlike
+/// `revisionDate` it does not move when only a note or a URI is edited.
+fn password_changed(item: &Value) -> Option<&str> {
+    let revised = item
+        .pointer("/login/passwordRevisionDate")
+        .and_then(Value::as_str);
+    let replaced = item
+        .get("passwordHistory")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.get("lastUsedDate").and_then(Value::as_str))
+        .max();
+    revised.max(replaced)
+}
+
+fn explain_dates(kept: &Value, removed: &Value) -> String {
+    let kept_date = best_date(kept).map(short_date).unwrap_or("no date");
+    let removed_date = best_date(removed).map(short_date).unwrap_or("no date");
+    match compare_dates(kept, removed) {
+        Ordering::Greater => format!("kept item revised {} > {}", kept_date, removed_date),
+        Ordering::Less => format!("kept item revised {} < {}", kept_date, removed_date),
+        Ordering::Equal => format!("both revised {}; kept the earlier occurrence", kept_date),
+    }
+}
+
+/// Non-empty scalars in an item, not counting `null`s and empty strings.
+fn filled_values(value: &Value) -> usize {
+    match value {
+        Value::Null => 0,
+        Value::String(text) => usize::from(!text.is_empty()),
+        Value::Array(values) => values.iter().map(filled_values).sum(),
+        Value::Object(map) => map.values().map(filled_values).sum(),
+        Value::Bool(_) | Value::Number(_) => 1,
+    }
+}
+
+/// One or more registered keep strategies, stored in the config by name.
+/// Later strategies only break the ties of earlier ones, so
+/// `newest,most-complete,first` keeps the newest copy, the fuller one of two
+/// revised at the same time, and the earlier one of two alike.
+#[derive(Clone)]
+pub struct Keep(Cow<'static, [&'static dyn KeepStrategy]>);
+
+impl Keep {
+    pub const FIRST: Keep = Keep(Cow::Borrowed(&[&First]));
+    pub const LAST: Keep = Keep(Cow::Borrowed(&[&Last]));
+    pub const NEWEST: Keep = Keep(Cow::Borrowed(&[&Newest]));
+    pub const OLDEST: Keep = Keep(Cow::Borrowed(&[&Oldest]));
+    pub const MOST_COMPLETE: Keep = Keep(Cow::Borrowed(&[&MostComplete]));
+    pub const HAS_PASSKEY: Keep = Keep(Cow::Borrowed(&[&HasPasskey]));
+    pub const LATEST_PASSWORD: Keep = Keep(Cow::Borrowed(&[&LatestPassword]));
+
+    /// Parses a comma-separated list of strategies from [`KEEP_STRATEGIES`],
+    /// ignoring case (older configs spell them `"First"`).
+    pub fn parse(names: &str) -> Result<Self, String> {
+        let chain = names
+            .split(',')
+            .map(|name| Self::lookup(name.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(name) = chain
+            .iter()
+            .enumerate()
+            .find(|&(index, strategy)| {
+                chain[..index]
+                    .iter()
+                    .any(|earlier| earlier.name() == strategy.name())
+            })
+            .map(|(_, strategy)| strategy.name())
+        {
+            return Err(format!("keep strategy {:?} is listed twice", name));
+        }
+        Ok(Keep(Cow::Owned(chain)))
+    }
+
+    fn lookup(name: &str) -> Result<&'static dyn KeepStrategy, String> {
+        KEEP_STRATEGIES
+            .iter()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| {
+                let names = KEEP_STRATEGIES
+                    .iter()
+                    .map(|strategy| strategy.name())
+                    .collect::<Vec<_>>();
+                format!(
+                    "unknown keep strategy {:?} (expected one of: {})",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// The strategies in the order they are consulted.
+    pub fn strategies(&self) -> &[&'static dyn KeepStrategy] {
+        &self.0
+    }
+
+    /// How `candidate` ranks against `existing`, by the first strategy that
+    /// does not call them a tie.
+    pub fn prefer(&self, existing: &Value, candidate: &Value) -> Ordering {
+        self.0
+            .iter()
+            .map(|strategy| strategy.prefer(existing, candidate))
+            .find(|order| order.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Why `removed` lost against `kept`, from the strategy that decided it
+    /// (the first one when all of them tie).
+    pub fn explain(&self, kept: &Value, removed: &Value) -> String {
+        let decided = self
+            .0
+            .iter()
+            .find(|strategy| strategy.prefer(kept, removed).is_ne())
+            .unwrap_or(&self.0[0]);
+        decided.explain(kept, removed)
+    }
+}
+
+impl PartialEq for Keep {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(other.0.iter())
+                .all(|(a, b)| a.name() == b.name())
+    }
+}
+
+impl Eq for Keep {}
+
+impl fmt::Display for Keep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.0.iter().map(|strategy| strategy.name());
+        f.write_str(&names.collect::<Vec<_>>().join(","))
+    }
+}
+
+impl fmt::Debug for Keep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Keep {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Keep {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Keep::parse(&name).map_err(de::Error::custom)
+    }
+}
+
+/// An item-relative path compared like a policy key (`policy_paths`,
+/// `--policy-path`): dotted keys, `[N]` for an array element and
+/// `[key=value]` for the elements whose `key` is `value`, as in
+/// `fields[name=Account ID].value`. Keys look through arrays, so
+/// `login.uris.uri` is every URI.
+#[derive(Clone, Eq, PartialEq)]
+pub struct PolicyPath {
+    source: String,
+    steps: Vec<PathStep>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum PathStep {
+    Key(String),
+    Index(usize),
+    Filter { key: String, value: String },
+}
+
+impl PolicyPath {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let source = source.trim();
+        let invalid = |reason: &str| format!("invalid policy path {:?}: {}", source, reason);
+        let mut steps = Vec::new();
+        let mut rest = source;
+        while !rest.is_empty() {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            let key = rest[..end].trim();
+            if !key.is_empty() {
+                steps.push(PathStep::Key(key.to_string()));
+            } else if !rest.starts_with('[') || steps.is_empty() {
+                return Err(invalid("empty key"));
+            }
+            rest = &rest[end..];
+            while let Some(bracketed) = rest.strip_prefix('[') {
+                let close = bracketed.find(']').ok_or_else(|| invalid("missing `]`"))?;
+                let inner = &bracketed[..close];
+                steps.push(match inner.split_once('=') {
+                    Some((key, value)) if !key.trim().is_empty() => PathStep::Filter {
+                        key: key.trim().to_string(),
+                        value: value.trim().to_string(),
+                    },
+                    Some(_) => return Err(invalid("filter without a key")),
+                    None => PathStep::Index(
+                        inner
+                            .trim()
+                            .parse()
+                            .map_err(|_| invalid("expected `[N]` or `[key=value]`"))?,
+                    ),
+                });
+                rest = &bracketed[close + 1..];
+            }
+            if let Some(next) = rest.strip_prefix('.') {
+                if next.is_empty() {
+                    return Err(invalid("empty key"));
+                }
+                rest = next;
+            } else if !rest.is_empty() {
+                return Err(invalid("expected `.` or `[` after `]`"));
+            }
+        }
+        if steps.is_empty() {
+            return Err(invalid("empty path"));
+        }
+        Ok(PolicyPath {
+            source: source.to_string(),
+            steps,
+        })
+    }
+
+    /// Whether the path ends at a password or TOTP secret, which reports
+    /// redact.
+    pub fn is_secret(&self) -> bool {
+        self.steps
+            .iter()
+            .rev()
+            .find_map(|step| match step {
+                PathStep::Key(key) => Some(key),
+                _ => None,
+            })
+            .is_some_and(|key| {
+                key.eq_ignore_ascii_case("password") || key.eq_ignore_ascii_case("totp")
+            })
+    }
+
+    /// The non-null values at the path, in item order.
+    pub fn resolve<'a>(&self, item: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![item];
+        for step in &self.steps {
+            let mut next = Vec::new();
+            for value in current {
+                match (step, value) {
+                    (PathStep::Key(key), Value::Object(map)) => next.extend(map.get(key)),
+                    (PathStep::Key(key), Value::Array(values)) => {
+                        next.extend(values.iter().filter_map(|value| value.get(key)))
+                    }
+                    (PathStep::Index(index), Value::Array(values)) => {
+                        next.extend(values.get(*index))
+                    }
+                    (PathStep::Filter { key, value: wanted }, Value::Array(values)) => next.extend(
+                        values
+                            .iter()
+                            .filter(|value| filter_matches(value, key, wanted)),
+                    ),
+                    (PathStep::Filter { key, value: wanted }, value)
+                        if filter_matches(value, key, wanted) =>
+                    {
+                        next.push(value)
+                    }
+                    _ => {}
+                }
+            }
+            current = next;
+        }
+        current.retain(|value| !value.is_null());
+        current
+    }
+}
+
+fn filter_matches(value: &Value, key: &str, wanted: &str) -> bool {
+    match value.get(key) {
+        Some(Value::String(text)) => text == wanted,
+        Some(Value::Null) | None => false,
+        Some(other) => serde_json::to_string(other).is_ok_and(|text| text == wanted),
+    }
+}
+
+impl fmt::Display for PolicyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl fmt::Debug for PolicyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for PolicyPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PolicyPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        PolicyPath::parse(&source).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupKey {
+    Domain,
+    Username,
+    Password,
+    Name,
+    Uri,
+    Totp,
+    /// The passkeys of a login, by credential ID and relying party.
+    Passkey,
+}
+
+/// How much of a URI the `domain` policy key compares.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ValueEnum, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DomainGranularity {
+    /// Host and port as written, so `example.com:8443` and `example.com`
+    /// differ.
+    Exact,
+    /// The host without port or credentials.
+    #[default]
+    Host,
+    /// The registrable domain by the Public Suffix List (eTLD+1), so
+    /// `login.example.com` and `www.example.com` match. IP addresses and
+    /// hosts without a known public suffix are compared as hosts.
+    Registrable,
+}
+
+/// Which items may be duplicates of each other at all.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ValueEnum, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupScope {
+    /// Anywhere in the vault.
+    #[default]
+    Global,
+    /// Only within the same folder (`folderId`; items without one form
+    /// their own scope).
+    Folder,
+    /// Only among items in exactly the same collections (`collectionIds`),
+    /// for organization exports.
+    Collection,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub keep: Keep,
+    /// Applied before `keep`; see [`Preference`].
+    pub prefer: Vec<Preference>,
+    pub policy_keys: Vec<DedupKey>,
+    /// Item paths compared along with the policy keys.
+    pub policy_paths: Vec<PolicyPath>,
+    pub domain_granularity: DomainGranularity,
+    pub scope: DedupScope,
+    pub hash_sections: Vec<String>,
+    /// Fold removed duplicates into the kept item (see `MERGE_RULES`).
+    pub merge: bool,
+    /// Groups that would lose more items than this are left untouched and
+    /// listed in the report for review.
+    pub max_auto_remove_per_group: Option<usize>,
+    /// Only treat items as duplicates when each copy was created (by
+    /// `creationDate`) within this many seconds of the previous one.
+    pub creation_window_secs: Option<u64>,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            keep: Keep::FIRST,
+            prefer: Vec::new(),
+            policy_keys: vec![DedupKey::Domain, DedupKey::Username, DedupKey::Password],
+            policy_paths: Vec::new(),
+            domain_granularity: DomainGranularity::Host,
+            scope: DedupScope::Global,
+            hash_sections: Vec::new(),
+            merge: false,
+            max_auto_remove_per_group: None,
+            creation_window_secs: None,
+        }
+    }
+}
+
+impl DedupConfig {
+    /// The preferences followed by the keep strategy: what picks the
+    /// survivor of a group.
+    pub fn ranking(&self) -> Keep {
+        if self.prefer.is_empty() {
+            return self.keep.clone();
+        }
+        let chain = self
+            .prefer
+            .iter()
+            .map(|preference| preference.strategy())
+            .chain(self.keep.strategies().iter().copied())
+            .collect();
+        Keep(Cow::Owned(chain))
+    }
+}
+
+/// Window used by `--assume-double-import` unless the config sets one.
+pub const DOUBLE_IMPORT_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// `--assume-double-import`: whole-item comparison that ignores everything
+/// a second import gives fresh values (ids, folders, collections, dates),
+/// within a creation window, keeping the copy imported first. Flags given
+/// alongside it still override these settings.
+pub fn apply_double_import_preset(config: &mut Config) {
+    config.dedup.policy_keys.clear();
+    config.dedup.hash_sections.clear();
+    config.dedup.keep = Keep::OLDEST;
+    config
+        .dedup
+        .creation_window_secs
+        .get_or_insert(DOUBLE_IMPORT_WINDOW_SECS);
+    let defaults = IgnoreConfig::default().keys;
+    let extra = ["folderId", "collectionIds"].map(String::from);
+    for key in defaults.into_iter().chain(extra) {
+        if !config.ignore.keys.contains(&key) {
+            config.ignore.keys.push(key);
+        }
+    }
+}
+
+pub fn should_replace(existing: &Value, candidate: &Value, keep: &Keep) -> bool {
+    keep.prefer(existing, candidate) == Ordering::Greater
+}
+
+/// The date part of an RFC 3339 timestamp.
+pub fn short_date(date: &str) -> &str {
+    date.split('T').next().unwrap_or(date)
+}
+
+pub fn compare_dates(existing: &Value, candidate: &Value) -> Ordering {
+    let existing_date = best_date(existing);
+    let candidate_date = best_date(candidate);
+
+    match (existing_date, candidate_date) {
+        (Some(a), Some(b)) => a.cmp(b),
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+pub fn best_date(item: &Value) -> Option<&str> {
+    item.get("revisionDate")
+        .and_then(Value::as_str)
+        .or_else(|| item.get("creationDate").and_then(Value::as_str))
+}
+
+/// Policy keys other than `name` only exist on logins. Other item types are
+/// compared as whole items unless `name` is a policy key or a policy path
+/// finds something in them, so secure notes, cards and identities do not all
+/// share one empty key.
+pub fn uses_policy_keys(item: &Value, config: &Config) -> bool {
+    let keys = &config.dedup.policy_keys;
+    (!keys.is_empty() && (item_type(item) == Some(1) || keys.contains(&DedupKey::Name)))
+        || config
+            .dedup
+            .policy_paths
+            .iter()
+            .any(|path| !path.resolve(item).is_empty())
+}
+
+pub(crate) fn build_policy_value(
+    item: &Value,
+    dedup: &DedupConfig,
+    equivalent: &EquivalentDomains,
+) -> Value {
+    let mut map = Map::new();
+    for key in &dedup.policy_keys {
+        match key {
+            DedupKey::Domain => {
+                let domains = policy_domains(item, dedup.domain_granularity, equivalent);
+                map.insert("domain".to_string(), Value::Array(domains));
+            }
+            DedupKey::Username => {
+                map.insert(
+                    "username".to_string(),
+                    extract_login_field(item, "username"),
+                );
+            }
+            DedupKey::Password => {
+                map.insert(
+                    "password".to_string(),
+                    extract_login_field(item, "password"),
+                );
+            }
+            DedupKey::Name => {
+                map.insert(
+                    "name".to_string(),
+                    item.get("name").cloned().unwrap_or(Value::Null),
+                );
+            }
+            DedupKey::Uri => {
+                let uris = login_uris(item)
+                    .into_iter()
+                    .filter(|(_, matching)| *matching != UriMatch::Never)
+                    .map(|(uri, _)| Value::String(uri))
+                    .collect();
+                map.insert("uri".to_string(), Value::Array(uris));
+            }
+            DedupKey::Totp => {
+                // The secret, so a bare secret and an otpauth:// URI for it
+                // compare equal; anything unparseable as it is.
+                let totp = extract_login_field(item, "totp");
+                let secret = totp.as_str().and_then(totp_secret);
+                map.insert(
+                    "totp".to_string(),
+                    secret.map(Value::String).unwrap_or(totp),
+                );
+            }
+            DedupKey::Passkey => {
+                map.insert("passkey".to_string(), Value::Array(passkey_ids(item)));
+            }
+        }
+    }
+    for path in &dedup.policy_paths {
+        let values = path.resolve(item).into_iter().cloned().collect();
+        map.insert(format!("path:{}", path), Value::Array(values));
+    }
+    Value::Object(map)
+}
+
+/// The passkeys of a login (`login.fido2Credentials`) as
+/// `{"credentialId", "rpId"}` pairs, sorted. The key material is left out.
+pub fn passkey_ids(item: &Value) -> Vec<Value> {
+    let Some(Value::Array(credentials)) = item.pointer("/login/fido2Credentials") else {
+        return Vec::new();
+    };
+    let mut ids = credentials
+        .iter()
+        .filter(|credential| credential.get("credentialId").is_some())
+        .map(|credential| {
+            serde_json::json!({
+                "credentialId": credential.get("credentialId"),
+                "rpId": credential.get("rpId"),
+            })
+        })
+        .collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.to_string());
+    ids.dedup();
+    ids
+}
+
+pub fn has_passkey(item: &Value) -> bool {
+    !passkey_ids(item).is_empty()
+}
+
+/// The login's password, borrowed from the item. Unlike
+/// [`extract_login_field`] it makes no copy, so none is left behind in freed
+/// memory.
+pub fn login_password(item: &Value) -> Option<&str> {
+    item.pointer("/login/password").and_then(Value::as_str)
+}
+
+pub fn extract_login_field(item: &Value, field: &str) -> Value {
+    item.get("login")
+        .and_then(Value::as_object)
+        .and_then(|login| login.get(field))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+/// Browser imports (Chrome in particular) name logins "example.com (user@mail.com)"
+/// and leave the username empty. Returns the parenthesized part for such logins.
+pub fn username_from_name(item: &Value) -> Option<String> {
+    if item_type(item) != Some(1) {
+        return None;
+    }
+    let username = extract_login_field(item, "username");
+    if username
+        .as_str()
+        .is_some_and(|value| !value.trim().is_empty())
+    {
+        return None;
+    }
+    let name = item.get("name").and_then(Value::as_str)?.trim();
+    let (_, rest) = name.rsplit_once(" (")?;
+    let candidate = rest.strip_suffix(')')?.trim();
+    if candidate.is_empty() || candidate.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(candidate.to_string())
+}
+
+pub(crate) fn with_username(item: &Value, username: String) -> Value {
+    let mut item = item.clone();
+    if let Some(login) = item.get_mut("login").and_then(Value::as_object_mut) {
+        login.insert("username".to_string(), Value::String(username));
+    }
+    item
+}
+
+pub fn extract_uris(item: &Value) -> Vec<Value> {
+    let mut uris = Vec::new();
+    if let Some(login) = item.get("login").and_then(Value::as_object)
+        && let Some(Value::Array(items)) = login.get("uris")
+    {
+        for entry in items {
+            match entry {
+                Value::Object(map) => {
+                    if let Some(Value::String(uri)) = map.get("uri") {
+                        uris.push(Value::String(uri.clone()));
+                    }
+                }
+                Value::String(uri) => uris.push(Value::String(uri.clone())),
+                _ => {}
+            }
+        }
+    }
+    uris
+}
+
+/// How Bitwarden matches a login URI for autofill (`login.uris[].match`).
+/// `null` and unknown values mean the account default, base domain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum UriMatch {
+    BaseDomain,
+    Host,
+    StartsWith,
+    Exact,
+    RegularExpression,
+    Never,
+}
+
+impl UriMatch {
+    fn of(entry: &Value) -> Self {
+        match entry.get("match").and_then(Value::as_u64) {
+            Some(1) => UriMatch::Host,
+            Some(2) => UriMatch::StartsWith,
+            Some(3) => UriMatch::Exact,
+            Some(4) => UriMatch::RegularExpression,
+            Some(5) => UriMatch::Never,
+            _ => UriMatch::BaseDomain,
+        }
+    }
+}
+
+/// An item's URIs with their match types, in order.
+fn login_uris(item: &Value) -> Vec<(String, UriMatch)> {
+    let Some(Value::Array(entries)) = item.pointer("/login/uris") else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Value::Object(map) => match map.get("uri") {
+                Some(Value::String(uri)) => Some((uri.clone(), UriMatch::of(entry))),
+                _ => None,
+            },
+            Value::String(uri) => Some((uri.clone(), UriMatch::BaseDomain)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The `domain` policy value. URIs set to never match are left out, and
+/// ones matched by prefix, exactly or by regular expression are compared
+/// whole, since their domain alone says less than the item does. Domains in
+/// an equivalent-domain group are compared by the group's name.
+fn policy_domains(
+    item: &Value,
+    granularity: DomainGranularity,
+    equivalent: &EquivalentDomains,
+) -> Vec<Value> {
+    let mut domains = login_uris(item)
+        .into_iter()
+        .filter_map(|(uri, matching)| match matching {
+            UriMatch::Never => None,
+            UriMatch::StartsWith | UriMatch::Exact | UriMatch::RegularExpression => Some(uri),
+            UriMatch::BaseDomain | UriMatch::Host => equivalent
+                .is_enabled()
+                .then(|| extract_domain_from_uri(&uri, DomainGranularity::Registrable))
+                .flatten()
+                .and_then(|domain| equivalent.canonical(&domain))
+                .or_else(|| extract_domain_from_uri(&uri, granularity))
+                .or(Some(uri)),
+        })
+        .collect::<Vec<_>>();
+    domains.sort();
+    domains.dedup();
+    domains.into_iter().map(Value::String).collect()
+}
+
+/// The hosts of an item's URIs, sorted and deduplicated.
+pub fn extract_domains(item: &Value) -> Vec<Value> {
+    extract_domains_with(item, DomainGranularity::Host)
+}
+
+pub fn extract_domains_with(item: &Value, granularity: DomainGranularity) -> Vec<Value> {
+    let mut domains: Vec<String> = Vec::new();
+    for uri_value in extract_uris(item) {
+        if let Value::String(uri) = uri_value {
+            if let Some(host) = extract_domain_from_uri(&uri, granularity) {
+                domains.push(host);
+            } else {
+                domains.push(uri);
+            }
+        }
+    }
+    domains.sort();
+    domains.dedup();
+    domains.into_iter().map(Value::String).collect()
+}
+
+fn extract_domain_from_uri(uri: &str, granularity: DomainGranularity) -> Option<String> {
+    // Package names read like reversed hosts; compared as hosts they would
+    // collide with web domains (and `com.example.app` would reduce to the
+    // registrable `example.app`).
+    if let Some(package) = android_package(uri) {
+        return Some(format!("androidapp://{}", package));
+    }
+    let without_scheme = uri.split("://").nth(1).unwrap_or(uri);
+    let host_port = without_scheme.split('/').next().unwrap_or(without_scheme);
+    let host_port = host_port.split('@').next_back().unwrap_or(host_port);
+    let host = host_port.split(':').next().unwrap_or(host_port);
+    if host.is_empty() {
+        return None;
+    }
+    Some(match granularity {
+        DomainGranularity::Exact => host_port.to_string(),
+        DomainGranularity::Host => host.to_string(),
+        DomainGranularity::Registrable => registrable_domain(host),
+    })
+}
+
+fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return host;
+    }
+    // Unknown TLDs fall under the list's implicit `*` rule, which would make
+    // `intranet.corp` its own registrable domain; keep such hosts whole.
+    match psl::domain(host.as_bytes()) {
+        Some(domain) if domain.suffix().is_known() => {
+            String::from_utf8_lossy(domain.as_bytes()).into_owned()
+        }
+        _ => host,
+    }
+}
+
+/// How `--merge` folds a removed duplicate into the item that is kept.
+#[derive(Clone, Copy, Debug)]
+enum MergeRule {
+    /// Append the entries whose values at these keys aren't present yet.
+    UnionBy(&'static [&'static str]),
+    /// Append text the kept item doesn't already contain, after a blank line.
+    AppendText,
+    /// Take the other value when the kept item has none.
+    FillEmpty,
+    /// True when either item is.
+    Or,
+    /// Append the values that aren't present yet, if both items belong to
+    /// the same organization (or neither does).
+    UnionInOrganization,
+}
+
+/// Per-field rules for merging duplicates, by path from the item root.
+/// Fields not listed keep the kept item's value.
+const MERGE_RULES: &[(&[&str], MergeRule)] = &[
+    (&["login", "uris"], MergeRule::UnionBy(&["uri"])),
+    (&["login", "totp"], MergeRule::FillEmpty),
+    PASSKEY_MERGE_RULE,
+    COLLECTIONS_MERGE_RULE,
+    (&["notes"], MergeRule::AppendText),
+    (&["fields"], MergeRule::UnionBy(&["name", "value"])),
+    (&["passwordHistory"], MergeRule::UnionBy(&["password"])),
+    (&["favorite"], MergeRule::Or),
+];
+
+/// Passkeys are carried over from removed duplicates even without `--merge`:
+/// a dropped one cannot be recovered from the site.
+const PASSKEY_MERGE_RULE: (&[&str], MergeRule) = (
+    &["login", "fido2Credentials"],
+    MergeRule::UnionBy(&["credentialId"]),
+);
+
+/// So are collection memberships, or the other members of a collection lose
+/// access to the item when the copy in it is the one removed.
+const COLLECTIONS_MERGE_RULE: (&[&str], MergeRule) =
+    (&["collectionIds"], MergeRule::UnionInOrganization);
+
+/// Applies [`MERGE_RULES`] to fold `other` into `target`. Returns whether
+/// `target` changed.
+pub fn merge_item(target: &mut Value, other: &Value) -> bool {
+    apply_merge_rules(target, other, MERGE_RULES)
+}
+
+/// Adds what removing `other` would otherwise lose for good, whether or not
+/// the removal merges: its passkeys and its collections. Returns whether
+/// `target` changed.
+pub fn carry_over(target: &mut Value, other: &Value) -> bool {
+    apply_merge_rules(target, other, &[PASSKEY_MERGE_RULE, COLLECTIONS_MERGE_RULE])
+}
+
+fn apply_merge_rules(target: &mut Value, other: &Value, rules: &[(&[&str], MergeRule)]) -> bool {
+    let is_empty = |value: &Value| value.is_null() || value.as_str() == Some("");
+    let organization = |item: &Value| {
+        item.get("organizationId")
+            .filter(|id| !id.is_null())
+            .cloned()
+    };
+    let same_organization = organization(target) == organization(other);
+    let mut changed = false;
+    for (path, rule) in rules {
+        let Some(incoming) = path
+            .iter()
+            .try_fold(other, |value, key| value.get(key))
+            .filter(|value| !is_empty(value))
+        else {
+            continue;
+        };
+        let Some((last, parents)) = path.split_last() else {
+            continue;
+        };
+        let Some(parent) = parents
+            .iter()
+            .try_fold(&mut *target, |value, key| value.get_mut(key))
+            .and_then(Value::as_object_mut)
+        else {
+            continue;
+        };
+        let current = parent.get(*last).unwrap_or(&Value::Null);
+        let merged = match rule {
+            MergeRule::UnionBy(keys) => match (current, incoming) {
+                (Value::Null | Value::Array(_), Value::Array(incoming)) => {
+                    let same =
+                        |a: &Value, b: &Value| keys.iter().all(|key| a.get(key) == b.get(key));
+                    let mut entries = current.as_array().cloned().unwrap_or_default();
+                    let before = entries.len();
+                    for entry in incoming {
+                        if !entries.iter().any(|known| same(known, entry)) {
+                            entries.push(entry.clone());
+                        }
+                    }
+                    (entries.len() > before).then_some(Value::Array(entries))
+                }
+                _ => None,
+            },
+            MergeRule::AppendText => match (current, incoming.as_str()) {
+                (_, None) => None,
+                (current, Some(_)) if is_empty(current) => Some(incoming.clone()),
+                (Value::String(existing), Some(text)) if !existing.contains(text) => {
+                    Some(Value::String(format!("{}\n\n{}", existing, text)))
+                }
+                _ => None,
+            },
+            MergeRule::FillEmpty => is_empty(current).then(|| incoming.clone()),
+            MergeRule::Or => (incoming == &Value::Bool(true) && current != &Value::Bool(true))
+                .then_some(Value::Bool(true)),
+            MergeRule::UnionInOrganization => match (current, incoming) {
+                (Value::Null | Value::Array(_), Value::Array(incoming)) if same_organization => {
+                    let mut values = current.as_array().cloned().unwrap_or_default();
+                    let before = values.len();
+                    for value in incoming {
+                        if !values.contains(value) {
+                            values.push(value.clone());
+                        }
+                    }
+                    (values.len() > before).then_some(Value::Array(values))
+                }
+                _ => None,
+            },
+        };
+        if let Some(merged) = merged {
+            parent.insert(last.to_string(), merged);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Folds `other` into `target` like `--merge` does, and also records a
+/// different password of `other` in the password history.
+pub fn merge_login(target: &mut Value, other: &Value) {
+    let other_password = extract_login_field(other, "password");
+    let target_password = extract_login_field(target, "password");
+    let other_revision = other.get("revisionDate").cloned().unwrap_or(Value::Null);
+    merge_item(target, other);
+
+    if let Some(password) = other_password
+        .as_str()
+        .filter(|password| !password.is_empty())
+        && target_password.as_str() != Some(password)
+        && let Some(target) = target.as_object_mut()
+    {
+        let history = target
+            .entry("passwordHistory")
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if let Some(history) = history.as_array_mut()
+            && !history
+                .iter()
+                .any(|entry| entry.get("password").and_then(Value::as_str) == Some(password))
+        {
+            history.push(serde_json::json!({
+                "lastUsedDate": other_revision,
+                "password": password,
+            }));
+        }
+    }
+}
+
+/// The base32 secret of a TOTP value, whether stored bare or as an
+/// `otpauth://` URI, with case, spaces and padding normalized away.
+pub fn totp_secret(totp: &str) -> Option<String> {
+    let totp = totp.trim();
+    let secret = if totp.to_ascii_lowercase().starts_with("otpauth://") {
+        let (_, query) = totp.split_once('?')?;
+        let query = query.split('#').next().unwrap_or(query);
+        query.split('&').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            name.eq_ignore_ascii_case("secret")
+                .then(|| percent_decode(value))
+        })?
+    } else {
+        totp.to_string()
+    };
+    let secret = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=' && *c != '-')
+        .collect::<String>()
+        .to_ascii_uppercase();
+    (!secret.is_empty()).then_some(secret)
+}
+
+/// Decodes `%XX` escapes and `+`; malformed escapes stay as written.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| bytes.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (escaped, bytes[index]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::login;
+    use serde_json::json;
+
+    #[test]
+    fn keep_strategies_rank_candidates() {
+        let mut sparse = login("a", "one", json!([]));
+        sparse["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+        let mut full = login("b", "one", json!([{"name": "PIN", "value": "1"}]));
+        full["revisionDate"] = json!("2023-01-01T00:00:00.000Z");
+
+        assert!(!should_replace(&sparse, &full, &Keep::FIRST));
+        assert!(should_replace(&sparse, &full, &Keep::LAST));
+        assert!(!should_replace(&sparse, &full, &Keep::NEWEST));
+        assert!(should_replace(&sparse, &full, &Keep::OLDEST));
+        assert!(should_replace(&sparse, &full, &Keep::MOST_COMPLETE));
+        assert!(!should_replace(&full, &sparse, &Keep::MOST_COMPLETE));
+        assert!(!should_replace(&full, &full.clone(), &Keep::MOST_COMPLETE));
+
+        assert_eq!(Keep::parse("First"), Ok(Keep::FIRST));
+        assert_eq!(Keep::parse("most-complete"), Ok(Keep::MOST_COMPLETE));
+        assert_eq!(Keep::parse("has-passkey"), Ok(Keep::HAS_PASSKEY));
+        let mut passkey = sparse.clone();
+        passkey["login"]["fido2Credentials"] = json!([{"credentialId": "c", "rpId": "r"}]);
+        assert!(should_replace(&sparse, &passkey, &Keep::HAS_PASSKEY));
+        assert!(!should_replace(&passkey, &sparse, &Keep::HAS_PASSKEY));
+        assert!(!should_replace(&sparse, &full, &Keep::HAS_PASSKEY));
+        assert_eq!(
+            Keep::HAS_PASSKEY.explain(&passkey, &sparse),
+            "kept item has a passkey"
+        );
+        assert!(Keep::parse("best").is_err());
+        let config: DedupConfig = toml::from_str("keep = \"oldest\"").unwrap();
+        assert_eq!(config.keep, Keep::OLDEST);
+    }
+
+    #[test]
+    fn latest_password_goes_by_password_dates_not_revision_dates() {
+        // Revised later for a note edit, but its password is the old one.
+        let mut edited = login("a", "old", json!([]));
+        edited["revisionDate"] = json!("2024-06-01T00:00:00.000Z");
+        edited["login"]["passwordRevisionDate"] = json!("2023-01-01T00:00:00.000Z");
+        let mut rotated = login("b", "new", json!([]));
+        rotated["revisionDate"] = json!("2024-03-01T00:00:00.000Z");
+        rotated["passwordHistory"] = json!([
+            {"lastUsedDate": "2023-01-01T00:00:00.000Z", "password": "older"},
+            {"lastUsedDate": "2024-02-29T00:00:00.000Z", "password": "old"},
+        ]);
+        let undated = login("c", "old", json!([]));
+
+        assert_eq!(Keep::parse("latest-password"), Ok(Keep::LATEST_PASSWORD));
+        assert!(should_replace(&edited, &rotated, &Keep::LATEST_PASSWORD));
+        assert!(!should_replace(&rotated, &edited, &Keep::LATEST_PASSWORD));
+        assert!(!should_replace(&edited, &rotated, &Keep::NEWEST));
+        assert!(should_replace(&undated, &edited, &Keep::LATEST_PASSWORD));
+        assert!(!should_replace(
+            &undated,
+            &undated.clone(),
+            &Keep::LATEST_PASSWORD
+        ));
+        assert_eq!(
+            Keep::LATEST_PASSWORD.explain(&rotated, &edited),
+            "kept item's password changed 2024-02-29 > 2023-01-01"
+        );
+        assert_eq!(
+            Keep::LATEST_PASSWORD.explain(&edited, &undated),
+            "kept item's password changed 2023-01-01; removed item has no password dates"
+        );
+    }
+
+    #[test]
+    fn preferences_narrow_the_group_before_the_keep_strategy() {
+        let mut seeded = login("a", "one", json!([]));
+        seeded["revisionDate"] = json!("2023-01-01T00:00:00.000Z");
+        seeded["login"]["totp"] = json!("otpauth://totp/Site:me?secret=JBSWY3DP");
+        let mut favorite = login("b", "one", json!([]));
+        favorite["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+        favorite["favorite"] = json!(true);
+        let mut filed = login("c", "one", json!([]));
+        filed["revisionDate"] = json!("2025-01-01T00:00:00.000Z");
+        filed["folderId"] = json!("f1");
+
+        let mut config = DedupConfig {
+            keep: Keep::NEWEST,
+            ..DedupConfig::default()
+        };
+        assert_eq!(config.ranking(), Keep::NEWEST);
+        config.prefer = vec![
+            Preference::WithTotp,
+            Preference::Favorite,
+            Preference::InFolder,
+        ];
+        let ranking = config.ranking();
+        assert_eq!(ranking.to_string(), "with-totp,favorite,in-folder,newest");
+        assert!(!should_replace(&seeded, &favorite, &ranking));
+        assert!(should_replace(&filed, &seeded, &ranking));
+        assert!(should_replace(&filed, &favorite, &ranking));
+        assert_eq!(
+            ranking.explain(&seeded, &filed),
+            "kept item has a TOTP secret"
+        );
+        assert_eq!(
+            ranking.explain(&favorite, &filed),
+            "kept item has the favorite mark"
+        );
+
+        // Without any of the properties the keep strategy decides as before.
+        let mut newer = login("d", "one", json!([]));
+        newer["revisionDate"] = json!("2026-01-01T00:00:00.000Z");
+        newer["login"]["totp"] = json!("  ");
+        assert!(should_replace(&filed, &newer, &Keep::NEWEST));
+        assert!(!should_replace(&filed, &newer, &ranking));
+        filed["folderId"] = Value::Null;
+        assert!(should_replace(&filed, &newer, &ranking));
+        assert_eq!(
+            ranking.explain(&newer, &filed),
+            "kept item revised 2026-01-01 > 2025-01-01"
+        );
+    }
+
+    #[test]
+    fn chained_keep_strategies_break_ties_in_order() {
+        let mut sparse = login("a", "one", json!([]));
+        sparse["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+        let mut full = login("b", "one", json!([{"name": "PIN", "value": "1"}]));
+        full["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+
+        let chain = Keep::parse("newest, most-complete,first").unwrap();
+        assert_eq!(chain.strategies().len(), 3);
+        assert!(should_replace(&sparse, &full, &chain));
+        assert!(!should_replace(&full, &sparse, &chain));
+        assert_eq!(
+            chain.explain(&full, &sparse),
+            "kept item has 9 filled values, removed 7"
+        );
+        // Nothing left to break the tie: the earlier item stays.
+        let alike = Keep::parse("newest,last").unwrap();
+        assert!(should_replace(&sparse, &sparse.clone(), &alike));
+        assert!(!should_replace(&sparse, &sparse.clone(), &Keep::NEWEST));
+
+        assert!(Keep::parse("newest,newest").is_err());
+        assert!(Keep::parse("newest,").is_err());
+        let config: DedupConfig = toml::from_str("keep = \"newest,most-complete\"").unwrap();
+        assert_eq!(config.keep.to_string(), "newest,most-complete");
+        assert_eq!(
+            toml::to_string(&config).unwrap().lines().next(),
+            Some("keep = \"newest,most-complete\"")
+        );
+    }
+
+    #[test]
+    fn totp_secret_normalizes_uris_and_raw_secrets() {
+        assert_eq!(
+            totp_secret("otpauth://totp/Site:me?secret=jbsw y3dp&issuer=Site").as_deref(),
+            Some("JBSWY3DP")
+        );
+        assert_eq!(totp_secret(" jbsw-y3dp== ").as_deref(), Some("JBSWY3DP"));
+        assert_eq!(totp_secret("otpauth://totp/Site:me?issuer=Site"), None);
+        assert_eq!(totp_secret("  "), None);
+        assert_eq!(
+            totp_secret("otpauth://totp/Site?issuer=Site&Secret=JBSW%20Y3DP%3D%3D#x").as_deref(),
+            Some("JBSWY3DP")
+        );
+        assert_eq!(percent_decode("a%2"), "a%2");
+
+        let with_totp = |totp: &str| {
+            let mut item = login("a", "one", json!([]));
+            item["login"]["totp"] = json!(totp);
+            item
+        };
+        let dedup = DedupConfig {
+            policy_keys: vec![DedupKey::Totp],
+            ..DedupConfig::default()
+        };
+        let value = |item: &Value| build_policy_value(item, &dedup, &EquivalentDomains::default());
+        assert_eq!(
+            value(&with_totp("jbsw y3dp ====")),
+            value(&with_totp(
+                "otpauth://totp/Site:me?secret=JBSWY3DP&issuer=Site"
+            ))
+        );
+        assert_eq!(
+            value(&with_totp("otpauth://totp/x"))["totp"],
+            "otpauth://totp/x"
+        );
+    }
+
+    #[test]
+    fn merge_login_combines_the_other_side() {
+        let mut target = login("a", "one", json!([{"name": "PIN", "value": "1"}]));
+        let mut other = login(
+            "b",
+            "two",
+            json!([{"name": "PIN", "value": "2"}, {"name": "Recovery", "value": "r"}]),
+        );
+        other["login"]["uris"] = json!([
+            {"uri": "https://forum.example.com"},
+            {"uri": "https://m.forum.example.com"},
+        ]);
+        other["login"]["totp"] = json!("JBSWY3DP");
+        other["notes"] = json!("from b");
+        other["revisionDate"] = json!("2024-01-01T00:00:00.000Z");
+
+        merge_login(&mut target, &other);
+        assert_eq!(target["login"]["password"], "one");
+        assert_eq!(target["login"]["uris"].as_array().unwrap().len(), 2);
+        assert_eq!(target["login"]["totp"], "JBSWY3DP");
+        assert_eq!(target["notes"], "from b");
+        assert_eq!(
+            target["passwordHistory"],
+            json!([{"lastUsedDate": "2024-01-01T00:00:00.000Z", "password": "two"}])
+        );
+        // Same-named fields with different values are both kept.
+        let fields = target["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0]["value"], "1");
+        assert_eq!(fields[1]["value"], "2");
+
+        // Merging again adds nothing new.
+        let merged = target.clone();
+        merge_login(&mut target, &other);
+        assert_eq!(target, merged);
+    }
+
+    #[test]
+    fn removals_carry_over_collections_of_the_same_organization() {
+        let mut target = login("a", "one", json!([]));
+        target["organizationId"] = json!("org-1");
+        target["collectionIds"] = json!(["c1"]);
+        let mut other = login("b", "one", json!([]));
+        other["organizationId"] = json!("org-1");
+        other["collectionIds"] = json!(["c2", "c1"]);
+        other["notes"] = json!("only merged");
+
+        assert!(carry_over(&mut target, &other));
+        assert_eq!(target["collectionIds"], json!(["c1", "c2"]));
+        assert_eq!(target["notes"], Value::Null);
+        assert!(!carry_over(&mut target, &other));
+
+        other["organizationId"] = json!("org-2");
+        other["collectionIds"] = json!(["c3"]);
+        assert!(merge_item(&mut target, &other));
+        assert_eq!(target["notes"], "only merged");
+        assert_eq!(target["collectionIds"], json!(["c1", "c2"]));
+        assert_eq!(target["organizationId"], "org-1");
+    }
+
+    #[test]
+    fn domain_granularity_controls_how_hosts_compare() {
+        let mut item = login("a", "one", json!([]));
+        item["login"]["uris"] = json!([
+            {"uri": "https://login.Example.co.uk:8443/sign-in"},
+            {"uri": "https://user:pw@alice.github.io/"},
+            {"uri": "http://192.168.1.10:8080"},
+            {"uri": "https://wiki.intranet.corp"},
+        ]);
+        let domains = |granularity| {
+            extract_domains_with(&item, granularity)
+                .into_iter()
+                .map(|domain| domain.as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            domains(DomainGranularity::Host),
+            [
+                "192.168.1.10",
+                "alice.github.io",
+                "login.Example.co.uk",
+                "wiki.intranet.corp"
+            ]
+        );
+        assert_eq!(
+            domains(DomainGranularity::Exact),
+            [
+                "192.168.1.10:8080",
+                "alice.github.io",
+                "login.Example.co.uk:8443",
+                "wiki.intranet.corp"
+            ]
+        );
+        assert_eq!(
+            domains(DomainGranularity::Registrable),
+            [
+                "192.168.1.10",
+                "alice.github.io",
+                "example.co.uk",
+                "wiki.intranet.corp"
+            ]
+        );
+        let mut app = login("b", "one", json!([]));
+        app["login"]["uris"] = json!([{"uri": "androidapp://com.example.app/"}]);
+        for granularity in [DomainGranularity::Host, DomainGranularity::Registrable] {
+            assert_eq!(
+                extract_domains_with(&app, granularity),
+                [json!("androidapp://com.example.app")]
+            );
+        }
+        let config: DedupConfig = toml::from_str("domain_granularity = \"registrable\"").unwrap();
+        assert_eq!(config.domain_granularity, DomainGranularity::Registrable);
+    }
+
+    #[test]
+    fn policy_paths_filter_arrays_and_reject_bad_syntax() {
+        let item = json!({
+            "type": 2,
+            "notes": "branch 12",
+            "fields": [
+                {"name": "Account ID", "value": "A-1"},
+                {"name": "PIN", "value": "1234"},
+                {"name": "Account ID", "value": null},
+            ],
+            "login": {"uris": [{"uri": "https://a.example"}, {"uri": "https://b.example"}]},
+        });
+        let resolve = |path: &str| {
+            PolicyPath::parse(path)
+                .unwrap()
+                .resolve(&item)
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(resolve("fields[name=Account ID].value"), [json!("A-1")]);
+        assert_eq!(resolve("fields[1].value"), [json!("1234")]);
+        assert_eq!(
+            resolve("login.uris.uri"),
+            [json!("https://a.example"), json!("https://b.example")]
+        );
+        assert_eq!(resolve("type"), [json!(2)]);
+        assert!(resolve("card.number").is_empty());
+        assert!(PolicyPath::parse("login.password").unwrap().is_secret());
+        assert!(
+            !PolicyPath::parse("fields[name=password].value")
+                .unwrap()
+                .is_secret()
+        );
+        for bad in [
+            "",
+            "fields[",
+            "fields[x]",
+            "fields..value",
+            "[0]",
+            "fields[0]value",
+            "notes.",
+        ] {
+            assert!(PolicyPath::parse(bad).is_err(), "{:?}", bad);
+        }
+
+        let config: DedupConfig = toml::from_str(
+            "policy_keys = []\npolicy_paths = [\"notes\", \"fields[name=PIN].value\"]",
+        )
+        .unwrap();
+        let value = build_policy_value(&item, &config, &EquivalentDomains::default());
+        assert_eq!(
+            value,
+            json!({"path:notes": ["branch 12"], "path:fields[name=PIN].value": ["1234"]})
+        );
+    }
+
+    #[test]
+    fn uri_match_types_shape_the_policy_values() {
+        let with_uris = |uris: Value| {
+            let mut item = login("a", "one", json!([]));
+            item["login"]["uris"] = uris;
+            item
+        };
+        let plain = with_uris(json!([{"uri": "https://forum.example.com/login", "match": null}]));
+        let never = with_uris(json!([
+            {"uri": "https://forum.example.com/login", "match": 0},
+            {"uri": "https://old.example.net", "match": 5},
+        ]));
+        let exact = with_uris(json!([{"uri": "https://forum.example.com/login", "match": 3}]));
+        let dedup = |keys: Vec<DedupKey>| DedupConfig {
+            policy_keys: keys,
+            ..DedupConfig::default()
+        };
+
+        let domain = dedup(vec![DedupKey::Domain]);
+        assert_eq!(
+            build_policy_value(&plain, &domain, &EquivalentDomains::default()),
+            build_policy_value(&never, &domain, &EquivalentDomains::default())
+        );
+        assert_eq!(
+            build_policy_value(&exact, &domain, &EquivalentDomains::default())["domain"],
+            json!(["https://forum.example.com/login"])
+        );
+        let uri = dedup(vec![DedupKey::Uri]);
+        assert_eq!(
+            build_policy_value(&plain, &uri, &EquivalentDomains::default()),
+            build_policy_value(&never, &uri, &EquivalentDomains::default())
+        );
+        let equivalent = EquivalentDomains {
+            global: true,
+            ..EquivalentDomains::default()
+        };
+        let amazon = |uri: &str| {
+            let item = with_uris(json!([{"uri": uri, "match": null}]));
+            build_policy_value(&item, &domain, &equivalent)["domain"].clone()
+        };
+        assert_eq!(amazon("https://www.amazon.de/"), json!(["amazon.com"]));
+        assert_eq!(amazon("https://smile.amazon.co.uk"), json!(["amazon.com"]));
+        assert_eq!(
+            amazon("https://forum.example.com"),
+            json!(["forum.example.com"])
+        );
+        // Reports still see every host.
+        assert_eq!(extract_domains(&never).len(), 2);
+    }
+}
@@ -0,0 +1,255 @@
+//! Password-protected Bitwarden JSON exports.
+//!
+//! Such an export carries the KDF settings and a salt next to two encrypted
+//! strings: `encKeyValidation_DO_NOT_EDIT`, which only decrypts with the right
+//! password, and `data`, the classic unencrypted export as JSON text. The
+//! password is stretched with PBKDF2-SHA256 or Argon2id into a 32-byte key,
+//! which HKDF expands into an AES-256-CBC key and an HMAC-SHA256 key, as the
+//! Bitwarden clients do. Strings use Bitwarden's type 2 format,
+//! `2.<iv>|<ciphertext>|<mac>` in base64.
+
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+const KDF_PBKDF2: u64 = 0;
+const KDF_ARGON2ID: u64 = 1;
+
+/// The fields of a protected export besides `encKeyValidation_DO_NOT_EDIT`
+/// and `data`, kept to write the output back with the same settings.
+const HEADER_FIELDS: [&str; 6] = [
+    "passwordProtected",
+    "salt",
+    "kdfType",
+    "kdfIterations",
+    "kdfMemory",
+    "kdfParallelism",
+];
+
+/// The keys derived from an export's password, plus the KDF settings they
+/// came from. The keys are wiped when it is dropped.
+pub(crate) struct ExportKey {
+    enc: [u8; 32],
+    mac: [u8; 32],
+    header: Map<String, Value>,
+}
+
+impl Drop for ExportKey {
+    fn drop(&mut self) {
+        self.enc.zeroize();
+        self.mac.zeroize();
+    }
+}
+
+impl ExportKey {
+    /// A 64-byte symmetric key as Bitwarden stores account and item keys:
+    /// the AES key followed by the HMAC key.
+    #[cfg_attr(not(feature = "server-api"), allow(dead_code))]
+    pub(crate) fn from_bytes(key: &[u8]) -> Result<Self> {
+        if key.len() != 64 {
+            anyhow::bail!("symmetric key is {} bytes long, expected 64", key.len());
+        }
+        let mut enc = [0u8; 32];
+        let mut mac = [0u8; 32];
+        enc.copy_from_slice(&key[..32]);
+        mac.copy_from_slice(&key[32..]);
+        Ok(ExportKey {
+            enc,
+            mac,
+            header: Map::new(),
+        })
+    }
+}
+
+pub(crate) fn is_password_protected(root: &Value) -> bool {
+    root.get("passwordProtected").and_then(Value::as_bool) == Some(true)
+        && root.get("data").is_some_and(Value::is_string)
+}
+
+/// Decrypts a protected export into the classic export shape. Fails on a
+/// wrong password before touching `data`.
+pub(crate) fn unlock(root: &Value, password: &str) -> Result<(Value, ExportKey)> {
+    let key = derive_key(root, password)?;
+    let plain = unlock_with_key(root, &key)?;
+    Ok((plain, key))
+}
+
+/// Like [`unlock`], with a key already derived, e.g. to read back an export
+/// written with [`lock`].
+pub(crate) fn unlock_with_key(root: &Value, key: &ExportKey) -> Result<Value> {
+    let validation = root
+        .get("encKeyValidation_DO_NOT_EDIT")
+        .and_then(Value::as_str)
+        .context("protected export has no encKeyValidation_DO_NOT_EDIT")?;
+    decrypt(validation, key).context("wrong password for the protected export")?;
+
+    let data = root.get("data").and_then(Value::as_str).unwrap_or_default();
+    let plain = Zeroizing::new(decrypt(data, key).context("failed to decrypt the export data")?);
+    serde_json::from_slice(&plain).context("decrypted export is not valid JSON")
+}
+
+/// Encrypts an export rendered as JSON text with the key and settings of
+/// the export it was read from.
+pub(crate) fn lock(contents: &str, key: &ExportKey, pretty: bool) -> Result<String> {
+    let mut validation = [0u8; 16];
+    getrandom::fill(&mut validation).context("failed to gather randomness for the export")?;
+
+    let mut root = Map::new();
+    root.insert("encrypted".to_string(), Value::Bool(true));
+    root.extend(key.header.clone());
+    root.insert(
+        "encKeyValidation_DO_NOT_EDIT".to_string(),
+        Value::String(encrypt(crate::format_uuid_v4(validation).as_bytes(), key)?),
+    );
+    root.insert(
+        "data".to_string(),
+        Value::String(encrypt(contents.as_bytes(), key)?),
+    );
+    let root = Value::Object(root);
+    Ok(if pretty {
+        serde_json::to_string_pretty(&root)?
+    } else {
+        serde_json::to_string(&root)?
+    })
+}
+
+/// Stretches `password` with the `salt` and KDF fields of `root`. The same
+/// derivation gives an account's master key, with the email as the salt.
+pub(crate) fn derive_key(root: &Value, password: &str) -> Result<ExportKey> {
+    let field = |name: &str| root.get(name).and_then(Value::as_u64);
+    let salt = root
+        .get("salt")
+        .and_then(Value::as_str)
+        .context("protected export has no salt")?;
+    let iterations = field("kdfIterations").context("protected export has no kdfIterations")?;
+    let iterations = u32::try_from(iterations).context("kdfIterations is out of range")?;
+
+    let mut master = Zeroizing::new([0u8; 32]);
+    match field("kdfType").unwrap_or(KDF_PBKDF2) {
+        KDF_PBKDF2 => {
+            pbkdf2::pbkdf2_hmac::<Sha256>(
+                password.as_bytes(),
+                salt.as_bytes(),
+                iterations,
+                &mut *master,
+            );
+        }
+        KDF_ARGON2ID => {
+            let memory_mib = field("kdfMemory").context("Argon2id export has no kdfMemory")?;
+            let parallelism =
+                field("kdfParallelism").context("Argon2id export has no kdfParallelism")?;
+            let params = argon2::Params::new(
+                u32::try_from(memory_mib.saturating_mul(1024)).unwrap_or(u32::MAX),
+                iterations,
+                u32::try_from(parallelism).unwrap_or(u32::MAX),
+                Some(master.len()),
+            )
+            .map_err(|err| anyhow::anyhow!("invalid Argon2id settings: {}", err))?;
+            let argon2 =
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+            argon2
+                .hash_password_into(password.as_bytes(), &Sha256::digest(salt), &mut *master)
+                .map_err(|err| anyhow::anyhow!("Argon2id failed: {}", err))?;
+        }
+        other => anyhow::bail!("unsupported kdfType {} in protected export", other),
+    }
+
+    let hkdf = hkdf::Hkdf::<Sha256>::from_prk(&*master)
+        .map_err(|_| anyhow::anyhow!("derived key has an invalid length"))?;
+    let mut enc = [0u8; 32];
+    let mut mac = [0u8; 32];
+    hkdf.expand(b"enc", &mut enc)
+        .and_then(|()| hkdf.expand(b"mac", &mut mac))
+        .map_err(|_| anyhow::anyhow!("failed to expand the derived key"))?;
+
+    let header = HEADER_FIELDS
+        .iter()
+        .filter_map(|name| Some((name.to_string(), root.get(*name)?.clone())))
+        .collect();
+    Ok(ExportKey { enc, mac, header })
+}
+
+pub(crate) fn decrypt(enc_string: &str, key: &ExportKey) -> Result<Vec<u8>> {
+    let parts = enc_string
+        .strip_prefix("2.")
+        .context("unsupported encrypted string type (expected 2)")?
+        .split('|')
+        .map(|part| BASE64.decode(part))
+        .collect::<Result<Vec<_>, _>>()
+        .context("encrypted string is not valid base64")?;
+    let [iv, ciphertext, mac] = parts.as_slice() else {
+        anyhow::bail!("encrypted string does not have an iv, data and mac");
+    };
+
+    let mut check = Hmac::<Sha256>::new_from_slice(&key.mac).expect("HMAC takes any key length");
+    check.update(iv);
+    check.update(ciphertext);
+    check
+        .verify_slice(mac)
+        .map_err(|_| anyhow::anyhow!("MAC mismatch"))?;
+
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(&key.enc, iv)
+        .map_err(|_| anyhow::anyhow!("invalid iv length"))?
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| anyhow::anyhow!("invalid padding"))
+}
+
+pub(crate) fn encrypt(plain: &[u8], key: &ExportKey) -> Result<String> {
+    let mut iv = [0u8; 16];
+    getrandom::fill(&mut iv).context("failed to gather randomness for the export")?;
+    let ciphertext = cbc::Encryptor::<aes::Aes256>::new(&key.enc.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plain);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.mac).expect("HMAC takes any key length");
+    mac.update(&iv);
+    mac.update(&ciphertext);
+    Ok(format!(
+        "2.{}|{}|{}",
+        BASE64.encode(iv),
+        BASE64.encode(&ciphertext),
+        BASE64.encode(mac.finalize().into_bytes())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn protected_exports_round_trip_and_reject_wrong_passwords() {
+        let header = json!({
+            "encrypted": true,
+            "passwordProtected": true,
+            "salt": "c2FsdHNhbHRzYWx0c2FsdA==",
+            "kdfType": KDF_PBKDF2,
+            "kdfIterations": 1000,
+            "kdfMemory": null,
+            "kdfParallelism": null,
+        });
+        let key = derive_key(&header, "correct horse").unwrap();
+        let plain = json!({"encrypted": false, "folders": [], "items": [{"id": "a"}]});
+        let locked = lock(&plain.to_string(), &key, false).unwrap();
+        let locked: Value = serde_json::from_str(&locked).unwrap();
+        assert!(is_password_protected(&locked));
+        assert_eq!(locked["kdfIterations"], 1000);
+
+        let (unlocked, _) = unlock(&locked, "correct horse").unwrap();
+        assert_eq!(unlocked, plain);
+        let err = unlock(&locked, "wrong").err().unwrap();
+        assert_eq!(err.to_string(), "wrong password for the protected export");
+
+        let mut argon = locked.clone();
+        argon["kdfType"] = json!(KDF_ARGON2ID);
+        argon["kdfIterations"] = json!(2);
+        argon["kdfMemory"] = json!(1);
+        argon["kdfParallelism"] = json!(1);
+        let argon_key = derive_key(&argon, "correct horse").unwrap();
+        assert_ne!(argon_key.enc, key.enc);
+    }
+}
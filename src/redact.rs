@@ -0,0 +1,105 @@
+//! Stand-ins for passwords and TOTP secrets wherever the tool prints item
+//! values (reports, previews): the length and the start of the SHA-256,
+//! enough to tell two values apart without showing either.
+//!
+//! `--no-redact` turns this off for debugging on a trusted machine.
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Cleared by `--no-redact`.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// `len=14, sha256:ab12…` for `secret`.
+pub(crate) fn describe(secret: &str) -> String {
+    let hash = Sha256::digest(secret.as_bytes());
+    format!(
+        "len={}, sha256:{}…",
+        secret.chars().count(),
+        hex::encode(&hash[..2])
+    )
+}
+
+/// Replaces a secret value with its description, unless redaction is off.
+/// Null stays null; other non-strings are described by their JSON.
+pub(crate) fn value(value: &mut Value) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let described = match &*value {
+        Value::Null => return,
+        Value::String(secret) => describe(secret),
+        other => describe(&other.to_string()),
+    };
+    *value = Value::String(described);
+}
+
+/// Redacts the secrets of an item as exported: the login password and TOTP
+/// secret, the password history and hidden custom fields.
+pub(crate) fn item(item: &mut Value) {
+    if let Some(login) = item.get_mut("login") {
+        for field in ["password", "totp"] {
+            if let Some(secret) = login.get_mut(field) {
+                value(secret);
+            }
+        }
+    }
+    for entry in item
+        .get_mut("passwordHistory")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(secret) = entry.get_mut("password") {
+            value(secret);
+        }
+    }
+    for field in item
+        .get_mut("fields")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter(|field| field.get("type").and_then(Value::as_u64) == Some(1))
+    {
+        if let Some(secret) = field.get_mut("value") {
+            value(secret);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn secrets_are_described_by_length_and_hash_prefix() {
+        assert_eq!(describe("password"), "len=8, sha256:5e88…");
+        assert_eq!(describe("pässword"), "len=8, sha256:3478…");
+
+        let mut login = json!({
+            "name": "Forum",
+            "login": {"username": "user", "password": "hunter2", "totp": null},
+            "passwordHistory": [{"password": "password", "lastUsedDate": "2024-01-01"}],
+            "fields": [
+                {"name": "pin", "value": "1234", "type": 1},
+                {"name": "note", "value": "visible", "type": 0},
+            ],
+        });
+        item(&mut login);
+        assert_eq!(login["login"]["password"], describe("hunter2"));
+        assert_eq!(login["login"]["username"], "user");
+        assert_eq!(login["login"]["totp"], Value::Null);
+        assert_eq!(
+            login["passwordHistory"][0]["password"],
+            "len=8, sha256:5e88…"
+        );
+        assert_eq!(login["fields"][0]["value"], describe("1234"));
+        assert_eq!(login["fields"][1]["value"], "visible");
+    }
+}
@@ -0,0 +1,151 @@
+//! Creates the files the tool writes so that only the current user can read
+//! them: outputs hold plaintext passwords. On Unix that is mode 0600, on
+//! Windows a protected DACL that grants access to the file's owner alone.
+//!
+//! Also tells whether a directory is a risky place to write them, such as a
+//! shared temporary directory where another user can swap files.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Creates (or truncates) `path` readable and writable by the current user
+/// only.
+#[cfg(unix)]
+pub(crate) fn create_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to a file that did not exist yet.
+    restrict_file(&file)?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_private(path: &Path) -> io::Result<fs::File> {
+    let file = fs::File::create(path)?;
+    restrict(path)?;
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn restrict_file(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+}
+
+/// Limits the file at `path` to the current user.
+#[cfg(windows)]
+fn restrict(path: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::{ERROR_SUCCESS, LocalFree};
+    use windows_sys::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1, SE_FILE_OBJECT,
+        SetNamedSecurityInfoW,
+    };
+    use windows_sys::Win32::Security::{
+        ACL, DACL_SECURITY_INFORMATION, GetSecurityDescriptorDacl,
+        PROTECTED_DACL_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR,
+    };
+
+    let wide = |value: &std::ffi::OsStr| value.encode_wide().chain([0]).collect::<Vec<u16>>();
+    // Protected (no inherited entries), full access for the owner only.
+    let sddl = wide("D:P(A;;FA;;;OW)".as_ref());
+    let name = wide(path.as_os_str());
+
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    // SAFETY: `sddl` is NUL-terminated and `descriptor` receives a buffer
+    // that is released with `LocalFree` below.
+    let converted = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            std::ptr::null_mut(),
+        )
+    };
+    if converted == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut present = 0;
+    let mut defaulted = 0;
+    let mut dacl: *mut ACL = std::ptr::null_mut();
+    // SAFETY: `descriptor` is the valid descriptor converted above, and the
+    // DACL it points into lives until the descriptor is freed.
+    let result = unsafe {
+        if GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted) == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            match SetNamedSecurityInfoW(
+                name.as_ptr(),
+                SE_FILE_OBJECT,
+                DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                dacl,
+                std::ptr::null(),
+            ) {
+                ERROR_SUCCESS => Ok(()),
+                code => Err(io::Error::from_raw_os_error(code as i32)),
+            }
+        }
+    };
+    // SAFETY: allocated by the conversion above and not used after this.
+    unsafe { LocalFree(descriptor) };
+    result
+}
+
+#[cfg(not(any(unix, windows)))]
+fn restrict(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Why writing into `dir` is unsafe, if it is: on Unix a directory every user
+/// can write to, like `/tmp`, where another user could replace the file
+/// between it being written and read. Windows temp directories are per user.
+#[cfg(unix)]
+pub(crate) fn insecure_location(dir: &Path) -> Option<&'static str> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(dir).ok()?.permissions().mode();
+    (mode & 0o002 != 0).then_some("every user can write to it")
+}
+
+#[cfg(not(unix))]
+pub(crate) fn insecure_location(_dir: &Path) -> Option<&'static str> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn files_are_private_and_shared_directories_are_flagged() {
+        let dir = std::env::temp_dir().join(format!("bw-dedup-private-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = dir.join("vault.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        create_private(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        assert_eq!(insecure_location(&dir), None);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert_eq!(insecure_location(&dir), Some("every user can write to it"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
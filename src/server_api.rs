@@ -0,0 +1,308 @@
+//! Deduplicating a vault in place through the Bitwarden or Vaultwarden REST
+//! API, without the `bw` CLI.
+//!
+//! The client logs in with a personal API key (`client_credentials`), derives
+//! the master key from the master password and the account's KDF settings,
+//! and unwraps the account key with it. The ciphers from `/api/sync` are
+//! decrypted in memory into the export shape, so the dedup pass sees the
+//! same items as with an export, and duplicates are soft-deleted by ID
+//! (`PUT /api/ciphers/<id>/delete`), which moves them to the trash.
+//! Organization items are encrypted with organization keys and left alone.
+
+use crate::bw_cli::{SyncAction, Vault};
+use crate::protected::{self, ExportKey};
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+/// `deviceType` of the Linux CLI, which the servers expect for API key logins.
+const DEVICE_TYPE_CLI: &str = "25";
+
+/// The key pair of a personal API key and the master password.
+pub(crate) struct ApiCredentials {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: Zeroizing<String>,
+    pub(crate) password: Zeroizing<String>,
+}
+
+/// A logged-in session with the account key unwrapped.
+pub(crate) struct ServerApi {
+    api_url: String,
+    token: String,
+    user_key: ExportKey,
+}
+
+impl ServerApi {
+    pub(crate) fn login(server: &str, credentials: &ApiCredentials) -> Result<Self> {
+        let server = server.trim_end_matches('/');
+        if !server.starts_with("https://") && crate::ensure_loopback_url(server).is_err() {
+            anyhow::bail!(
+                "refusing to send the API key to {} over plain http; use an https:// URL",
+                server
+            );
+        }
+        let (identity_url, api_url) = endpoints(server);
+
+        let mut device = [0u8; 16];
+        getrandom::fill(&mut device).context("failed to gather randomness for the login")?;
+        let token_url = format!("{}/connect/token", identity_url);
+        let response = ureq::post(&token_url)
+            .send_form([
+                ("grant_type", "client_credentials"),
+                ("scope", "api"),
+                ("client_id", credentials.client_id.as_str()),
+                ("client_secret", credentials.client_secret.as_str()),
+                ("deviceType", DEVICE_TYPE_CLI),
+                ("deviceIdentifier", &crate::format_uuid_v4(device)),
+                ("deviceName", "bw-passport-dedup"),
+            ])
+            .with_context(|| format!("failed to log in at {}", token_url))?;
+        let login: Value = serde_json::from_reader(response.into_body().into_reader())
+            .with_context(|| format!("failed to parse the login response from {}", token_url))?;
+        let login = camel_case_keys(login);
+        let token = login
+            .get("access_token")
+            .and_then(Value::as_str)
+            .context("the login response has no access_token")?
+            .to_string();
+
+        let profile = get_json(&api_url, &token, "accounts/profile")?;
+        let email = profile
+            .get("email")
+            .and_then(Value::as_str)
+            .context("the account profile has no email")?;
+        let kdf = serde_json::json!({
+            "salt": email.trim().to_lowercase(),
+            "kdfType": login.get("kdf"),
+            "kdfIterations": login.get("kdfIterations"),
+            "kdfMemory": login.get("kdfMemory"),
+            "kdfParallelism": login.get("kdfParallelism"),
+        });
+        let master_key = protected::derive_key(&kdf, &credentials.password)?;
+        let wrapped = login
+            .get("key")
+            .and_then(Value::as_str)
+            .context("the login response has no account key")?;
+        let user_key = Zeroizing::new(
+            protected::decrypt(wrapped, &master_key).context("wrong master password")?,
+        );
+        Ok(ServerApi {
+            api_url,
+            token,
+            user_key: ExportKey::from_bytes(&user_key)?,
+        })
+    }
+}
+
+impl Vault for ServerApi {
+    fn list_items(&self) -> Result<Vec<Value>> {
+        let sync = get_json(&self.api_url, &self.token, "sync?excludeDomains=true")?;
+        let ciphers = sync
+            .get("ciphers")
+            .and_then(Value::as_array)
+            .context("the sync response has no ciphers")?;
+
+        let mut items = Vec::new();
+        let mut organization_items = 0;
+        for cipher in ciphers {
+            if cipher
+                .get("deletedDate")
+                .is_some_and(|date| !date.is_null())
+            {
+                continue;
+            }
+            if cipher.get("organizationId").is_some_and(|id| !id.is_null()) {
+                organization_items += 1;
+                continue;
+            }
+            let mut item = cipher.clone();
+            match decrypt_cipher(&mut item, &self.user_key) {
+                Ok(()) => items.push(item),
+                Err(err) => {
+                    let id = cipher.get("id").and_then(Value::as_str);
+                    warn!("left out cipher {}: {:#}", id.unwrap_or("<no id>"), err);
+                }
+            }
+        }
+        info!("Loaded {} item(s) from {}", items.len(), self.api_url);
+        if organization_items > 0 {
+            info!(
+                "note: left out {} organization item(s); they are encrypted with organization keys this mode does not read",
+                organization_items
+            );
+        }
+        Ok(items)
+    }
+
+    fn apply(&self, action: &SyncAction) -> Result<()> {
+        let SyncAction::Delete { id, .. } = action else {
+            anyhow::bail!("the server API mode only deletes items");
+        };
+        let url = format!("{}/ciphers/{}/delete", self.api_url, id);
+        ureq::put(&url)
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send_empty()
+            .with_context(|| format!("failed to delete item {} at {}", id, url))?;
+        Ok(())
+    }
+}
+
+/// GETs an API path with the session's bearer token.
+fn get_json(api_url: &str, token: &str, path: &str) -> Result<Value> {
+    let url = format!("{}/{}", api_url, path);
+    let response = ureq::get(&url)
+        .header("Authorization", format!("Bearer {}", token))
+        .call()
+        .with_context(|| format!("failed to query {}", url))?;
+    let body = serde_json::from_reader(response.into_body().into_reader())
+        .with_context(|| format!("failed to parse response from {}", url))?;
+    Ok(camel_case_keys(body))
+}
+
+/// The identity and API base URLs. The Bitwarden clouds serve them from
+/// their own hosts, self-hosted servers under `/identity` and `/api`.
+fn endpoints(server: &str) -> (String, String) {
+    for region in ["bitwarden.com", "bitwarden.eu"] {
+        if server == format!("https://vault.{}", region) {
+            return (
+                format!("https://identity.{}", region),
+                format!("https://api.{}", region),
+            );
+        }
+    }
+    (format!("{}/identity", server), format!("{}/api", server))
+}
+
+/// Bitwarden answers in PascalCase where Vaultwarden uses camelCase; this
+/// brings both to the camelCase of exports.
+fn camel_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let mut chars = key.chars();
+                    let key = match chars.next() {
+                        Some(first) => first.to_lowercase().chain(chars).collect(),
+                        None => key,
+                    };
+                    (key, camel_case_keys(value))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(camel_case_keys).collect()),
+        other => other,
+    }
+}
+
+/// Decrypts a cipher's strings in place with its own key when it has one,
+/// else the account key. Attachment metadata stays encrypted; only whether
+/// there is any matters to the dedup.
+fn decrypt_cipher(item: &mut Value, user_key: &ExportKey) -> Result<()> {
+    let Value::Object(map) = item else {
+        anyhow::bail!("cipher is not an object");
+    };
+    // Legacy copy of the type-specific fields.
+    map.remove("data");
+    let item_key = match map.remove("key") {
+        Some(Value::String(wrapped)) => Some(ExportKey::from_bytes(&Zeroizing::new(
+            protected::decrypt(&wrapped, user_key).context("failed to decrypt the item key")?,
+        ))?),
+        _ => None,
+    };
+    let key = item_key.as_ref().unwrap_or(user_key);
+    for (field, value) in map.iter_mut() {
+        if field != "attachments" {
+            decrypt_strings(value, key)?;
+        }
+    }
+    Ok(())
+}
+
+fn decrypt_strings(value: &mut Value, key: &ExportKey) -> Result<()> {
+    match value {
+        Value::String(text) if is_enc_string(text) => {
+            let plain = protected::decrypt(text, key)?;
+            *text = String::from_utf8(plain).context("decrypted value is not UTF-8")?;
+        }
+        Value::Array(values) => {
+            for value in values {
+                decrypt_strings(value, key)?;
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                decrypt_strings(value, key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Whether `text` looks like an encrypted string (`<type>.<iv>|<data>|<mac>`)
+/// rather than a plain id, date or number.
+fn is_enc_string(text: &str) -> bool {
+    text.split_once('.').is_some_and(|(kind, rest)| {
+        kind.len() == 1 && kind.chars().all(|c| c.is_ascii_digit()) && rest.split('|').count() >= 2
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ciphers_decrypt_into_the_export_shape() {
+        let user_key = ExportKey::from_bytes(&[7; 64]).unwrap();
+        let item_key = ExportKey::from_bytes(&[9; 64]).unwrap();
+        let enc = |text: &str, key| protected::encrypt(text.as_bytes(), key).unwrap();
+
+        let cipher = camel_case_keys(json!({
+            "Id": "a",
+            "Type": 1,
+            "Key": protected::encrypt(&[9; 64], &user_key).unwrap(),
+            "Name": enc("Forum", &item_key),
+            "Login": {
+                "Username": enc("user", &item_key),
+                "Uris": [{"Uri": enc("https://forum.example.com", &item_key), "Match": null}],
+            },
+            "Attachments": [{"FileName": enc("a.txt", &item_key)}],
+            "RevisionDate": "2024-01-01T00:00:00.000Z",
+            "Data": {"Name": "legacy"},
+        }));
+        let mut item = cipher.clone();
+        decrypt_cipher(&mut item, &user_key).unwrap();
+        assert_eq!(item["name"], "Forum");
+        assert_eq!(item["login"]["username"], "user");
+        assert_eq!(item["login"]["uris"][0]["uri"], "https://forum.example.com");
+        assert_eq!(item["revisionDate"], "2024-01-01T00:00:00.000Z");
+        assert_eq!(item["attachments"], cipher["attachments"]);
+        assert!(item.get("key").is_none() && item.get("data").is_none());
+
+        let mut wrong = cipher;
+        let other = ExportKey::from_bytes(&[1; 64]).unwrap();
+        assert!(decrypt_cipher(&mut wrong, &other).is_err());
+    }
+
+    #[test]
+    fn cloud_and_self_hosted_endpoints() {
+        assert_eq!(
+            endpoints("https://vault.bitwarden.eu"),
+            (
+                "https://identity.bitwarden.eu".to_string(),
+                "https://api.bitwarden.eu".to_string()
+            )
+        );
+        assert_eq!(
+            endpoints("https://vw.example.com"),
+            (
+                "https://vw.example.com/identity".to_string(),
+                "https://vw.example.com/api".to_string()
+            )
+        );
+        assert!(!is_enc_string("2024-01-01T00:00:00.000Z"));
+        assert!(is_enc_string("2.aXY=|ZGF0YQ==|bWFj"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Structural checks on an export before it goes back into Bitwarden:
+//! references to folders and collections that are not in it, item IDs used
+//! more than once, and login URIs no client can parse.
+//!
+//! `validate` runs them on any export, and every dedup run runs them on its
+//! output and fails if deduplicating introduced a problem.
+
+use bw_passport_dedup::item_str;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// One structural problem, about the item labelled `item` (its ID, else its
+/// position) when there is one.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct Problem {
+    pub(crate) item: Option<String>,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.item {
+            Some(item) => write!(f, "item {}: {}", item, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// What [`validate_export`] looks at in one item: small enough to keep for
+/// every item of an input whose items are not kept themselves.
+pub(crate) struct ItemFacts {
+    pub(crate) id: Option<String>,
+    folder: Option<String>,
+    collections: Vec<String>,
+    /// Problems of the item on its own, such as URIs no client can parse.
+    problems: Vec<String>,
+}
+
+impl ItemFacts {
+    pub(crate) fn of(item: &Value) -> Self {
+        let mut problems = Vec::new();
+        for entry in item
+            .pointer("/login/uris")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            // Regular expressions are matched as written, not parsed.
+            if entry.get("match").and_then(Value::as_u64) == Some(4) {
+                continue;
+            }
+            match entry.get("uri") {
+                None | Some(Value::Null) => {}
+                Some(Value::String(uri)) => {
+                    if let Some(reason) = uri_problem(uri) {
+                        problems.push(format!("URI {:?} {}", uri, reason));
+                    }
+                }
+                Some(other) => problems.push(format!("URI {} is not a string", other)),
+            }
+        }
+        Self {
+            id: item_str(item, "id"),
+            folder: item_str(item, "folderId"),
+            collections: item
+                .get("collectionIds")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            problems,
+        }
+    }
+}
+
+/// Checks `items` against the `folders` and `collections` of `root`. The
+/// references are only checked when `root` has the array, since `bw list
+/// items` dumps and other item-only inputs carry neither.
+pub(crate) fn validate_export(root: &Value, items: &[Value]) -> Vec<Problem> {
+    validate_facts(root, items.iter().map(ItemFacts::of))
+}
+
+/// [`validate_export`] for items already reduced to their [`ItemFacts`], in
+/// input order.
+pub(crate) fn validate_facts(
+    root: &Value,
+    items: impl IntoIterator<Item = ItemFacts>,
+) -> Vec<Problem> {
+    let ids = |field: &str| {
+        root.get(field).and_then(Value::as_array).map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| item_str(entry, "id"))
+                .collect::<HashSet<_>>()
+        })
+    };
+    let folders = ids("folders");
+    let collections = ids("collections");
+
+    let mut problems = Vec::new();
+    let mut by_id: BTreeMap<String, usize> = BTreeMap::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let label = item.id.clone().unwrap_or_else(|| format!("#{}", index));
+        let mut problem = |message: String| {
+            problems.push(Problem {
+                item: Some(label.clone()),
+                message,
+            })
+        };
+        if let Some(id) = item.id {
+            *by_id.entry(id).or_default() += 1;
+        }
+
+        if let (Some(folders), Some(folder)) = (&folders, item.folder)
+            && !folders.contains(&folder)
+        {
+            problem(format!("folderId {} is not in the folders", folder));
+        }
+        if let Some(collections) = &collections {
+            for collection in item
+                .collections
+                .into_iter()
+                .filter(|collection| !collections.contains(collection))
+            {
+                problem(format!(
+                    "collectionIds has {}, which is not in the collections",
+                    collection
+                ));
+            }
+        }
+        for message in item.problems {
+            problem(message);
+        }
+    }
+    problems.extend(
+        by_id
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(id, _)| Problem {
+                item: None,
+                message: format!("id {} is used by more than one item", id),
+            }),
+    );
+    problems
+}
+
+/// Why a login URI cannot be parsed, if it cannot. Bitwarden accepts URIs
+/// without a scheme (`example.com`), so only what no client makes sense of
+/// is reported.
+fn uri_problem(uri: &str) -> Option<&'static str> {
+    if uri.trim().is_empty() {
+        return Some("is empty");
+    }
+    if uri.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Some("contains whitespace or control characters");
+    }
+    let (scheme, rest) = uri.split_once("://")?;
+    let mut chars = scheme.chars();
+    let valid_scheme = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !valid_scheme {
+        return Some("has an invalid scheme");
+    }
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    if host.is_empty() && !scheme.eq_ignore_ascii_case("file") {
+        return Some("has no host");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_dangling_references_duplicate_ids_and_bad_uris() {
+        let root = json!({
+            "folders": [{"id": "f1", "name": "Work"}],
+            "collections": [{"id": "c1", "name": "Ops"}],
+        });
+        let items = [
+            json!({"id": "a", "folderId": "f1", "collectionIds": ["c1"], "login": {"uris": [
+                {"uri": "example.com"},
+                {"uri": "androidapp://com.example.app"},
+                {"uri": "^https://.*$", "match": 4},
+                {"uri": "file:///home/me/vault.kdbx"},
+            ]}}),
+            json!({"id": "a", "folderId": "f2", "collectionIds": ["c1", "c9"]}),
+            json!({"login": {"uris": [
+                {"uri": "https://"},
+                {"uri": "https://exa mple.com"},
+                {"uri": "1http://example.com"},
+                {"uri": 5},
+                {"uri": ""},
+            ]}}),
+        ];
+        let problems = validate_export(&root, &items)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "item a: folderId f2 is not in the folders",
+                "item a: collectionIds has c9, which is not in the collections",
+                "item #2: URI \"https://\" has no host",
+                "item #2: URI \"https://exa mple.com\" contains whitespace or control characters",
+                "item #2: URI \"1http://example.com\" has an invalid scheme",
+                "item #2: URI 5 is not a string",
+                "item #2: URI \"\" is empty",
+                "id a is used by more than one item",
+            ]
+        );
+
+        // Item-only inputs have no folders to check against.
+        assert!(validate_export(&json!({}), &items[1..2]).is_empty());
+    }
+}